@@ -0,0 +1,435 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::{File, OpenOptions},
+    future::Future,
+    io::Write,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+use crate::shutdown::{Phase, Shutdown};
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Time allowed for one POST of the [`HttpSink`] by default.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "metrics")]
+static DROPPED: std::sync::LazyLock<crate::metrics::IntCounter> = std::sync::LazyLock::new(|| {
+    crate::counter!(
+        "audit_dropped_total",
+        "Audit records dropped as the writer was behind"
+    )
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// Who did what to which resource, when, and how it ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// set by [`Audit`] to the service writing the record
+    pub service: String,
+    pub actor: String,
+    pub action: String,
+    pub resource: String,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub detail: Value,
+}
+
+impl AuditRecord {
+    /// A successful `action` on `resource` by `actor`, stamped with the current time.
+    pub fn new(actor: &str, action: &str, resource: &str) -> Self {
+        Self {
+            timestamp: crate::clock::unix_millis(),
+            service: String::new(),
+            actor: actor.to_owned(),
+            action: action.to_owned(),
+            resource: resource.to_owned(),
+            outcome: Outcome::Success,
+            request_id: None,
+            detail: Value::Null,
+        }
+    }
+
+    pub const fn outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = outcome;
+        self
+    }
+
+    pub fn request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_owned());
+        self
+    }
+
+    pub fn detail(mut self, detail: Value) -> Self {
+        self.detail = detail;
+        self
+    }
+}
+
+/// Where audit records are appended.
+pub trait AuditSink: Send + Sync {
+    fn name(&self) -> String;
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> SinkFuture<'a>;
+}
+
+/// Appends one JSON record per line to a local file.
+pub struct FileSink {
+    file: Arc<Mutex<File>>,
+}
+
+impl FileSink {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| eyre!("open audit file `{}` failed: {e}", path.display()))?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn name(&self) -> String {
+        "file".to_owned()
+    }
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(record)
+                .map_err(|e| eyre!("serialize audit record failed: {e}"))?;
+            line.push(b'\n');
+            let file = self.file.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.write_all(&line)?;
+                file.sync_data()
+            })
+            .await
+            .map_err(|e| eyre!("audit file task failed: {e}"))?
+            .map_err(|e| eyre!("write audit file failed: {e}"))
+        })
+    }
+}
+
+/// Stores every record under its own key below `prefix`, ordered by time. The keys name
+/// the writing instance, so that replicas of a service sharing the prefix never collide.
+#[cfg(feature = "etcd")]
+pub struct EtcdSink {
+    etcd: crate::etcd::Etcd,
+    prefix: String,
+    instance: String,
+    sequence: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(feature = "etcd")]
+impl EtcdSink {
+    pub fn new(etcd: crate::etcd::Etcd, prefix: &str) -> Self {
+        Self {
+            etcd,
+            prefix: prefix.to_owned(),
+            instance: format!(
+                "{}-{}",
+                std::env::var("HOSTNAME").unwrap_or_default(),
+                std::process::id()
+            ),
+            sequence: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "etcd")]
+impl AuditSink for EtcdSink {
+    fn name(&self) -> String {
+        "etcd".to_owned()
+    }
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let sequence = self
+                .sequence
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let key = format!(
+                "{}{:013}-{}-{}-{sequence:06}",
+                self.prefix, record.timestamp, record.service, self.instance
+            );
+            let value = serde_json::to_vec(record)
+                .map_err(|e| eyre!("serialize audit record failed: {e}"))?;
+            self.etcd.put(key, value, 0).await?;
+            Ok(())
+        })
+    }
+}
+
+/// POSTs every record as JSON to `url`.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+    timeout: Duration,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+            timeout: HTTP_TIMEOUT,
+        }
+    }
+
+    /// Time allowed for one POST, 10 seconds by default.
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl AuditSink for HttpSink {
+    fn name(&self) -> String {
+        "http".to_owned()
+    }
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(record)
+                .map_err(|e| eyre!("serialize audit record failed: {e}"))?;
+            self.client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .timeout(self.timeout)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| eyre!("post audit record to `{}` failed: {e}", self.url))?;
+            Ok(())
+        })
+    }
+}
+
+enum Op {
+    Record(Box<AuditRecord>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes audit records to every sink, in order, from a background task.
+#[derive(Clone)]
+pub struct Audit {
+    service: Arc<str>,
+    sender: mpsc::Sender<Op>,
+}
+
+impl Audit {
+    /// `capacity` bounds the records waiting for the sinks before [`Audit::record`] waits.
+    pub fn new(service: &str, sinks: Vec<Box<dyn AuditSink>>, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel(capacity.max(1));
+        tokio::spawn(async move {
+            while let Some(op) = receiver.recv().await {
+                match op {
+                    Op::Record(record) => {
+                        for sink in &sinks {
+                            if let Err(e) = sink.write(&record).await {
+                                error!(
+                                    "audit {} failed for {} {} by {}: {e}",
+                                    sink.name(),
+                                    record.action,
+                                    record.resource,
+                                    record.actor
+                                );
+                            }
+                        }
+                    }
+                    Op::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Self {
+            service: service.into(),
+            sender,
+        }
+    }
+
+    pub async fn record(&self, mut record: AuditRecord) {
+        record.service = self.service.to_string();
+        if self
+            .sender
+            .send(Op::Record(Box::new(record)))
+            .await
+            .is_err()
+        {
+            error!("audit writer stopped, record dropped");
+        }
+    }
+
+    /// Queues `record` unless the writer is `capacity` records behind, in which case it
+    /// is dropped, counted in `audit_dropped_total`, and `false` returned.
+    pub fn try_record(&self, mut record: AuditRecord) -> bool {
+        record.service = self.service.to_string();
+        match self.sender.try_send(Op::Record(Box::new(record))) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(Op::Record(record))) => {
+                warn!(
+                    "audit writer behind, dropped {} {} by {}",
+                    record.action, record.resource, record.actor
+                );
+                #[cfg(feature = "metrics")]
+                DROPPED.inc();
+                false
+            }
+            Err(_) => {
+                error!("audit writer stopped, record dropped");
+                false
+            }
+        }
+    }
+
+    /// Resolves once every record sent before has been written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Op::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Flushes the pending records in the [`Phase::Cancel`] phase of `shutdown`.
+    pub fn flush_on_shutdown(&self, shutdown: &Shutdown) {
+        let audit = self.clone();
+        shutdown.on(Phase::Cancel, "audit", move || async move {
+            audit.flush().await;
+            Ok(())
+        });
+    }
+
+    /// A hoop auditing every request other than GET, HEAD and OPTIONS, with the
+    /// authenticated caller as actor and a status below 400 as success. Requests never
+    /// wait for the sinks, records beyond `capacity` are dropped as by
+    /// [`Audit::try_record`].
+    #[cfg(feature = "http")]
+    pub fn handler(&self) -> AuditRequests {
+        AuditRequests {
+            audit: self.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+pub struct AuditRequests {
+    audit: Audit,
+}
+
+#[cfg(feature = "http")]
+#[salvo::async_trait]
+impl salvo::Handler for AuditRequests {
+    async fn handle(
+        &self,
+        req: &mut salvo::Request,
+        depot: &mut salvo::Depot,
+        res: &mut salvo::Response,
+        ctrl: &mut salvo::FlowCtrl,
+    ) {
+        use salvo::http::Method;
+
+        if [Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method()) {
+            return;
+        }
+        ctrl.call_next(req, depot, res).await;
+
+        let actor = req
+            .extensions()
+            .get::<crate::auth::Caller>()
+            .map_or("anonymous", |caller| caller.id.as_str());
+        let outcome = match res.status_code {
+            Some(status) if status.as_u16() >= 400 => Outcome::Failure,
+            _ => Outcome::Success,
+        };
+        let mut record =
+            AuditRecord::new(actor, req.method().as_str(), req.uri().path()).outcome(outcome);
+        if let Some(request_id) = crate::restful::request_id(depot) {
+            record = record.request_id(request_id);
+        }
+        self.audit.try_record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Holds every write until `release` has a permit for it.
+    struct Stalled {
+        release: Arc<tokio::sync::Semaphore>,
+        written: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AuditSink for Stalled {
+        fn name(&self) -> String {
+            "stalled".to_owned()
+        }
+
+        fn write<'a>(&'a self, record: &'a AuditRecord) -> SinkFuture<'a> {
+            Box::pin(async move {
+                self.release.acquire().await?.forget();
+                self.written.lock().unwrap().push(record.resource.clone());
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn try_record_drops_when_behind() {
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink = Stalled {
+            release: release.clone(),
+            written: written.clone(),
+        };
+        let audit = Audit::new("test", vec![Box::new(sink)], 1);
+        assert!(audit.try_record(AuditRecord::new("a", "put", "1")));
+        // wait for the writer to take the first record and stall on it
+        while audit.sender.capacity() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(audit.try_record(AuditRecord::new("a", "put", "2")));
+        assert!(!audit.try_record(AuditRecord::new("a", "put", "3")));
+        release.add_permits(2);
+        audit.flush().await;
+        assert_eq!(*written.lock().unwrap(), ["1", "2"]);
+    }
+}
@@ -0,0 +1,410 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use salvo::{
+    extract::{Extractible, Metadata},
+    http::header::AUTHORIZATION,
+    oapi::{Components, EndpointArgRegister, Operation},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::CALError,
+    restful::{path_has_prefix, RESTfulError},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// accepted api keys mapped to the caller id they identify
+    pub api_keys: HashMap<String, String>,
+    pub api_key_header: String,
+    pub jwt: Option<JwtConfig>,
+    /// path prefixes served without credentials, `/admin` checks its own token
+    pub public_paths: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            api_keys: Default::default(),
+            api_key_header: "x-api-key".to_owned(),
+            jwt: None,
+            public_paths: ["/health", "/ready", "/live", "/metrics", "/admin"]
+                .map(str::to_owned)
+                .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    /// one of HS256, HS384, HS512, RS256, RS384, RS512, PS256, PS384, PS512, ES256, ES384, EdDSA
+    pub algorithm: String,
+    /// shared secret for the HS algorithms
+    pub secret: Option<String>,
+    /// PEM encoded public keys for the asymmetric algorithms, keyed by `kid`
+    pub public_keys: HashMap<String, String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// etcd prefix holding additional keys, the last segment of each key is its `kid`
+    pub etcd_prefix: Option<String>,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: "HS256".to_owned(),
+            secret: None,
+            public_keys: Default::default(),
+            issuer: None,
+            audience: None,
+            etcd_prefix: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallerKind {
+    ApiKey,
+    Jwt,
+}
+
+/// The authenticated caller of the current request, taken as a handler argument.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Caller {
+    pub id: String,
+    pub kind: CallerKind,
+    /// the verified claims of a jwt caller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims: Option<serde_json::Value>,
+}
+
+impl<'ex> Extractible<'ex> for Caller {
+    fn metadata() -> &'ex Metadata {
+        static METADATA: LazyLock<Metadata> = LazyLock::new(|| Metadata::new("Caller"));
+        &METADATA
+    }
+
+    async fn extract(
+        req: &'ex mut Request,
+    ) -> Result<Self, impl Writer + Send + std::fmt::Debug + 'static> {
+        req.extensions()
+            .get::<Caller>()
+            .cloned()
+            .ok_or(RESTfulError {
+                code: CALError::Unauthorized.into(),
+                err: CALError::Unauthorized.to_string(),
+            })
+    }
+}
+
+impl EndpointArgRegister for Caller {
+    fn register(_components: &mut Components, _operation: &mut Operation, _arg: &str) {}
+}
+
+fn decoding_key(algorithm: Algorithm, material: &str) -> Result<DecodingKey> {
+    let pem = material.as_bytes();
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Ok(DecodingKey::from_secret(pem)),
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem),
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+    }
+    .map_err(|e| eyre!("parse jwt key failed: {e}"))
+}
+
+struct Jwt {
+    #[cfg_attr(not(feature = "etcd"), allow(dead_code))]
+    algorithm: Algorithm,
+    validation: Validation,
+    keys: HashMap<String, DecodingKey>,
+    etcd_keys: RwLock<HashMap<String, DecodingKey>>,
+}
+
+impl Jwt {
+    fn new(config: &JwtConfig) -> Result<Self> {
+        let algorithm = Algorithm::from_str(&config.algorithm)
+            .map_err(|e| eyre!("unsupported jwt algorithm `{}`: {e}", config.algorithm))?;
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let mut keys = HashMap::new();
+        if let Some(secret) = &config.secret {
+            keys.insert(String::new(), decoding_key(algorithm, secret)?);
+        }
+        for (kid, pem) in &config.public_keys {
+            keys.insert(kid.clone(), decoding_key(algorithm, pem)?);
+        }
+        Ok(Self {
+            algorithm,
+            validation,
+            keys,
+            etcd_keys: Default::default(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<serde_json::Value> {
+        let kid = decode_header(token)
+            .map_err(|e| eyre!("invalid jwt: {e}"))?
+            .kid;
+        let etcd_keys = self.etcd_keys.read().unwrap_or_else(|e| e.into_inner());
+        let candidates: Vec<&DecodingKey> = match kid
+            .as_ref()
+            .and_then(|kid| self.keys.get(kid).or_else(|| etcd_keys.get(kid)))
+        {
+            Some(key) => vec![key],
+            None => self.keys.values().chain(etcd_keys.values()).collect(),
+        };
+        let mut last_error = eyre!("no jwt key configured");
+        for key in candidates {
+            match decode::<serde_json::Value>(token, key, &self.validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = eyre!("invalid jwt: {e}"),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Authenticates requests by api key header or `Authorization: Bearer <jwt>`, answering
+/// 401 otherwise. The authenticated [`Caller`] is available to handlers as an argument.
+#[derive(Clone)]
+pub struct Auth {
+    config: Arc<AuthConfig>,
+    jwt: Option<Arc<Jwt>>,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Result<Self> {
+        let jwt = config.jwt.as_ref().map(Jwt::new).transpose()?.map(Arc::new);
+        Ok(Self {
+            config: Arc::new(config),
+            jwt,
+        })
+    }
+
+    fn authenticate(&self, req: &Request) -> Result<Caller> {
+        if let Some(key) = req
+            .headers()
+            .get(&self.config.api_key_header)
+            .and_then(|v| v.to_str().ok())
+        {
+            return self
+                .config
+                .api_keys
+                .get(key)
+                .map(|id| Caller {
+                    id: id.clone(),
+                    kind: CallerKind::ApiKey,
+                    claims: None,
+                })
+                .ok_or_else(|| eyre!("invalid api key"));
+        }
+
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| eyre!("missing credentials"))?;
+        let claims = self
+            .jwt
+            .as_ref()
+            .ok_or_else(|| eyre!("jwt not enabled"))?
+            .verify(token)?;
+        let id = claims["sub"]
+            .as_str()
+            .filter(|sub| !sub.is_empty())
+            .ok_or_else(|| eyre!("jwt without sub"))?
+            .to_owned();
+        Ok(Caller {
+            id,
+            kind: CallerKind::Jwt,
+            claims: Some(claims),
+        })
+    }
+
+    /// Replaces the jwt keys loaded from `jwt.etcd_prefix`.
+    #[cfg(feature = "etcd")]
+    pub async fn load_etcd_keys(&self, etcd: &crate::etcd::Etcd) -> Result<()> {
+        let (Some(jwt), Some(prefix)) = (
+            &self.jwt,
+            self.config
+                .jwt
+                .as_ref()
+                .and_then(|c| c.etcd_prefix.as_ref()),
+        ) else {
+            return Ok(());
+        };
+        let mut keys = HashMap::new();
+        for kv in etcd.get_with_prefix(prefix.as_str()).await? {
+            let name = kv
+                .key_str()
+                .map_err(|e| eyre!("invalid jwt key name: {e}"))?;
+            let kid = name.rsplit('/').next().unwrap_or(name).to_owned();
+            let material = kv
+                .value_str()
+                .map_err(|e| eyre!("invalid jwt key `{name}`: {e}"))?;
+            keys.insert(kid, decoding_key(jwt.algorithm, material)?);
+        }
+        *jwt.etcd_keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+        Ok(())
+    }
+
+    /// Reloads the etcd jwt keys every `interval` until `scope` is cancelled, keeping
+    /// the previous set on failure.
+    #[cfg(feature = "etcd")]
+    pub fn watch_etcd_keys(
+        &self,
+        scope: &crate::shutdown::TaskScope,
+        etcd: crate::etcd::Etcd,
+        interval: std::time::Duration,
+    ) {
+        let auth = self.clone();
+        scope.spawn("jwt_keys", async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = auth.load_etcd_keys(&etcd).await {
+                    tracing::warn!("reload jwt keys failed: {e}");
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Handler for Auth {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let path = req.uri().path();
+        if self
+            .config
+            .public_paths
+            .iter()
+            .any(|prefix| path_has_prefix(path, prefix))
+        {
+            return;
+        }
+        match self.authenticate(req) {
+            Ok(caller) => {
+                req.extensions_mut().insert(caller);
+            }
+            Err(e) => {
+                RESTfulError {
+                    code: CALError::Unauthorized.into(),
+                    err: e.to_string(),
+                }
+                .write(req, depot, res)
+                .await;
+                ctrl.skip_rest();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::new(AuthConfig {
+            api_keys: HashMap::from([("key".to_owned(), "tool".to_owned())]),
+            jwt: Some(JwtConfig {
+                secret: Some("secret".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn bearer(claims: serde_json::Value) -> Request {
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let mut req = Request::new();
+        req.headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        req
+    }
+
+    #[test]
+    fn jwt_caller_is_its_sub() {
+        let exp = crate::clock::unix_secs() + 60;
+        let caller = auth()
+            .authenticate(&bearer(json!({"sub": "alice", "exp": exp})))
+            .unwrap();
+        assert_eq!(caller.id, "alice");
+        assert_eq!(caller.kind, CallerKind::Jwt);
+    }
+
+    #[test]
+    fn jwt_without_sub_is_rejected() {
+        let exp = crate::clock::unix_secs() + 60;
+        let auth = auth();
+        for claims in [
+            json!({"exp": exp}),
+            json!({"sub": "", "exp": exp}),
+            json!({"sub": 7, "exp": exp}),
+        ] {
+            assert!(auth.authenticate(&bearer(claims)).is_err());
+        }
+    }
+
+    #[test]
+    fn api_key_caller() {
+        let mut req = Request::new();
+        req.headers_mut()
+            .insert("x-api-key", "key".parse().unwrap());
+        assert_eq!(auth().authenticate(&req).unwrap().id, "tool");
+        req.headers_mut()
+            .insert("x-api-key", "other".parse().unwrap());
+        assert!(auth().authenticate(&req).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{debug, error};
+
+use crate::shutdown::{Phase, Shutdown};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    /// items that flush a batch at once
+    pub max_items: usize,
+    /// milliseconds the first item of a batch waits for more at most
+    pub max_delay: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_items: 100,
+            max_delay: 100,
+        }
+    }
+}
+
+enum Command<T> {
+    Add(T),
+    Flush(oneshot::Sender<()>),
+    Close(oneshot::Sender<()>),
+}
+
+#[cfg(feature = "metrics")]
+static FLUSHES: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "batcher_flushes_total",
+            "Batches flushed by a batcher by outcome: success or failure",
+            ["batcher", "outcome"]
+        )
+    });
+
+/// Collects items into batches handed to an async flush once `max_items` are in or the
+/// first of them waited `max_delay`, whichever comes first. Adding waits while a flush
+/// is in progress and the next batch is full, so a slow flush pushes back on the
+/// producers. Cloning is cheap and every clone adds to the same batches.
+pub struct Batcher<T> {
+    name: Arc<str>,
+    sender: mpsc::Sender<Command<T>>,
+}
+
+impl<T> Clone for Batcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    /// Starts the task flushing the batches with `flush`. A batch that failed to flush
+    /// is logged and dropped, retry within `flush` to keep it.
+    pub fn new<F, Fut>(name: &str, config: BatchConfig, flush: F) -> Self
+    where
+        F: Fn(Vec<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(config.max_items.max(1));
+        let name: Arc<str> = name.into();
+        tokio::spawn(collect(name.clone(), config, receiver, flush));
+        Self { name, sender }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn add(&self, item: T) -> Result<()> {
+        self.send(Command::Add(item)).await
+    }
+
+    /// Flushes the items added so far, returns once they are.
+    pub async fn flush(&self) -> Result<()> {
+        let (done, flushed) = oneshot::channel();
+        self.send(Command::Flush(done)).await?;
+        flushed.await.map_err(|_| self.closed())
+    }
+
+    /// Flushes the items added so far and stops the batcher, adding to any clone fails
+    /// from then on.
+    pub async fn close(&self) -> Result<()> {
+        let (done, closed) = oneshot::channel();
+        self.send(Command::Close(done)).await?;
+        closed.await.map_err(|_| self.closed())
+    }
+
+    /// Closes the batcher in the [`Phase::Drain`] phase of `shutdown`.
+    pub fn close_on_shutdown(&self, shutdown: &Shutdown) {
+        let batcher = self.clone();
+        let name = format!("batcher {}", self.name);
+        shutdown.on(
+            Phase::Drain,
+            &name,
+            move || async move { batcher.close().await },
+        );
+    }
+
+    async fn send(&self, command: Command<T>) -> Result<()> {
+        self.sender.send(command).await.map_err(|_| self.closed())
+    }
+
+    fn closed(&self) -> color_eyre::Report {
+        eyre!("batcher {} is closed", self.name)
+    }
+}
+
+async fn collect<T, F, Fut>(
+    name: Arc<str>,
+    config: BatchConfig,
+    mut receiver: mpsc::Receiver<Command<T>>,
+    flush: F,
+) where
+    F: Fn(Vec<T>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let max_items = config.max_items.max(1);
+    let max_delay = Duration::from_millis(config.max_delay);
+    let mut batch = Vec::with_capacity(max_items);
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let command = match deadline {
+            Some(at) => tokio::select! {
+                command = receiver.recv() => command,
+                _ = tokio::time::sleep_until(at) => {
+                    deadline = None;
+                    run(&name, &flush, std::mem::take(&mut batch)).await;
+                    continue;
+                }
+            },
+            None => receiver.recv().await,
+        };
+        match command {
+            Some(Command::Add(item)) => {
+                batch.push(item);
+                if batch.len() >= max_items {
+                    deadline = None;
+                    run(
+                        &name,
+                        &flush,
+                        std::mem::replace(&mut batch, Vec::with_capacity(max_items)),
+                    )
+                    .await;
+                } else if deadline.is_none() {
+                    deadline = Some(Instant::now() + max_delay);
+                }
+            }
+            Some(Command::Flush(done)) => {
+                deadline = None;
+                run(&name, &flush, std::mem::take(&mut batch)).await;
+                let _ = done.send(());
+            }
+            Some(Command::Close(done)) => {
+                receiver.close();
+                // commands sent before the close was seen
+                let mut waiting = vec![done];
+                while let Ok(command) = receiver.try_recv() {
+                    match command {
+                        Command::Add(item) => batch.push(item),
+                        Command::Flush(done) | Command::Close(done) => waiting.push(done),
+                    }
+                }
+                while batch.len() > max_items {
+                    let rest = batch.split_off(max_items);
+                    run(&name, &flush, std::mem::replace(&mut batch, rest)).await;
+                }
+                run(&name, &flush, batch).await;
+                for done in waiting {
+                    let _ = done.send(());
+                }
+                return;
+            }
+            None => {
+                run(&name, &flush, batch).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn run<T, F, Fut>(name: &str, flush: &F, batch: Vec<T>)
+where
+    F: Fn(Vec<T>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if batch.is_empty() {
+        return;
+    }
+    let items = batch.len();
+    let result = flush(batch).await;
+    #[cfg(feature = "metrics")]
+    FLUSHES
+        .with_label_values(&[name, if result.is_ok() { "success" } else { "failure" }])
+        .inc();
+    match result {
+        Ok(()) => debug!("batcher {name} flushed {items} items"),
+        Err(e) => error!("batcher {name} failed to flush {items} items: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    type Batches = Arc<Mutex<Vec<Vec<u32>>>>;
+
+    /// A batcher recording the batches it flushed.
+    fn recorded(max_items: usize, max_delay: u64) -> (Batcher<u32>, Batches) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let config = BatchConfig {
+            max_items,
+            max_delay,
+        };
+        let batcher = Batcher::new("test", config, {
+            let batches = batches.clone();
+            move |batch| {
+                batches.lock().unwrap().push(batch);
+                async { Ok(()) }
+            }
+        });
+        (batcher, batches)
+    }
+
+    #[tokio::test]
+    async fn flushes_full_batches() {
+        let (batcher, batches) = recorded(3, 60000);
+        for item in 1..=7 {
+            batcher.add(item).await.unwrap();
+        }
+        // the flush is handled after the adds sent before it
+        batcher.flush().await.unwrap();
+        assert_eq!(
+            *batches.lock().unwrap(),
+            [vec![1, 2, 3], vec![4, 5, 6], vec![7]]
+        );
+    }
+
+    #[tokio::test]
+    async fn flushes_after_max_delay() {
+        let (batcher, batches) = recorded(100, 20);
+        batcher.add(1).await.unwrap();
+        batcher.add(2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(batches.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*batches.lock().unwrap(), [vec![1, 2]]);
+        // the delay starts again with the next first item
+        batcher.add(3).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(batches.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn explicit_flush() {
+        let (batcher, batches) = recorded(100, 60000);
+        // nothing to flush, no empty batch
+        batcher.flush().await.unwrap();
+        batcher.add(1).await.unwrap();
+        batcher.clone().add(2).await.unwrap();
+        batcher.flush().await.unwrap();
+        assert_eq!(*batches.lock().unwrap(), [vec![1, 2]]);
+    }
+
+    #[tokio::test]
+    async fn close_drains() {
+        let (batcher, batches) = recorded(2, 60000);
+        for item in 1..=3 {
+            batcher.add(item).await.unwrap();
+        }
+        batcher.close().await.unwrap();
+        assert_eq!(*batches.lock().unwrap(), [vec![1, 2], vec![3]]);
+        assert!(batcher.add(4).await.is_err());
+        assert!(batcher.flush().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_batch_dropped() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let config = BatchConfig {
+            max_items: 2,
+            max_delay: 60000,
+        };
+        let batcher = Batcher::new("test", config, {
+            let attempts = attempts.clone();
+            move |batch: Vec<u32>| {
+                attempts.lock().unwrap().push(batch.clone());
+                async move {
+                    if batch.contains(&1) {
+                        Err(eyre!("unavailable"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            }
+        });
+        for item in 1..=3 {
+            batcher.add(item).await.unwrap();
+        }
+        batcher.close().await.unwrap();
+        // the failed batch is not tried again
+        assert_eq!(*attempts.lock().unwrap(), [vec![1, 2], vec![3]]);
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronous wrappers of the etcd and redis clients, for CLI tools and build scripts.
+//! Every call blocks the calling thread on a runtime shared by the process, started on
+//! first use, whose worker keeps the connections alive between calls. Calling them from
+//! async code panics, use the async clients there.
+
+use std::{future::Future, sync::LazyLock};
+
+use tokio::runtime::Runtime;
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("common-rs-blocking")
+        .enable_all()
+        .build()
+        .expect("failed to start the blocking runtime")
+});
+
+/// Runs `future` to completion on the shared runtime, for the async APIs without a
+/// wrapper here.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.block_on(future)
+}
+
+/// A blocking [`crate::etcd::Etcd`].
+#[cfg(feature = "etcd")]
+#[derive(Clone)]
+pub struct Etcd {
+    inner: crate::etcd::Etcd,
+}
+
+#[cfg(feature = "etcd")]
+impl Etcd {
+    pub fn new(config: &crate::etcd::EtcdConfig) -> color_eyre::Result<Self> {
+        Ok(Self {
+            inner: block_on(crate::etcd::Etcd::new(config))?,
+        })
+    }
+
+    /// The async client, to be used with [`block_on`].
+    pub const fn inner(&self) -> &crate::etcd::Etcd {
+        &self.inner
+    }
+
+    pub fn put(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: i64,
+    ) -> color_eyre::Result<Option<crate::etcd::KeyValue>> {
+        block_on(self.inner.put(key, value, ttl))
+    }
+
+    pub fn get(&self, key: impl Into<Vec<u8>>) -> color_eyre::Result<crate::etcd::KeyValue> {
+        block_on(self.inner.get(key))
+    }
+
+    pub fn get_with_prefix(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> color_eyre::Result<Vec<crate::etcd::KeyValue>> {
+        block_on(self.inner.get_with_prefix(key))
+    }
+
+    pub fn delete(&self, key: impl Into<Vec<u8>>) -> color_eyre::Result<i64> {
+        block_on(self.inner.delete(key))
+    }
+
+    pub fn delete_with_prefix(&self, key: impl Into<Vec<u8>>) -> color_eyre::Result<i64> {
+        block_on(self.inner.delete_with_prefix(key))
+    }
+
+    pub fn touch(&self, key: impl Into<Vec<u8>>) -> color_eyre::Result<()> {
+        block_on(self.inner.touch(key))
+    }
+}
+
+/// A blocking [`crate::redis::Redis`].
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct Redis {
+    inner: crate::redis::Redis,
+}
+
+#[cfg(feature = "redis")]
+impl Redis {
+    pub fn new(config: &crate::redis::RedisConfig) -> color_eyre::Result<Self> {
+        Ok(Self {
+            inner: block_on(crate::redis::Redis::new(config))?,
+        })
+    }
+
+    /// The async client, to be used with [`block_on`].
+    pub const fn inner(&self) -> &crate::redis::Redis {
+        &self.inner
+    }
+
+    /// See [`crate::redis::Redis::query`].
+    pub fn query<T: crate::redis::FromRedisValue>(
+        &self,
+        cmd: &crate::redis::Cmd,
+    ) -> color_eyre::Result<T> {
+        block_on(self.inner.query(cmd))
+    }
+
+    /// See [`crate::redis::Redis::query_idempotent`].
+    pub fn query_idempotent<T: crate::redis::FromRedisValue>(
+        &self,
+        cmd: &crate::redis::Cmd,
+    ) -> color_eyre::Result<T> {
+        block_on(self.inner.query_idempotent(cmd))
+    }
+
+    pub fn rate_limit(&self, key: &str, rate: f64, burst: u64) -> color_eyre::Result<bool> {
+        block_on(self.inner.rate_limit(key, rate, burst))
+    }
+}
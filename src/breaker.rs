@@ -0,0 +1,447 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BreakerConfig {
+    /// share of failed calls within a window that opens the breaker, 0 disables it
+    pub failure_rate: f64,
+    /// calls a window needs before its failure rate counts
+    pub min_calls: u32,
+    /// milliseconds the failure rate is measured over
+    pub window: u64,
+    /// milliseconds the breaker fails calls fast before letting probes through
+    pub open_duration: u64,
+    /// probes that must all succeed to close the breaker again
+    pub probes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            min_calls: 10,
+            window: 10000,
+            open_duration: 5000,
+            probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// calls pass and are counted
+    Closed,
+    /// calls fail fast until `open_duration` passes
+    Open,
+    /// only probes pass, the outcome of which closes or reopens the breaker
+    HalfOpen,
+}
+
+/// Returned instead of running a call while the breaker is open.
+#[derive(Debug)]
+pub enum BreakerError<E> {
+    Open(String),
+    Inner(E),
+}
+
+impl<E: Display> Display for BreakerError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(name) => write!(f, "circuit breaker `{name}` is open"),
+            Self::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for BreakerError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Open(_) => None,
+            Self::Inner(e) => Some(e),
+        }
+    }
+}
+
+struct Window {
+    state: BreakerState,
+    since: Instant,
+    calls: u32,
+    failures: u32,
+    probes: u32,
+    probe_successes: u32,
+}
+
+/// Stops calling a backend once too many calls to it fail, so callers fail fast instead
+/// of each waiting out a timeout, and lets a few probes through after a while to find
+/// out whether it came back.
+pub struct CircuitBreaker {
+    name: String,
+    config: BreakerConfig,
+    window: Mutex<Window>,
+}
+
+#[cfg(feature = "metrics")]
+static BREAKER_STATE: std::sync::LazyLock<crate::metrics::IntGaugeVec> =
+    std::sync::LazyLock::new(|| {
+        crate::gauge!(
+            "circuit_breaker_open",
+            "Whether a circuit breaker fails calls fast, 2 while half-open",
+            ["breaker"]
+        )
+    });
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: BreakerConfig) -> Self {
+        Self {
+            name: name.to_owned(),
+            config,
+            window: Mutex::new(Window {
+                state: BreakerState::Closed,
+                since: Instant::now(),
+                calls: 0,
+                failures: 0,
+                probes: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let mut window = self.lock();
+        self.advance(&mut window);
+        window.state
+    }
+
+    /// A permit to make one call, `None` while the breaker is open or all probes of the
+    /// half-open breaker are out. A permit dropped without an outcome counts for nothing.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        if self.config.failure_rate <= 0.0 {
+            return Some(Permit {
+                breaker: self,
+                probe: false,
+            });
+        }
+        let mut window = self.lock();
+        self.advance(&mut window);
+        let probe = match window.state {
+            BreakerState::Closed => false,
+            BreakerState::Open => return None,
+            BreakerState::HalfOpen if window.probes >= self.config.probes.max(1) => return None,
+            BreakerState::HalfOpen => {
+                window.probes += 1;
+                true
+            }
+        };
+        Some(Permit {
+            breaker: self,
+            probe,
+        })
+    }
+
+    /// Runs `op` unless the breaker is open, counting the errors `is_failure` picks as
+    /// failures of the backend and every other outcome as a success.
+    pub async fn call<T, E, Fut>(
+        &self,
+        op: Fut,
+        is_failure: impl Fn(&E) -> bool,
+    ) -> Result<T, BreakerError<E>>
+    where
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let permit = self
+            .try_acquire()
+            .ok_or_else(|| BreakerError::Open(self.name.clone()))?;
+        let result = op.await;
+        match &result {
+            Err(e) if is_failure(e) => permit.failure(),
+            _ => permit.success(),
+        }
+        result.map_err(BreakerError::Inner)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Half-opens an open breaker once `open_duration` passed, starts a new window of a
+    /// closed one once `window` passed.
+    fn advance(&self, window: &mut Window) {
+        match window.state {
+            BreakerState::Open
+                if window.since.elapsed() >= Duration::from_millis(self.config.open_duration) =>
+            {
+                self.transit(window, BreakerState::HalfOpen);
+            }
+            BreakerState::Closed
+                if window.since.elapsed() >= Duration::from_millis(self.config.window) =>
+            {
+                window.since = Instant::now();
+                window.calls = 0;
+                window.failures = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn transit(&self, window: &mut Window, state: BreakerState) {
+        match state {
+            BreakerState::Open if window.state == BreakerState::HalfOpen => {
+                warn!(
+                    "circuit breaker {} open again after a failed probe",
+                    self.name
+                )
+            }
+            BreakerState::Open => warn!(
+                "circuit breaker {} open after {}/{} failed calls",
+                self.name, window.failures, window.calls
+            ),
+            BreakerState::HalfOpen => info!("circuit breaker {} half-open", self.name),
+            BreakerState::Closed => info!("circuit breaker {} closed", self.name),
+        }
+        #[cfg(feature = "metrics")]
+        BREAKER_STATE
+            .with_label_values(&[&self.name])
+            .set(match state {
+                BreakerState::Closed => 0,
+                BreakerState::Open => 1,
+                BreakerState::HalfOpen => 2,
+            });
+        *window = Window {
+            state,
+            since: Instant::now(),
+            calls: 0,
+            failures: 0,
+            probes: 0,
+            probe_successes: 0,
+        };
+    }
+
+    fn record(&self, probe: bool, success: bool) {
+        let mut window = self.lock();
+        match window.state {
+            BreakerState::HalfOpen if probe => {
+                if !success {
+                    self.transit(&mut window, BreakerState::Open);
+                    return;
+                }
+                window.probe_successes += 1;
+                if window.probe_successes >= self.config.probes.max(1) {
+                    self.transit(&mut window, BreakerState::Closed);
+                }
+            }
+            BreakerState::Closed => {
+                window.calls += 1;
+                if !success {
+                    window.failures += 1;
+                }
+                if window.calls >= self.config.min_calls.max(1)
+                    && window.failures as f64 >= window.calls as f64 * self.config.failure_rate
+                {
+                    self.transit(&mut window, BreakerState::Open);
+                }
+            }
+            // outcomes of calls started before the breaker changed state
+            _ => {}
+        }
+    }
+
+    fn release(&self) {
+        let mut window = self.lock();
+        if window.state == BreakerState::HalfOpen {
+            window.probes = window.probes.saturating_sub(1);
+        }
+    }
+}
+
+/// Leave to make one call through a [`CircuitBreaker`], report its outcome with
+/// [`Permit::success`] or [`Permit::failure`].
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(self) {
+        self.finish(true);
+    }
+
+    pub fn failure(self) {
+        self.finish(false);
+    }
+
+    fn finish(self, success: bool) {
+        if self.breaker.config.failure_rate > 0.0 {
+            self.breaker.record(self.probe, success);
+        }
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(min_calls: u32, probes: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_rate: 0.5,
+                min_calls,
+                window: 60000,
+                open_duration: 20,
+                probes,
+            },
+        )
+    }
+
+    fn fail(breaker: &CircuitBreaker, calls: u32) {
+        for _ in 0..calls {
+            breaker.try_acquire().unwrap().failure();
+        }
+    }
+
+    /// Runs a future that completes without waiting, the breaker comes without a runtime.
+    fn ready<T>(fut: impl Future<Output = T>) -> T {
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::pin::pin!(fut).poll(&mut cx) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("future not ready"),
+        }
+    }
+
+    fn open_then_half_open(breaker: &CircuitBreaker) {
+        fail(breaker, breaker.config.min_calls);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn stays_closed_below_min_calls() {
+        let breaker = breaker(4, 1);
+        fail(&breaker, 3);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        fail(&breaker, 1);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn opens_at_failure_rate() {
+        let breaker = breaker(4, 1);
+        // 1 failure in 4 calls stays below half
+        breaker.try_acquire().unwrap().failure();
+        for _ in 0..3 {
+            breaker.try_acquire().unwrap().success();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        // 3 in 6 reach it
+        fail(&breaker, 1);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        fail(&breaker, 1);
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn disabled_never_opens() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_rate: 0.0,
+                min_calls: 1,
+                ..Default::default()
+            },
+        );
+        fail(&breaker, 10);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn probes_close_again() {
+        let breaker = breaker(2, 2);
+        open_then_half_open(&breaker);
+        let first = breaker.try_acquire().unwrap();
+        let second = breaker.try_acquire().unwrap();
+        // every probe is out
+        assert!(breaker.try_acquire().is_none());
+        first.success();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        second.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.try_acquire().is_some());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = breaker(2, 2);
+        open_then_half_open(&breaker);
+        let first = breaker.try_acquire().unwrap();
+        let second = breaker.try_acquire().unwrap();
+        first.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        // the outcome of the probe still out no longer counts
+        second.success();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn dropped_probe_released() {
+        let breaker = breaker(2, 1);
+        open_then_half_open(&breaker);
+        drop(breaker.try_acquire().unwrap());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.try_acquire().unwrap().success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn call_counts_picked_failures() {
+        let breaker = breaker(2, 1);
+        for _ in 0..2 {
+            let result = ready(breaker.call(async { Err::<(), _>("not found") }, |_| false));
+            assert!(matches!(result, Err(BreakerError::Inner("not found"))));
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        for _ in 0..2 {
+            let _ = ready(breaker.call(async { Err::<(), _>("unavailable") }, |_| true));
+        }
+        let result = ready(breaker.call(async { Ok::<_, &str>(()) }, |_| true));
+        assert!(matches!(result, Err(BreakerError::Open(name)) if name == "test"));
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::Future,
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::Result;
+
+use crate::error::CommonError;
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Milliseconds since the unix epoch, 0 if the system clock is before it.
+pub fn unix_millis() -> u64 {
+    unix_time().as_millis() as u64
+}
+
+/// Nanoseconds since the unix epoch, 0 if the system clock is before it.
+pub fn unix_nanos() -> u64 {
+    unix_time().as_nanos() as u64
+}
+
+/// Seconds since the unix epoch, 0 if the system clock is before it.
+pub fn unix_secs() -> u64 {
+    unix_time().as_secs()
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Monotonic time since the clock was first read in this process, unaffected by the
+/// system clock being set.
+pub fn uptime() -> Duration {
+    START.elapsed()
+}
+
+/// A ttl as the whole seconds etcd leases and redis `EX` take, rounded up so that a
+/// sub-second ttl does not turn into 0, which means no ttl to both.
+pub fn ttl_secs(ttl: Duration) -> i64 {
+    let secs = ttl
+        .as_secs()
+        .saturating_add(u64::from(ttl.subsec_nanos() > 0));
+    secs.try_into().unwrap_or(i64::MAX)
+}
+
+/// How often a key with a ttl of `ttl` seconds is renewed: every half ttl, at least
+/// every 500 milliseconds.
+pub fn renew_interval(ttl: i64) -> Duration {
+    (Duration::from_secs(ttl.max(0) as u64) / 2).max(Duration::from_millis(500))
+}
+
+/// The instant an operation has to be done by. Deadlines nest: [`timeout_at`] runs a
+/// future under the earlier of its own and the caller's deadline, which the calls made
+/// by that future find with [`Deadline::current`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub const fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    /// The deadline the current task runs under, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|deadline| *deadline).ok()
+    }
+
+    pub const fn instant(&self) -> Instant {
+        self.0
+    }
+
+    /// Time left until the deadline, zero once it passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0 <= Instant::now()
+    }
+
+    /// The earlier of `self` and `timeout` from now, for a call that should not take
+    /// longer than `timeout` on its own.
+    pub fn shorten(self, timeout: Duration) -> Self {
+        self.min(Self::after(timeout))
+    }
+}
+
+impl From<Instant> for Deadline {
+    fn from(instant: Instant) -> Self {
+        Self(instant)
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Deadline;
+}
+
+/// Runs `fut` until `deadline` or the deadline of the caller, whichever comes first,
+/// with [`Deadline::current`] returning that one within `fut`.
+pub async fn timeout_at<F: Future>(deadline: Deadline, fut: F) -> Result<F::Output> {
+    let deadline = Deadline::current().map_or(deadline, |current| current.min(deadline));
+    CURRENT
+        .scope(
+            deadline,
+            tokio::time::timeout_at(deadline.instant().into(), fut),
+        )
+        .await
+        .map_err(|_| CommonError::Timeout("deadline exceeded".to_owned()).into())
+}
+
+/// [`timeout_at`] the deadline `timeout` from now.
+pub async fn timeout<F: Future>(timeout: Duration, fut: F) -> Result<F::Output> {
+    timeout_at(Deadline::after(timeout), fut).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_secs_rounds_up() {
+        assert_eq!(ttl_secs(Duration::ZERO), 0);
+        assert_eq!(ttl_secs(Duration::from_millis(1)), 1);
+        assert_eq!(ttl_secs(Duration::from_secs(5)), 5);
+        assert_eq!(ttl_secs(Duration::from_millis(5001)), 6);
+        assert_eq!(ttl_secs(Duration::MAX), i64::MAX);
+    }
+
+    #[test]
+    fn renew_interval_half_ttl() {
+        assert_eq!(renew_interval(10), Duration::from_secs(5));
+        assert_eq!(renew_interval(3), Duration::from_millis(1500));
+        // never more often than every 500 milliseconds
+        assert_eq!(renew_interval(1), Duration::from_millis(500));
+        assert_eq!(renew_interval(0), Duration::from_millis(500));
+        assert_eq!(renew_interval(-1), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn deadline_shorten() {
+        let deadline = Deadline::after(Duration::from_secs(10));
+        assert_eq!(deadline.shorten(Duration::from_secs(60)), deadline);
+        let shortened = deadline.shorten(Duration::from_secs(1));
+        assert!(shortened < deadline && shortened.remaining() <= Duration::from_secs(1));
+        let passed = Deadline::at(Instant::now() - Duration::from_millis(1));
+        assert!(passed.is_expired());
+        assert_eq!(passed.remaining(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn deadlines_nest() {
+        assert_eq!(Deadline::current(), None);
+        let outer = Deadline::after(Duration::from_millis(100));
+        let later = Deadline::after(Duration::from_secs(10));
+        let earlier = Deadline::after(Duration::from_millis(10));
+        let (inner, nested) = timeout_at(outer, async {
+            let inner = timeout_at(later, async { Deadline::current() })
+                .await
+                .unwrap();
+            let nested = timeout_at(earlier, async { Deadline::current() })
+                .await
+                .unwrap();
+            (inner, nested)
+        })
+        .await
+        .unwrap();
+        // an inner call never outlasts the caller, but may end sooner
+        assert_eq!(inner, Some(outer));
+        assert_eq!(nested, Some(earlier));
+        assert_eq!(Deadline::current(), None);
+    }
+
+    #[tokio::test]
+    async fn timeout_cuts_off() {
+        let e = timeout(Duration::from_millis(10), std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<CommonError>(),
+            Some(CommonError::Timeout(_))
+        ));
+        let start = Instant::now();
+        // the deadline of the caller cuts the longer inner timeout short
+        let inner = timeout(Duration::from_millis(20), async {
+            timeout(Duration::from_secs(10), std::future::pending::<()>()).await
+        })
+        .await;
+        assert!(matches!(inner, Ok(Err(_)) | Err(_)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -12,7 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fmt::Debug, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Result};
@@ -22,15 +27,78 @@ use parking_lot::RwLock;
 use serde::Deserialize;
 use tracing::{error, info};
 
+use crate::error::CommonError;
+
+/// Top level key listing the config files to include, resolved relative to
+/// the including file. Included files are loaded first, so the including file
+/// overrides whatever they define. The key itself is left out of the loaded config.
+pub const INCLUDE_KEY: &str = "include";
+
 pub fn file_config<T: for<'a> Deserialize<'a>>(path: &str) -> Result<T> {
-    let settings = Config::builder()
-        .add_source(config::File::with_name(path))
+    let mut settings = config_files(path)?
+        .iter()
+        .fold(Config::builder(), |builder, file| {
+            builder.add_source(config::File::with_name(&file.to_string_lossy()))
+        })
         .build()
+        .and_then(|settings| settings.try_deserialize::<Map<String, config::Value>>())
         .map_err(|e| eyre!("load file config failed: {}", e))?;
+    settings.remove(INCLUDE_KEY);
 
-    settings
+    config::Value::from(settings)
         .try_deserialize::<T>()
-        .map_err(|e| eyre!("deserialize config failed: {}", e))
+        .map_err(|e| {
+            CommonError::Serde {
+                context: "deserialize config".to_owned(),
+                source: e.into(),
+            }
+            .into()
+        })
+}
+
+/// The config file at `path` and the ones it includes, included files first and each file
+/// once.
+fn config_files(path: &str) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    collect_includes(Path::new(path), &mut vec![], &mut files)?;
+    Ok(files)
+}
+
+/// Adds `path` after its includes to `files`, by canonical path so that a file included
+/// through different relative paths is loaded once.
+fn collect_includes(path: &Path, stack: &mut Vec<PathBuf>, files: &mut Vec<PathBuf>) -> Result<()> {
+    let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&key) {
+        return Err(eyre!(
+            "config include cycle detected: {} -> {}",
+            stack
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> "),
+            key.display()
+        ));
+    }
+    if files.contains(&key) {
+        return Ok(());
+    }
+
+    let includes = Config::builder()
+        .add_source(config::File::with_name(&path.to_string_lossy()))
+        .build()
+        .map_err(|e| eyre!("load file config failed: {}: {}", path.display(), e))?
+        .get::<Vec<String>>(INCLUDE_KEY)
+        .unwrap_or_default();
+
+    stack.push(key.clone());
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    for include in includes {
+        collect_includes(&base.join(include), stack, files)?;
+    }
+    stack.pop();
+
+    files.push(key);
+    Ok(())
 }
 
 pub async fn async_config(uri: &str) -> Result<Config> {
@@ -67,28 +135,148 @@ impl<F: config::Format + Send + Sync + Debug> AsyncSource for HttpSource<F> {
     }
 }
 
+/// Reloads `config` whenever the file at `config_path` or one of the files it includes
+/// changes, followed by a thread of its own for the life of the process. Files included
+/// after a reload are watched from then on.
 pub fn config_hot_reload<T: for<'a> Deserialize<'a> + Sync + Send + 'static>(
     config: Arc<RwLock<T>>,
     config_path: String,
 ) -> Result<()> {
-    let config_path_clone = config_path.clone();
-    // reload config
-    let mut watcher = RecommendedWatcher::new(
-        move |result: Result<Event, notify::Error>| {
-            let event = result.unwrap();
-
-            if event.kind.is_modify() {
-                match file_config(&config_path_clone) {
+    let (events, changes) = std::sync::mpsc::channel();
+    let mut watcher = RecommendedWatcher::new(events, notify::Config::default())?;
+    let mut watched = HashSet::new();
+    watch_files(&mut watcher, &mut watched, &config_files(&config_path)?);
+    std::thread::Builder::new()
+        .name("config-hot-reload".to_owned())
+        .spawn(move || {
+            for event in changes {
+                let event: Event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!("watch config failed: {e}");
+                        continue;
+                    }
+                };
+                if event.kind.is_remove() {
+                    // replaced rather than written in place, watched again once back
+                    for path in &event.paths {
+                        watched.remove(path);
+                    }
+                } else if !(event.kind.is_modify() || event.kind.is_create()) {
+                    continue;
+                }
+                match file_config(&config_path) {
                     Ok(new_config) => {
                         info!("reloading config");
                         *config.write() = new_config;
                     }
                     Err(error) => error!("Error reloading config: {:?}", error),
                 }
+                if let Ok(files) = config_files(&config_path) {
+                    watch_files(&mut watcher, &mut watched, &files);
+                }
             }
-        },
-        notify::Config::default(),
-    )?;
-    watcher.watch(Path::new(&config_path), RecursiveMode::Recursive)?;
+        })?;
     Ok(())
 }
+
+/// Watches the `files` not in `watched` yet.
+fn watch_files(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut HashSet<PathBuf>,
+    files: &[PathBuf],
+) {
+    for file in files {
+        if watched.contains(file) {
+            continue;
+        }
+        match watcher.watch(file, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(file.clone());
+            }
+            Err(e) => error!("watch config {} failed: {e}", file.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("configure-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn includes_load_first_and_once() {
+        let dir = dir(
+            "order",
+            &[
+                ("base.toml", "a = 1\nb = 1\nc = 1\n"),
+                ("mid.toml", "include = [\"base.toml\"]\nb = 2\nc = 2\n"),
+                ("other.toml", "include = [\"./base.toml\"]\nc = 4\n"),
+                (
+                    "main.toml",
+                    "include = [\"mid.toml\", \"other.toml\"]\nd = 5\n",
+                ),
+            ],
+        );
+        let main = dir.join("main.toml");
+        let files = config_files(main.to_str().unwrap()).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["base.toml", "mid.toml", "other.toml", "main.toml"]);
+
+        let config: HashMap<String, i64> = file_config(main.to_str().unwrap()).unwrap();
+        // a later include overrides an earlier one, the including file overrides both
+        assert_eq!(
+            config,
+            HashMap::from([
+                ("a".to_owned(), 1),
+                ("b".to_owned(), 2),
+                ("c".to_owned(), 4),
+                ("d".to_owned(), 5),
+            ])
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn including_file_overrides() {
+        let dir = dir(
+            "override",
+            &[
+                ("base.toml", "port = 1\n"),
+                ("main.toml", "include = [\"base.toml\"]\nport = 2\n"),
+            ],
+        );
+        let config: HashMap<String, i64> =
+            file_config(dir.join("main.toml").to_str().unwrap()).unwrap();
+        assert_eq!(config, HashMap::from([("port".to_owned(), 2)]));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn include_cycle_fails() {
+        let dir = dir(
+            "cycle",
+            &[
+                ("a.toml", "include = [\"b.toml\"]\n"),
+                ("b.toml", "include = [\"a.toml\"]\n"),
+            ],
+        );
+        let e = config_files(dir.join("a.toml").to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().contains("cycle"), "{e}");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,629 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service registration and discovery through the local agent of a Consul cluster, for
+//! deployments routed by Consul instead of the traefik keys kept in etcd or redis.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    clock::renew_interval,
+    error::{BoxError, CommonError},
+    service_register::{
+        RegisterHealth, RegisterStatus, RegisterStatuses, ServiceDiscovery, ServiceRegister,
+        ServiceRegisterConfig,
+    },
+    shutdown::{Phase, Shutdown},
+};
+
+/// Longest a blocking query of [`ServiceDiscovery::changed`] waits on the agent.
+const BLOCKING_WAIT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsulConfig {
+    /// the http address of the agent
+    pub address: String,
+    /// ACL token sent with every call, none if empty
+    pub token: String,
+    /// milliseconds a call to the agent may take
+    pub timeout: u64,
+}
+
+impl Default for ConsulConfig {
+    fn default() -> Self {
+        Self {
+            address: "http://127.0.0.1:8500".to_owned(),
+            token: Default::default(),
+            timeout: 2000,
+        }
+    }
+}
+
+/// A client of the Consul agent. Cloning is cheap and every clone shares the register
+/// status of each service.
+#[derive(Clone)]
+pub struct Consul {
+    client: reqwest::Client,
+    address: Arc<str>,
+    token: Arc<str>,
+    register_statuses: Arc<RegisterStatuses>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Registration<'a> {
+    #[serde(rename = "ID")]
+    id: &'a str,
+    name: &'a str,
+    tags: &'a [String],
+    meta: HashMap<&'a str, &'a str>,
+    check: Check<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check<'a> {
+    #[serde(rename = "CheckID")]
+    check_id: &'a str,
+    #[serde(rename = "TTL")]
+    ttl: String,
+    deregister_critical_service_after: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    service: AgentService,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentService {
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    port: u16,
+    /// null for services registered without any
+    #[serde(default)]
+    meta: Option<HashMap<String, String>>,
+}
+
+fn op_error(op: &'static str, e: impl Into<BoxError>) -> CommonError {
+    CommonError::ConsulOp {
+        op,
+        source: e.into(),
+    }
+}
+
+/// The id this instance registers `service_name` under, one per replica.
+fn service_id(service_name: &str, config: &ServiceRegisterConfig) -> String {
+    format!("{service_name}-{}", config.instance_id())
+}
+
+fn check_id(service_id: &str) -> String {
+    format!("service:{service_id}")
+}
+
+impl Consul {
+    pub fn new(config: &ConsulConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout))
+            .build()
+            .map_err(|e| op_error("connect", e))?;
+        Ok(Self {
+            client,
+            address: config.address.trim_end_matches('/').into(),
+            token: config.token.as_str().into(),
+            register_statuses: Default::default(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{path}", self.address));
+        if self.token.is_empty() {
+            request
+        } else {
+            request.header("X-Consul-Token", &*self.token)
+        }
+    }
+
+    /// Sends `request`, failing on any status but success.
+    async fn send(&self, op: &'static str, request: RequestBuilder) -> Result<reqwest::Response> {
+        Ok(request
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| op_error(op, e))?)
+    }
+
+    pub async fn service_register(
+        &self,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) -> Result<()> {
+        self.keep_service_register(service_name, config).await
+    }
+
+    /// Stops the service register loop and removes this instance from the agent, the
+    /// other replicas stay registered.
+    pub async fn service_deregister(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+    ) -> Result<()> {
+        self.register_statuses
+            .get(service_name)
+            .stop_and_wait()
+            .await;
+        self.deregister(&service_id(service_name, config)).await?;
+        info!("service_deregister: {service_name}");
+        Ok(())
+    }
+
+    /// Deregisters `service_name` in the first phase of `shutdown`.
+    pub fn deregister_on_shutdown(
+        &self,
+        shutdown: &Shutdown,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
+        let consul = self.clone();
+        let service_name = service_name.to_owned();
+        shutdown.on(Phase::Deregister, "consul_register", move || async move {
+            consul.service_deregister(&service_name, &config).await
+        });
+    }
+
+    pub fn register_health(&self, service_name: &str) -> RegisterHealth {
+        RegisterHealth {
+            name: format!("consul_register/{service_name}"),
+            status: self.register_statuses.get(service_name),
+        }
+    }
+
+    /// Registers this instance of `service_name` with a ttl check, dropped by the agent
+    /// once it stays failed for ten ttls.
+    async fn register(&self, service_name: &str, config: &ServiceRegisterConfig) -> Result<()> {
+        let id = service_id(service_name, config);
+        let check = check_id(&id);
+        let registration = Registration {
+            id: &id,
+            name: service_name,
+            tags: &config.tags,
+            meta: HashMap::from([("url", config.url.as_str())]),
+            check: Check {
+                check_id: &check,
+                ttl: format!("{}s", config.ttl.max(1)),
+                deregister_critical_service_after: format!("{}s", config.ttl.max(1) * 10),
+            },
+        };
+        let body = serde_json::to_vec(&registration).map_err(|e| op_error("register", e))?;
+        self.send(
+            "register",
+            self.request(Method::PUT, "/v1/agent/service/register")
+                .body(body),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn deregister(&self, service_id: &str) -> Result<()> {
+        let path = format!("/v1/agent/service/deregister/{service_id}");
+        self.send("deregister", self.request(Method::PUT, &path))
+            .await?;
+        Ok(())
+    }
+
+    /// Marks the ttl check of `service_id` as passing, `false` if the agent does not
+    /// know it, e.g. having restarted since.
+    async fn pass(&self, service_id: &str) -> Result<bool> {
+        let path = format!("/v1/agent/check/pass/{}", check_id(service_id));
+        let res = self
+            .request(Method::PUT, &path)
+            .send()
+            .await
+            .map_err(|e| op_error("pass", e))?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status().map_err(|e| op_error("pass", e))?;
+        Ok(true)
+    }
+
+    /// The passing instances of `service_name` and the index of the answer, waiting for
+    /// a later index than `after` if given.
+    async fn health(
+        &self,
+        service_name: &str,
+        after: Option<u64>,
+    ) -> Result<(Vec<AgentService>, u64)> {
+        let mut request = self
+            .request(Method::GET, &format!("/v1/health/service/{service_name}"))
+            .query(&[("passing", "true")]);
+        if let Some(index) = after {
+            request = request
+                .query(&[
+                    ("index", index.to_string()),
+                    ("wait", format!("{}s", BLOCKING_WAIT.as_secs())),
+                ])
+                .timeout(BLOCKING_WAIT + Duration::from_secs(10));
+        }
+        let res = self.send("health", request).await?;
+        let index = res
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|index| index.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        let body = res.bytes().await.map_err(|e| op_error("health", e))?;
+        let entries: Vec<HealthEntry> =
+            serde_json::from_slice(&body).map_err(|e| op_error("health", e))?;
+        Ok((
+            entries.into_iter().map(|entry| entry.service).collect(),
+            index,
+        ))
+    }
+
+    /// Registers this instance once, passes its check every half ttl until the service
+    /// deregisters and registers it again should the agent forget it.
+    async fn register_loop(
+        self,
+        service_name: String,
+        config: ServiceRegisterConfig,
+        status: Arc<RegisterStatus>,
+        generation: u64,
+    ) -> Result<()> {
+        let id = service_id(&service_name, &config);
+        let mut keep_alive_interval = tokio::time::interval(renew_interval(config.ttl));
+        let mut registered = false;
+        let mut withdrawn = false;
+        loop {
+            keep_alive_interval.tick().await;
+            let _round = status.round().await;
+            if !status.is_current(generation) {
+                break;
+            }
+            if status.is_withdrawn() {
+                if !withdrawn {
+                    info!("service register {service_name} withdrawn");
+                    if let Err(e) = self.deregister(&id).await {
+                        error!("withdraw service register failed: {e}");
+                    }
+                    registered = false;
+                    withdrawn = true;
+                }
+                continue;
+            }
+            withdrawn = false;
+            if !registered {
+                if let Err(e) = self.register(&service_name, &config).await {
+                    error!("keep_service_register failed: {:?}", e);
+                    status.failure();
+                    continue;
+                }
+                registered = true;
+            }
+            match self.pass(&id).await {
+                Ok(true) => status.success(),
+                Ok(false) => {
+                    error!("keep_service_register failed: {id} unknown to the agent");
+                    registered = false;
+                    status.failure();
+                }
+                Err(e) => {
+                    error!("keep_service_register failed: {:?}", e);
+                    status.failure();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceRegister for Consul {
+    async fn keep_service_register(
+        &self,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) -> Result<()> {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        tokio::spawn(self.clone().register_loop(
+            service_name.to_owned(),
+            config,
+            status,
+            generation,
+        ));
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for Consul {
+    /// The urls of the passing instances, their address and port for the ones not
+    /// registered by this crate.
+    async fn discover(&self, service_name: &str) -> Result<Vec<String>> {
+        let (services, _) = self.health(service_name, None).await?;
+        Ok(services
+            .into_iter()
+            .map(
+                |service| match service.meta.as_ref().and_then(|meta| meta.get("url")) {
+                    Some(url) => url.clone(),
+                    None => format!("http://{}:{}", service.address, service.port),
+                },
+            )
+            .collect())
+    }
+
+    /// A blocking query, resolving once the passing instances change or after five
+    /// minutes at most.
+    async fn changed(&self, service_name: &str) -> Result<()> {
+        let (_, index) = self.health(service_name, None).await?;
+        self.health(service_name, Some(index)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthCheck;
+
+    #[test]
+    fn registration_as_the_agent_expects() {
+        let tags = vec!["traefik.enable=true".to_owned()];
+        let registration = Registration {
+            id: "cache-1",
+            name: "cache",
+            tags: &tags,
+            meta: HashMap::from([("url", "http://10.0.0.1:3000")]),
+            check: Check {
+                check_id: "service:cache-1",
+                ttl: "60s".to_owned(),
+                deregister_critical_service_after: "600s".to_owned(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&registration).unwrap(),
+            serde_json::json!({
+                "ID": "cache-1",
+                "Name": "cache",
+                "Tags": ["traefik.enable=true"],
+                "Meta": {"url": "http://10.0.0.1:3000"},
+                "Check": {
+                    "CheckID": "service:cache-1",
+                    "TTL": "60s",
+                    "DeregisterCriticalServiceAfter": "600s",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn health_entries_parsed() {
+        let body = r#"[
+            {"Node": {}, "Service": {"ID": "cache-1", "Address": "10.0.0.1", "Port": 3000,
+                "Meta": {"url": "http://cache-1:3000"}}, "Checks": []},
+            {"Service": {"Address": "10.0.0.2", "Port": 3001, "Meta": null}}
+        ]"#;
+        let entries: Vec<HealthEntry> = serde_json::from_str(body).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].service.meta.as_ref().unwrap()["url"],
+            "http://cache-1:3000"
+        );
+        assert_eq!(entries[1].service.address, "10.0.0.2");
+        assert_eq!(entries[1].service.port, 3001);
+        assert!(entries[1].service.meta.is_none());
+    }
+
+    /// A fake agent answering with `reply(method, path)`, recording every request as
+    /// `METHOD path` followed by its body.
+    async fn agent(
+        reply: fn(&str, &str) -> (u16, &'static str),
+    ) -> (Consul, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = Vec::new();
+                let mut chunk = [0; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut chunk).await.unwrap_or(0);
+                    if n == 0 {
+                        break (String::new(), String::new());
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&buf).into_owned();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|n| n.trim().parse().unwrap_or(0))
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break (head.to_owned(), body.to_owned());
+                    }
+                };
+                let mut line = head.lines().next().unwrap_or_default().split(' ');
+                let (method, path) = (
+                    line.next().unwrap_or_default(),
+                    line.next().unwrap_or_default(),
+                );
+                seen.lock()
+                    .unwrap()
+                    .push(format!("{method} {path} {body}").trim_end().to_owned());
+                let (status, body) = reply(method, path);
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nX-Consul-Index: 7\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let consul = Consul::new(&ConsulConfig {
+            address,
+            ..Default::default()
+        })
+        .unwrap();
+        (consul, requests)
+    }
+
+    fn register_config() -> ServiceRegisterConfig {
+        ServiceRegisterConfig {
+            url: "http://10.0.0.1:3000".to_owned(),
+            ttl: 1,
+            instance: "1".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    async fn wait_for(requests: &std::sync::Mutex<Vec<String>>, prefix: &str) {
+        for _ in 0..100 {
+            if requests
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|r| r.starts_with(prefix))
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no `{prefix}` in {:?}", requests.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn register_passes_then_deregisters() {
+        let (consul, requests) = agent(|_, _| (200, "")).await;
+        let config = register_config();
+        consul
+            .keep_service_register("cache", config.clone())
+            .await
+            .unwrap();
+        wait_for(&requests, "PUT /v1/agent/check/pass/service:cache-1").await;
+        let register = requests.lock().unwrap()[0].clone();
+        assert!(register.starts_with("PUT /v1/agent/service/register {"));
+        assert!(register.contains(r#""ID":"cache-1""#));
+        assert!(register.contains(r#""url":"http://10.0.0.1:3000""#));
+        assert!(consul.register_health("cache").check().await.is_ok());
+
+        consul.service_deregister("cache", &config).await.unwrap();
+        let count = {
+            let requests = requests.lock().unwrap();
+            assert_eq!(
+                requests.last().unwrap(),
+                "PUT /v1/agent/service/deregister/cache-1"
+            );
+            requests.len()
+        };
+        // the loop stopped, nothing is passed or registered again
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(requests.lock().unwrap().len(), count);
+    }
+
+    #[tokio::test]
+    async fn registers_again_once_the_agent_forgets() {
+        let (consul, requests) = agent(|_, path| match path {
+            "/v1/agent/check/pass/service:cache-1" => (404, ""),
+            _ => (200, ""),
+        })
+        .await;
+        consul
+            .keep_service_register("cache", register_config())
+            .await
+            .unwrap();
+        // register, unknown check, then a second register the next round
+        for _ in 0..100 {
+            let registers = requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.starts_with("PUT /v1/agent/service/register"))
+                .count();
+            if registers >= 2 {
+                assert!(consul.register_health("cache").check().await.is_err());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("not registered again: {:?}", requests.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn discover_passing_instances() {
+        let (consul, requests) = agent(|_, _| {
+            (
+                200,
+                r#"[{"Service": {"Address": "10.0.0.1", "Port": 3000, "Meta": {"url": "http://cache-1:3000"}}},
+                    {"Service": {"Address": "10.0.0.2", "Port": 3001, "Meta": null}}]"#,
+            )
+        })
+        .await;
+        assert_eq!(
+            consul.discover("cache").await.unwrap(),
+            ["http://cache-1:3000", "http://10.0.0.2:3001"]
+        );
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /v1/health/service/cache?passing=true"
+        );
+    }
+
+    #[tokio::test]
+    async fn services_register_apart() {
+        let (consul, requests) = agent(|_, _| (200, "")).await;
+        let config = register_config();
+        consul
+            .keep_service_register("http", config.clone())
+            .await
+            .unwrap();
+        consul
+            .keep_service_register("grpc", config.clone())
+            .await
+            .unwrap();
+        wait_for(&requests, "PUT /v1/agent/check/pass/service:http-1").await;
+        wait_for(&requests, "PUT /v1/agent/check/pass/service:grpc-1").await;
+        consul.service_deregister("http", &config).await.unwrap();
+        requests.lock().unwrap().clear();
+        // grpc keeps passing on its own, http comes back once registered again
+        wait_for(&requests, "PUT /v1/agent/check/pass/service:grpc-1").await;
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.contains("service:http-1")));
+        consul
+            .keep_service_register("http", config.clone())
+            .await
+            .unwrap();
+        wait_for(&requests, "PUT /v1/agent/check/pass/service:http-1").await;
+        assert!(consul.register_health("grpc").check().await.is_ok());
+    }
+}
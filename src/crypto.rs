@@ -0,0 +1,115 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::str::FromStr;
+
+use color_eyre::{eyre::eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::util::Hash;
+
+/// The crypto service a chain runs, which decides how its blocks and transactions are
+/// hashed. Configured as `sm` or `eth`, the names of the CITA-Cloud crypto services
+/// `crypto_sm` and `crypto_eth` are accepted too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CryptoType {
+    /// SM3
+    #[default]
+    #[serde(alias = "crypto_sm")]
+    Sm,
+    /// Keccak-256
+    #[serde(alias = "crypto_eth")]
+    Eth,
+}
+
+impl CryptoType {
+    /// Hashes `data` the way the chain does, e.g. the encoded `Transaction` of a raw
+    /// transaction to get its hash.
+    pub fn hash(&self, data: &[u8]) -> Hash {
+        match self {
+            Self::Sm => sm3(data),
+            Self::Eth => keccak256(data),
+        }
+    }
+}
+
+impl FromStr for CryptoType {
+    type Err = Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "sm" | "crypto_sm" => Ok(Self::Sm),
+            "eth" | "crypto_eth" => Ok(Self::Eth),
+            _ => Err(eyre!(
+                "unknown crypto type `{text}`, expected `sm` or `eth`"
+            )),
+        }
+    }
+}
+
+pub fn sm3(data: &[u8]) -> Hash {
+    let mut hash = [0u8; Hash::LEN];
+    hash.copy_from_slice(&libsm::sm3::hash::Sm3Hash::new(data).get_hash());
+    Hash::new(hash)
+}
+
+pub fn keccak256(data: &[u8]) -> Hash {
+    let mut hash = [0u8; Hash::LEN];
+    let mut keccak = Keccak::v256();
+    keccak.update(data);
+    keccak.finalize(&mut hash);
+    Hash::new(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sm3_known_answers() {
+        assert_eq!(
+            sm3(b"").to_string(),
+            "0x1ab21d8355cfa17f8e61194831e81a8f22bec8c728fefb747ed035eb5082aa2b"
+        );
+        // the example of GB/T 32905-2016
+        assert_eq!(
+            sm3(b"abc").to_string(),
+            "0x66c7f0f462eeedd9d1f2d46bdc10e4e24167c4875cf2f7a2297da02b8f4ba8e0"
+        );
+    }
+
+    #[test]
+    fn keccak256_known_answers() {
+        // the original keccak padding, not the one of SHA3-256
+        assert_eq!(
+            keccak256(b"").to_string(),
+            "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            keccak256(b"abc").to_string(),
+            "0x4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45"
+        );
+    }
+
+    #[test]
+    fn crypto_type() {
+        assert_eq!("crypto_eth".parse::<CryptoType>().unwrap(), CryptoType::Eth);
+        assert_eq!("SM".parse::<CryptoType>().unwrap(), CryptoType::Sm);
+        assert!("sha256".parse::<CryptoType>().is_err());
+        assert_eq!(CryptoType::Eth.hash(b"abc"), keccak256(b"abc"));
+        assert_eq!(CryptoType::default().hash(b"abc"), sm3(b"abc"));
+    }
+}
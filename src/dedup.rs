@@ -0,0 +1,199 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// seconds a key counts as seen
+    pub ttl: u64,
+    /// keys kept in memory before the expired ones are dropped, at least 1
+    pub max_entries: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 60,
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// Answers whether a key, e.g. a tx hash, was seen in the last `ttl` seconds, to drop
+/// duplicate submissions. Keys are kept in memory and, given [`DedupSet::with_redis`],
+/// in redis too, so that the instances of a service see the keys of each other.
+/// Cloning is cheap and every clone shares the keys.
+#[derive(Clone)]
+pub struct DedupSet {
+    config: DedupConfig,
+    seen: Arc<Mutex<HashMap<Vec<u8>, Instant>>>,
+    #[cfg(feature = "redis")]
+    redis: Option<(crate::redis::Redis, Arc<str>)>,
+}
+
+impl DedupSet {
+    pub fn new(config: DedupConfig) -> Self {
+        Self {
+            config,
+            seen: Default::default(),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    /// Shares the keys through redis, stored under `prefix` followed by their hex. Redis
+    /// failing, the keys seen by this instance alone decide.
+    #[cfg(feature = "redis")]
+    pub fn with_redis(mut self, redis: crate::redis::Redis, prefix: &str) -> Self {
+        self.redis = Some((redis, prefix.into()));
+        self
+    }
+
+    /// Records `key`, returns whether it was not seen within the ttl, like
+    /// [`std::collections::HashSet::insert`].
+    pub async fn insert(&self, key: impl AsRef<[u8]>) -> bool {
+        let key = key.as_ref();
+        if !self.insert_local(key) {
+            return false;
+        }
+        #[cfg(feature = "redis")]
+        if let Some((redis, prefix)) = &self.redis {
+            let mut cmd = crate::redis::cmd("SET");
+            cmd.arg(format!("{prefix}{}", crate::util::to_hex(key)))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(self.config.ttl.max(1));
+            match redis.query::<Option<String>>(&cmd).await {
+                Ok(set) => return set.is_some(),
+                Err(e) => tracing::warn!("dedup falls back to local keys: {e}"),
+            }
+        }
+        true
+    }
+
+    /// Whether `key` was seen within the ttl, by this instance alone.
+    pub fn contains(&self, key: impl AsRef<[u8]>) -> bool {
+        let ttl = Duration::from_secs(self.config.ttl);
+        self.lock()
+            .get(key.as_ref())
+            .is_some_and(|at| at.elapsed() < ttl)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert_local(&self, key: &[u8]) -> bool {
+        let ttl = Duration::from_secs(self.config.ttl);
+        let now = Instant::now();
+        let mut seen = self.lock();
+        if seen
+            .get(key)
+            .is_some_and(|at| now.duration_since(*at) < ttl)
+        {
+            return false;
+        }
+        let max_entries = self.config.max_entries.max(1);
+        if seen.len() >= max_entries {
+            seen.retain(|_, at| now.duration_since(*at) < ttl);
+            // all of them still fresh, the oldest go down to half, so that the next
+            // eviction is as many inserts away
+            if seen.len() >= max_entries {
+                let mut by_age: Vec<_> = seen.iter().map(|(key, at)| (*at, key.clone())).collect();
+                by_age.sort_unstable_by_key(|(at, _)| *at);
+                let cut = by_age.len() - max_entries / 2;
+                for (_, key) in by_age.into_iter().take(cut) {
+                    seen.remove(&key);
+                }
+            }
+        }
+        seen.insert(key.to_vec(), now);
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Vec<u8>, Instant>> {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(ttl: u64, max_entries: usize) -> DedupSet {
+        DedupSet::new(DedupConfig { ttl, max_entries })
+    }
+
+    // `insert_local` is `insert` without redis, which needs no runtime
+    #[test]
+    fn seen_within_ttl() {
+        let set = dedup(1, 10);
+        assert!(set.insert_local(b"a"));
+        assert!(!set.insert_local(b"a"));
+        assert!(set.contains("a"));
+        assert!(!set.contains("b"));
+        std::thread::sleep(Duration::from_millis(1010));
+        assert!(!set.contains("a"));
+        assert!(set.insert_local(b"a"));
+        // every clone sees the keys
+        assert!(!set.clone().insert_local(b"a"));
+    }
+
+    #[test]
+    fn expired_dropped_first() {
+        let set = dedup(1, 2);
+        set.insert_local(b"a");
+        set.insert_local(b"b");
+        std::thread::sleep(Duration::from_millis(1010));
+        set.insert_local(b"c");
+        assert_eq!(set.len(), 1);
+        assert!(set.contains("c"));
+    }
+
+    #[test]
+    fn oldest_evicted_at_max_entries() {
+        let set = dedup(60, 4);
+        for key in ["a", "b", "c", "d"] {
+            set.insert_local(key.as_bytes());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // the two oldest go to make room
+        set.insert_local(b"e");
+        assert_eq!(set.len(), 3);
+        assert!(!set.contains("a") && !set.contains("b"));
+        assert!(set.contains("c") && set.contains("d") && set.contains("e"));
+        // the smallest limits hold as well, the newest key always kept
+        for max_entries in [1, 2, 3] {
+            let set = dedup(60, max_entries);
+            for key in 0..100u32 {
+                set.insert_local(&key.to_be_bytes());
+                assert!(set.len() <= max_entries);
+            }
+            assert!(set.contains(99u32.to_be_bytes()));
+        }
+    }
+}
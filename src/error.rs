@@ -12,10 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use num_enum::IntoPrimitive;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 use thiserror::Error;
 
-#[derive(Debug, Clone, Copy, Error, IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, IntoPrimitive, TryFromPrimitive)]
 #[repr(u16)]
 pub enum CALError {
     #[error("Bad Request")]
@@ -57,3 +57,156 @@ pub enum CALError {
     #[error("Cita CMC Create Failed")]
     CitaCMCCreateFailed = 4001,
 }
+
+impl CALError {
+    pub const ALL: [Self; 17] = [
+        Self::BadRequest,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::NotFound,
+        Self::TooManyRequests,
+        Self::InternalServerError,
+        Self::NotImplemented,
+        Self::BadGateway,
+        Self::ServiceUnavailable,
+        Self::GatewayTimeout,
+        Self::ChainError,
+        Self::TransactionError,
+        Self::TransactionTimeout,
+        Self::TransactionReverted,
+        Self::KMSError,
+        Self::ExternalError,
+        Self::CitaCMCCreateFailed,
+    ];
+}
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors a service handler can return; each variant maps to a [`CALError`] code
+/// while source errors are kept for logging.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Not Found: {0}")]
+    NotFound(String),
+    #[error("Too Many Requests")]
+    TooManyRequests,
+    #[error("Service Unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("Upstream Timeout")]
+    UpstreamTimeout(#[source] BoxError),
+    #[error("Upstream Error")]
+    Upstream(#[source] BoxError),
+    #[error("Etcd Unavailable")]
+    EtcdUnavailable(#[source] BoxError),
+    #[error("Redis Unavailable")]
+    RedisUnavailable(#[source] BoxError),
+    #[error("Internal Server Error")]
+    Internal(#[source] BoxError),
+}
+
+impl Error {
+    pub const fn code(&self) -> CALError {
+        match self {
+            Self::BadRequest(_) => CALError::BadRequest,
+            Self::Unauthorized(_) => CALError::Unauthorized,
+            Self::Forbidden(_) => CALError::Forbidden,
+            Self::NotFound(_) => CALError::NotFound,
+            Self::TooManyRequests => CALError::TooManyRequests,
+            Self::ServiceUnavailable(_) | Self::EtcdUnavailable(_) | Self::RedisUnavailable(_) => {
+                CALError::ServiceUnavailable
+            }
+            Self::UpstreamTimeout(_) => CALError::GatewayTimeout,
+            Self::Upstream(_) => CALError::BadGateway,
+            Self::Internal(_) => CALError::InternalServerError,
+        }
+    }
+}
+
+impl From<color_eyre::Report> for Error {
+    fn from(e: color_eyre::Report) -> Self {
+        match e.downcast::<CommonError>() {
+            Ok(e) => e.into(),
+            Err(e) => Self::Internal(e.into()),
+        }
+    }
+}
+
+/// Failures of the etcd, redis and register helpers of this crate, so callers can match on
+/// the kind instead of the message. They convert into [`color_eyre::Report`] like any
+/// error, get them back with `report.downcast_ref::<CommonError>()`.
+#[derive(Debug, Error)]
+pub enum CommonError {
+    #[error("etcd connect failed: {0}")]
+    EtcdConnect(#[source] BoxError),
+    #[error("etcd {op} failed: {source}")]
+    EtcdOp {
+        op: &'static str,
+        key: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("redis connect failed: {0}")]
+    RedisConnect(#[source] BoxError),
+    #[error("redis {op} failed: {source}")]
+    RedisOp {
+        op: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{context} failed: {source}")]
+    Serde {
+        context: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("{0}")]
+    Registration(String),
+    #[error("consul {op} failed: {source}")]
+    ConsulOp {
+        op: &'static str,
+        #[source]
+        source: BoxError,
+    },
+}
+
+impl CommonError {
+    pub const fn code(&self) -> CALError {
+        match self {
+            Self::EtcdConnect(_)
+            | Self::EtcdOp { .. }
+            | Self::RedisConnect(_)
+            | Self::RedisOp { .. }
+            | Self::ConsulOp { .. } => CALError::ServiceUnavailable,
+            Self::NotFound(_) => CALError::NotFound,
+            Self::Timeout(_) => CALError::GatewayTimeout,
+            Self::Serde { .. } | Self::Registration(_) => CALError::InternalServerError,
+        }
+    }
+}
+
+impl From<CommonError> for Error {
+    fn from(e: CommonError) -> Self {
+        match e {
+            CommonError::EtcdConnect(_) | CommonError::EtcdOp { .. } => {
+                Self::EtcdUnavailable(e.into())
+            }
+            CommonError::RedisConnect(_) | CommonError::RedisOp { .. } => {
+                Self::RedisUnavailable(e.into())
+            }
+            CommonError::ConsulOp { .. } => Self::ServiceUnavailable(e.to_string()),
+            CommonError::NotFound(what) => Self::NotFound(what),
+            CommonError::Timeout(_) => Self::UpstreamTimeout(e.into()),
+            CommonError::Serde { .. } | CommonError::Registration(_) => Self::Internal(e.into()),
+        }
+    }
+}
@@ -12,23 +12,75 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::Duration;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use color_eyre::{
-    eyre::{eyre, OptionExt},
-    Result,
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{
+    Client, Compare, CompareOp, ConnectOptions, DeleteOptions, GetOptions, KeyValue as KV,
+    PutOptions, ResignOptions, Txn, TxnOp, WatchOptions,
 };
-use etcd_client::{Client, ConnectOptions, DeleteOptions, GetOptions, KeyValue as KV, PutOptions};
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
+use tokio::time::Instant;
+use tracing::{error, info, info_span, warn, Instrument, Span};
 
-use crate::service_register::{ServiceRegister, ServiceRegisterConfig};
+use crate::{
+    breaker::{BreakerConfig, BreakerError, CircuitBreaker},
+    clock::renew_interval,
+    error::{BoxError, CommonError},
+    etcd_watch::WatchBroadcast,
+    health::{CheckFuture, HealthCheck},
+    retry::{retry_if, RetryPolicy},
+    service_register::{
+        discovery_prefix, instance_entries, register_entries, RegisterHealth, RegisterStatus,
+        RegisterStatuses, ServiceDiscovery, ServiceRegister, ServiceRegisterConfig,
+    },
+    shutdown::{CancellationToken, Phase, Shutdown, TaskScope},
+    slow::SlowLog,
+    supervisor::Supervisor,
+    tenant::{Tenant, TenantEtcd},
+};
 
 pub type KeyValue = KV;
 
+#[cfg(feature = "metrics")]
+static LEADER: std::sync::LazyLock<crate::metrics::IntGaugeVec> = std::sync::LazyLock::new(|| {
+    crate::gauge!(
+        "etcd_leader",
+        "Whether this instance leads an election, 1 or 0",
+        ["election"]
+    )
+});
+
+/// A lock taken with [`Etcd::try_lock`], held until [`EtcdLock::unlock`] or until its ttl
+/// passes. Dropping it does not release it, the lock is kept for the rest of the ttl.
+pub struct EtcdLock {
+    client: Client,
+    key: String,
+    lease: i64,
+}
+
+impl EtcdLock {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub async fn unlock(mut self) -> Result<()> {
+        self.client
+            .lease_revoke(self.lease)
+            .await
+            .map_err(|e| op_error("lease_revoke", &self.key, e))?;
+        Ok(())
+    }
+}
+
+/// A client made by [`Etcd::new`], every field but `client` kept private.
 #[derive(Clone)]
 pub struct Etcd {
     pub client: Client,
+    register_statuses: Arc<RegisterStatuses>,
+    slow_threshold: Duration,
+    retry: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +89,12 @@ pub struct EtcdConfig {
     pub endpoints: Vec<String>,
     pub timeout: u64,
     pub keep_alive: u64,
+    /// milliseconds after which a call is logged as slow, 0 disables it
+    pub slow_threshold: u64,
+    /// retries of reads and deletes failing with an unavailable cluster or a timeout
+    pub retry: RetryPolicy,
+    /// fails calls fast while too many of them meet an unavailable cluster or time out
+    pub breaker: BreakerConfig,
 }
 
 impl Default for EtcdConfig {
@@ -45,10 +103,165 @@ impl Default for EtcdConfig {
             endpoints: vec!["http://127.0.0.1:2379".to_owned()],
             timeout: 2000,
             keep_alive: 300,
+            slow_threshold: 500,
+            retry: RetryPolicy {
+                max_retries: 2,
+                max_backoff: 1000,
+                ..Default::default()
+            },
+            breaker: Default::default(),
+        }
+    }
+}
+
+/// Whether `e` may pass on retry: the cluster is unreachable, has no leader yet, or the
+/// call timed out.
+fn transient(e: &etcd_client::Error) -> bool {
+    // etcd-client is on its own tonic, so its status codes are compared by value
+    const DEADLINE_EXCEEDED: i32 = 4;
+    const UNAVAILABLE: i32 = 14;
+    match e {
+        etcd_client::Error::GRpcStatus(status) => {
+            matches!(status.code() as i32, DEADLINE_EXCEEDED | UNAVAILABLE)
+        }
+        etcd_client::Error::IoError(_) | etcd_client::Error::TransportError(_) => true,
+        _ => false,
+    }
+}
+
+/// Pages of the keys under a prefix, from [`Etcd::scan_prefix`].
+pub struct PrefixScan<'a> {
+    etcd: &'a Etcd,
+    end: Vec<u8>,
+    /// the first key of the next page, `None` once the scan is done
+    next: Option<Vec<u8>>,
+    page_size: i64,
+}
+
+impl PrefixScan<'_> {
+    /// Skips the keys up to and including `key`, e.g. to resume a scan from the last key
+    /// handled before a restart.
+    pub fn resume_after(mut self, key: &[u8]) -> Self {
+        self.next = self.next.map(|start| start.max([key, &[0]].concat()));
+        self
+    }
+
+    /// The next page in key order, `None` once every key was read. The key values are
+    /// moved out of the response, not copied.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<KeyValue>>> {
+        let Some(start) = self.next.take() else {
+            return Ok(None);
+        };
+        let span = span("scan_prefix", &start);
+        let _slow = SlowLog::start("etcd", "scan_prefix", &start, self.etcd.slow_threshold);
+        let mut rsp = self
+            .etcd
+            .idempotent("get", |mut client| {
+                let start = start.clone();
+                let options = GetOptions::new()
+                    .with_range(self.end.clone())
+                    .with_limit(self.page_size);
+                async move { client.get(start, Some(options)).await }
+            })
+            .instrument(span)
+            .await
+            .map_err(|e| op_error("get", &start, e))?;
+        let more = rsp.more();
+        let kvs = rsp.take_kvs();
+        if more {
+            // right after the last key read
+            self.next = kvs.last().map(|kv| [kv.key(), &[0]].concat());
+        }
+        Ok((!kvs.is_empty()).then_some(kvs))
+    }
+}
+
+/// The end of the range of the keys starting with `prefix`, `\0` for all keys when
+/// `prefix` is only `0xff` bytes, as etcd expects.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![0]
+}
+
+/// Keeps `lease`, granted as requested at `granted`, alive every half `ttl`, returns why
+/// it could not. A renewal not answered by a quarter of the ttl before the lease would
+/// expire counts as lost, so that the holder stops before another instance can take
+/// over what the lease guards.
+pub(crate) async fn keep_lease(
+    mut client: Client,
+    lease: i64,
+    ttl: i64,
+    granted: Instant,
+) -> color_eyre::Report {
+    let margin = Duration::from_secs(ttl.max(1) as u64) / 4;
+    // the lease lives at least that long, counted from before the request renewing it
+    let mut expires = granted + Duration::from_secs(ttl.max(1) as u64);
+    let not_renewed = || eyre!("lease {lease} not renewed in time, taken as lost");
+    let (mut keeper, mut stream) =
+        match tokio::time::timeout_at(expires - margin, client.lease_keep_alive(lease)).await {
+            Ok(Ok(keep_alive)) => keep_alive,
+            Ok(Err(e)) => return op_error("lease_keep_alive", "", e).into(),
+            Err(_) => return not_renewed(),
+        };
+    let mut interval = tokio::time::interval(renew_interval(ttl));
+    loop {
+        interval.tick().await;
+        let sent = Instant::now();
+        let round_trip = async {
+            keeper.keep_alive().await?;
+            stream.message().await
+        };
+        match tokio::time::timeout_at(expires - margin, round_trip).await {
+            Ok(Ok(Some(rsp))) if rsp.ttl() > 0 => {
+                expires = sent + Duration::from_secs(rsp.ttl() as u64);
+            }
+            Ok(Ok(_)) => return eyre!("lease {lease} expired"),
+            Ok(Err(e)) => return op_error("lease_keep_alive", "", e).into(),
+            Err(_) => return not_renewed(),
         }
     }
 }
 
+pub(crate) fn op_error(
+    op: &'static str,
+    key: impl AsRef<[u8]>,
+    e: impl Into<BoxError>,
+) -> CommonError {
+    CommonError::EtcdOp {
+        op,
+        key: String::from_utf8_lossy(key.as_ref()).into_owned(),
+        source: e.into(),
+    }
+}
+
+/// `HOSTNAME/pid`, naming this process as the owner of a lock, a claim or a candidacy.
+pub(crate) fn owner_id() -> String {
+    format!(
+        "{}/{}",
+        std::env::var("HOSTNAME").unwrap_or_default(),
+        std::process::id()
+    )
+}
+
+/// A client span for one etcd call, exported as a child of the active trace. etcd-client
+/// has no per-request metadata, so the trace context stops at this process.
+fn span(operation: &str, key: &[u8]) -> Span {
+    info_span!(
+        "etcd",
+        otel.name = %format_args!("etcd {operation}"),
+        otel.kind = "client",
+        db.system = "etcd",
+        db.operation = operation,
+        db.key = %String::from_utf8_lossy(key),
+    )
+}
+
 impl Etcd {
     pub async fn new(config: &EtcdConfig) -> Result<Self> {
         let client = Client::connect(
@@ -65,8 +278,83 @@ impl Etcd {
             ),
         )
         .await
-        .map_err(|e| eyre!("etcd connect failed: {e}"))?;
-        Ok(Self { client })
+        .map_err(|e| CommonError::EtcdConnect(e.into()))?;
+        Ok(Self {
+            client,
+            register_statuses: Default::default(),
+            slow_threshold: Duration::from_millis(config.slow_threshold),
+            retry: config.retry,
+            breaker: Arc::new(CircuitBreaker::new("etcd", config.breaker)),
+        })
+    }
+
+    /// Runs `call` through the circuit breaker, counting transient errors as failures.
+    #[cfg_attr(not(feature = "faults"), allow(unused_variables))]
+    async fn guarded<T>(
+        &self,
+        op: &'static str,
+        call: impl Future<Output = Result<T, etcd_client::Error>>,
+    ) -> Result<T, BreakerError<etcd_client::Error>> {
+        #[cfg(feature = "faults")]
+        let call = async move {
+            if let Some(fault) = crate::faults::inject("etcd", op).await {
+                return Err(etcd_client::Error::IoError(std::io::Error::other(fault)));
+            }
+            call.await
+        };
+        self.breaker.call(call, transient).await
+    }
+
+    /// Runs an idempotent call on a client of its own, retrying it on transient errors
+    /// unless the circuit breaker opens.
+    async fn idempotent<T, F, Fut>(
+        &self,
+        op: &'static str,
+        call: F,
+    ) -> Result<T, BreakerError<etcd_client::Error>>
+    where
+        F: Fn(Client) -> Fut,
+        Fut: Future<Output = Result<T, etcd_client::Error>>,
+    {
+        retry_if(
+            &self.retry,
+            || self.guarded(op, call(self.client.clone())),
+            |e| matches!(e, BreakerError::Inner(e) if transient(e)),
+        )
+        .await
+    }
+
+    /// Runs an idempotent call of another module of this crate like the ones here, with
+    /// retries, the circuit breaker, a span and the slow log.
+    pub(crate) async fn call_idempotent<T, F, Fut>(
+        &self,
+        op: &'static str,
+        key: &[u8],
+        call: F,
+    ) -> Result<T>
+    where
+        F: Fn(Client) -> Fut,
+        Fut: Future<Output = Result<T, etcd_client::Error>>,
+    {
+        let _slow = SlowLog::start("etcd", op, key, self.slow_threshold);
+        self.idempotent(op, call)
+            .instrument(span(op, key))
+            .await
+            .map_err(|e| op_error(op, key, e).into())
+    }
+
+    /// Like [`Etcd::call_idempotent`] for a call not to be retried, e.g. a txn.
+    pub(crate) async fn call_once<T>(
+        &self,
+        op: &'static str,
+        key: &[u8],
+        call: impl Future<Output = Result<T, etcd_client::Error>>,
+    ) -> Result<T> {
+        let _slow = SlowLog::start("etcd", op, key, self.slow_threshold);
+        self.guarded(op, call)
+            .instrument(span(op, key))
+            .await
+            .map_err(|e| op_error(op, key, e).into())
     }
 
     pub async fn put(
@@ -75,102 +363,213 @@ impl Etcd {
         value: impl Into<Vec<u8>>,
         ttl: i64,
     ) -> Result<Option<KeyValue>> {
-        let mut client = self.client.clone();
-        let option = if ttl == 0 {
-            PutOptions::new().with_prev_key()
-        } else {
-            let lease = client
-                .lease_grant(ttl, None)
+        let key = key.into();
+        let span = span("put", &key);
+        let _slow = SlowLog::start("etcd", "put", &key, self.slow_threshold);
+        async move {
+            let mut client = self.client.clone();
+            let option = if ttl == 0 {
+                PutOptions::new().with_prev_key()
+            } else {
+                let lease = self
+                    .guarded("lease_grant", client.lease_grant(ttl, None))
+                    .await
+                    .map_err(|e| op_error("lease_grant", &key, e))?;
+                PutOptions::new().with_lease(lease.id()).with_prev_key()
+            };
+            let put_rsp = self
+                .guarded("put", client.put(key.clone(), value, Some(option)))
                 .await
-                .map_err(|e| eyre!("etcd lease_grant failed: {e}"))?;
-            PutOptions::new().with_lease(lease.id()).with_prev_key()
-        };
-        let put_rsp = client
-            .put(key, value, Some(option))
-            .await
-            .map_err(|e| eyre!("etcd put failed: {e}"))?;
-        Ok(put_rsp.prev_key().cloned())
+                .map_err(|e| op_error("put", &key, e))?;
+            Ok(put_rsp.prev_key().cloned())
+        }
+        .instrument(span)
+        .await
     }
 
+    /// The value at `key`. A missing key fails with [`CommonError::NotFound`], which
+    /// handlers answer with 404 rather than the 500 of the other etcd errors.
     pub async fn get(&self, key: impl Into<Vec<u8>>) -> Result<KeyValue> {
-        self.client
-            .to_owned()
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-            .cloned()
-            .ok_or_eyre("data not found")
+        let key = key.into();
+        let span = span("get", &key);
+        let _slow = SlowLog::start("etcd", "get", &key, self.slow_threshold);
+        self.idempotent("get", |mut client| {
+            let key = key.clone();
+            async move { client.get(key, Some(GetOptions::new().with_limit(1))).await }
+        })
+        .instrument(span)
+        .await
+        .map_err(|e| op_error("get", &key, e))?
+        .take_kvs()
+        .pop()
+        .ok_or_else(|| {
+            CommonError::NotFound(format!("etcd key `{}`", String::from_utf8_lossy(&key))).into()
+        })
     }
 
     pub async fn get_with_prefix(&self, key: impl Into<Vec<u8>>) -> Result<Vec<KeyValue>> {
+        let key = key.into();
+        let span = span("get_with_prefix", &key);
+        let _slow = SlowLog::start("etcd", "get_with_prefix", &key, self.slow_threshold);
         Ok(self
-            .client
-            .to_owned()
-            .get(key, Some(GetOptions::new().with_prefix()))
+            .idempotent("get", |mut client| {
+                let key = key.clone();
+                async move { client.get(key, Some(GetOptions::new().with_prefix())).await }
+            })
+            .instrument(span)
             .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .to_vec())
+            .map_err(|e| op_error("get", &key, e))?
+            .take_kvs())
+    }
+
+    /// Reads the keys under `prefix` a page of `page_size` at a time, to scan large
+    /// prefixes without holding them all in memory at once.
+    pub fn scan_prefix(&self, prefix: impl Into<Vec<u8>>, page_size: i64) -> PrefixScan<'_> {
+        let prefix = prefix.into();
+        PrefixScan {
+            etcd: self,
+            end: prefix_end(&prefix),
+            next: Some(prefix),
+            page_size: page_size.max(1),
+        }
+    }
+
+    /// Watches `prefix` once in `scope` for every receiver subscribed to the returned
+    /// broadcast, holding up to 1024 events for the slowest one.
+    pub fn watch_to_broadcast(&self, scope: &TaskScope, prefix: &str) -> WatchBroadcast {
+        WatchBroadcast::start(self.clone(), scope, prefix, 1024)
+    }
+
+    /// The keys of `tenant` only, see [`Tenant`].
+    pub fn for_tenant(&self, tenant: Tenant) -> TenantEtcd {
+        TenantEtcd::new(self.clone(), tenant)
     }
 
     pub async fn delete(&self, key: impl Into<Vec<u8>>) -> Result<i64> {
+        let key = key.into();
+        let span = span("delete", &key);
+        let _slow = SlowLog::start("etcd", "delete", &key, self.slow_threshold);
         Ok(self
-            .client
-            .to_owned()
-            .delete(key, None)
+            .idempotent("delete", |mut client| {
+                let key = key.clone();
+                async move { client.delete(key, None).await }
+            })
+            .instrument(span)
             .await
-            .map_err(|e| eyre!("etcd delete failed: {e}"))?
+            .map_err(|e| op_error("delete", &key, e))?
             .deleted())
     }
 
     pub async fn delete_with_prefix(&self, key: impl Into<Vec<u8>>) -> Result<i64> {
+        let key = key.into();
+        let span = span("delete_with_prefix", &key);
+        let _slow = SlowLog::start("etcd", "delete_with_prefix", &key, self.slow_threshold);
         Ok(self
-            .client
-            .to_owned()
-            .delete(key, Some(DeleteOptions::new().with_prefix()))
+            .idempotent("delete", |mut client| {
+                let key = key.clone();
+                async move {
+                    client
+                        .delete(key, Some(DeleteOptions::new().with_prefix()))
+                        .await
+                }
+            })
+            .instrument(span)
             .await
-            .map_err(|e| eyre!("etcd delete failed: {e}"))?
+            .map_err(|e| op_error("delete", &key, e))?
             .deleted())
     }
 
     pub async fn touch(&self, key: impl Into<Vec<u8>>) -> Result<()> {
-        let mut client = self.client.clone();
-        let lease = client
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-            .map(|kv| kv.lease())
-            .unwrap_or(0);
-        if lease != 0 {
-            client
-                .lease_keep_alive(lease)
+        let key = key.into();
+        let span = span("touch", &key);
+        let _slow = SlowLog::start("etcd", "touch", &key, self.slow_threshold);
+        async move {
+            let mut client = self.client.clone();
+            let lease = self
+                .guarded(
+                    "get",
+                    client.get(key.clone(), Some(GetOptions::new().with_limit(1))),
+                )
                 .await
-                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
+                .map_err(|e| op_error("get", &key, e))?
+                .kvs()
+                .first()
+                .map(|kv| kv.lease())
+                .unwrap_or(0);
+            if lease != 0 {
+                self.guarded("lease_keep_alive", client.lease_keep_alive(lease))
+                    .await
+                    .map_err(|e| op_error("lease_keep_alive", &key, e))?;
+            }
+            Ok(())
         }
-        Ok(())
+        .instrument(span)
+        .await
     }
 
     pub async fn put_or_touch(&self, key: &str, value: impl Into<Vec<u8>>, ttl: i64) -> Result<()> {
-        let mut client = self.client.clone();
-        if let Some(prev) = client
-            .get(key, Some(GetOptions::new().with_limit(1)))
-            .await
-            .map_err(|e| eyre!("etcd get failed: {e}"))?
-            .kvs()
-            .first()
-        {
-            client
-                .lease_keep_alive(prev.lease())
+        let _slow = SlowLog::start("etcd", "put_or_touch", key.as_bytes(), self.slow_threshold);
+        async move {
+            let mut client = self.client.clone();
+            if let Some(prev) = self
+                .guarded(
+                    "get",
+                    client.get(key, Some(GetOptions::new().with_limit(1))),
+                )
                 .await
-                .map_err(|e| eyre!("etcd lease_keep_alive failed: {e}"))?;
-        } else {
-            self.put(key, value, ttl).await?;
+                .map_err(|e| op_error("get", key, e))?
+                .kvs()
+                .first()
+            {
+                self.guarded("lease_keep_alive", client.lease_keep_alive(prev.lease()))
+                    .await
+                    .map_err(|e| op_error("lease_keep_alive", key, e))?;
+            } else {
+                self.put(key, value, ttl).await?;
+            }
+            Ok(())
         }
-        Ok(())
+        .instrument(span("put_or_touch", key.as_bytes()))
+        .await
+    }
+
+    /// Takes the lock at `key` unless another holder has it, holding it for at most
+    /// `ttl` seconds, at least 1. `None` if the lock is taken.
+    pub async fn try_lock(&self, key: &str, ttl: i64) -> Result<Option<EtcdLock>> {
+        let _slow = SlowLog::start("etcd", "try_lock", key.as_bytes(), self.slow_threshold);
+        async move {
+            let mut client = self.client.clone();
+            let lease = self
+                .guarded("lease_grant", client.lease_grant(ttl.max(1), None))
+                .await
+                .map_err(|e| op_error("lease_grant", key, e))?
+                .id();
+            let txn = Txn::new()
+                .when([Compare::create_revision(key, CompareOp::Equal, 0)])
+                .and_then([TxnOp::put(
+                    key,
+                    crate::clock::unix_millis().to_string(),
+                    Some(PutOptions::new().with_lease(lease)),
+                )]);
+            let locked = match self.guarded("txn", client.txn(txn)).await {
+                Ok(rsp) => rsp.succeeded(),
+                Err(e) => {
+                    let _ = client.lease_revoke(lease).await;
+                    return Err(op_error("txn", key, e).into());
+                }
+            };
+            if !locked {
+                let _ = client.lease_revoke(lease).await;
+                return Ok(None);
+            }
+            Ok(Some(EtcdLock {
+                client,
+                key: key.to_owned(),
+                lease,
+            }))
+        }
+        .instrument(span("try_lock", key.as_bytes()))
+        .await
     }
 
     pub async fn service_register(
@@ -180,57 +579,321 @@ impl Etcd {
     ) -> Result<()> {
         self.keep_service_register(service_name, config).await
     }
-}
 
-impl ServiceRegister for Etcd {
-    async fn keep_service_register(
+    /// Stops the service register loop and removes the keys of this instance, leaving the
+    /// ones shared with the other replicas.
+    pub async fn service_deregister(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+    ) -> Result<()> {
+        self.register_statuses
+            .get(service_name)
+            .stop_and_wait()
+            .await;
+        for (key, _) in instance_entries(service_name, config) {
+            self.delete(key).await?;
+        }
+        info!("service_deregister: {service_name}");
+        Ok(())
+    }
+
+    /// Deregisters `service_name` in the first phase of `shutdown`.
+    pub fn deregister_on_shutdown(
         &self,
+        shutdown: &Shutdown,
         service_name: &str,
         config: ServiceRegisterConfig,
+    ) {
+        let etcd = self.clone();
+        let service_name = service_name.to_owned();
+        shutdown.on(Phase::Deregister, "etcd_register", move || async move {
+            etcd.service_deregister(&service_name, &config).await
+        });
+    }
+
+    /// Renews the keys of `service_name` every half ttl until the service deregisters.
+    async fn register_loop(
+        self,
+        service_name: String,
+        config: ServiceRegisterConfig,
+        status: Arc<RegisterStatus>,
+        generation: u64,
     ) -> Result<()> {
+        let mut keep_alive_interval = tokio::time::interval(renew_interval(config.ttl));
+        let mut withdrawn = false;
+        loop {
+            keep_alive_interval.tick().await;
+            let _round = status.round().await;
+            if !status.is_current(generation) {
+                break;
+            }
+            if status.is_withdrawn() {
+                if !withdrawn {
+                    info!("service register {service_name} withdrawn");
+                    for (key, _) in instance_entries(&service_name, &config) {
+                        if let Err(e) = self.delete(key).await {
+                            error!("withdraw service register failed: {e}");
+                        }
+                    }
+                    withdrawn = true;
+                }
+                continue;
+            }
+            withdrawn = false;
+            let mut failed = false;
+            for (key, value) in register_entries(&service_name, &config) {
+                if let Err(e) = self.put_or_touch(&key, value, config.ttl).await {
+                    error!("keep_service_register failed: {:?}", e);
+                    failed = true;
+                }
+            }
+            if failed {
+                status.failure();
+            } else {
+                status.success();
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`ServiceRegister::keep_service_register`], with the register loop owned by
+    /// `supervisor` so it is restarted should it panic.
+    pub fn supervise_register(
+        &self,
+        supervisor: &Supervisor,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
         info!("keep_service_register: {config:?}");
-        let mut keep_alive_interval =
-            tokio::time::interval(tokio::time::Duration::from_secs((config.ttl / 2) as u64));
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        let etcd = self.clone();
+        let service_name = service_name.to_owned();
+        supervisor.spawn("etcd_register", move || {
+            etcd.clone().register_loop(
+                service_name.clone(),
+                config.clone(),
+                status.clone(),
+                generation,
+            )
+        });
+    }
 
+    /// Like [`ServiceRegister::keep_service_register`], with the register loop spawned
+    /// into `scope` so it stops once the scope is cancelled.
+    pub fn register_in(
+        &self,
+        scope: &TaskScope,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        let register =
+            self.clone()
+                .register_loop(service_name.to_owned(), config, status, generation);
+        scope.spawn("etcd_register", async move {
+            if let Err(e) = register.await {
+                error!("keep_service_register failed: {e}");
+            }
+        });
+    }
+
+    /// Deregisters `service_name` once `cancel` is cancelled, e.g. by
+    /// [`crate::shutdown::wait_for_shutdown`].
+    pub fn deregister_on_cancel(
+        &self,
+        cancel: CancellationToken,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
         let etcd = self.clone();
         let service_name = service_name.to_owned();
         tokio::spawn(async move {
-            loop {
-                keep_alive_interval.tick().await;
-                let tags = config.tags.clone();
-                let service_name = service_name.clone();
-
-                if let Err(e) = etcd
-                    .put_or_touch(
-                        &format!(
-                            "traefik/http/services/{}/loadbalancer/servers/{}/url",
-                            service_name, service_name
-                        ),
-                        config.url.clone(),
-                        config.ttl,
-                    )
-                    .await
-                {
-                    error!("keep_service_register failed: {:?}", e);
+            cancel.cancelled().await;
+            if let Err(e) = etcd.service_deregister(&service_name, &config).await {
+                error!("service_deregister {service_name} failed: {e}");
+            }
+        });
+    }
+
+    /// Health check reporting whether the register loop of `service_name` keeps renewing
+    /// its keys.
+    pub fn register_health(&self, service_name: &str) -> RegisterHealth {
+        RegisterHealth {
+            name: format!("etcd_register/{service_name}"),
+            status: self.register_statuses.get(service_name),
+        }
+    }
+
+    /// Runs the task made by `start` under `supervisor` only while this instance is the
+    /// elected leader of `name`, e.g. a periodic reconciliation job meant to run once in
+    /// the fleet. The task is dropped once the leadership is lost and made again once it
+    /// is won back. The leadership is held by a lease of `ttl` seconds, an instance gone
+    /// without resigning keeps it that long. The task is dropped as well once a renewal
+    /// of the lease is late, before another instance can win the leadership.
+    pub fn run_if_leader<F, Fut>(&self, supervisor: &Supervisor, name: &str, ttl: i64, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let etcd = self.clone();
+        let name = name.to_owned();
+        let start = Arc::new(start);
+        supervisor.spawn(&format!("leader {name}"), move || {
+            etcd.clone().lead(name.clone(), ttl, start.clone())
+        });
+    }
+
+    /// Campaigns for `name` and runs `start` while leading, until it returns.
+    async fn lead<F, Fut>(self, name: String, ttl: i64, start: Arc<F>) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let election = format!("election/{name}");
+        let candidate = owner_id();
+        let mut client = self.client.clone();
+        loop {
+            let granted = Instant::now();
+            let lease = client
+                .lease_grant(ttl.max(1), None)
+                .await
+                .map_err(|e| op_error("lease_grant", &election, e))?
+                .id();
+            let keep = keep_lease(client.clone(), lease, ttl, granted);
+            tokio::pin!(keep);
+            let leader = tokio::select! {
+                campaign = client.campaign(election.as_str(), candidate.as_str(), lease) => {
+                    match campaign {
+                        Ok(rsp) => rsp.leader().cloned(),
+                        Err(e) => {
+                            let _ = client.lease_revoke(lease).await;
+                            return Err(op_error("campaign", &election, e).into());
+                        }
+                    }
                 }
-                if let Err(e) = etcd
-                    .put_or_touch(
-                        &format!("traefik/http/routers/{}/service", service_name),
-                        service_name,
-                        config.ttl,
-                    )
-                    .await
-                {
-                    error!("keep_service_register failed: {:?}", e);
+                e = &mut keep => {
+                    let _ = client.lease_revoke(lease).await;
+                    return Err(e);
                 }
-                for tag in tags {
-                    let (key, value) = tag.split_once('=').unwrap_or_default();
-                    if let Err(e) = etcd.put_or_touch(key, value, config.ttl).await {
-                        error!("keep_service_register failed: {:?}", e);
-                    }
+            };
+            info!("{candidate} leads {name}");
+            #[cfg(feature = "metrics")]
+            LEADER.with_label_values(&[&name]).set(1);
+            let result = tokio::select! {
+                result = start() => Some(result),
+                e = &mut keep => {
+                    warn!("{candidate} lost the leadership of {name}: {e}");
+                    None
                 }
+            };
+            #[cfg(feature = "metrics")]
+            LEADER.with_label_values(&[&name]).set(0);
+            if let (Some(_), Some(leader)) = (&result, leader) {
+                let _ = client
+                    .resign(Some(ResignOptions::new().with_leader(leader)))
+                    .await;
             }
-        });
+            let _ = client.lease_revoke(lease).await;
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+}
+
+impl HealthCheck for Etcd {
+    fn name(&self) -> String {
+        "etcd".to_owned()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            self.client
+                .to_owned()
+                .status()
+                .await
+                .map_err(|e| op_error("status", "", e))?;
+            Ok(())
+        })
+    }
+}
+
+impl ServiceDiscovery for Etcd {
+    async fn discover(&self, service_name: &str) -> Result<Vec<String>> {
+        let mut urls = Vec::new();
+        for kv in self.get_with_prefix(discovery_prefix(service_name)).await? {
+            if kv.key().ends_with(b"/url") {
+                let url = kv
+                    .value_str()
+                    .map_err(|e| eyre!("invalid service url: {e}"))?;
+                urls.push(url.to_owned());
+            }
+        }
+        Ok(urls)
+    }
+
+    async fn changed(&self, service_name: &str) -> Result<()> {
+        let prefix = discovery_prefix(service_name);
+        let (_watcher, mut stream) = self
+            .client
+            .to_owned()
+            .watch(prefix.as_str(), Some(WatchOptions::new().with_prefix()))
+            .await
+            .map_err(|e| op_error("watch", &prefix, e))?;
+        while let Some(response) = stream
+            .message()
+            .await
+            .map_err(|e| op_error("watch", &prefix, e))?
+        {
+            if !response.events().is_empty() {
+                return Ok(());
+            }
+        }
+        Err(eyre!("etcd watch closed"))
+    }
+}
+
+impl ServiceRegister for Etcd {
+    async fn keep_service_register(
+        &self,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) -> Result<()> {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        tokio::spawn(self.clone().register_loop(
+            service_name.to_owned(),
+            config,
+            status,
+            generation,
+        ));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_end;
+
+    #[test]
+    fn prefix_end_increments_last_byte() {
+        assert_eq!(prefix_end(b"a"), b"b");
+        assert_eq!(prefix_end(b"queue/x/items/"), b"queue/x/items0");
+        assert_eq!(prefix_end(&[0x01, 0x00]), [0x01, 0x01]);
+    }
+
+    #[test]
+    fn prefix_end_carries_past_0xff() {
+        assert_eq!(prefix_end(&[b'a', 0xff]), b"b");
+        assert_eq!(prefix_end(&[0x01, 0xff, 0xff]), [0x02]);
+    }
+
+    #[test]
+    fn prefix_end_of_all_keys() {
+        assert_eq!(prefix_end(b""), [0]);
+        assert_eq!(prefix_end(&[0xff]), [0]);
+        assert_eq!(prefix_end(&[0xff, 0xff]), [0]);
+    }
+}
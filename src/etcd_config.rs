@@ -0,0 +1,293 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{Compare, CompareOp, GetOptions, SortOrder, SortTarget, Txn, TxnOp};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    error::CommonError,
+    etcd::{op_error, Etcd},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigVersion {
+    pub version: u64,
+    pub content: String,
+}
+
+/// A config kept on etcd with every version published, so a bad push to the fleet can
+/// be rolled back in one call. The current content is at `config/{name}`, for services
+/// to load and watch, and every version at `config/{name}/versions/{n}`.
+#[derive(Clone)]
+pub struct ConfigStore {
+    etcd: Etcd,
+    name: String,
+}
+
+impl ConfigStore {
+    pub fn new(etcd: Etcd, name: &str) -> Self {
+        Self {
+            etcd,
+            name: name.to_owned(),
+        }
+    }
+
+    pub fn key(&self) -> String {
+        format!("config/{}", self.name)
+    }
+
+    fn versions_prefix(&self) -> String {
+        format!("config/{}/versions/", self.name)
+    }
+
+    fn version_key(&self, version: u64) -> String {
+        // zero padded to list in order
+        format!("{}{version:010}", self.versions_prefix())
+    }
+
+    /// The latest version, `None` if nothing was published yet.
+    pub async fn current(&self) -> Result<Option<ConfigVersion>> {
+        let prefix = self.versions_prefix();
+        let rsp = self
+            .etcd
+            .client
+            .clone()
+            .get(
+                prefix.as_str(),
+                Some(
+                    GetOptions::new()
+                        .with_prefix()
+                        .with_sort(SortTarget::Key, SortOrder::Descend)
+                        .with_limit(1),
+                ),
+            )
+            .await
+            .map_err(|e| op_error("get", &prefix, e))?;
+        rsp.kvs()
+            .first()
+            .map(|kv| {
+                Ok(ConfigVersion {
+                    version: self.parse_version(kv.key())?,
+                    content: String::from_utf8_lossy(kv.value()).into_owned(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Deserializes the latest version written in `format`.
+    #[cfg(feature = "config")]
+    pub async fn load<T: serde::de::DeserializeOwned>(
+        &self,
+        format: config::FileFormat,
+    ) -> Result<T> {
+        let current = self
+            .current()
+            .await?
+            .ok_or_else(|| CommonError::NotFound(format!("config {}", self.name)))?;
+        config::Config::builder()
+            .add_source(config::File::from_str(&current.content, format))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| {
+                CommonError::Serde {
+                    context: format!(
+                        "deserialize config {} version {}",
+                        self.name, current.version
+                    ),
+                    source: e.into(),
+                }
+                .into()
+            })
+    }
+
+    /// Every published version, oldest first.
+    pub async fn versions(&self) -> Result<Vec<u64>> {
+        let prefix = self.versions_prefix();
+        self.etcd
+            .client
+            .clone()
+            .get(
+                prefix.as_str(),
+                Some(GetOptions::new().with_prefix().with_keys_only()),
+            )
+            .await
+            .map_err(|e| op_error("get", &prefix, e))?
+            .kvs()
+            .iter()
+            .map(|kv| self.parse_version(kv.key()))
+            .collect()
+    }
+
+    pub async fn get(&self, version: u64) -> Result<ConfigVersion> {
+        let key = self.version_key(version);
+        let rsp = self
+            .etcd
+            .client
+            .clone()
+            .get(key.as_str(), None)
+            .await
+            .map_err(|e| op_error("get", &key, e))?;
+        let kv = rsp.kvs().first().ok_or_else(|| {
+            CommonError::NotFound(format!("config {} version {version}", self.name))
+        })?;
+        Ok(ConfigVersion {
+            version,
+            content: String::from_utf8_lossy(kv.value()).into_owned(),
+        })
+    }
+
+    /// Publishes `content` as the next version and makes it current, returns its number.
+    pub async fn publish(&self, content: &str) -> Result<u64> {
+        let mut client = self.etcd.client.clone();
+        loop {
+            let version = self
+                .current()
+                .await?
+                .map_or(1, |current| current.version + 1);
+            let key = self.version_key(version);
+            // another publisher taking the same number retries with the next one
+            let txn = Txn::new()
+                .when([Compare::create_revision(key.as_str(), CompareOp::Equal, 0)])
+                .and_then([
+                    TxnOp::put(key.as_str(), content, None),
+                    TxnOp::put(self.key(), content, None),
+                ]);
+            let published = client
+                .txn(txn)
+                .await
+                .map_err(|e| op_error("txn", &key, e))?
+                .succeeded();
+            if published {
+                info!("config {} version {version} published", self.name);
+                return Ok(version);
+            }
+        }
+    }
+
+    /// Publishes the content of `version` again as the next version, returns its number.
+    pub async fn rollback(&self, version: u64) -> Result<u64> {
+        let old = self.get(version).await?;
+        let new = self.publish(&old.content).await?;
+        info!(
+            "config {} rolled back to version {version} as version {new}",
+            self.name
+        );
+        Ok(new)
+    }
+
+    /// The lines changed from version `from` to version `to`, see [`diff`].
+    pub async fn diff(&self, from: u64, to: u64) -> Result<String> {
+        let from = self.get(from).await?;
+        let to = self.get(to).await?;
+        Ok(diff(&from.content, &to.content))
+    }
+
+    /// Removes all but the latest `keep` versions.
+    pub async fn prune(&self, keep: usize) -> Result<usize> {
+        let versions = self.versions().await?;
+        let stale = versions.len().saturating_sub(keep.max(1));
+        let mut client = self.etcd.client.clone();
+        for version in &versions[..stale] {
+            let key = self.version_key(*version);
+            client
+                .delete(key.as_str(), None)
+                .await
+                .map_err(|e| op_error("delete", &key, e))?;
+        }
+        Ok(stale)
+    }
+
+    fn parse_version(&self, key: &[u8]) -> Result<u64> {
+        let prefix = self.versions_prefix();
+        std::str::from_utf8(key)
+            .ok()
+            .and_then(|key| key.strip_prefix(&prefix))
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| {
+                eyre!(
+                    "invalid config version key: {}",
+                    String::from_utf8_lossy(key)
+                )
+            })
+    }
+}
+
+/// The lines removed from `old` prefixed by `-` and the ones added in `new` prefixed by
+/// `+`, in the order of the files, unchanged lines left out.
+pub fn diff(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    // longest common subsequence of lines, from the end
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff;
+
+    #[test]
+    fn unchanged_is_empty() {
+        assert_eq!(diff("", ""), "");
+        assert_eq!(diff("a\nb\n", "a\nb"), "");
+    }
+
+    #[test]
+    fn added_and_removed_lines() {
+        assert_eq!(diff("", "a\nb"), "+a\n+b\n");
+        assert_eq!(diff("a\nb", ""), "-a\n-b\n");
+        assert_eq!(diff("a\nc", "a\nb\nc"), "+b\n");
+        assert_eq!(diff("a\nb\nc", "a\nc"), "-b\n");
+    }
+
+    #[test]
+    fn changed_lines_in_order() {
+        let old = "port = 8080\nhost = \"a\"\nlog = \"info\"\n";
+        let new = "port = 9090\nhost = \"a\"\nlog = \"debug\"\nextra = 1\n";
+        assert_eq!(
+            diff(old, new),
+            "-port = 8080\n+port = 9090\n-log = \"info\"\n+log = \"debug\"\n+extra = 1\n"
+        );
+    }
+
+    #[test]
+    fn moved_line_kept_once() {
+        assert_eq!(diff("a\nb\nc", "b\nc\na"), "-a\n+a\n");
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{Compare, CompareOp, GetOptions, PutOptions, Txn, TxnOp, WatchOptions};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use crate::{
+    etcd::{op_error, owner_id, Etcd, KeyValue},
+    shutdown::TaskScope,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EtcdQueueConfig {
+    /// seconds a claimed item is hidden from other workers before it is handed out again
+    pub visibility_timeout: i64,
+    /// milliseconds an idle worker waits for new items before looking again
+    pub poll_interval: u64,
+    /// items read at a time while looking for a free one
+    pub batch: i64,
+    /// failed attempts after which an item is moved to `queue/{name}/dead/`, 0 for no
+    /// limit
+    pub max_attempts: u32,
+}
+
+impl Default for EtcdQueueConfig {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: 30,
+            poll_interval: 1000,
+            batch: 64,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// A queue on etcd shared by the replicas of a service, e.g. to spread reindexing work.
+/// Items are kept in the order they were pushed under `queue/{name}/items/`. A worker
+/// claims an item by writing its claim under `queue/{name}/claims/` with a lease of
+/// `visibility_timeout`, so an item whose worker died or did not finish in time is handed
+/// out again. Items are delivered at least once. The failed attempts of an item are
+/// counted under `queue/{name}/attempts/`, and after `max_attempts` the item is moved to
+/// the dead letters under `queue/{name}/dead/`, see [`EtcdQueue::dead_letters`].
+#[derive(Clone)]
+pub struct EtcdQueue {
+    etcd: Etcd,
+    name: Arc<str>,
+    config: EtcdQueueConfig,
+}
+
+/// An item claimed from an [`EtcdQueue`], to be acknowledged once it is handled.
+pub struct QueueItem {
+    queue: EtcdQueue,
+    seq: String,
+    key: String,
+    claim: String,
+    lease: i64,
+    value: Vec<u8>,
+}
+
+static CLAIMS: AtomicU64 = AtomicU64::new(0);
+
+impl EtcdQueue {
+    pub fn new(etcd: Etcd, name: &str, config: EtcdQueueConfig) -> Self {
+        Self {
+            etcd,
+            name: name.into(),
+            config,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn items(&self) -> String {
+        format!("queue/{}/items/", self.name)
+    }
+
+    fn claims(&self) -> String {
+        format!("queue/{}/claims/", self.name)
+    }
+
+    fn attempts(&self) -> String {
+        format!("queue/{}/attempts/", self.name)
+    }
+
+    fn dead(&self) -> String {
+        format!("queue/{}/dead/", self.name)
+    }
+
+    /// Items given up after `max_attempts` failed attempts, keyed as they were queued.
+    /// They stay until deleted.
+    pub async fn dead_letters(&self) -> Result<Vec<KeyValue>> {
+        self.etcd.get_with_prefix(self.dead()).await
+    }
+
+    /// Appends `value`, returns the key it is stored at.
+    pub async fn push(&self, value: impl Into<Vec<u8>>) -> Result<String> {
+        let value = value.into();
+        let mut seq = crate::clock::unix_nanos();
+        loop {
+            let key = format!("{}{seq:020}", self.items());
+            let txn = Txn::new()
+                .when([Compare::create_revision(key.as_str(), CompareOp::Equal, 0)])
+                .and_then([TxnOp::put(key.as_str(), value.clone(), None)]);
+            let pushed = self
+                .etcd
+                .call_once("txn", key.as_bytes(), self.etcd.client.clone().txn(txn))
+                .await?
+                .succeeded();
+            if pushed {
+                return Ok(key);
+            }
+            // pushed at the same nanosecond by another producer
+            seq += 1;
+        }
+    }
+
+    /// Items waiting or claimed.
+    pub async fn len(&self) -> Result<i64> {
+        let items = self.items();
+        Ok(self
+            .etcd
+            .call_idempotent("get", items.as_bytes(), |mut client| {
+                let items = items.clone();
+                let options = GetOptions::new().with_prefix().with_count_only();
+                async move { client.get(items, Some(options)).await }
+            })
+            .await?
+            .count())
+    }
+
+    /// Claims the oldest item not claimed by another worker, `None` if there is none.
+    /// The items are read a batch at a time until a free one is found.
+    pub async fn claim(&self) -> Result<Option<QueueItem>> {
+        let items = self.items();
+        let claims = self.claims();
+        let claimed: HashSet<Vec<u8>> = self
+            .etcd
+            .call_idempotent("get", claims.as_bytes(), |mut client| {
+                let claims = claims.clone();
+                let options = GetOptions::new().with_prefix().with_keys_only();
+                async move { client.get(claims, Some(options)).await }
+            })
+            .await?
+            .kvs()
+            .iter()
+            .map(|kv| kv.key()[claims.len()..].to_vec())
+            .collect();
+
+        let worker = format!("{}/{}", owner_id(), CLAIMS.fetch_add(1, Ordering::Relaxed));
+        let mut pending = self
+            .etcd
+            .scan_prefix(items.as_str(), self.config.batch.max(1));
+        while let Some(page) = pending.next_page().await? {
+            for kv in page {
+                let seq = &kv.key()[items.len()..];
+                if claimed.contains(seq) {
+                    continue;
+                }
+                let claim = format!("{claims}{}", String::from_utf8_lossy(seq));
+                if let Some(item) = self.try_claim(kv, &claim, &worker).await? {
+                    return Ok(Some(item));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Claims `kv` under `claim` unless another worker did or it was acked meanwhile.
+    async fn try_claim(
+        &self,
+        kv: KeyValue,
+        claim: &str,
+        worker: &str,
+    ) -> Result<Option<QueueItem>> {
+        let lease = self
+            .etcd
+            .call_once(
+                "lease_grant",
+                claim.as_bytes(),
+                self.etcd
+                    .client
+                    .clone()
+                    .lease_grant(self.config.visibility_timeout.max(1), None),
+            )
+            .await?
+            .id();
+        // the item must still be there and not claimed in the meantime
+        let txn = Txn::new()
+            .when([
+                Compare::create_revision(claim, CompareOp::Equal, 0),
+                Compare::create_revision(kv.key(), CompareOp::Equal, kv.create_revision()),
+            ])
+            .and_then([TxnOp::put(
+                claim,
+                worker,
+                Some(PutOptions::new().with_lease(lease)),
+            )]);
+        let claimed = self
+            .etcd
+            .call_once("txn", claim.as_bytes(), self.etcd.client.clone().txn(txn))
+            .await;
+        match claimed {
+            Ok(rsp) if rsp.succeeded() => Ok(Some(QueueItem {
+                queue: self.clone(),
+                seq: claim[self.claims().len()..].to_owned(),
+                key: String::from_utf8_lossy(kv.key()).into_owned(),
+                claim: claim.to_owned(),
+                lease,
+                value: kv.value().to_vec(),
+            })),
+            claimed => {
+                let _ = self.etcd.client.clone().lease_revoke(lease).await;
+                claimed.map(|_| None)
+            }
+        }
+    }
+
+    /// Claims the oldest free item, waiting for one to be pushed or to become visible
+    /// again.
+    pub async fn next(&self) -> Result<QueueItem> {
+        let poll = Duration::from_millis(self.config.poll_interval.max(1));
+        let items = self.items();
+        // opened before claiming so that no push in between goes unseen, and kept open
+        // across the polls
+        let mut watch = None;
+        loop {
+            let (_watcher, stream) = match &mut watch {
+                Some(watch) => watch,
+                None => watch.insert(
+                    self.etcd
+                        .client
+                        .clone()
+                        .watch(items.as_str(), Some(WatchOptions::new().with_prefix()))
+                        .await
+                        .map_err(|e| op_error("watch", &items, e))?,
+                ),
+            };
+            if let Some(item) = self.claim().await? {
+                return Ok(item);
+            }
+            // a claim expiring is not seen by the watch, look again after a while
+            let polled = tokio::time::timeout(poll, stream.message()).await;
+            if matches!(polled, Ok(Ok(None) | Err(_))) {
+                // the watch broke, opened again for the next round
+                watch = None;
+            }
+        }
+    }
+
+    /// Runs `workers` workers in `scope`, each handling one item at a time with
+    /// `handler`. An item is acknowledged once handled. One that failed is handed out
+    /// again after its visibility timeout, which spaces out the attempts, unless it
+    /// failed `max_attempts` times, see [`QueueItem::fail`].
+    pub fn work_in<F, Fut>(&self, scope: &TaskScope, workers: usize, handler: F)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        for i in 0..workers.max(1) {
+            let queue = self.clone();
+            let handler = handler.clone();
+            let name = format!("etcd queue {} worker {i}", self.name);
+            scope.spawn(&name.clone(), async move {
+                loop {
+                    let item = match queue.next().await {
+                        Ok(item) => item,
+                        Err(e) => {
+                            warn!("{name} failed to claim: {e}");
+                            tokio::time::sleep(Duration::from_millis(queue.config.poll_interval))
+                                .await;
+                            continue;
+                        }
+                    };
+                    let key = item.key().to_owned();
+                    match handler(item.value().to_vec()).await {
+                        Ok(()) => match item.ack().await {
+                            Ok(true) => debug!("{name} handled {key}"),
+                            Ok(false) => warn!("{name} handled {key} after its claim expired"),
+                            Err(e) => error!("{name} failed to ack {key}: {e}"),
+                        },
+                        Err(e) => match item.fail().await {
+                            Ok(true) => {
+                                error!("{name} failed to handle {key}: {e}, moved to dead letters")
+                            }
+                            Ok(false) => error!("{name} failed to handle {key}: {e}"),
+                            Err(fail) => {
+                                error!("{name} failed to handle {key}: {e}, not counted: {fail}")
+                            }
+                        },
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl QueueItem {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    const fn etcd(&self) -> &Etcd {
+        &self.queue.etcd
+    }
+
+    fn attempts(&self) -> String {
+        format!("{}{}", self.queue.attempts(), self.seq)
+    }
+
+    /// Removes the item from the queue. `false` if the claim expired first, the item may
+    /// have been handed to another worker then.
+    pub async fn ack(self) -> Result<bool> {
+        let mut client = self.etcd().client.clone();
+        let txn = Txn::new()
+            .when([Compare::lease(
+                self.claim.as_str(),
+                CompareOp::Equal,
+                self.lease,
+            )])
+            .and_then([
+                TxnOp::delete(self.key.as_str(), None),
+                TxnOp::delete(self.claim.as_str(), None),
+                TxnOp::delete(self.attempts(), None),
+            ]);
+        let acked = self
+            .etcd()
+            .call_once("txn", self.key.as_bytes(), client.clone().txn(txn))
+            .await?
+            .succeeded();
+        let _ = client.lease_revoke(self.lease).await;
+        Ok(acked)
+    }
+
+    /// Gives the item back to be claimed again at once, not counted as an attempt.
+    pub async fn release(self) -> Result<()> {
+        self.etcd()
+            .call_once(
+                "lease_revoke",
+                self.claim.as_bytes(),
+                self.etcd().client.clone().lease_revoke(self.lease),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Counts a failed attempt and leaves the item claimed until its visibility timeout,
+    /// or moves it to the dead letters once it failed `max_attempts` times. Returns
+    /// whether it was moved. Nothing is counted if the claim expired first.
+    pub async fn fail(self) -> Result<bool> {
+        let attempts_key = self.attempts();
+        let attempts: u32 = self
+            .etcd()
+            .call_idempotent("get", attempts_key.as_bytes(), |mut client| {
+                let key = attempts_key.clone();
+                async move { client.get(key, None).await }
+            })
+            .await?
+            .kvs()
+            .first()
+            .and_then(|kv| String::from_utf8_lossy(kv.value()).parse().ok())
+            .unwrap_or(0)
+            + 1;
+        let max_attempts = self.queue.config.max_attempts;
+        let dead = max_attempts > 0 && attempts >= max_attempts;
+        let ops = if dead {
+            vec![
+                TxnOp::put(
+                    format!("{}{}", self.queue.dead(), self.seq),
+                    self.value.as_slice(),
+                    None,
+                ),
+                TxnOp::delete(self.key.as_str(), None),
+                TxnOp::delete(self.claim.as_str(), None),
+                TxnOp::delete(attempts_key.as_str(), None),
+            ]
+        } else {
+            vec![TxnOp::put(
+                attempts_key.as_str(),
+                attempts.to_string(),
+                None,
+            )]
+        };
+        // only while the claim is this one, a late worker counts nothing
+        let txn = Txn::new()
+            .when([Compare::lease(
+                self.claim.as_str(),
+                CompareOp::Equal,
+                self.lease,
+            )])
+            .and_then(ops);
+        let counted = self
+            .etcd()
+            .call_once(
+                "txn",
+                self.key.as_bytes(),
+                self.etcd().client.clone().txn(txn),
+            )
+            .await?
+            .succeeded();
+        if dead {
+            let _ = self.etcd().client.clone().lease_revoke(self.lease).await;
+        }
+        Ok(counted && dead)
+    }
+
+    /// Hides the item from other workers for another visibility timeout.
+    pub async fn extend(&self) -> Result<()> {
+        let (mut keeper, mut stream) = self
+            .etcd()
+            .client
+            .clone()
+            .lease_keep_alive(self.lease)
+            .await
+            .map_err(|e| op_error("lease_keep_alive", &self.claim, e))?;
+        keeper
+            .keep_alive()
+            .await
+            .map_err(|e| op_error("lease_keep_alive", &self.claim, e))?;
+        match stream.message().await {
+            Ok(Some(rsp)) if rsp.ttl() > 0 => Ok(()),
+            Ok(_) => Err(eyre!("claim {} expired", self.claim)),
+            Err(e) => Err(op_error("lease_keep_alive", &self.claim, e).into()),
+        }
+    }
+}
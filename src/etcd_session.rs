@@ -0,0 +1,235 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::Result;
+use etcd_client::{Compare, CompareOp, PutOptions, Txn, TxnOp};
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+use crate::{
+    etcd::{keep_lease, owner_id, Etcd},
+    service_register::{instance_entries, register_entries, ServiceRegisterConfig},
+    supervisor::Supervisor,
+};
+
+/// A lease owned by this process and kept alive by a supervised task, see
+/// [`Session::run_in`]. Keys attached to the session live as long as its lease and are
+/// written again under the next lease should it be lost, e.g. while etcd was out of
+/// reach for longer than `ttl`. A renewal still unanswered shortly before the lease
+/// would expire counts as lost too. Consumers holding other state on the lease, such as a
+/// lock from [`Session::try_lock`], follow it with [`Session::subscribe`] to take it
+/// again. Cloning is cheap and every clone shares the session.
+///
+/// Sessions are opt-in: [`Etcd::put`] with a ttl, [`Etcd::put_or_touch`] and the register
+/// loop keep a lease per key as before, use [`Session::attach`] and [`Session::register`]
+/// for keys to share the one lease instead.
+#[derive(Clone)]
+pub struct Session {
+    etcd: Etcd,
+    ttl: i64,
+    /// the current lease, `None` until it is granted and while it is lost
+    lease: Arc<watch::Sender<Option<i64>>>,
+    /// locked while the keys are written under a new lease
+    keys: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Session {
+    /// A session on a lease of `ttl` seconds, granted once it runs.
+    pub fn new(etcd: Etcd, ttl: i64) -> Self {
+        Self {
+            etcd,
+            ttl: ttl.max(1),
+            lease: Arc::new(watch::Sender::new(None)),
+            keys: Default::default(),
+            closed: Default::default(),
+        }
+    }
+
+    /// Keeps the lease alive under `supervisor`, granting a new one whenever it is lost.
+    pub fn run_in(&self, supervisor: &Supervisor) {
+        let session = self.clone();
+        supervisor.spawn("etcd_session", move || session.clone().keep());
+    }
+
+    pub fn lease(&self) -> Option<i64> {
+        *self.lease.borrow()
+    }
+
+    /// Waits for the lease, returns its id.
+    pub async fn ready(&self) -> i64 {
+        let mut lease = self.lease.subscribe();
+        let ready = lease.wait_for(Option::is_some).await;
+        ready.ok().and_then(|lease| *lease).unwrap_or_default()
+    }
+
+    /// Follows the lease: it changes once it is lost, to `None`, and again once a new
+    /// one is granted.
+    pub fn subscribe(&self) -> watch::Receiver<Option<i64>> {
+        self.lease.subscribe()
+    }
+
+    /// Writes `key` under the lease, now if it is alive and under every later one.
+    pub async fn attach(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let mut keys = self.keys.lock().await;
+        if let Some(lease) = self.lease() {
+            self.put(&key, &value, lease).await?;
+        }
+        keys.insert(key, value);
+        Ok(())
+    }
+
+    /// Removes `key` from the session and from etcd.
+    pub async fn detach(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.keys.lock().await.remove(&key);
+        self.etcd.delete(key).await?;
+        Ok(())
+    }
+
+    /// Attaches the keys registering `service_name`, so they are renewed with the lease
+    /// instead of by a register loop of their own. `config.ttl` is not used.
+    pub async fn register(&self, service_name: &str, config: &ServiceRegisterConfig) -> Result<()> {
+        for (key, value) in register_entries(service_name, config) {
+            self.attach(key, value).await?;
+        }
+        info!("service_register in session: {service_name}");
+        Ok(())
+    }
+
+    /// Detaches the keys of this instance, the ones shared with the other replicas of
+    /// `service_name` are no longer renewed.
+    pub async fn deregister(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+    ) -> Result<()> {
+        let own = instance_entries(service_name, config);
+        for (key, _) in register_entries(service_name, config) {
+            if own.iter().any(|(instance_key, _)| *instance_key == key) {
+                self.detach(key).await?;
+            } else {
+                // shared with the other replicas, left in etcd to expire with the lease
+                self.keys.lock().await.remove(key.as_bytes());
+            }
+        }
+        info!("service_deregister in session: {service_name}");
+        Ok(())
+    }
+
+    /// Takes the lock at `key` unless another holder has it, `false` then. The lock is
+    /// held until the lease is lost or [`Session::unlock`], and not taken back under the
+    /// next lease.
+    pub async fn try_lock(&self, key: &str) -> Result<bool> {
+        let lease = self.ready().await;
+        let txn = Txn::new()
+            .when([Compare::create_revision(key, CompareOp::Equal, 0)])
+            .and_then([TxnOp::put(
+                key,
+                owner_id(),
+                Some(PutOptions::new().with_lease(lease)),
+            )]);
+        Ok(self
+            .etcd
+            .call_once("txn", key.as_bytes(), self.etcd.client.clone().txn(txn))
+            .await?
+            .succeeded())
+    }
+
+    /// Releases a lock taken by [`Session::try_lock`] under the current lease.
+    pub async fn unlock(&self, key: &str) -> Result<()> {
+        let Some(lease) = self.lease() else {
+            return Ok(());
+        };
+        let txn = Txn::new()
+            .when([Compare::lease(key, CompareOp::Equal, lease)])
+            .and_then([TxnOp::delete(key, None)]);
+        self.etcd
+            .call_once("txn", key.as_bytes(), self.etcd.client.clone().txn(txn))
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes the lease, removing every key on it, and stops renewing it.
+    pub async fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(lease) = self.lease.send_replace(None) {
+            self.etcd
+                .call_once(
+                    "lease_revoke",
+                    b"",
+                    self.etcd.client.clone().lease_revoke(lease),
+                )
+                .await?;
+            info!("etcd session {lease:x} closed");
+        }
+        Ok(())
+    }
+
+    async fn keep(self) -> Result<()> {
+        let mut client = self.etcd.client.clone();
+        while !self.closed.load(Ordering::Relaxed) {
+            let granted = tokio::time::Instant::now();
+            let lease = self
+                .etcd
+                .call_once("lease_grant", b"", client.lease_grant(self.ttl, None))
+                .await?
+                .id();
+            {
+                let keys = self.keys.lock().await;
+                if self.closed.load(Ordering::Relaxed) {
+                    let _ = client.lease_revoke(lease).await;
+                    break;
+                }
+                for (key, value) in keys.iter() {
+                    if let Err(e) = self.put(key, value, lease).await {
+                        let _ = client.lease_revoke(lease).await;
+                        return Err(e);
+                    }
+                }
+                self.lease.send_replace(Some(lease));
+            }
+            info!("etcd session {lease:x} established");
+            let e = keep_lease(client.clone(), lease, self.ttl, granted).await;
+            if self.closed.load(Ordering::Relaxed) {
+                break;
+            }
+            self.lease.send_replace(None);
+            warn!("etcd session {lease:x} lost: {e}");
+            let _ = client.lease_revoke(lease).await;
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8], lease: i64) -> Result<()> {
+        let options = PutOptions::new().with_lease(lease);
+        self.etcd
+            .call_once(
+                "put",
+                key,
+                self.etcd.client.clone().put(key, value, Some(options)),
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{EventType, WatchOptions};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::{
+    etcd::{op_error, Etcd, KeyValue},
+    shutdown::TaskScope,
+};
+
+#[cfg(feature = "metrics")]
+static LAGGED: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "etcd_watch_lagged_total",
+            "Watch events a slow receiver of an etcd watch broadcast missed",
+            ["prefix"]
+        )
+    });
+
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Put(KeyValue),
+    Delete(KeyValue),
+    /// events were missed, by a receiver lagging behind or by the watch starting over
+    /// past a compaction, the prefix is to be read again
+    Resync,
+}
+
+/// One etcd watch of a prefix shared by any number of receivers, from
+/// [`Etcd::watch_to_broadcast`]. The watch is resumed from the last revision seen once
+/// its stream breaks.
+#[derive(Clone)]
+pub struct WatchBroadcast {
+    prefix: Arc<str>,
+    sender: broadcast::Sender<WatchEvent>,
+}
+
+pub struct WatchReceiver {
+    prefix: Arc<str>,
+    receiver: broadcast::Receiver<WatchEvent>,
+}
+
+impl WatchBroadcast {
+    pub(crate) fn start(etcd: Etcd, scope: &TaskScope, prefix: &str, capacity: usize) -> Self {
+        let broadcast = Self {
+            prefix: prefix.into(),
+            sender: broadcast::Sender::new(capacity.max(1)),
+        };
+        let watch = broadcast.clone();
+        scope.spawn(&format!("etcd watch {prefix}"), async move {
+            let mut revision = 0;
+            loop {
+                if let Err(e) = watch.forward(&etcd, &mut revision).await {
+                    warn!("etcd watch of {} failed: {e}, retry in 1s", watch.prefix);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        broadcast
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Receives the events from now on.
+    pub fn subscribe(&self) -> WatchReceiver {
+        WatchReceiver {
+            prefix: self.prefix.clone(),
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub fn receivers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Sends the events of the watch from after `revision`, or from now and a
+    /// [`WatchEvent::Resync`] if it is 0, and moves `revision` along.
+    async fn forward(&self, etcd: &Etcd, revision: &mut i64) -> Result<()> {
+        let prefix = self.prefix.to_string();
+        let mut options = WatchOptions::new().with_prefix().with_progress_notify();
+        if *revision != 0 {
+            options = options.with_start_revision(*revision + 1);
+        }
+        let (_watcher, mut stream) = etcd
+            .client
+            .clone()
+            .watch(prefix.as_str(), Some(options))
+            .await
+            .map_err(|e| op_error("watch", &prefix, e))?;
+        if *revision == 0 {
+            // nothing tells what happened before now, e.g. since the last watch failed
+            let _ = self.sender.send(WatchEvent::Resync);
+        }
+        while let Some(rsp) = stream
+            .message()
+            .await
+            .map_err(|e| op_error("watch", &prefix, e))?
+        {
+            if rsp.canceled() {
+                // compacted past the revision, the events in between are gone
+                *revision = 0;
+                return Err(eyre!("etcd watch of {prefix} canceled"));
+            }
+            for event in rsp.events() {
+                let Some(kv) = event.kv() else {
+                    continue;
+                };
+                let event = match event.event_type() {
+                    EventType::Put => WatchEvent::Put(kv.clone()),
+                    EventType::Delete => WatchEvent::Delete(kv.clone()),
+                };
+                // no receiver is no error, the watch goes on for later ones
+                let _ = self.sender.send(event);
+            }
+            // progress notifications move it along too, past the events of other prefixes
+            if let Some(header) = rsp.header() {
+                *revision = header.revision();
+            }
+        }
+        Err(eyre!("etcd watch of {prefix} closed"))
+    }
+}
+
+impl WatchReceiver {
+    /// The next event, [`WatchEvent::Resync`] once this receiver fell behind. `None` once
+    /// the scope of the watch is cancelled and every [`WatchBroadcast`] dropped.
+    pub async fn recv(&mut self) -> Option<WatchEvent> {
+        match self.receiver.recv().await {
+            Ok(event) => Some(event),
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "receiver of etcd watch {} lagged, missed {missed} events",
+                    self.prefix
+                );
+                #[cfg(feature = "metrics")]
+                LAGGED.with_label_values(&[&self.prefix]).inc_by(missed);
+                Some(WatchEvent::Resync)
+            }
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+/// Keeps a local copy of the keys under the prefix of `broadcast` in step, e.g. the
+/// feature flags or the maintenance mode: `load` reads them all, returning the revision read at, first and again
+/// on every [`WatchEvent::Resync`], and `apply` takes the events after that revision.
+/// Returns once the broadcast ends.
+pub(crate) async fn follow<L, Fut>(broadcast: &WatchBroadcast, load: L, apply: impl Fn(WatchEvent))
+where
+    L: Fn() -> Fut,
+    Fut: Future<Output = Result<i64>>,
+{
+    // subscribed before loading, so that no later event goes unseen
+    let mut receiver = broadcast.subscribe();
+    let load = || async {
+        loop {
+            match load().await {
+                Ok(revision) => return revision,
+                Err(e) => warn!("load of {} failed: {e}, retry in 1s", broadcast.prefix),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+    let mut revision = load().await;
+    while let Some(event) = receiver.recv().await {
+        match event {
+            WatchEvent::Resync => revision = load().await,
+            // read by the load already
+            WatchEvent::Put(ref kv) | WatchEvent::Delete(ref kv)
+                if kv.mod_revision() <= revision => {}
+            event => apply(event),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+use tracing::warn;
+
+#[cfg(feature = "metrics")]
+static PUBLISHED: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "event_bus_published_total",
+            "Events published on the event bus",
+            ["event"]
+        )
+    });
+
+#[cfg(feature = "metrics")]
+static LAGGED: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "event_bus_lagged_total",
+            "Events a slow subscriber of the event bus missed",
+            ["event"]
+        )
+    });
+
+/// Broadcasts events by their type between the modules of a service, e.g. new blocks from
+/// the block follower to the cache invalidator and the websocket pusher, without either
+/// knowing of the other. Each event type has a channel of its own holding `capacity`
+/// events, a subscriber falling further behind misses the oldest ones. Cloning is cheap
+/// and every clone publishes on the same channels.
+#[derive(Clone)]
+pub struct EventBus {
+    capacity: usize,
+    channels: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            channels: Default::default(),
+        }
+    }
+
+    /// Sends `event` to the current subscribers of `E`, returns how many there are.
+    pub fn publish<E: Clone + Send + Sync + 'static>(&self, event: E) -> usize {
+        #[cfg(feature = "metrics")]
+        PUBLISHED.with_label_values(&[event_name::<E>()]).inc();
+        self.sender::<E>().send(event).unwrap_or(0)
+    }
+
+    /// Receives the events of type `E` published from now on.
+    pub fn subscribe<E: Clone + Send + Sync + 'static>(&self) -> Subscriber<E> {
+        Subscriber {
+            receiver: self.sender::<E>().subscribe(),
+        }
+    }
+
+    pub fn subscribers<E: Clone + Send + Sync + 'static>(&self) -> usize {
+        self.sender::<E>().receiver_count()
+    }
+
+    fn sender<E: Clone + Send + Sync + 'static>(&self) -> broadcast::Sender<E> {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        channels
+            .entry(TypeId::of::<E>())
+            .or_insert_with(|| Box::new(broadcast::channel::<E>(self.capacity).0))
+            .downcast_ref::<broadcast::Sender<E>>()
+            .expect("channel keyed by its event type")
+            .clone()
+    }
+}
+
+/// Events of type `E` from an [`EventBus`].
+pub struct Subscriber<E> {
+    receiver: broadcast::Receiver<E>,
+}
+
+impl<E: Clone> Subscriber<E> {
+    /// The next event, skipping over the ones missed by lagging behind. `None` once every
+    /// clone of the bus is dropped.
+    pub async fn recv(&mut self) -> Option<E> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => lagged::<E>(missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event if one is waiting.
+    pub fn try_recv(&mut self) -> Option<E> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(missed)) => lagged::<E>(missed),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn lagged<E>(missed: u64) {
+    warn!(
+        "subscriber of {} lagged, missed {missed} events",
+        event_name::<E>()
+    );
+    #[cfg(feature = "metrics")]
+    LAGGED
+        .with_label_values(&[event_name::<E>()])
+        .inc_by(missed);
+}
+
+/// The type name without its module path, e.g. `NewBlock`.
+fn event_name<E>() -> &'static str {
+    let name = type_name::<E>();
+    let base = name.split('<').next().unwrap_or(name);
+    match base.rfind("::") {
+        Some(i) => &name[i + 2..],
+        None => name,
+    }
+}
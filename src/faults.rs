@@ -0,0 +1,208 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Faults injected into the etcd, redis and grpc clients as [`install`]ed, for chaos
+//! tests of the services built on them. Meant for test builds only.
+//!
+//! A fault applies to a whole call: it is delayed, then fails or goes through as one.
+//! Partial failures, such as some keys of a multi-get, some commands of a pipeline or
+//! some messages of a stream failing while the rest succeed, are not injected; tests of
+//! those paths have to fake the backend reply themselves.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultScenario {
+    /// for each call the first rule matching it applies
+    pub rules: Vec<FaultRule>,
+    /// seed of the error rolls, 0 for a random one
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultRule {
+    /// `etcd`, `redis` or `grpc`, empty for every backend
+    pub backend: String,
+    /// an etcd call such as `get` or `txn`, a redis command such as `GET` or a grpc
+    /// path such as `/controller.RPCService/GetBlockNumber`, empty for every operation
+    pub operation: String,
+    /// milliseconds added to the call
+    pub latency: u64,
+    /// chance from 0 to 1 of the call failing as if the backend were out of reach
+    pub error_rate: f64,
+    /// calls the rule applies to before it is spent, 0 for no limit
+    pub limit: u64,
+}
+
+impl FaultRule {
+    fn matches(&self, backend: &str, operation: &str) -> bool {
+        (self.backend.is_empty() || self.backend == backend)
+            && (self.operation.is_empty() || self.operation.eq_ignore_ascii_case(operation))
+    }
+}
+
+/// The error a failed call gets from [`install`]ed faults, wrapped in the error of its
+/// client.
+#[derive(Debug, thiserror::Error)]
+#[error("injected {backend} fault on {operation}")]
+pub struct InjectedFault {
+    pub backend: &'static str,
+    pub operation: String,
+}
+
+struct Active {
+    rules: Vec<(FaultRule, AtomicU64)>,
+    rng: AtomicU64,
+}
+
+static SCENARIO: RwLock<Option<Arc<Active>>> = RwLock::new(None);
+
+static INJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Injects the faults of `scenario` from now on, in place of any installed before.
+pub fn install(scenario: FaultScenario) {
+    let seed = match scenario.seed {
+        0 => crate::clock::unix_nanos() | 1,
+        seed => seed,
+    };
+    let active = Active {
+        rules: scenario
+            .rules
+            .into_iter()
+            .map(|rule| (rule, AtomicU64::new(0)))
+            .collect(),
+        rng: AtomicU64::new(seed),
+    };
+    *SCENARIO.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(active));
+}
+
+/// Stops injecting faults.
+pub fn clear() {
+    *SCENARIO.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Calls failed by injected faults so far.
+pub fn injected() -> u64 {
+    INJECTED.load(Ordering::Relaxed)
+}
+
+/// Delays `operation` of `backend` and decides whether it fails as the installed
+/// scenario says, called by the clients of this crate and open to other ones.
+pub async fn inject(backend: &'static str, operation: &str) -> Option<InjectedFault> {
+    let active = SCENARIO.read().unwrap_or_else(|e| e.into_inner()).clone()?;
+    let (rule, _) = active.rules.iter().find(|(rule, calls)| {
+        rule.matches(backend, operation)
+            && (rule.limit == 0 || calls.fetch_add(1, Ordering::Relaxed) < rule.limit)
+    })?;
+    if rule.latency != 0 {
+        tokio::time::sleep(Duration::from_millis(rule.latency)).await;
+    }
+    if rule.error_rate <= 0.0 || active.roll() >= rule.error_rate {
+        return None;
+    }
+    INJECTED.fetch_add(1, Ordering::Relaxed);
+    debug!("injected {backend} fault on {operation}");
+    Some(InjectedFault {
+        backend,
+        operation: operation.to_owned(),
+    })
+}
+
+impl Active {
+    /// A number in 0..1 from a xorshift generator, reproducible given the seed.
+    fn roll(&self) -> f64 {
+        let next = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^ (x << 17)
+        };
+        let x = self
+            .rng
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x)))
+            .map_or(0, next);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(backend: &str, operation: &str) -> FaultRule {
+        FaultRule {
+            backend: backend.to_owned(),
+            operation: operation.to_owned(),
+            error_rate: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rule_matching() {
+        assert!(rule("", "").matches("etcd", "get"));
+        assert!(rule("redis", "get").matches("redis", "GET"));
+        assert!(!rule("redis", "GET").matches("etcd", "get"));
+        assert!(!rule("etcd", "put").matches("etcd", "get"));
+    }
+
+    #[test]
+    fn rolls_are_reproducible() {
+        let active = |seed| Active {
+            rules: Vec::new(),
+            rng: AtomicU64::new(seed),
+        };
+        let (a, b) = (active(42), active(42));
+        for _ in 0..100 {
+            let roll = a.roll();
+            assert!((0.0..1.0).contains(&roll));
+            assert_eq!(roll, b.roll());
+        }
+    }
+
+    // the only test touching the installed scenario, which is global
+    #[tokio::test]
+    async fn limited_rule_is_spent() {
+        install(FaultScenario {
+            rules: vec![
+                FaultRule {
+                    limit: 2,
+                    ..rule("etcd", "get")
+                },
+                FaultRule {
+                    error_rate: 0.0,
+                    ..rule("etcd", "")
+                },
+            ],
+            seed: 7,
+        });
+        assert!(inject("etcd", "get").await.is_some());
+        assert!(inject("etcd", "get").await.is_some());
+        assert!(inject("etcd", "get").await.is_none());
+        assert!(inject("etcd", "put").await.is_none());
+        assert!(inject("redis", "GET").await.is_none());
+        clear();
+        assert!(inject("etcd", "get").await.is_none());
+    }
+}
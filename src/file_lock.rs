@@ -0,0 +1,105 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::eyre, Result};
+
+/// An exclusive advisory lock on a file, held until dropped, so that two processes on a
+/// host cannot both work on what the file guards, e.g. a data directory. The lock goes
+/// with the process, a crashed holder leaves no stale lock behind. The file keeps the
+/// pid of the holder and is not removed on release.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Takes the lock at `path`, creating the file, or fails at once if another process
+    /// holds it.
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| eyre!("open lock file {} failed: {e}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                return Err(eyre!(
+                    "{} is locked by another process (pid {})",
+                    path.display(),
+                    holder.trim()
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(eyre!("lock {} failed: {e}", path.display()))
+            }
+        }
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .and_then(|_| file.flush())
+            .map_err(|e| eyre!("write lock file {} failed: {e}", path.display()))?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Takes the lock `name` in the directory `dir`, e.g. `LOCK` in a data directory.
+    pub fn acquire_in(dir: impl AsRef<Path>, name: &str) -> Result<Self> {
+        Self::acquire(dir.as_ref().join(name))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the path still names the locked file, not once it is removed or replaced.
+    pub fn is_current(&self) -> bool {
+        let Ok(at_path) = std::fs::metadata(&self.path) else {
+            return false;
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.file
+                .metadata()
+                .is_ok_and(|locked| (locked.dev(), locked.ino()) == (at_path.dev(), at_path.ino()))
+        }
+        // an open file cannot be removed elsewhere
+        #[cfg(not(unix))]
+        {
+            let _ = at_path;
+            true
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
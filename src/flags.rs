@@ -0,0 +1,281 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use color_eyre::Result;
+use etcd_client::GetOptions;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    error::CommonError,
+    etcd::{op_error, Etcd, KeyValue},
+    etcd_watch::{follow, WatchEvent},
+    shutdown::TaskScope,
+};
+
+/// A flag as stored in etcd, in json.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Flag {
+    pub enabled: bool,
+    /// percent of the keys the flag is enabled for, see [`FeatureFlags::is_enabled_for`]
+    pub rollout: u8,
+    /// chains the flag is on or off for whatever `enabled` and `rollout` say
+    pub chains: HashMap<String, bool>,
+}
+
+impl Default for Flag {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rollout: 100,
+            chains: HashMap::new(),
+        }
+    }
+}
+
+impl Flag {
+    pub fn evaluate(&self, name: &str, key: Option<&str>, chain: Option<&str>) -> bool {
+        if let Some(enabled) = chain.and_then(|chain| self.chains.get(chain)) {
+            return *enabled;
+        }
+        if !self.enabled {
+            return false;
+        }
+        if self.rollout >= 100 {
+            return true;
+        }
+        // the same key stays on the same side of the rollout as it grows
+        key.is_some_and(|key| bucket(name, key) < u32::from(self.rollout))
+    }
+}
+
+/// Feature flags defined under an etcd prefix, `flags/` by default, one json [`Flag`] per
+/// key named after the flag. They are evaluated from a local copy kept up to date by a
+/// watch, so checking a flag is cheap enough for every request. A flag that is not
+/// defined is off. Cloning is cheap and every clone shares the flags.
+#[derive(Clone)]
+pub struct FeatureFlags {
+    etcd: Etcd,
+    prefix: Arc<str>,
+    flags: Arc<RwLock<HashMap<String, Flag>>>,
+}
+
+impl FeatureFlags {
+    pub fn new(etcd: Etcd) -> Self {
+        Self::with_prefix(etcd, "flags/")
+    }
+
+    pub fn with_prefix(etcd: Etcd, prefix: &str) -> Self {
+        Self {
+            etcd,
+            prefix: prefix.into(),
+            flags: Default::default(),
+        }
+    }
+
+    /// Whether the flag `name` is on for everyone, off while it is rolled out to part of
+    /// the keys only.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.is_enabled_for(name, None, None)
+    }
+
+    /// Whether the flag `name` is on for `chain` and `key`, e.g. a user or request id
+    /// placed in or out of the rollout by its hash.
+    pub fn is_enabled_for(&self, name: &str, key: Option<&str>, chain: Option<&str>) -> bool {
+        self.read()
+            .get(name)
+            .is_some_and(|flag| flag.evaluate(name, key, chain))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Flag> {
+        self.read().get(name).cloned()
+    }
+
+    pub fn all(&self) -> HashMap<String, Flag> {
+        self.read().clone()
+    }
+
+    /// Defines or updates the flag `name` for every instance.
+    pub async fn set(&self, name: &str, flag: &Flag) -> Result<()> {
+        let value = serde_json::to_vec(flag).map_err(|e| CommonError::Serde {
+            context: format!("serialize flag {name}"),
+            source: e.into(),
+        })?;
+        self.etcd
+            .put(format!("{}{name}", self.prefix), value, 0)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        self.etcd.delete(format!("{}{name}", self.prefix)).await?;
+        Ok(())
+    }
+
+    /// Reads every flag, returns the etcd revision they were read at.
+    pub async fn load(&self) -> Result<i64> {
+        let prefix = self.prefix.to_string();
+        let rsp = self
+            .etcd
+            .client
+            .clone()
+            .get(prefix.as_str(), Some(GetOptions::new().with_prefix()))
+            .await
+            .map_err(|e| op_error("get", &prefix, e))?;
+        let revision = rsp.header().map_or(0, |header| header.revision());
+        let flags = rsp.kvs().iter().filter_map(|kv| self.parse(kv)).collect();
+        *self.flags.write().unwrap_or_else(|e| e.into_inner()) = flags;
+        Ok(revision)
+    }
+
+    /// Loads the flags and keeps them up to date until `scope` is cancelled.
+    pub fn watch_in(&self, scope: &TaskScope) {
+        let flags = self.clone();
+        let broadcast = self.etcd.watch_to_broadcast(scope, &self.prefix);
+        scope.spawn("feature_flags", async move {
+            follow(
+                &broadcast,
+                || async {
+                    let revision = flags.load().await?;
+                    info!("feature flags loaded: {}", flags.read().len());
+                    Ok(revision)
+                },
+                |event| flags.apply(event),
+            )
+            .await;
+        });
+    }
+
+    fn apply(&self, event: WatchEvent) {
+        match event {
+            WatchEvent::Put(kv) => {
+                if let Some((name, flag)) = self.parse(&kv) {
+                    info!("feature flag {name} set: {flag:?}");
+                    self.write_flag(name, Some(flag));
+                }
+            }
+            WatchEvent::Delete(kv) => {
+                let name = String::from_utf8_lossy(&kv.key()[self.prefix.len()..]);
+                info!("feature flag {name} removed");
+                self.write_flag(name.into_owned(), None);
+            }
+            WatchEvent::Resync => {}
+        }
+    }
+
+    fn parse(&self, kv: &KeyValue) -> Option<(String, Flag)> {
+        let name = String::from_utf8_lossy(kv.key().get(self.prefix.len()..)?).into_owned();
+        match serde_json::from_slice(kv.value()) {
+            Ok(flag) => Some((name, flag)),
+            Err(e) => {
+                warn!("invalid feature flag {name}: {e}");
+                None
+            }
+        }
+    }
+
+    fn write_flag(&self, name: String, flag: Option<Flag>) {
+        let mut flags = self.flags.write().unwrap_or_else(|e| e.into_inner());
+        match flag {
+            Some(flag) => flags.insert(name, flag),
+            None => flags.remove(&name),
+        };
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, Flag>> {
+        self.flags.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Where `key` falls in 0..100 for the flag `name`, by a fnv-1a hash stable across
+/// builds and instances.
+fn bucket(name: &str, key: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.bytes().chain([b':']).chain(key.bytes()) {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % 100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_is_fnv_1a() {
+        // pinned, so a change of the hash moving keys across rollouts is noticed
+        assert_eq!(bucket("new-index", "user-1"), 71);
+        assert_eq!(bucket("new-index", "user-2"), 90);
+        assert_eq!(bucket("dark-mode", "chain-a"), 58);
+    }
+
+    #[test]
+    fn buckets_spread_evenly() {
+        let mut counts = [0u32; 10];
+        for i in 0..10_000 {
+            let bucket = bucket("flag", &format!("key-{i}"));
+            assert!(bucket < 100);
+            counts[bucket as usize / 10] += 1;
+        }
+        assert!(
+            counts.iter().all(|count| (800..1200).contains(count)),
+            "{counts:?}"
+        );
+    }
+
+    #[test]
+    fn rollout_only_grows() {
+        let flag = |rollout| Flag {
+            enabled: true,
+            rollout,
+            ..Default::default()
+        };
+        for i in 0..1000 {
+            let key = format!("key-{i}");
+            let on = |rollout| flag(rollout).evaluate("flag", Some(&key), None);
+            assert!(!on(0));
+            if on(10) {
+                assert!(on(50));
+            }
+            assert!(on(100));
+        }
+        assert!(!flag(50).evaluate("flag", None, None));
+        assert!(flag(100).evaluate("flag", None, None));
+    }
+
+    #[test]
+    fn chains_override() {
+        let flag = Flag {
+            enabled: false,
+            rollout: 0,
+            chains: HashMap::from([("a".to_owned(), true), ("b".to_owned(), false)]),
+        };
+        assert!(flag.evaluate("flag", None, Some("a")));
+        assert!(!flag.evaluate("flag", None, Some("b")));
+        assert!(!flag.evaluate("flag", None, Some("c")));
+        let enabled = Flag {
+            enabled: true,
+            ..flag
+        };
+        assert!(!enabled.evaluate("flag", Some("key"), Some("b")));
+        assert!(!enabled.evaluate("flag", Some("key"), None));
+    }
+}
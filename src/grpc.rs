@@ -0,0 +1,1560 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{Display, Formatter},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use cita_cloud_proto::{
+    blockchain::Block,
+    controller::{rpc_service_client::RpcServiceClient, BlockNumber, Flag},
+    evm::rpc_service_client::RpcServiceClient as EvmServiceClient,
+    executor::executor_service_client::ExecutorServiceClient,
+    health_check::{
+        health_check_response::ServingStatus,
+        health_client::HealthClient,
+        health_server::{Health, HealthServer},
+        HealthCheckRequest, HealthCheckResponse,
+    },
+};
+use color_eyre::{eyre::eyre, Result};
+use http_body_util::{BodyExt, Full};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Bytes, Service, StdError},
+    server::NamedService,
+    service::Routes,
+    transport::{
+        server::TcpIncoming, Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server,
+    },
+    Code, Status,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    breaker::{BreakerConfig, BreakerState, CircuitBreaker},
+    clock::Deadline,
+    health::{CheckFuture, HealthAggregator, HealthCheck, HealthRegistry},
+    limiter::{RateLimitQuota, RateLimiter},
+    service_register::{ServiceDiscovery, ServiceRegister, ServiceRegisterConfig},
+    shutdown::{signal_received, CancellationToken, Phase, Shutdown, TaskScope},
+    slow::SlowLog,
+};
+
+pub type ControllerClient = RpcServiceClient<GrpcChannel>;
+pub type ExecutorClient = ExecutorServiceClient<GrpcChannel>;
+pub type EvmClient = EvmServiceClient<GrpcChannel>;
+
+/// The CITA-Cloud services a cache talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Upstream {
+    Controller,
+    Executor,
+    Evm,
+}
+
+impl Upstream {
+    pub const ALL: [Self; 3] = [Self::Controller, Self::Executor, Self::Evm];
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Controller => write!(f, "controller"),
+            Self::Executor => write!(f, "executor"),
+            Self::Evm => write!(f, "evm"),
+        }
+    }
+}
+
+/// How calls are spread across the instances of an upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// the instance with the fewest calls in flight
+    LeastLoaded,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// e.g. `http://127.0.0.1:50004`
+    pub addr: String,
+    /// resolve the instances from service discovery under this name instead of `addr`
+    pub discovery: Option<String>,
+    pub balance: Balance,
+    /// overrides `GrpcConfig::tls` for this upstream
+    pub tls: Option<GrpcTlsConfig>,
+}
+
+/// TLS of the channels to `https://` upstreams, mutual when a client certificate is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcTlsConfig {
+    /// PEM CA bundle the upstream certificates are verified against, none trusts the
+    /// webpki roots
+    pub ca_path: Option<String>,
+    /// PEM client certificate presented for mutual TLS, along with `key_path`
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// name the upstream certificate is verified for instead of the address host
+    pub domain_name: Option<String>,
+}
+
+impl GrpcTlsConfig {
+    fn client_tls_config(&self) -> Result<ClientTlsConfig> {
+        let read = |path: &str| {
+            std::fs::read(path).map_err(|e| eyre!("read grpc tls file `{path}` failed: {e}"))
+        };
+        let mut tls = ClientTlsConfig::new();
+        tls = match &self.ca_path {
+            Some(ca) => tls.ca_certificate(Certificate::from_pem(read(ca)?)),
+            None => tls.with_webpki_roots(),
+        };
+        match (&self.cert_path, &self.key_path) {
+            (Some(cert), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(eyre!(
+                    "grpc tls cert_path and key_path must be set together"
+                ))
+            }
+        }
+        if let Some(domain_name) = &self.domain_name {
+            tls = tls.domain_name(domain_name);
+        }
+        Ok(tls)
+    }
+}
+
+impl UpstreamConfig {
+    fn with_addr(addr: &str) -> Self {
+        Self {
+            addr: addr.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcConfig {
+    pub controller: UpstreamConfig,
+    pub executor: UpstreamConfig,
+    pub evm: UpstreamConfig,
+    /// milliseconds to establish a connection
+    pub connect_timeout: u64,
+    /// milliseconds a call may take, 0 means no limit
+    pub timeout: u64,
+    /// largest response accepted, in bytes
+    pub max_decoding_message_size: usize,
+    /// TLS of the `https://` upstreams
+    pub tls: Option<GrpcTlsConfig>,
+    /// seconds between the grpc health checking probes of every upstream, 0 disables them
+    pub health_check_interval: u64,
+    /// seconds between two discoveries of the upstreams configured with `discovery`,
+    /// backends able to watch the registry also rediscover on every change
+    pub discovery_interval: u64,
+    pub retry: RetryConfig,
+    /// fails calls to an upstream fast while too many of them fail, per upstream
+    pub breaker: BreakerConfig,
+    /// milliseconds after which a call is logged as slow, 0 disables it
+    pub slow_threshold: u64,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            controller: UpstreamConfig::with_addr("http://127.0.0.1:50004"),
+            executor: UpstreamConfig::with_addr("http://127.0.0.1:50002"),
+            evm: UpstreamConfig::with_addr("http://127.0.0.1:50002"),
+            connect_timeout: 3000,
+            timeout: 10000,
+            max_decoding_message_size: 64 * 1024 * 1024,
+            tls: None,
+            health_check_interval: 10,
+            discovery_interval: 30,
+            retry: Default::default(),
+            breaker: Default::default(),
+            slow_threshold: 1000,
+        }
+    }
+}
+
+impl GrpcConfig {
+    pub const fn upstream(&self, upstream: Upstream) -> &UpstreamConfig {
+        match upstream {
+            Upstream::Controller => &self.controller,
+            Upstream::Executor => &self.executor,
+            Upstream::Evm => &self.evm,
+        }
+    }
+}
+
+/// What the pool knows about one upstream, as reported by [`GrpcPool::status`].
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    pub upstream: Upstream,
+    /// whether any instance is healthy
+    pub healthy: bool,
+    pub breaker: BreakerState,
+    pub instances: Vec<InstanceStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceStatus {
+    pub addr: String,
+    pub healthy: bool,
+    pub consecutive_failures: u64,
+    /// calls waiting for a response
+    pub in_flight: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message = format!("{message}: {cause}");
+        source = cause.source();
+    }
+    message
+}
+
+/// One instance of an upstream.
+struct Instance {
+    upstream: Upstream,
+    addr: String,
+    endpoint: Endpoint,
+    /// replaced by a freshly connected one when a probe finds it broken
+    channel: RwLock<Channel>,
+    in_flight: AtomicUsize,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Instance {
+    fn new(upstream: Upstream, addr: String, endpoint: Endpoint) -> Self {
+        Self {
+            upstream,
+            addr,
+            channel: RwLock::new(endpoint.connect_lazy()),
+            endpoint,
+            in_flight: Default::default(),
+            failures: Default::default(),
+            last_error: Default::default(),
+        }
+    }
+
+    fn channel(&self) -> Channel {
+        self.channel
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn reconnect(&self) {
+        *self.channel.write().unwrap_or_else(|e| e.into_inner()) = self.endpoint.connect_lazy();
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) == 0
+    }
+
+    /// Runs one `grpc.health.v1.Health/Check` probe, reconnecting on transport failures.
+    async fn probe(&self) {
+        let mut client = HealthClient::new(self.channel());
+        match client
+            .check(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+        {
+            Ok(response) => match response.into_inner().status() {
+                ServingStatus::Serving => self.success(),
+                status => self.failure(format!("health check: {}", status.as_str_name())),
+            },
+            Err(status) => {
+                let cause = std::error::Error::source(&status)
+                    .map_or_else(|| status.message().to_owned(), error_chain);
+                self.failure(format!("health check {:?}: {cause}", status.code()));
+                if matches!(status.code(), Code::Unavailable | Code::Unknown) {
+                    debug!("reconnect grpc upstream {} {}", self.upstream, self.addr);
+                    self.reconnect();
+                }
+            }
+        }
+    }
+
+    fn success(&self) {
+        if self.failures.swap(0, Ordering::Relaxed) != 0 {
+            info!("grpc upstream {} {} recovered", self.upstream, self.addr);
+        }
+    }
+
+    fn failure(&self, message: String) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!(
+                "grpc upstream {} {} unavailable: {message}",
+                self.upstream, self.addr
+            );
+        }
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(message);
+    }
+
+    fn status(&self) -> InstanceStatus {
+        let consecutive_failures = self.failures.load(Ordering::Relaxed);
+        InstanceStatus {
+            addr: self.addr.clone(),
+            healthy: consecutive_failures == 0,
+            consecutive_failures,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            last_error: self
+                .last_error
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+}
+
+/// Counts a call against its instance until the response arrives or the call is dropped.
+struct InFlight(Arc<Instance>);
+
+impl InFlight {
+    fn new(instance: Arc<Instance>) -> Self {
+        instance.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(instance)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct UpstreamState {
+    upstream: Upstream,
+    balance: Balance,
+    /// never empty, discovery keeps the previous instances when it resolves none
+    instances: RwLock<Vec<Arc<Instance>>>,
+    next: AtomicUsize,
+    breaker: CircuitBreaker,
+}
+
+impl UpstreamState {
+    fn new(config: &GrpcConfig, upstream: Upstream, addrs: Vec<String>) -> Result<Self> {
+        let state = Self {
+            upstream,
+            balance: config.upstream(upstream).balance,
+            instances: Default::default(),
+            next: Default::default(),
+            breaker: CircuitBreaker::new(&format!("grpc_{upstream}"), config.breaker),
+        };
+        state.update(config, addrs)?;
+        Ok(state)
+    }
+
+    fn instances(&self) -> Vec<Arc<Instance>> {
+        self.instances
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replaces the instances with `addrs`, keeping the connections of those still listed.
+    fn update(&self, config: &GrpcConfig, mut addrs: Vec<String>) -> Result<()> {
+        let upstream = self.upstream;
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() {
+            return Err(eyre!("no instance of grpc upstream {upstream}"));
+        }
+        let current = self.instances();
+        if current.iter().map(|i| &i.addr).eq(addrs.iter()) {
+            return Ok(());
+        }
+        let mut instances = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let instance = match current.iter().find(|i| i.addr == addr) {
+                Some(instance) => instance.clone(),
+                None => {
+                    let endpoint = endpoint(config, upstream, &addr)?;
+                    Arc::new(Instance::new(upstream, addr, endpoint))
+                }
+            };
+            instances.push(instance);
+        }
+        info!(
+            "grpc upstream {upstream}: {}",
+            instances
+                .iter()
+                .map(|i| i.addr.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        *self.instances.write().unwrap_or_else(|e| e.into_inner()) = instances;
+        Ok(())
+    }
+
+    /// Picks the instance for the next call among the healthy ones, or among all of them
+    /// when none is healthy.
+    fn pick(&self) -> Arc<Instance> {
+        let instances = self.instances.read().unwrap_or_else(|e| e.into_inner());
+        let healthy: Vec<&Arc<Instance>> = instances.iter().filter(|i| i.is_healthy()).collect();
+        let candidates = if healthy.is_empty() {
+            instances.iter().collect()
+        } else {
+            healthy
+        };
+        let picked = match self.balance {
+            Balance::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Balance::LeastLoaded => candidates
+                .into_iter()
+                .min_by_key(|i| i.in_flight.load(Ordering::Relaxed))
+                .unwrap_or(&instances[0]),
+        };
+        picked.clone()
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        let instances: Vec<InstanceStatus> = self.instances().iter().map(|i| i.status()).collect();
+        UpstreamStatus {
+            upstream: self.upstream,
+            healthy: instances.iter().any(|i| i.healthy),
+            breaker: self.breaker.state(),
+            instances,
+        }
+    }
+}
+
+/// Methods retried by default besides every `Get*` one, they only read state.
+const IDEMPOTENT_METHODS: [&str; 2] = ["Call", "EstimateQuota"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// retries of an idempotent call failing with `Unavailable` or `DeadlineExceeded`
+    pub max_retries: u32,
+    /// milliseconds before the first retry, doubled on every further one
+    pub initial_backoff: u64,
+    /// milliseconds the backoff grows to at most
+    pub max_backoff: u64,
+    /// retries earned by every call, capping retries to this share of the traffic
+    pub budget_ratio: f64,
+    /// retries available up front, also the most that can be saved up
+    pub budget_reserve: f64,
+    /// `max_retries` overrides keyed by method name or full path, e.g. `SendRawTransaction`
+    /// or `/controller.RPCService/GetBlockNumber`; 0 disables retries of that method
+    pub methods: HashMap<String, u32>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: 100,
+            max_backoff: 2000,
+            budget_ratio: 0.1,
+            budget_reserve: 10.0,
+            methods: Default::default(),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn max_retries(&self, path: &str) -> u32 {
+        let method = path.rsplit('/').next().unwrap_or(path);
+        if let Some(max_retries) = self.methods.get(path).or_else(|| self.methods.get(method)) {
+            return *max_retries;
+        }
+        if method.starts_with("Get") || IDEMPOTENT_METHODS.contains(&method) {
+            self.max_retries
+        } else {
+            0
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        crate::retry::jitter(Duration::from_millis(
+            self.initial_backoff
+                .saturating_mul(1 << retry.min(16))
+                .min(self.max_backoff),
+        ))
+    }
+}
+
+struct Retry {
+    config: RetryConfig,
+    budget: Mutex<f64>,
+}
+
+impl Retry {
+    const fn new(config: RetryConfig) -> Self {
+        Self {
+            budget: Mutex::new(config.budget_reserve),
+            config,
+        }
+    }
+
+    fn deposit(&self) {
+        let mut budget = self.budget.lock().unwrap_or_else(|e| e.into_inner());
+        *budget = (*budget + self.config.budget_ratio).min(self.config.budget_reserve);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut budget = self.budget.lock().unwrap_or_else(|e| e.into_inner());
+        let granted = *budget >= 1.0;
+        if granted {
+            *budget -= 1.0;
+        }
+        granted
+    }
+}
+
+fn grpc_status(headers: &http::HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i32>().ok())
+        .map(Code::from_i32)
+}
+
+/// The status of a trailers-only response, which is how servers answer failed calls.
+fn retryable(response: &http::Response<BoxBody>) -> Option<Code> {
+    let code = grpc_status(response.headers())?;
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded).then_some(code)
+}
+
+#[cfg(feature = "metrics")]
+struct GrpcMetrics {
+    requests: crate::metrics::IntCounterVec,
+    duration: crate::metrics::HistogramVec,
+    retries: crate::metrics::IntCounterVec,
+}
+
+#[cfg(feature = "metrics")]
+static GRPC_METRICS: std::sync::LazyLock<GrpcMetrics> = std::sync::LazyLock::new(|| {
+    use crate::metrics::{register, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+
+    GrpcMetrics {
+        requests: register(
+            IntCounterVec::new(
+                Opts::new(
+                    "grpc_client_requests_total",
+                    "Total number of gRPC calls to upstreams",
+                ),
+                &["upstream", "method", "code"],
+            )
+            .unwrap(),
+        ),
+        duration: register(
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "grpc_client_request_duration_seconds",
+                    "gRPC upstream call latencies in seconds, retries included",
+                ),
+                &["upstream", "method", "code"],
+            )
+            .unwrap(),
+        ),
+        retries: register(
+            IntCounterVec::new(
+                Opts::new(
+                    "grpc_client_retries_total",
+                    "Total number of gRPC upstream call retries",
+                ),
+                &["upstream", "method"],
+            )
+            .unwrap(),
+        ),
+    }
+});
+
+/// Records one call once its status is known, which for successful calls is only when
+/// the trailers arrive. Calls dropped before that count as `Cancelled`.
+#[cfg(feature = "metrics")]
+struct CallMetrics {
+    upstream: String,
+    method: String,
+    start: Instant,
+    finished: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(feature = "metrics")]
+impl CallMetrics {
+    fn new(upstream: Upstream, path: &str) -> Self {
+        Self {
+            upstream: upstream.to_string(),
+            method: path.trim_start_matches('/').to_owned(),
+            start: Instant::now(),
+            finished: Default::default(),
+        }
+    }
+
+    fn finish(&self, code: Code) {
+        if self.finished.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let code = format!("{code:?}");
+        let labels = [self.upstream.as_str(), &self.method, &code];
+        GRPC_METRICS.requests.with_label_values(&labels).inc();
+        GRPC_METRICS
+            .duration
+            .with_label_values(&labels)
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+
+    async fn instrument(
+        self,
+        call: impl Future<Output = Result<http::Response<BoxBody>, StdError>>,
+    ) -> Result<http::Response<BoxBody>, StdError> {
+        let response = match call.await {
+            Ok(response) => response,
+            Err(e) => {
+                self.finish(Code::Unavailable);
+                return Err(e);
+            }
+        };
+        if let Some(code) = grpc_status(response.headers()) {
+            self.finish(code);
+            return Ok(response);
+        }
+        let on_trailers = Arc::new(self);
+        let on_error = on_trailers.clone();
+        let (parts, body) = response.into_parts();
+        let body = body
+            .map_frame(move |frame| {
+                if let Some(trailers) = frame.trailers_ref() {
+                    on_trailers.finish(grpc_status(trailers).unwrap_or(Code::Unknown));
+                }
+                frame
+            })
+            .map_err(move |status| {
+                on_error.finish(status.code());
+                status
+            })
+            .boxed_unsync();
+        Ok(http::Response::from_parts(parts, body))
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for CallMetrics {
+    fn drop(&mut self) {
+        self.finish(Code::Cancelled);
+    }
+}
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Parses a `grpc-timeout` header value, e.g. `250m` or `3S`.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    // digits only, `parse` would take a sign too
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount.saturating_mul(3600)),
+        "M" => Duration::from_secs(amount.saturating_mul(60)),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// The remaining time as a `grpc-timeout` header value, at most 8 digits as the spec
+/// requires.
+fn grpc_timeout_value(remaining: Duration) -> String {
+    let millis = remaining.as_millis();
+    if millis < 100_000_000 {
+        format!("{millis}m")
+    } else {
+        format!("{}S", remaining.as_secs().min(99_999_999))
+    }
+}
+
+fn deadline_exceeded() -> http::Response<BoxBody> {
+    Status::deadline_exceeded("deadline exceeded before the upstream answered").into_http()
+}
+
+fn replay(
+    parts: &http::request::Parts,
+    body: &Bytes,
+    deadline: Option<Instant>,
+) -> http::Request<BoxBody> {
+    let mut request = http::Request::new(BoxBody::new(
+        Full::new(body.clone()).map_err(|never| match never {}),
+    ));
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    *request.extensions_mut() = parts.extensions.clone();
+    if let Some(deadline) = deadline {
+        let remaining = grpc_timeout_value(deadline.saturating_duration_since(Instant::now()));
+        if let Ok(value) = http::HeaderValue::from_str(&remaining) {
+            request.headers_mut().insert(GRPC_TIMEOUT, value);
+        }
+    }
+    request
+}
+
+/// Wraps `message` in a request whose `grpc-timeout` is the time left until `deadline`
+/// or the [`Deadline::current`] of the caller, whichever comes first, so neither the
+/// upstream nor the pool spend longer on the call than its caller waits.
+pub fn with_deadline<T>(message: T, deadline: Option<Instant>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    let deadline = match (deadline.map(Deadline::at), Deadline::current()) {
+        (Some(deadline), Some(current)) => Some(deadline.min(current)),
+        (deadline, current) => deadline.or(current),
+    };
+    if let Some(deadline) = deadline {
+        request.set_timeout(deadline.remaining());
+    }
+    request
+}
+
+/// [`with_deadline`] bounded by the timeout of the HTTP request being handled, see
+/// [`crate::restful::request_deadline`].
+#[cfg(feature = "http")]
+pub fn upstream_request<T>(depot: &salvo::Depot, message: T) -> tonic::Request<T> {
+    with_deadline(message, crate::restful::request_deadline(depot))
+}
+
+/// A pooled channel to one upstream. Cloning is cheap and every clone shares the
+/// connections; every attempt of a call goes to the instance picked by the configured
+/// [`Balance`], transport failures are recorded against that instance and idempotent
+/// calls are retried as configured by [`RetryConfig`].
+#[derive(Clone)]
+pub struct GrpcChannel {
+    state: Arc<UpstreamState>,
+    retry: Arc<Retry>,
+    slow_threshold: Duration,
+}
+
+impl GrpcChannel {
+    async fn send(
+        state: &UpstreamState,
+        request: http::Request<BoxBody>,
+    ) -> Result<http::Response<BoxBody>, tonic::transport::Error> {
+        #[cfg(feature = "faults")]
+        if let Some(fault) = crate::faults::inject("grpc", request.uri().path()).await {
+            return Ok(Status::unavailable(fault.to_string()).into_http());
+        }
+        let in_flight = InFlight::new(state.pick());
+        let instance = &in_flight.0;
+        let mut channel = instance.channel();
+        std::future::poll_fn(|cx| channel.poll_ready(cx)).await?;
+        let response = channel.call(request).await;
+        match &response {
+            Ok(_) => instance.success(),
+            Err(e) => instance.failure(error_chain(e)),
+        }
+        response
+    }
+
+    /// Answers `DeadlineExceeded` itself once `deadline` passes instead of waiting on, and
+    /// `Unavailable` while the circuit breaker of the upstream is open.
+    async fn attempt(
+        state: &UpstreamState,
+        request: http::Request<BoxBody>,
+        deadline: Option<Instant>,
+    ) -> Result<http::Response<BoxBody>, tonic::transport::Error> {
+        let Some(permit) = state.breaker.try_acquire() else {
+            return Ok(Status::unavailable(format!(
+                "circuit breaker of upstream {} is open",
+                state.upstream
+            ))
+            .into_http());
+        };
+        let response = match deadline {
+            None => Self::send(state, request).await,
+            Some(deadline) if deadline <= Instant::now() => return Ok(deadline_exceeded()),
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), Self::send(state, request))
+                .await
+                .unwrap_or_else(|_| Ok(deadline_exceeded())),
+        };
+        match &response {
+            Ok(response) if retryable(response).is_none() => permit.success(),
+            _ => permit.failure(),
+        }
+        response
+    }
+
+    async fn call_with_retry(
+        state: Arc<UpstreamState>,
+        retry: Arc<Retry>,
+        request: http::Request<BoxBody>,
+    ) -> Result<http::Response<BoxBody>, StdError> {
+        let path = request.uri().path().to_owned();
+        let deadline = request
+            .headers()
+            .get(GRPC_TIMEOUT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map(|timeout| Instant::now() + timeout);
+        let max_retries = retry.config.max_retries(&path);
+        if max_retries == 0 {
+            return Ok(Self::attempt(&state, request, deadline).await?);
+        }
+        retry.deposit();
+
+        let (parts, body) = request.into_parts();
+        let body = body.collect().await?.to_bytes();
+        let mut retries = 0;
+        loop {
+            let response = Self::attempt(&state, replay(&parts, &body, deadline), deadline).await;
+            let code = match &response {
+                Ok(response) => retryable(response),
+                Err(_) => Some(Code::Unavailable),
+            };
+            let backoff = retry.config.backoff(retries);
+            // a retry the deadline would cut short is not worth its budget
+            let in_time = deadline.is_none_or(|deadline| Instant::now() + backoff < deadline);
+            let closed = state.breaker.state() != BreakerState::Open;
+            let Some(code) =
+                code.filter(|_| retries < max_retries && in_time && closed && retry.withdraw())
+            else {
+                return Ok(response?);
+            };
+            retries += 1;
+            #[cfg(feature = "metrics")]
+            GRPC_METRICS
+                .retries
+                .with_label_values(&[&state.upstream.to_string(), path.trim_start_matches('/')])
+                .inc();
+            debug!("retry {path} on {code:?} in {backoff:?}, attempt {retries}/{max_retries}");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+impl Service<http::Request<BoxBody>> for GrpcChannel {
+    type Response = http::Response<BoxBody>;
+    type Error = StdError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    /// Always ready, every attempt waits for the channel of its instance instead.
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        #[cfg(feature = "metrics")]
+        let metrics = CallMetrics::new(self.state.upstream, request.uri().path());
+        let slow = SlowLog::start(
+            "grpc",
+            &self.state.upstream.to_string(),
+            request.uri().path().as_bytes(),
+            self.slow_threshold,
+        );
+        let call = Self::call_with_retry(self.state.clone(), self.retry.clone(), request);
+        let call = async move {
+            let response = call.await;
+            drop(slow);
+            response
+        };
+        #[cfg(feature = "metrics")]
+        let call = metrics.instrument(call);
+        Box::pin(call)
+    }
+}
+
+/// Lazily connected channels to the controller, executor and evm services.
+#[derive(Clone)]
+pub struct GrpcPool {
+    config: Arc<GrpcConfig>,
+    channels: Arc<HashMap<Upstream, GrpcChannel>>,
+    scope: TaskScope,
+}
+
+impl GrpcPool {
+    /// Builds the pool from the configured addresses, connecting on first use.
+    pub fn new(config: GrpcConfig) -> Result<Self> {
+        let mut addrs = HashMap::new();
+        for upstream in Upstream::ALL {
+            let upstream_config = config.upstream(upstream);
+            if let Some(name) = &upstream_config.discovery {
+                return Err(eyre!(
+                    "grpc upstream {upstream} uses discovery `{name}`, see `GrpcPool::discover`"
+                ));
+            }
+            addrs.insert(upstream, vec![upstream_config.addr.clone()]);
+        }
+        Self::build(config, addrs)
+    }
+
+    /// Like [`GrpcPool::new`], balancing across every registered instance of the
+    /// upstreams configured with `discovery`. See [`GrpcPool::watch_discovery`] to follow
+    /// later changes.
+    pub async fn discover(config: GrpcConfig, discovery: &impl ServiceDiscovery) -> Result<Self> {
+        let mut addrs = HashMap::new();
+        for upstream in Upstream::ALL {
+            let upstream_config = config.upstream(upstream);
+            let instances = match &upstream_config.discovery {
+                Some(name) => discovery.discover(name).await?,
+                None => vec![upstream_config.addr.clone()],
+            };
+            if instances.is_empty() {
+                return Err(eyre!(
+                    "no instance of `{}` registered",
+                    upstream_config.discovery.as_deref().unwrap_or_default()
+                ));
+            }
+            addrs.insert(upstream, instances);
+        }
+        Self::build(config, addrs)
+    }
+
+    fn build(config: GrpcConfig, addrs: HashMap<Upstream, Vec<String>>) -> Result<Self> {
+        let mut channels = HashMap::new();
+        for (upstream, addrs) in addrs {
+            let state = Arc::new(UpstreamState::new(&config, upstream, addrs)?);
+            let retry = Arc::new(Retry::new(config.retry.clone()));
+            channels.insert(
+                upstream,
+                GrpcChannel {
+                    state,
+                    retry,
+                    slow_threshold: Duration::from_millis(config.slow_threshold),
+                },
+            );
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            channels: Arc::new(channels),
+            scope: TaskScope::new(),
+        })
+    }
+
+    /// Stop the health and discovery watchers and the block followers once `cancel` is
+    /// cancelled.
+    pub fn cancel_on(mut self, cancel: CancellationToken) -> Self {
+        self.scope = TaskScope::from(cancel);
+        self
+    }
+
+    /// Spawn the health and discovery watchers and the block followers into `scope`.
+    pub fn in_scope(mut self, scope: &TaskScope) -> Self {
+        self.scope = scope.clone();
+        self
+    }
+
+    pub fn channel(&self, upstream: Upstream) -> GrpcChannel {
+        self.channels[&upstream].clone()
+    }
+
+    pub fn controller(&self) -> ControllerClient {
+        RpcServiceClient::new(self.channel(Upstream::Controller))
+            .max_decoding_message_size(self.config.max_decoding_message_size)
+    }
+
+    pub fn executor(&self) -> ExecutorClient {
+        ExecutorServiceClient::new(self.channel(Upstream::Executor))
+            .max_decoding_message_size(self.config.max_decoding_message_size)
+    }
+
+    pub fn evm(&self) -> EvmClient {
+        EvmServiceClient::new(self.channel(Upstream::Evm))
+            .max_decoding_message_size(self.config.max_decoding_message_size)
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        Upstream::ALL
+            .iter()
+            .map(|upstream| self.channels[upstream].state.status())
+            .collect()
+    }
+
+    /// Probes every instance each `health_check_interval`, rebuilding the channels found
+    /// broken so later calls connect afresh.
+    pub fn watch_health(&self) {
+        if self.config.health_check_interval == 0 {
+            return;
+        }
+        let period = Duration::from_secs(self.config.health_check_interval);
+        for channel in self.channels.values() {
+            let state = channel.state.clone();
+            let name = format!("grpc upstream {} health watcher", state.upstream);
+            self.scope.spawn(&name, async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    for instance in state.instances() {
+                        instance.probe().await;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Keeps the instances of the upstreams configured with `discovery` in line with the
+    /// registry, rediscovering on every change it reports and each `discovery_interval`.
+    pub fn watch_discovery<D>(&self, discovery: D)
+    where
+        D: ServiceDiscovery + Send + Sync + 'static,
+    {
+        let discovery = Arc::new(discovery);
+        let period = Duration::from_secs(self.config.discovery_interval.max(1));
+        for upstream in Upstream::ALL {
+            let Some(name) = self.config.upstream(upstream).discovery.clone() else {
+                continue;
+            };
+            let pool = self.clone();
+            let discovery = discovery.clone();
+            let task = format!("grpc upstream {upstream} discovery watcher");
+            self.scope.spawn(&task, async move {
+                loop {
+                    let changed = tokio::select! {
+                        changed = discovery.changed(&name) => changed,
+                        _ = tokio::time::sleep(period) => Ok(()),
+                    };
+                    if let Err(e) = changed {
+                        warn!("watch grpc upstream {upstream} `{name}` failed: {e}");
+                        tokio::time::sleep(period).await;
+                    }
+                    let updated = match discovery.discover(&name).await {
+                        Ok(addrs) => pool.channels[&upstream].state.update(&pool.config, addrs),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = updated {
+                        warn!("discover grpc upstream {upstream} `{name}` failed: {e}");
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockFollowerConfig {
+    /// milliseconds between two polls of the controller height once caught up
+    pub poll_interval: u64,
+    /// milliseconds to wait after the first failure, doubled on every further one
+    pub initial_backoff: u64,
+    pub max_backoff: u64,
+    /// blocks fetched ahead of the consumer
+    pub buffer: usize,
+    /// blocks fetched per second at most, e.g. to spare the executor a replay from an
+    /// old height, 0 for no limit
+    pub max_rate: f64,
+}
+
+impl Default for BlockFollowerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: 1000,
+            initial_backoff: 500,
+            max_backoff: 30000,
+            buffer: 16,
+            max_rate: 0.0,
+        }
+    }
+}
+
+/// Committed blocks in height order without gaps, see [`GrpcPool::follow_blocks`].
+pub struct BlockStream {
+    receiver: mpsc::Receiver<Block>,
+    next_height: u64,
+}
+
+impl BlockStream {
+    /// The height of the next block to yield, where a restarted follower should resume.
+    pub const fn next_height(&self) -> u64 {
+        self.next_height
+    }
+}
+
+impl futures_core::Stream for BlockStream {
+    type Item = Block;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Block>> {
+        let polled = self.receiver.poll_recv(cx);
+        if let Poll::Ready(Some(block)) = &polled {
+            if let Some(header) = &block.header {
+                self.next_height = header.height + 1;
+            }
+        }
+        polled
+    }
+}
+
+struct BlockFollower {
+    pool: GrpcPool,
+    config: BlockFollowerConfig,
+    sender: mpsc::Sender<Block>,
+    next: u64,
+    backoff: u64,
+    limiter: Option<RateLimiter<()>>,
+}
+
+impl BlockFollower {
+    async fn run(mut self) {
+        while !self.sender.is_closed() {
+            let tip = match self
+                .pool
+                .controller()
+                .get_block_number(Flag { flag: false })
+                .await
+            {
+                Ok(tip) => tip.into_inner().block_number,
+                Err(e) => {
+                    self.failed(format!("get block number {:?}: {}", e.code(), e.message()))
+                        .await;
+                    continue;
+                }
+            };
+            if self.next > tip {
+                // caught up, the stream may be dropped again before the next block
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(self.config.poll_interval)) => {}
+                    _ = self.sender.closed() => {
+                        debug!("block stream dropped, follower stopped at {}", self.next);
+                        return;
+                    }
+                }
+                continue;
+            }
+            while self.next <= tip {
+                if let Some(limiter) = &self.limiter {
+                    limiter.acquire(&()).await;
+                }
+                let height = self.next;
+                let block = match self
+                    .pool
+                    .controller()
+                    .get_block_detail_by_number(BlockNumber {
+                        block_number: height,
+                    })
+                    .await
+                {
+                    Ok(block) => block.into_inner(),
+                    Err(e) => {
+                        self.failed(format!(
+                            "get block {height} {:?}: {}",
+                            e.code(),
+                            e.message()
+                        ))
+                        .await;
+                        break;
+                    }
+                };
+                let got = block.header.as_ref().map(|header| header.height);
+                if got != Some(height) {
+                    self.failed(format!("block gap, expected {height} got {got:?}"))
+                        .await;
+                    break;
+                }
+                if self.sender.send(block).await.is_err() {
+                    debug!("block stream dropped, follower stopped at {height}");
+                    return;
+                }
+                self.next += 1;
+                self.backoff = self.config.initial_backoff;
+            }
+        }
+    }
+
+    async fn failed(&mut self, message: String) {
+        warn!("follow blocks: {message}, retry in {}ms", self.backoff);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(self.backoff)) => {}
+            _ = self.sender.closed() => {}
+        }
+        self.backoff = self
+            .backoff
+            .saturating_mul(2)
+            .min(self.config.max_backoff.max(1));
+    }
+}
+
+impl GrpcPool {
+    /// Follows the controller from height `from` on. Every committed block is fetched
+    /// once in height order, failures are retried with backoff, and the follower stops
+    /// when the stream is dropped.
+    pub fn follow_blocks(&self, from: u64, config: BlockFollowerConfig) -> BlockStream {
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        let follower = BlockFollower {
+            pool: self.clone(),
+            config,
+            sender,
+            next: from,
+            backoff: config.initial_backoff.max(1),
+            limiter: (config.max_rate > 0.0).then(|| {
+                RateLimiter::new(RateLimitQuota {
+                    rate: config.max_rate,
+                    burst: config.max_rate.ceil() as u64,
+                })
+            }),
+        };
+        self.scope.spawn("block follower", follower.run());
+        BlockStream {
+            receiver,
+            next_height: from,
+        }
+    }
+}
+
+/// Down while any upstream has no healthy instance.
+impl HealthCheck for GrpcPool {
+    fn name(&self) -> String {
+        "grpc".to_owned()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            let failing: Vec<String> = self
+                .status()
+                .into_iter()
+                .filter(|status| !status.healthy)
+                .map(|status| {
+                    let errors: Vec<String> = status
+                        .instances
+                        .into_iter()
+                        .map(|i| format!("{}: {}", i.addr, i.last_error.unwrap_or_default()))
+                        .collect();
+                    format!("{} ({})", status.upstream, errors.join("; "))
+                })
+                .collect();
+            if failing.is_empty() {
+                Ok(())
+            } else {
+                Err(eyre!("grpc upstreams failing: {}", failing.join(", ")))
+            }
+        })
+    }
+}
+
+fn endpoint(config: &GrpcConfig, upstream: Upstream, addr: &str) -> Result<Endpoint> {
+    let mut endpoint = Endpoint::from_shared(addr.to_owned())
+        .map_err(|e| eyre!("invalid grpc {upstream} address `{addr}`: {e}"))?
+        .connect_timeout(Duration::from_millis(config.connect_timeout));
+    if config.timeout != 0 {
+        endpoint = endpoint.timeout(Duration::from_millis(config.timeout));
+    }
+    let tls = config
+        .upstream(upstream)
+        .tls
+        .as_ref()
+        .or(config.tls.as_ref());
+    match tls {
+        Some(tls) if addr.starts_with("https://") => endpoint
+            .tls_config(tls.client_tls_config()?)
+            .map_err(|e| eyre!("invalid grpc {upstream} tls config: {e}")),
+        _ => Ok(endpoint),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcServerConfig {
+    pub listen_addr: String,
+    pub port: u16,
+    /// seconds to wait for in-flight calls to finish on shutdown, 0 means no limit
+    pub shutdown_timeout: u64,
+    /// milliseconds a call may take, 0 means no limit
+    pub timeout: u64,
+    /// serve `grpc.reflection.v1` for the registered file descriptor sets
+    pub reflection: bool,
+}
+
+impl Default for GrpcServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0".to_owned(),
+            port: 50051,
+            shutdown_timeout: 30,
+            timeout: 0,
+            reflection: true,
+        }
+    }
+}
+
+type RegisterFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// `grpc.health.v1.Health` backed by the server's [`HealthAggregator`].
+struct HealthService {
+    health: HealthAggregator,
+    services: Vec<&'static str>,
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    async fn check(
+        &self,
+        request: tonic::Request<HealthCheckRequest>,
+    ) -> std::result::Result<tonic::Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        if !service.is_empty() && !self.services.contains(&service.as_str()) {
+            return Err(Status::not_found(format!("unknown service `{service}`")));
+        }
+        let status = if self.health.report().await.is_up() {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        Ok(tonic::Response::new(HealthCheckResponse {
+            status: status as i32,
+        }))
+    }
+}
+
+/// Serves tonic services along with the standard health service and, when enabled,
+/// reflection.
+pub struct GrpcServer {
+    config: GrpcServerConfig,
+    service_name: String,
+    routes: Routes,
+    services: Vec<&'static str>,
+    descriptor_sets: Vec<&'static [u8]>,
+    health: HealthAggregator,
+    shutdown: Option<Shutdown>,
+    cancel: Option<CancellationToken>,
+    register: Option<Box<dyn FnOnce(String) -> RegisterFuture + Send>>,
+}
+
+impl GrpcServer {
+    pub fn new(config: GrpcServerConfig) -> Self {
+        Self {
+            config,
+            service_name: "grpc".to_owned(),
+            routes: Default::default(),
+            services: Vec::new(),
+            descriptor_sets: Vec::new(),
+            health: HealthRegistry::default().into(),
+            shutdown: None,
+            cancel: None,
+            register: None,
+        }
+    }
+
+    pub fn service_name(mut self, service_name: &str) -> Self {
+        service_name.clone_into(&mut self.service_name);
+        self
+    }
+
+    pub fn add_service<S>(mut self, service: S) -> Self
+    where
+        S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.routes = std::mem::take(&mut self.routes).add_service(service);
+        self.services.push(S::NAME);
+        self
+    }
+
+    /// Describes services to reflection, e.g. `cita_cloud_proto::CONTROLLER_DESCRIPTOR_SET`.
+    pub fn file_descriptor_set(mut self, encoded: &'static [u8]) -> Self {
+        self.descriptor_sets.push(encoded);
+        self
+    }
+
+    /// The checks deciding whether the health service answers `SERVING`.
+    pub fn health(mut self, health: impl Into<HealthAggregator>) -> Self {
+        self.health = health.into();
+        self
+    }
+
+    /// Drain the server in the [`Phase::Drain`] phase of `shutdown` instead of on its own
+    /// signal handler. The health service answers `NOT_SERVING` as soon as the shutdown is
+    /// triggered.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Drain the server once `cancel` is cancelled instead of on its own signal handler,
+    /// e.g. with the token of [`crate::shutdown::wait_for_shutdown`].
+    pub fn cancel_on(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Keep the service registered through `register` once the server is listening.
+    pub fn register<R>(mut self, register: R, config: ServiceRegisterConfig) -> Self
+    where
+        R: ServiceRegister + Send + Sync + 'static,
+    {
+        self.register = Some(Box::new(move |service_name| {
+            Box::pin(async move { register.keep_service_register(&service_name, config).await })
+        }));
+        self
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        let mut health = self.health;
+        if let Some(shutdown) = &self.shutdown {
+            health = health.live(shutdown.clone());
+        }
+        let mut services = self.services;
+        services.push(<HealthServer<HealthService> as NamedService>::NAME);
+        let mut routes = self
+            .routes
+            .add_service(HealthServer::new(HealthService { health, services }));
+        if self.config.reflection {
+            let reflection = self
+                .descriptor_sets
+                .iter()
+                .fold(
+                    tonic_reflection::server::Builder::configure(),
+                    |builder, set| builder.register_encoded_file_descriptor_set(set),
+                )
+                .build_v1()
+                .map_err(|e| eyre!("build grpc reflection failed: {e}"))?;
+            routes = routes.add_service(reflection);
+        }
+
+        let addr = format!("{}:{}", self.config.listen_addr, self.config.port);
+        let socket: SocketAddr = addr
+            .parse()
+            .map_err(|e| eyre!("invalid grpc listen address {addr}: {e}"))?;
+        let incoming =
+            TcpIncoming::new(socket, true, None).map_err(|e| eyre!("bind {addr} failed: {e}"))?;
+        info!("{} listening on grpc://{}", self.service_name, addr);
+        if let Some(register) = self.register {
+            register(self.service_name.clone()).await?;
+        }
+
+        let mut builder = Server::builder();
+        if self.config.timeout != 0 {
+            builder = builder.timeout(Duration::from_millis(self.config.timeout));
+        }
+        let (stopping, wait_stopping) = oneshot::channel();
+        let (stopped, wait_stopped) = oneshot::channel::<()>();
+        let signal = {
+            let shutdown = self.shutdown.clone();
+            let cancel = self.cancel.clone();
+            async move {
+                match (shutdown, cancel) {
+                    (Some(shutdown), _) => shutdown.reached(Phase::Drain).await,
+                    (None, Some(cancel)) => cancel.cancelled().await,
+                    (None, None) => signal_received().await,
+                }
+                let _ = stopping.send(());
+            }
+        };
+        if let Some(shutdown) = &self.shutdown {
+            shutdown.on(Phase::Drain, "grpc_server", move || async move {
+                let _ = wait_stopped.await;
+                Ok(())
+            });
+        }
+        let serve = builder
+            .add_routes(routes)
+            .serve_with_incoming_shutdown(incoming, signal);
+        let shutdown_timeout = Duration::from_secs(self.config.shutdown_timeout);
+        let drain_timeout = async {
+            if wait_stopping.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep(shutdown_timeout).await;
+        };
+        let result = tokio::select! {
+            result = serve => result.map_err(|e| eyre!("grpc serve failed: {e}")),
+            _ = drain_timeout, if self.config.shutdown_timeout != 0 => {
+                warn!("{} drain timed out after {shutdown_timeout:?}", self.service_name);
+                Ok(())
+            }
+        };
+        let _ = stopped.send(());
+        info!("{} stopped", self.service_name);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grpc_timeout_parsed() {
+        let parse = parse_grpc_timeout;
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse("3S"), Some(Duration::from_secs(3)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("20u"), Some(Duration::from_micros(20)));
+        assert_eq!(parse("5n"), Some(Duration::from_nanos(5)));
+        assert_eq!(parse("0m"), Some(Duration::ZERO));
+        assert_eq!(
+            parse("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 3600))
+        );
+    }
+
+    #[test]
+    fn grpc_timeout_rejected() {
+        for value in [
+            "", "m", "5", "5s", "5ms", "-5m", "+5m", " 5m", "5 m", "1.5S",
+        ] {
+            assert_eq!(parse_grpc_timeout(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn grpc_timeout_rendered() {
+        assert_eq!(grpc_timeout_value(Duration::ZERO), "0m");
+        assert_eq!(grpc_timeout_value(Duration::from_micros(2500)), "2m");
+        assert_eq!(grpc_timeout_value(Duration::from_secs(30)), "30000m");
+        assert_eq!(
+            grpc_timeout_value(Duration::from_millis(99_999_999)),
+            "99999999m"
+        );
+        assert_eq!(
+            grpc_timeout_value(Duration::from_millis(100_000_000)),
+            "100000S"
+        );
+        assert_eq!(grpc_timeout_value(Duration::MAX), "99999999S");
+        for millis in [0, 1, 999, 100_000_000] {
+            let remaining = Duration::from_millis(millis);
+            let parsed = parse_grpc_timeout(&grpc_timeout_value(remaining)).unwrap();
+            assert_eq!(parsed, remaining);
+        }
+    }
+}
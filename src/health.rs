@@ -0,0 +1,316 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+pub type CheckFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Criticality {
+    /// the service is down while the check fails
+    #[default]
+    Critical,
+    /// a failure is reported without taking the service down
+    NonCritical,
+}
+
+/// A subsystem whose availability is reported by the health endpoints.
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> String;
+
+    fn check(&self) -> CheckFuture<'_>;
+
+    fn criticality(&self) -> Criticality {
+        Criticality::Critical
+    }
+}
+
+/// Reports the wrapped check as [`Criticality::NonCritical`].
+pub struct NonCritical<C>(pub C);
+
+impl<C: HealthCheck> HealthCheck for NonCritical<C> {
+    fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        self.0.check()
+    }
+
+    fn criticality(&self) -> Criticality {
+        Criticality::NonCritical
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: HealthStatus,
+    pub criticality: Criticality,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    /// Down as soon as one critical check fails.
+    pub fn new(checks: Vec<CheckResult>) -> Self {
+        let status = if checks
+            .iter()
+            .all(|c| c.status == HealthStatus::Up || c.criticality == Criticality::NonCritical)
+        {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        };
+        Self { status, checks }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == HealthStatus::Up
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    timeout: Option<std::time::Duration>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, check: impl HealthCheck + 'static) {
+        self.checks.push(Arc::new(check));
+    }
+
+    pub fn with(mut self, check: impl HealthCheck + 'static) -> Self {
+        self.register(check);
+        self
+    }
+
+    /// How long a check may take before it is reported down, 5s by default.
+    pub const fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        HealthReport::new(self.run().await)
+    }
+
+    /// Runs the checks concurrently, each within the timeout.
+    async fn run(&self) -> Vec<CheckResult> {
+        let timeout = self.timeout.unwrap_or(std::time::Duration::from_secs(5));
+        futures_util::future::join_all(self.checks.iter().map(|check| async move {
+            let result = match tokio::time::timeout(timeout, check.check()).await {
+                Ok(result) => result,
+                Err(_) => Err(color_eyre::eyre::eyre!("timed out after {timeout:?}")),
+            };
+            CheckResult {
+                name: check.name(),
+                status: if result.is_ok() {
+                    HealthStatus::Up
+                } else {
+                    HealthStatus::Down
+                },
+                criticality: check.criticality(),
+                error: result.err().map(|e| e.to_string()),
+            }
+        }))
+        .await
+    }
+}
+
+#[cfg(feature = "metrics")]
+static HEALTH_UP: std::sync::LazyLock<crate::metrics::IntGaugeVec> =
+    std::sync::LazyLock::new(|| {
+        crate::gauge!(
+            "health_check_up",
+            "Whether the last run of a health check passed",
+            ["check"]
+        )
+    });
+
+/// Serves the health endpoints from the results of the last periodic run of its checks,
+/// so probes stay cheap and a slow backend does not stall them.
+#[derive(Clone)]
+pub struct HealthAggregator {
+    registry: HealthRegistry,
+    live: HealthRegistry,
+    interval: std::time::Duration,
+    latest: Arc<std::sync::RwLock<Option<Snapshot>>>,
+}
+
+type Snapshot = (std::time::Instant, Vec<CheckResult>);
+
+impl HealthAggregator {
+    /// Runs the checks of `registry` every `interval` once [`HealthAggregator::watch`]ed,
+    /// a zero interval runs them on every report instead.
+    pub fn new(registry: HealthRegistry, interval: std::time::Duration) -> Self {
+        Self {
+            registry,
+            live: Default::default(),
+            interval,
+            latest: Default::default(),
+        }
+    }
+
+    /// Adds a check run on every report, for cheap ones whose change must show at once
+    /// such as [`crate::shutdown::Shutdown`].
+    pub fn live(mut self, check: impl HealthCheck + 'static) -> Self {
+        self.live.register(check);
+        self
+    }
+
+    /// Runs the checks periodically until `scope` is cancelled.
+    #[cfg(feature = "shutdown")]
+    pub fn watch(&self, scope: &crate::shutdown::TaskScope) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let aggregator = self.clone();
+        scope.spawn("health_aggregator", async move {
+            let mut interval = tokio::time::interval(aggregator.interval);
+            loop {
+                interval.tick().await;
+                aggregator.refresh().await;
+            }
+        });
+    }
+
+    async fn refresh(&self) -> Vec<CheckResult> {
+        let checks = self.registry.run().await;
+        #[cfg(feature = "metrics")]
+        for check in &checks {
+            HEALTH_UP
+                .with_label_values(&[&check.name])
+                .set((check.status == HealthStatus::Up).into());
+        }
+        for check in checks.iter().filter(|c| c.status == HealthStatus::Down) {
+            tracing::debug!(
+                "health check {} down: {}",
+                check.name,
+                check.error.as_deref().unwrap_or_default()
+            );
+        }
+        *self.latest.write().unwrap_or_else(|e| e.into_inner()) =
+            Some((std::time::Instant::now(), checks.clone()));
+        checks
+    }
+
+    /// The cached results while not older than two intervals, fresh ones otherwise,
+    /// along with the live checks.
+    pub async fn report(&self) -> HealthReport {
+        let cached = self
+            .latest
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .filter(|(at, _)| !self.interval.is_zero() && at.elapsed() <= self.interval * 2)
+            .map(|(_, checks)| checks.clone());
+        let mut checks = match cached {
+            Some(checks) => checks,
+            None => self.refresh().await,
+        };
+        checks.extend(self.live.run().await);
+        HealthReport::new(checks)
+    }
+}
+
+impl From<HealthRegistry> for HealthAggregator {
+    fn from(registry: HealthRegistry) -> Self {
+        Self::new(registry, std::time::Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    struct Sleepy(&'static str, Duration);
+
+    impl HealthCheck for Sleepy {
+        fn name(&self) -> String {
+            self.0.to_owned()
+        }
+
+        fn check(&self) -> CheckFuture<'_> {
+            Box::pin(async move {
+                tokio::time::sleep(self.1).await;
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_run_concurrently_within_the_timeout() {
+        let registry = HealthRegistry::new()
+            .timeout(Duration::from_millis(400))
+            .with(Sleepy("a", Duration::from_millis(300)))
+            .with(Sleepy("b", Duration::from_millis(300)))
+            .with(NonCritical(Sleepy("slow", Duration::from_secs(10))));
+        let started = Instant::now();
+        let report = registry.report().await;
+        // one after the other they would take a second
+        assert!(started.elapsed() < Duration::from_millis(900));
+        assert!(report.is_up());
+        let status: Vec<_> = report
+            .checks
+            .iter()
+            .map(|c| (c.name.as_str(), c.status))
+            .collect();
+        assert_eq!(
+            status,
+            [
+                ("a", HealthStatus::Up),
+                ("b", HealthStatus::Up),
+                ("slow", HealthStatus::Down)
+            ]
+        );
+        assert!(report.checks[2]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn a_critical_timeout_is_down() {
+        let registry = HealthRegistry::new()
+            .timeout(Duration::from_millis(10))
+            .with(Sleepy("slow", Duration::from_secs(10)));
+        assert!(!registry.report().await.is_up());
+    }
+}
@@ -12,24 +12,147 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "http")]
+pub mod admin;
+
+#[cfg(feature = "audit")]
+pub mod audit;
+
+#[cfg(feature = "http")]
+pub mod auth;
+
+#[cfg(feature = "batch")]
+pub mod batch;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "breaker")]
+pub mod breaker;
+
+#[cfg(feature = "clock")]
+pub mod clock;
+
 #[cfg(feature = "config")]
 pub mod configure;
 
+#[cfg(feature = "consul")]
+pub mod consul;
+
+#[cfg(feature = "crypto")]
+pub mod crypto;
+
+#[cfg(feature = "dedup")]
+pub mod dedup;
+
 #[cfg(feature = "etcd")]
 pub mod etcd;
 
+#[cfg(feature = "etcd")]
+pub mod etcd_config;
+
+#[cfg(feature = "etcd")]
+pub mod etcd_queue;
+
+#[cfg(feature = "etcd")]
+pub mod etcd_session;
+
+#[cfg(feature = "etcd")]
+pub mod etcd_watch;
+
+#[cfg(feature = "faults")]
+pub mod faults;
+
+#[cfg(feature = "flags")]
+pub mod flags;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "events")]
+pub mod events;
+
+#[cfg(feature = "health")]
+pub mod health;
+
+#[cfg(feature = "limiter")]
+pub mod limiter;
+
 #[cfg(feature = "log")]
 pub mod log;
 
-#[cfg(feature = "restful")]
+#[cfg(feature = "etcd")]
+pub mod maintenance;
+
+#[cfg(feature = "memory")]
+pub mod memory;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "migrate")]
+pub mod migrate;
+
+#[cfg(feature = "queue")]
+pub mod queue;
+
+#[cfg(feature = "http")]
 pub mod restful;
 
 #[cfg(feature = "redis")]
 pub mod redis;
 
+#[cfg(feature = "retry")]
+pub mod retry;
+
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+
+#[cfg(feature = "shutdown")]
+pub mod shutdown;
+
+#[cfg(any(feature = "etcd", feature = "grpc", feature = "redis"))]
+mod slow;
+
 #[cfg(feature = "sm")]
 pub mod sm;
 
+#[cfg(feature = "supervisor")]
+pub mod supervisor;
+
+#[cfg(feature = "grpc")]
+pub mod transcode;
+
+#[cfg(feature = "http")]
+pub mod websocket;
+
 pub mod error;
 
+pub mod file_lock;
+
+pub mod pidfile;
+
 pub mod service_register;
+
+pub mod tenant;
+
+pub mod util;
+
+pub mod version;
+
+pub use error::CommonError;
+
+#[cfg(feature = "consul")]
+pub use consul::{Consul, ConsulConfig};
+
+#[cfg(feature = "etcd")]
+pub use etcd::{Etcd, EtcdConfig};
+
+#[cfg(feature = "grpc")]
+pub use grpc::{GrpcConfig, GrpcPool};
+
+#[cfg(feature = "redis")]
+pub use redis::{Redis, RedisConfig};
+
+#[cfg(feature = "http")]
+pub use restful::{HttpConfig, HttpServer};
@@ -0,0 +1,232 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Buckets kept at most. Beyond, the idle ones are dropped and, with every one still in
+/// use, the least recently used half, so that a flood of keys cannot grow the map.
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimitQuota {
+    /// tokens refilled per second
+    pub rate: f64,
+    pub burst: u64,
+}
+
+impl RateLimitQuota {
+    /// How long a drained bucket takes to fill up again.
+    fn refill_time(&self) -> Duration {
+        Duration::try_from_secs_f64(self.burst as f64 / self.rate).unwrap_or(Duration::MAX)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// In-memory token buckets, one per key, each starting full with `burst` tokens and
+/// refilled at `rate` tokens per second. Cloning is cheap and every clone shares the
+/// buckets; use `RateLimiter<()>` for a single bucket.
+#[derive(Clone)]
+pub struct RateLimiter<K = String> {
+    quota: RateLimitQuota,
+    buckets: Arc<Mutex<HashMap<K, Bucket>>>,
+    max_buckets: usize,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(quota: RateLimitQuota) -> Self {
+        Self {
+            quota,
+            buckets: Default::default(),
+            max_buckets: MAX_BUCKETS,
+        }
+    }
+
+    pub const fn quota(&self) -> RateLimitQuota {
+        self.quota
+    }
+
+    /// Takes one token from the bucket of `key`, returns whether there was one.
+    pub fn try_acquire(&self, key: &K) -> bool {
+        self.try_acquire_with(key, self.quota, 1.0)
+    }
+
+    /// Takes `tokens` tokens at once, e.g. the size of a batch, or none if the bucket
+    /// does not hold that many.
+    pub fn try_acquire_n(&self, key: &K, tokens: u64) -> bool {
+        self.try_acquire_with(key, self.quota, tokens as f64)
+    }
+
+    /// Waits until the bucket of `key` has a token for this caller. Waiting callers are
+    /// served in order, each one reserving its token up front. Never resolves given a
+    /// zero rate and a drained bucket.
+    pub async fn acquire(&self, key: &K) {
+        let wait = self.reserve(key, 1.0);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `tokens` from the bucket of `key` using `quota` instead of the limiter's own,
+    /// for callers keeping buckets of different quotas in one limiter.
+    pub(crate) fn try_acquire_with(&self, key: &K, quota: RateLimitQuota, tokens: f64) -> bool {
+        self.with_bucket(key, quota, |bucket| {
+            let granted = bucket.tokens >= tokens;
+            if granted {
+                bucket.tokens -= tokens;
+            }
+            granted
+        })
+    }
+
+    /// Takes `tokens` even if the bucket runs into debt, returns how long until the debt
+    /// is paid off.
+    fn reserve(&self, key: &K, tokens: f64) -> Duration {
+        let quota = self.quota;
+        self.with_bucket(key, quota, |bucket| {
+            bucket.tokens -= tokens;
+            if bucket.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::try_from_secs_f64(-bucket.tokens / quota.rate).unwrap_or(Duration::MAX)
+            }
+        })
+    }
+
+    fn with_bucket<T>(
+        &self,
+        key: &K,
+        quota: RateLimitQuota,
+        take: impl FnOnce(&mut Bucket) -> T,
+    ) -> T {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(key) {
+            let idle = quota.refill_time();
+            buckets.retain(|_, b| now.duration_since(b.updated) < idle);
+            // down to half at most, so that the next eviction is as many inserts away
+            if buckets.len() > self.max_buckets / 2 {
+                let mut by_age: Vec<_> = buckets
+                    .iter()
+                    .map(|(key, b)| (b.updated, key.clone()))
+                    .collect();
+                by_age.sort_unstable_by_key(|(updated, _)| *updated);
+                let cut = by_age.len() - self.max_buckets / 2;
+                for (_, key) in by_age.into_iter().take(cut) {
+                    buckets.remove(&key);
+                }
+            }
+        }
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: quota.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * quota.rate)
+            .min(quota.burst as f64);
+        bucket.updated = now;
+        take(bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u64) -> RateLimiter<&'static str> {
+        RateLimiter::new(RateLimitQuota { rate, burst })
+    }
+
+    #[test]
+    fn bucket_starts_full_and_drains() {
+        let limiter = limiter(0.0, 3);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(&"a"));
+        }
+        assert!(!limiter.try_acquire(&"a"));
+        // every key has a bucket of its own
+        assert!(limiter.try_acquire(&"b"));
+    }
+
+    #[test]
+    fn acquire_n_takes_all_or_nothing() {
+        let limiter = limiter(0.0, 5);
+        assert!(!limiter.try_acquire_n(&"a", 6));
+        assert!(limiter.try_acquire_n(&"a", 4));
+        assert!(!limiter.try_acquire_n(&"a", 2));
+        assert!(limiter.try_acquire_n(&"a", 1));
+        assert!(!limiter.try_acquire(&"a"));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let limiter = limiter(1000.0, 2);
+        assert!(limiter.try_acquire_n(&"a", 2));
+        assert!(!limiter.try_acquire(&"a"));
+        std::thread::sleep(Duration::from_millis(20));
+        // 20 tokens were refilled, only 2 are kept
+        assert!(limiter.try_acquire_n(&"a", 2));
+        assert!(!limiter.try_acquire(&"a"));
+    }
+
+    #[test]
+    fn refill_time() {
+        let quota = RateLimitQuota {
+            rate: 4.0,
+            burst: 10,
+        };
+        assert_eq!(quota.refill_time(), Duration::from_millis(2500));
+        let quota = RateLimitQuota {
+            rate: 0.0,
+            burst: 10,
+        };
+        assert_eq!(quota.refill_time(), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_its_token() {
+        let limiter = limiter(100.0, 1);
+        let start = Instant::now();
+        limiter.acquire(&"a").await;
+        // the second and third callers reserve the next two tokens, 10ms apart
+        limiter.acquire(&"a").await;
+        limiter.acquire(&"a").await;
+        assert!(start.elapsed() >= Duration::from_millis(19));
+    }
+
+    #[test]
+    fn buckets_capped() {
+        let mut limiter = RateLimiter::<u32>::new(RateLimitQuota {
+            rate: 0.0,
+            burst: 1,
+        });
+        limiter.max_buckets = 100;
+        for key in 0..1000 {
+            assert!(limiter.try_acquire(&key));
+            assert!(limiter.buckets.lock().unwrap().len() <= 100);
+        }
+        // the most recent buckets are kept, still drained
+        assert!(!limiter.try_acquire(&999));
+    }
+}
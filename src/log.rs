@@ -13,11 +13,64 @@
 // limitations under the License.
 
 use chrono::{Local, Offset};
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{eyre, OptionExt},
+    Result,
+};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use serde_json::{Map, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
 use time::{format_description::well_known, UtcOffset};
-use tracing_subscriber::{fmt::format, fmt::time::OffsetTime, prelude::*, EnvFilter};
+use tracing::field::{Field, Visit};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, RollingFileAppender},
+};
+use tracing_subscriber::{
+    fmt::{
+        format::{self, format, JsonFields},
+        time::{FormatTime, OffsetTime},
+        FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter,
+    },
+    layer::Layered,
+    prelude::*,
+    registry::LookupSpan,
+    reload, EnvFilter, Layer, Registry,
+};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static GUARD: Mutex<Option<LogGuard>> = Mutex::new(None);
+
+type Subscriber = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type BoxLayer = Box<dyn Layer<Subscriber> + Send + Sync>;
+type Timer = OffsetTime<well_known::Rfc3339>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    /// start a new file once the current one reaches `max_file_size`
+    Size,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    /// one object per line with the fields of the event and its spans flattened in
+    Json,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -25,6 +78,20 @@ pub struct LogConfig {
     max_level: String,
     filter: String,
     rolling_file_path: Option<String>,
+    format: LogFormat,
+    rotation: Rotation,
+    /// megabytes written to a file before the `size` rotation starts a new one
+    max_file_size: u64,
+    /// rotated files kept in `rolling_file_path`, 0 keeps all of them
+    max_files: usize,
+    /// also log to stdout when logging to a rolling file
+    console: bool,
+    /// export spans to an OpenTelemetry collector
+    #[cfg(feature = "otlp")]
+    otlp: Option<OtlpConfig>,
+    /// report `error` events to Sentry, keeping lower levels as breadcrumbs
+    #[cfg(feature = "sentry")]
+    sentry: Option<SentryConfig>,
 }
 
 impl Default for LogConfig {
@@ -33,54 +100,615 @@ impl Default for LogConfig {
             max_level: "info".to_owned(),
             filter: "info".to_owned(),
             rolling_file_path: Default::default(),
+            format: LogFormat::Compact,
+            rotation: Rotation::Daily,
+            max_file_size: 100,
+            max_files: 0,
+            console: false,
+            #[cfg(feature = "otlp")]
+            otlp: None,
+            #[cfg(feature = "sentry")]
+            sentry: None,
         }
     }
 }
 
-pub fn init_tracing(name: &str, log_config: &LogConfig) -> Result<()> {
-    // log
-    let mut logfile = None;
-    let mut stdout = None;
-    if let Some(rolling_file_path) = &log_config.rolling_file_path {
-        // logfile
-        logfile = Some(tracing_appender::rolling::daily(rolling_file_path, name));
-    } else {
-        // stdout
-        stdout = Some(
-            std::io::stdout
-                .with_max_level(tracing::Level::from_str(&log_config.max_level).unwrap()),
-        );
+impl LogConfig {
+    /// The env-filter directives applied by [`init_tracing`].
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    /// grpc endpoint of the collector
+    endpoint: String,
+    /// defaults to the name passed to [`init_tracing`]
+    service_name: Option<String>,
+    /// fraction of the root spans exported, child spans follow their parent
+    sampling_ratio: f64,
+    /// milliseconds allowed for one export
+    timeout: u64,
+}
+
+#[cfg(feature = "otlp")]
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4317".to_owned(),
+            service_name: None,
+            sampling_ratio: 1.0,
+            timeout: 10000,
+        }
     }
+}
+
+#[cfg(feature = "sentry")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SentryConfig {
+    dsn: String,
+    release: Option<String>,
+    environment: Option<String>,
+    /// fraction of the events sent
+    sample_rate: f32,
+}
+
+#[cfg(feature = "sentry")]
+impl Default for SentryConfig {
+    fn default() -> Self {
+        Self {
+            dsn: Default::default(),
+            release: None,
+            environment: None,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// Flushes the non-blocking log writers when dropped, hold it until the process exits.
+#[must_use = "logs are lost once the guard is dropped"]
+pub struct LogGuard {
+    _guards: Vec<WorkerGuard>,
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+    #[cfg(feature = "sentry")]
+    _sentry: Option<sentry::ClientInitGuard>,
+}
 
+#[cfg(feature = "otlp")]
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(e) = tracer_provider.shutdown() {
+                eprintln!("shutdown otlp exporter failed: {e}");
+            }
+        }
+    }
+}
+
+/// Logs to stdout, or to files named after `name` in `rolling_file_path`. Writes go
+/// through a background thread so logging never blocks the caller on io; the writers
+/// are kept until [`flush_tracing`], call it before the process exits.
+///
+/// With `otlp` configured spans are also exported in batches from a tokio task, so it
+/// must be called within a multi-threaded tokio runtime.
+pub fn init_tracing(name: &str, log_config: &LogConfig) -> Result<()> {
+    let guard = init_tracing_with_guard(name, log_config)?;
+    *GUARD.lock().unwrap_or_else(|e| e.into_inner()) = Some(guard);
+    Ok(())
+}
+
+/// Flushes the logs written so far and shuts the exporters down, logging stops after.
+pub fn flush_tracing() {
+    drop(GUARD.lock().unwrap_or_else(|e| e.into_inner()).take());
+}
+
+/// Like [`init_tracing`], with the writers flushed once the returned guard is dropped.
+pub fn init_tracing_with_guard(name: &str, log_config: &LogConfig) -> Result<LogGuard> {
     // set timer
     let local_offset_sec = Local::now().offset().fix().local_minus_utc();
     let utc_offset = UtcOffset::from_whole_seconds(local_offset_sec)
         .unwrap_or(UtcOffset::from_hms(8, 0, 0).unwrap());
     let timer = OffsetTime::new(utc_offset, well_known::Rfc3339);
 
-    if let Some(stdout) = stdout {
-        tracing_subscriber::registry()
-            .with(EnvFilter::new(&log_config.filter))
-            .with(
-                tracing_subscriber::fmt::layer()
-                    .event_format(format().compact())
-                    .with_ansi(false)
-                    .with_timer(timer)
-                    .with_writer(stdout),
-            )
-            .try_init()?;
-    } else {
-        tracing_subscriber::registry()
-            .with(EnvFilter::new(&log_config.filter))
-            .with(
-                tracing_subscriber::fmt::layer()
-                    .event_format(format().compact())
-                    .with_ansi(false)
-                    .with_timer(timer)
-                    .with_writer(logfile.unwrap()),
-            )
-            .try_init()?;
+    let mut guards = Vec::new();
+    let mut layers: Vec<BoxLayer> = Vec::new();
+    if let Some(rolling_file_path) = &log_config.rolling_file_path {
+        // logfile
+        let (logfile, guard) =
+            tracing_appender::non_blocking(rolling_file(rolling_file_path, name, log_config)?);
+        guards.push(guard);
+        layers.push(fmt_layer(name, log_config.format, timer.clone(), logfile));
+    }
+    if log_config.rolling_file_path.is_none() || log_config.console {
+        // stdout
+        let max_level = tracing::Level::from_str(&log_config.max_level)
+            .map_err(|e| eyre!("invalid max_level `{}`: {e}", log_config.max_level))?;
+        let (stdout, guard) = tracing_appender::non_blocking(io::stdout());
+        guards.push(guard);
+        layers.push(fmt_layer(
+            name,
+            log_config.format,
+            timer,
+            stdout.with_max_level(max_level),
+        ));
+    }
+
+    #[cfg(feature = "otlp")]
+    let tracer_provider = match &log_config.otlp {
+        Some(otlp) => {
+            let (layer, tracer_provider) = otlp_layer(name, otlp)?;
+            layers.push(layer);
+            Some(tracer_provider)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "sentry")]
+    let sentry = match &log_config.sentry {
+        Some(config) => {
+            let guard = init_sentry(name, config)?;
+            layers.push(sentry::integrations::tracing::layer().boxed());
+            Some(guard)
+        }
+        None => None,
+    };
+
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&log_config.filter));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layers)
+        .try_init()?;
+    let _ = FILTER.set(handle);
+
+    Ok(LogGuard {
+        _guards: guards,
+        #[cfg(feature = "otlp")]
+        tracer_provider,
+        #[cfg(feature = "sentry")]
+        _sentry: sentry,
+    })
+}
+
+#[cfg(feature = "sentry")]
+fn init_sentry(name: &str, config: &SentryConfig) -> Result<sentry::ClientInitGuard> {
+    let dsn = config
+        .dsn
+        .parse()
+        .map_err(|e| eyre!("invalid sentry dsn: {e}"))?;
+    let guard = sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn),
+        release: config.release.clone().map(Into::into),
+        environment: config.environment.clone().map(Into::into),
+        sample_rate: config.sample_rate,
+        ..Default::default()
+    });
+    sentry::configure_scope(|scope| scope.set_tag("service", name));
+    Ok(guard)
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer(
+    name: &str,
+    config: &OtlpConfig,
+) -> Result<(BoxLayer, opentelemetry_sdk::trace::TracerProvider)> {
+    use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        runtime,
+        trace::{Config, Sampler},
+        Resource,
+    };
+
+    let service_name = config.service_name.as_deref().unwrap_or(name).to_owned();
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(&config.endpoint)
+        .with_timeout(std::time::Duration::from_millis(config.timeout));
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio)));
+    let tracer_provider =
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(Config::default().with_sampler(sampler).with_resource(
+                Resource::new([KeyValue::new("service.name", service_name.clone())]),
+            ))
+            .install_batch(runtime::Tokio)
+            .map_err(|e| {
+                eyre!(
+                    "install otlp exporter for `{}` failed: {e}",
+                    config.endpoint
+                )
+            })?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(tracer_provider.clone());
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer_provider.tracer(service_name))
+        .boxed();
+    Ok((layer, tracer_provider))
+}
+
+fn fmt_layer<W>(name: &str, log_format: LogFormat, timer: Timer, writer: W) -> BoxLayer
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    match log_format {
+        LogFormat::Compact => tracing_subscriber::fmt::layer()
+            .event_format(format().compact())
+            .with_ansi(false)
+            .with_timer(timer)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonFormat {
+                service: name.to_owned(),
+                timer,
+            })
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Writes `timestamp`, `level`, `target`, `service`, the fields of the enclosing spans
+/// from the root down, e.g. `request_id`, and then the fields of the event itself.
+struct JsonFormat {
+    service: String,
+    timer: Timer,
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        let mut timestamp = String::new();
+        self.timer
+            .format_time(&mut format::Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        let mut object = Map::new();
+        object.insert("timestamp".to_owned(), timestamp.into());
+        object.insert("level".to_owned(), metadata.level().as_str().into());
+        object.insert("target".to_owned(), metadata.target().into());
+        object.insert("service".to_owned(), self.service.as_str().into());
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str::<Map<String, Value>>(fields).ok());
+                object.extend(fields.unwrap_or_default());
+            }
+        }
+        event.record(&mut JsonVisitor(&mut object));
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{value:?}").into());
+    }
+}
+
+fn rolling_file(
+    dir: &str,
+    name: &str,
+    log_config: &LogConfig,
+) -> Result<Box<dyn Write + Send + 'static>> {
+    let rotation = match log_config.rotation {
+        Rotation::Minutely => rolling::Rotation::MINUTELY,
+        Rotation::Hourly => rolling::Rotation::HOURLY,
+        Rotation::Daily => rolling::Rotation::DAILY,
+        Rotation::Never => rolling::Rotation::NEVER,
+        Rotation::Size => {
+            let max_size = log_config.max_file_size.max(1) * 1024 * 1024;
+            return Ok(Box::new(SizeRolling::new(
+                dir,
+                name,
+                max_size,
+                log_config.max_files,
+            )?));
+        }
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name);
+    if log_config.max_files > 0 {
+        builder = builder.max_log_files(log_config.max_files);
+    }
+    let appender = builder
+        .build(dir)
+        .map_err(|e| eyre!("open log file in `{dir}` failed: {e}"))?;
+    Ok(Box::new(appender))
+}
+
+/// Writes to `dir/name`, renaming it to `name.<local time>` once it holds `max_size`
+/// bytes and removing the oldest renamed files beyond `max_files`.
+struct SizeRolling {
+    dir: PathBuf,
+    name: String,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+    last_stamp: String,
+    seq: u32,
+}
+
+impl SizeRolling {
+    fn new(dir: &str, name: &str, max_size: u64, max_files: usize) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .map_err(|e| eyre!("create log directory `{}` failed: {e}", dir.display()))?;
+        let file = open_append(&dir.join(name))
+            .map_err(|e| eyre!("open log file `{}` failed: {e}", dir.join(name).display()))?;
+        let written = file.metadata().map(|m| m.len()).unwrap_or_default();
+        Ok(Self {
+            dir,
+            name: name.to_owned(),
+            max_size,
+            max_files,
+            file,
+            written,
+            last_stamp: String::new(),
+            seq: 0,
+        })
     }
 
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let current = self.dir.join(&self.name);
+        let stamp = Local::now().format("%Y%m%d-%H%M%S%.3f").to_string();
+        // rotations within the same millisecond are numbered rather than overwritten
+        let rotated = if self.last_stamp == stamp {
+            self.seq += 1;
+            format!("{}.{stamp}.{:04}", self.name, self.seq)
+        } else {
+            self.seq = 0;
+            format!("{}.{stamp}", self.name)
+        };
+        self.last_stamp = stamp;
+        fs::rename(&current, self.dir.join(rotated))?;
+        self.file = open_append(&current)?;
+        self.written = 0;
+        if self.max_files > 0 {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let prefix = format!("{}.", self.name);
+        let mut rotated: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        rotated.sort();
+        // the active file counts towards `max_files`
+        let excess = (rotated.len() + 1).saturating_sub(self.max_files);
+        for path in &rotated[..excess.min(rotated.len())] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for SizeRolling {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                eprintln!("rotate log file failed: {e}");
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The env-filter directives in effect, `None` before [`init_tracing`].
+pub fn log_filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
+}
+
+/// Replaces the env-filter directives of the running subscriber.
+pub fn set_log_filter(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| eyre!("invalid log filter `{directives}`: {e}"))?;
+    FILTER
+        .get()
+        .ok_or_eyre("tracing not initialized")?
+        .reload(filter)
+        .map_err(|e| eyre!("reload log filter failed: {e}"))
+}
+
+/// Applies the directives returned by `load` on every SIGHUP, e.g. the filter of the
+/// config file read again. A failed load or invalid directives keep the current filter.
+#[cfg(unix)]
+pub fn reload_filter_on_sighup<F>(load: F) -> Result<()>
+where
+    F: Fn() -> Result<String> + Send + 'static,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup =
+        signal(SignalKind::hangup()).map_err(|e| eyre!("install SIGHUP handler failed: {e}"))?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match load().and_then(|directives| {
+                set_log_filter(&directives)?;
+                Ok(directives)
+            }) {
+                Ok(directives) => tracing::info!("SIGHUP: log filter set to `{directives}`"),
+                Err(e) => tracing::warn!("SIGHUP: reload log filter failed: {e}"),
+            }
+        }
+    });
     Ok(())
 }
+
+/// Logs every panic, with its location and a backtrace, as an `error` event instead of
+/// printing it to stderr, and counts it in `panics_total`. With `abort` the process
+/// aborts after the report rather than just unwinding the panicking thread or task.
+pub fn install_panic_hook(abort: bool) {
+    #[cfg(feature = "metrics")]
+    static PANICS: std::sync::LazyLock<crate::metrics::IntCounter> =
+        std::sync::LazyLock::new(|| {
+            crate::metrics::register(
+                crate::metrics::IntCounter::new("panics_total", "panics caught by the panic hook")
+                    .unwrap(),
+            )
+        });
+
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_default();
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("<unnamed>");
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        tracing::error!(
+            panic.thread = thread,
+            panic.location = location,
+            panic.backtrace = %backtrace,
+            "panicked: {message}"
+        );
+        #[cfg(feature = "metrics")]
+        PANICS.inc();
+        if abort {
+            // the non-blocking writers may not get to the report before the abort
+            eprintln!("thread '{thread}' panicked at {location}: {message}\n{backtrace}");
+            std::process::abort();
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn rotated(dir: &Path, name: &str) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|file| file.starts_with(&format!("{name}.")))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn size_rolling_rotates_at_max_size() {
+        let dir = temp_dir("rotate");
+        let mut rolling = SizeRolling::new(dir.to_str().unwrap(), "app", 10, 0).unwrap();
+        rolling.write_all(b"12345\n").unwrap();
+        rolling.write_all(b"123\n").unwrap();
+        assert!(rotated(&dir, "app").is_empty());
+        // the third line would take the file past 10 bytes
+        rolling.write_all(b"abc\n").unwrap();
+        rolling.flush().unwrap();
+        let files = rotated(&dir, "app");
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.join(&files[0])).unwrap(),
+            "12345\n123\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("app")).unwrap(), "abc\n");
+        // a line larger than the limit still goes into a file of its own
+        rolling.write_all(b"0123456789abcdef\n").unwrap();
+        rolling.write_all(b"x\n").unwrap();
+        assert_eq!(rotated(&dir, "app").len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_rolling_continues_existing_file() {
+        let dir = temp_dir("continue");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app"), b"12345678\n").unwrap();
+        let mut rolling = SizeRolling::new(dir.to_str().unwrap(), "app", 10, 0).unwrap();
+        rolling.write_all(b"abc\n").unwrap();
+        assert_eq!(rotated(&dir, "app").len(), 1);
+        assert_eq!(fs::read_to_string(dir.join("app")).unwrap(), "abc\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_rolling_prunes_oldest() {
+        let dir = temp_dir("prune");
+        let mut rolling = SizeRolling::new(dir.to_str().unwrap(), "app", 4, 3).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            rolling.write_all(line.as_bytes()).unwrap();
+        }
+        rolling.flush().unwrap();
+        // the active file and the two latest rotated ones
+        let files = rotated(&dir, "app");
+        assert_eq!(files.len(), 2);
+        assert_eq!(fs::read_to_string(dir.join(&files[0])).unwrap(), "three\n");
+        assert_eq!(fs::read_to_string(dir.join(&files[1])).unwrap(), "four\n");
+        assert_eq!(fs::read_to_string(dir.join("app")).unwrap(), "five\n");
+        // files of other services sharing the directory are left alone
+        fs::write(dir.join("other.1"), b"").unwrap();
+        rolling.write_all(b"six\n").unwrap();
+        assert_eq!(rotated(&dir, "app").len(), 2);
+        assert!(dir.join("other.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
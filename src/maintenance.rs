@@ -0,0 +1,171 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use color_eyre::Result;
+use tracing::{info, warn};
+
+use crate::{
+    etcd::{op_error, Etcd},
+    etcd_watch::{follow, WatchEvent},
+    service_register::{RegisterHealth, RegisterStatus},
+    shutdown::TaskScope,
+};
+
+/// A cluster wide maintenance mode, on while the etcd key `maintenance` exists, holding
+/// the reason. As a salvo handler it answers 503 to every request but reads while on,
+/// and the register loops handed to [`MaintenanceMode::withdraw_routes`] remove their
+/// routes, so operators can drain the fleet e.g. for an etcd upgrade. Cloning is cheap
+/// and every clone shares the state.
+#[derive(Clone)]
+pub struct MaintenanceMode {
+    etcd: Etcd,
+    key: Arc<str>,
+    reason: Arc<RwLock<Option<String>>>,
+    registers: Arc<Mutex<Vec<Arc<RegisterStatus>>>>,
+}
+
+impl MaintenanceMode {
+    pub fn new(etcd: Etcd) -> Self {
+        Self::with_key(etcd, "maintenance")
+    }
+
+    pub fn with_key(etcd: Etcd, key: &str) -> Self {
+        Self {
+            etcd,
+            key: key.into(),
+            reason: Default::default(),
+            registers: Default::default(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.reason
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Turns the maintenance mode on for every instance.
+    pub async fn enable(&self, reason: &str) -> Result<()> {
+        self.etcd.put(self.key.as_bytes(), reason, 0).await?;
+        Ok(())
+    }
+
+    pub async fn disable(&self) -> Result<()> {
+        self.etcd.delete(self.key.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Removes the routes kept by the register loop of `register`, e.g.
+    /// [`Etcd::register_health`], while the maintenance mode is on.
+    pub fn withdraw_routes(&self, register: &RegisterHealth) {
+        register.status.withdraw(self.is_active());
+        self.registers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(register.status.clone());
+    }
+
+    /// Follows the key until `scope` is cancelled.
+    pub fn watch_in(&self, scope: &TaskScope) {
+        let mode = self.clone();
+        let broadcast = self.etcd.watch_to_broadcast(scope, &self.key);
+        scope.spawn("maintenance_mode", async move {
+            follow(
+                &broadcast,
+                || mode.load(),
+                |event| match event {
+                    // the watch covers every key starting with this one
+                    WatchEvent::Put(kv) if kv.key() == mode.key.as_bytes() => {
+                        mode.set(Some(String::from_utf8_lossy(kv.value()).into_owned()))
+                    }
+                    WatchEvent::Delete(kv) if kv.key() == mode.key.as_bytes() => mode.set(None),
+                    _ => {}
+                },
+            )
+            .await;
+        });
+    }
+
+    /// Reads the key, returns the etcd revision it was read at.
+    async fn load(&self) -> Result<i64> {
+        let key = self.key.to_string();
+        let rsp = self
+            .etcd
+            .client
+            .clone()
+            .get(key.as_str(), None)
+            .await
+            .map_err(|e| op_error("get", &key, e))?;
+        self.set(
+            rsp.kvs()
+                .first()
+                .map(|kv| String::from_utf8_lossy(kv.value()).into_owned()),
+        );
+        Ok(rsp.header().map_or(0, |header| header.revision()))
+    }
+
+    fn set(&self, reason: Option<String>) {
+        let active = reason.is_some();
+        let was_active = {
+            let mut current = self.reason.write().unwrap_or_else(|e| e.into_inner());
+            std::mem::replace(&mut *current, reason.clone()).is_some()
+        };
+        match (&reason, was_active) {
+            (Some(reason), false) => warn!("maintenance mode on: {reason}"),
+            (None, true) => info!("maintenance mode off"),
+            _ => {}
+        }
+        for register in self
+            .registers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            register.withdraw(active);
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[salvo::async_trait]
+impl salvo::Handler for MaintenanceMode {
+    async fn handle(
+        &self,
+        req: &mut salvo::Request,
+        depot: &mut salvo::Depot,
+        res: &mut salvo::Response,
+        ctrl: &mut salvo::FlowCtrl,
+    ) {
+        use salvo::{http::Method, Writer};
+
+        let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        if read || !self.is_active() {
+            return;
+        }
+        crate::restful::RESTfulError {
+            code: crate::error::CALError::ServiceUnavailable.into(),
+            err: format!("maintenance: {}", self.reason().unwrap_or_default()),
+        }
+        .write(req, depot, res)
+        .await;
+        ctrl.skip_rest();
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::shutdown::TaskScope;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// mebibytes of resident memory the process may use, 0 takes the cgroup limit
+    pub limit: u64,
+    /// milliseconds between two reads of the resident memory
+    pub interval: u64,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            limit: 0,
+            interval: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    /// resident bytes
+    pub rss: u64,
+    /// bytes the process may use
+    pub limit: u64,
+}
+
+impl MemoryUsage {
+    pub fn ratio(&self) -> f64 {
+        self.rss as f64 / self.limit as f64
+    }
+}
+
+type Callback = Box<dyn Fn(MemoryUsage) + Send + Sync>;
+
+struct Watermark {
+    name: String,
+    ratio: f64,
+    callback: Callback,
+    above: AtomicBool,
+}
+
+#[cfg(feature = "metrics")]
+static CROSSINGS: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "memory_watermark_crossings_total",
+            "Times the resident memory rose above a watermark",
+            ["watermark"]
+        )
+    });
+
+/// Watches the resident memory of the process against a limit and calls the callbacks of
+/// a watermark once the memory rises above it, e.g. to shrink the local caches or shed
+/// load before the process is killed for running out of memory. A watermark fires again
+/// only after the memory fell back below it. Cloning is cheap and every clone shares the
+/// watermarks.
+#[derive(Clone)]
+pub struct MemoryMonitor {
+    interval: Duration,
+    limit: Option<u64>,
+    watermarks: Arc<Mutex<Vec<Arc<Watermark>>>>,
+}
+
+impl MemoryMonitor {
+    /// Without a configured limit nor a cgroup one the monitor never fires.
+    pub fn new(config: MemoryConfig) -> Self {
+        let limit = match config.limit {
+            0 => cgroup_limit(),
+            mb => Some(mb << 20),
+        };
+        Self {
+            interval: Duration::from_millis(config.interval.max(1)),
+            limit,
+            watermarks: Default::default(),
+        }
+    }
+
+    /// The byte limit the usage is compared against.
+    pub const fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Calls `callback` once the resident memory rises above `ratio` of the limit, a rise
+    /// above any watermark is also logged as a warning.
+    pub fn on(
+        &self,
+        ratio: f64,
+        name: &str,
+        callback: impl Fn(MemoryUsage) + Send + Sync + 'static,
+    ) {
+        self.lock().push(Arc::new(Watermark {
+            name: name.to_owned(),
+            ratio,
+            callback: Box::new(callback),
+            above: AtomicBool::new(false),
+        }));
+    }
+
+    /// The resident memory now, `None` where it cannot be read or no limit is known.
+    pub fn usage(&self) -> Option<MemoryUsage> {
+        Some(MemoryUsage {
+            rss: rss()?,
+            limit: self.limit?,
+        })
+    }
+
+    /// Compares the usage against the watermarks once, what the task of
+    /// [`MemoryMonitor::run_in`] does every interval.
+    pub fn check(&self) -> Option<MemoryUsage> {
+        let usage = self.usage()?;
+        let ratio = usage.ratio();
+        let watermarks = self.lock().clone();
+        for watermark in watermarks {
+            let above = ratio >= watermark.ratio;
+            if watermark.above.swap(above, Ordering::Relaxed) == above {
+                continue;
+            }
+            if above {
+                warn!(
+                    "memory above watermark {}: {} of {} bytes resident",
+                    watermark.name, usage.rss, usage.limit
+                );
+                #[cfg(feature = "metrics")]
+                CROSSINGS.with_label_values(&[&watermark.name]).inc();
+                (watermark.callback)(usage);
+            } else {
+                info!(
+                    "memory back below watermark {}: {} of {} bytes resident",
+                    watermark.name, usage.rss, usage.limit
+                );
+            }
+        }
+        Some(usage)
+    }
+
+    /// Checks the usage every interval until `scope` is cancelled.
+    pub fn run_in(&self, scope: &TaskScope) {
+        if self.usage().is_none() {
+            warn!("memory monitor disabled, resident memory or limit unknown");
+            return;
+        }
+        let monitor = self.clone();
+        scope.spawn("memory_monitor", async move {
+            let mut interval = tokio::time::interval(monitor.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                monitor.check();
+            }
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Arc<Watermark>>> {
+        self.watermarks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Resident bytes of this process, linux only.
+pub fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb << 10)
+}
+
+/// Memory limit of the cgroup of this process, v2 or v1, `None` if unlimited.
+fn cgroup_limit() -> Option<u64> {
+    [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ]
+    .iter()
+    .find_map(|path| std::fs::read_to_string(path).ok())
+    .and_then(|limit| limit.trim().parse::<u64>().ok())
+    // v1 reports no limit as a huge page aligned number
+    .filter(|limit| *limit < 1 << 60)
+}
@@ -0,0 +1,290 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::OnceLock;
+
+use color_eyre::{eyre::eyre, Result};
+pub use prometheus::{
+    self,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder, TEXT_FORMAT,
+};
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The registry shared by every metric defined in common-rs and exposed by `/metrics`.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| with_builtin(Registry::new()))
+}
+
+/// Makes `registry`, e.g. one with a prefix or constant labels, the shared registry.
+/// Fails once any metric has been registered.
+pub fn set_registry(registry: Registry) -> Result<()> {
+    REGISTRY
+        .set(with_builtin(registry))
+        .map_err(|_| eyre!("metrics registry already in use"))
+}
+
+fn with_builtin(registry: Registry) -> Registry {
+    #[cfg(target_os = "linux")]
+    if let Err(e) = registry.register(Box::new(
+        prometheus::process_collector::ProcessCollector::for_self(),
+    )) {
+        tracing::warn!("register process metrics failed: {e}");
+    }
+    if let Err(e) = registry.register(Box::new(RuntimeCollector::new())) {
+        tracing::warn!("register tokio runtime metrics failed: {e}");
+    }
+    registry
+}
+
+/// Registers `collector` into the shared registry and hands it back.
+pub fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    if let Err(e) = registry().register(Box::new(collector.clone())) {
+        tracing::warn!("register metrics failed: {e}");
+    }
+    collector
+}
+
+/// Encodes the shared registry in the prometheus text exposition format.
+pub fn gather() -> Result<String> {
+    TextEncoder::new()
+        .encode_to_string(&registry().gather())
+        .map_err(|e| eyre!("encode metrics failed: {e}"))
+}
+
+/// An `IntCounter`, or an `IntCounterVec` when labels are given, registered in the
+/// shared registry: `counter!("cache_hits_total", "Cache hits", ["cache"])`.
+#[macro_export]
+macro_rules! counter {
+    ($name:expr, $help:expr $(,)?) => {
+        $crate::metrics::register($crate::metrics::IntCounter::new($name, $help).unwrap())
+    };
+    ($name:expr, $help:expr, [$($label:expr),+ $(,)?] $(,)?) => {
+        $crate::metrics::register(
+            $crate::metrics::IntCounterVec::new(
+                $crate::metrics::Opts::new($name, $help),
+                &[$($label),+],
+            )
+            .unwrap(),
+        )
+    };
+}
+
+/// An `IntGauge`, or an `IntGaugeVec` when labels are given, registered in the shared
+/// registry.
+#[macro_export]
+macro_rules! gauge {
+    ($name:expr, $help:expr $(,)?) => {
+        $crate::metrics::register($crate::metrics::IntGauge::new($name, $help).unwrap())
+    };
+    ($name:expr, $help:expr, [$($label:expr),+ $(,)?] $(,)?) => {
+        $crate::metrics::register(
+            $crate::metrics::IntGaugeVec::new(
+                $crate::metrics::Opts::new($name, $help),
+                &[$($label),+],
+            )
+            .unwrap(),
+        )
+    };
+}
+
+/// A `Histogram`, or a `HistogramVec` when labels are given, registered in the shared
+/// registry, with the default buckets unless `buckets` follow the labels.
+#[macro_export]
+macro_rules! histogram {
+    ($name:expr, $help:expr $(,)?) => {
+        $crate::metrics::register(
+            $crate::metrics::Histogram::with_opts($crate::metrics::HistogramOpts::new($name, $help))
+                .unwrap(),
+        )
+    };
+    ($name:expr, $help:expr, [$($label:expr),+ $(,)?] $(,)?) => {
+        $crate::metrics::register(
+            $crate::metrics::HistogramVec::new(
+                $crate::metrics::HistogramOpts::new($name, $help),
+                &[$($label),+],
+            )
+            .unwrap(),
+        )
+    };
+    ($name:expr, $help:expr, [$($label:expr),+ $(,)?], $buckets:expr $(,)?) => {
+        $crate::metrics::register(
+            $crate::metrics::HistogramVec::new(
+                $crate::metrics::HistogramOpts::new($name, $help).buckets($buckets),
+                &[$($label),+],
+            )
+            .unwrap(),
+        )
+    };
+}
+
+/// Samples the tokio runtime `/metrics` is gathered on. Per worker busy time and park
+/// counts show stalled or saturated workers, mean poll times and local queue depths are
+/// added when built with `--cfg tokio_unstable`.
+struct RuntimeCollector {
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    worker_busy: CounterVec,
+    worker_parks: IntCounterVec,
+    #[cfg(tokio_unstable)]
+    worker_mean_poll: GaugeVec,
+    #[cfg(tokio_unstable)]
+    worker_polls: IntCounterVec,
+    #[cfg(tokio_unstable)]
+    worker_local_queue_depth: IntGaugeVec,
+}
+
+impl RuntimeCollector {
+    fn new() -> Self {
+        let per_worker = |name: &str, help: &str| Opts::new(name, help).variable_label("worker");
+        Self {
+            workers: IntGauge::new("tokio_workers", "Worker threads of the tokio runtime").unwrap(),
+            alive_tasks: IntGauge::new("tokio_alive_tasks", "Tasks alive in the tokio runtime")
+                .unwrap(),
+            global_queue_depth: IntGauge::new(
+                "tokio_global_queue_depth",
+                "Tasks waiting in the global queue of the tokio runtime",
+            )
+            .unwrap(),
+            worker_busy: CounterVec::new(
+                per_worker(
+                    "tokio_worker_busy_seconds_total",
+                    "Time a tokio worker spent polling tasks",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+            worker_parks: IntCounterVec::new(
+                per_worker(
+                    "tokio_worker_parks_total",
+                    "Times a tokio worker parked for lack of work",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            worker_mean_poll: GaugeVec::new(
+                per_worker(
+                    "tokio_worker_mean_poll_seconds",
+                    "Moving average of the task poll time of a tokio worker",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            worker_polls: IntCounterVec::new(
+                per_worker("tokio_worker_polls_total", "Tasks polled by a tokio worker"),
+                &["worker"],
+            )
+            .unwrap(),
+            #[cfg(tokio_unstable)]
+            worker_local_queue_depth: IntGaugeVec::new(
+                per_worker(
+                    "tokio_worker_local_queue_depth",
+                    "Tasks waiting in the local queue of a tokio worker",
+                ),
+                &["worker"],
+            )
+            .unwrap(),
+        }
+    }
+
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        vec![
+            &self.workers,
+            &self.alive_tasks,
+            &self.global_queue_depth,
+            &self.worker_busy,
+            &self.worker_parks,
+            #[cfg(tokio_unstable)]
+            &self.worker_mean_poll,
+            #[cfg(tokio_unstable)]
+            &self.worker_polls,
+            #[cfg(tokio_unstable)]
+            &self.worker_local_queue_depth,
+        ]
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors()
+            .into_iter()
+            .flat_map(|collector| collector.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return Vec::new();
+        };
+        let metrics = handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+            // the runtime keeps running totals, counters only move forward by the difference
+            let busy = self.worker_busy.with_label_values(&[&label]);
+            busy.inc_by(
+                (metrics.worker_total_busy_duration(worker).as_secs_f64() - busy.get()).max(0.0),
+            );
+            let parks = self.worker_parks.with_label_values(&[&label]);
+            parks.inc_by(
+                metrics
+                    .worker_park_count(worker)
+                    .saturating_sub(parks.get()),
+            );
+            #[cfg(tokio_unstable)]
+            {
+                self.worker_mean_poll
+                    .with_label_values(&[&label])
+                    .set(metrics.worker_mean_poll_time(worker).as_secs_f64());
+                let polls = self.worker_polls.with_label_values(&[&label]);
+                polls.inc_by(
+                    metrics
+                        .worker_poll_count(worker)
+                        .saturating_sub(polls.get()),
+                );
+                self.worker_local_queue_depth
+                    .with_label_values(&[&label])
+                    .set(metrics.worker_local_queue_depth(worker) as i64);
+            }
+        }
+        self.collectors()
+            .into_iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+/// A dump of every task of the current runtime with its await-point backtrace, `None`
+/// when the runtime did not answer within `timeout`, e.g. with a worker blocked.
+#[cfg(all(feature = "taskdump", tokio_unstable, target_os = "linux"))]
+pub async fn task_dump(timeout: std::time::Duration) -> Option<String> {
+    use std::fmt::Write;
+
+    let handle = tokio::runtime::Handle::current();
+    let dump = tokio::time::timeout(timeout, handle.dump()).await.ok()?;
+    let mut text = String::new();
+    for (i, task) in dump.tasks().iter().enumerate() {
+        let _ = writeln!(text, "task {i} ({:?}):\n{}\n", task.id(), task.trace());
+    }
+    Some(text)
+}
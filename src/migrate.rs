@@ -0,0 +1,481 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use color_eyre::{eyre::eyre, Result};
+use etcd_client::{Compare, CompareOp, PutOptions, Txn, TxnOp};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    etcd::{op_error, Etcd, KeyValue},
+    limiter::{RateLimitQuota, RateLimiter},
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MigrateConfig {
+    /// keys copied per batch, at most 128 as etcd limits the operations of a txn
+    pub batch: i64,
+    /// keys copied per second, 0 for no limit
+    pub rate: f64,
+    /// counts the keys to copy without writing anything
+    pub dry_run: bool,
+    /// deletes the source keys once copied, moving them
+    pub delete_source: bool,
+}
+
+impl Default for MigrateConfig {
+    fn default() -> Self {
+        Self {
+            batch: 128,
+            rate: 0.0,
+            dry_run: false,
+            delete_source: false,
+        }
+    }
+}
+
+/// What a [`Migration`] did, or would do in a dry run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MigrateReport {
+    pub scanned: u64,
+    pub copied: u64,
+    /// keys the key mapping left out
+    pub skipped: u64,
+    pub deleted: u64,
+    /// source keys written to between their copy and their deletion, left in place
+    pub changed: u64,
+    /// whether the run went on from the checkpoint of an earlier one
+    pub resumed: bool,
+    pub dry_run: bool,
+}
+
+enum Target {
+    /// the etcd the keys are read from
+    Source,
+    Etcd(Box<Etcd>),
+    #[cfg(feature = "redis")]
+    Redis {
+        redis: Box<crate::redis::Redis>,
+        ttl: u64,
+    },
+}
+
+type KeyMap = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Copies or moves the keys under an etcd prefix to another prefix, on the same etcd, on
+/// another one or on redis, e.g. for a change of the key layout. Keys are copied in key
+/// order a batch at a time, and the last key of every batch is saved at
+/// `migrate/{name}/checkpoint` on the source etcd, so a run stopped halfway goes on from
+/// there; neither prefix may cover it. The checkpoint is removed once the run completes. Keys moved on the same etcd
+/// keep their lease, the leases of keys copied to another etcd or to redis are dropped,
+/// the keys written there for good or for the ttl given to [`Migration::redis_target`].
+pub struct Migration {
+    source: Etcd,
+    name: String,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    target: Target,
+    map: Option<KeyMap>,
+    config: MigrateConfig,
+}
+
+impl Migration {
+    /// Copies the keys under `from` to the same keys under `to`, on the same etcd unless
+    /// another target is given.
+    pub fn new(source: Etcd, name: &str, from: impl Into<Vec<u8>>, to: impl Into<Vec<u8>>) -> Self {
+        Self {
+            source,
+            name: name.to_owned(),
+            from: from.into(),
+            to: to.into(),
+            target: Target::Source,
+            map: None,
+            config: MigrateConfig::default(),
+        }
+    }
+
+    pub fn etcd_target(mut self, etcd: Etcd) -> Self {
+        self.target = Target::Etcd(Box::new(etcd));
+        self
+    }
+
+    /// Writes the keys to redis, expiring after `ttl` seconds, or never with 0.
+    #[cfg(feature = "redis")]
+    pub fn redis_target(mut self, redis: crate::redis::Redis, ttl: u64) -> Self {
+        self.target = Target::Redis {
+            redis: Box::new(redis),
+            ttl,
+        };
+        self
+    }
+
+    /// Maps every key, without the `from` prefix, to its new key without the `to`
+    /// prefix, `None` leaving the key out.
+    pub fn map_key(
+        mut self,
+        map: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.map = Some(Arc::new(map));
+        self
+    }
+
+    pub const fn config(mut self, config: MigrateConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn checkpoint_key(&self) -> String {
+        checkpoint_key(&self.name)
+    }
+
+    /// The last key copied by a run that did not complete.
+    pub async fn checkpoint(&self) -> Result<Option<Vec<u8>>> {
+        let key = self.checkpoint_key();
+        Ok(self
+            .source
+            .client
+            .clone()
+            .get(key.as_str(), None)
+            .await
+            .map_err(|e| op_error("get", &key, e))?
+            .take_kvs()
+            .pop()
+            .map(|kv| kv.value().to_vec()))
+    }
+
+    /// Drops the checkpoint, for the next run to start over.
+    pub async fn reset(&self) -> Result<()> {
+        self.source.delete(self.checkpoint_key()).await?;
+        Ok(())
+    }
+
+    pub async fn run(&self) -> Result<MigrateReport> {
+        check_prefixes(
+            &self.name,
+            &self.from,
+            &self.to,
+            matches!(self.target, Target::Source),
+        )?;
+        let batch = self.config.batch.clamp(1, 128);
+        let limiter = (self.config.rate > 0.0).then(|| {
+            RateLimiter::<()>::new(RateLimitQuota {
+                rate: self.config.rate,
+                burst: batch as u64,
+            })
+        });
+        let mut report = MigrateReport {
+            dry_run: self.config.dry_run,
+            ..Default::default()
+        };
+        let mut scan = self.source.scan_prefix(self.from.clone(), batch);
+        if let Some(checkpoint) = self.checkpoint().await? {
+            info!(
+                "migration {} resumed after `{}`",
+                self.name,
+                String::from_utf8_lossy(&checkpoint)
+            );
+            scan = scan.resume_after(&checkpoint);
+            report.resumed = true;
+        }
+        while let Some(kvs) = scan.next_page().await? {
+            let mut writes = Vec::with_capacity(kvs.len());
+            let mut moved = Vec::with_capacity(kvs.len());
+            for kv in &kvs {
+                report.scanned += 1;
+                let key = &kv.key()[self.from.len()..];
+                let key = match &self.map {
+                    Some(map) => map(key),
+                    None => Some(key.to_vec()),
+                };
+                match key {
+                    Some(key) => {
+                        writes.push(([self.to.as_slice(), &key].concat(), kv));
+                        moved.push(kv);
+                    }
+                    None => report.skipped += 1,
+                }
+            }
+            report.copied += writes.len() as u64;
+            if self.config.dry_run {
+                continue;
+            }
+            if let Some(limiter) = &limiter {
+                for _ in &writes {
+                    limiter.acquire(&()).await;
+                }
+            }
+            if !writes.is_empty() {
+                self.write(&writes).await?;
+                // keys left out by the mapping stay where they are
+                if self.config.delete_source {
+                    let deleted = self.delete(&moved).await?;
+                    report.deleted += deleted;
+                    report.changed += moved.len() as u64 - deleted;
+                }
+            }
+            if let Some(last) = kvs.last() {
+                self.source
+                    .put(self.checkpoint_key(), last.key(), 0)
+                    .await?;
+            }
+            debug!("migration {}: {report:?}", self.name);
+        }
+        if !self.config.dry_run {
+            self.reset().await?;
+        }
+        info!("migration {} done: {report:?}", self.name);
+        Ok(report)
+    }
+
+    async fn write(&self, writes: &[(Vec<u8>, &KeyValue)]) -> Result<()> {
+        let etcd = match &self.target {
+            Target::Source => &self.source,
+            Target::Etcd(etcd) => etcd,
+            #[cfg(feature = "redis")]
+            Target::Redis { redis, ttl } => {
+                for (key, kv) in writes {
+                    let mut cmd = crate::redis::cmd("SET");
+                    cmd.arg(key).arg(kv.value());
+                    if *ttl != 0 {
+                        cmd.arg("EX").arg(*ttl);
+                    }
+                    redis.query_idempotent::<()>(&cmd).await?;
+                }
+                return Ok(());
+            }
+        };
+        // a lease only means something on the etcd that granted it
+        let same_etcd = matches!(self.target, Target::Source);
+        let txn = Txn::new().and_then(
+            writes
+                .iter()
+                .map(|(key, kv)| {
+                    let options = (same_etcd && kv.lease() != 0)
+                        .then(|| PutOptions::new().with_lease(kv.lease()));
+                    TxnOp::put(key.as_slice(), kv.value(), options)
+                })
+                .collect::<Vec<_>>(),
+        );
+        etcd.client
+            .clone()
+            .txn(txn)
+            .await
+            .map_err(|e| op_error("txn", &self.to, e))?;
+        Ok(())
+    }
+
+    /// Deletes the keys not written to since they were read, returns how many.
+    async fn delete(&self, kvs: &[&KeyValue]) -> Result<u64> {
+        let mut client = self.source.client.clone();
+        let mut deleted = 0;
+        for kv in kvs {
+            let txn = Txn::new()
+                .when([Compare::mod_revision(
+                    kv.key(),
+                    CompareOp::Equal,
+                    kv.mod_revision(),
+                )])
+                .and_then([TxnOp::delete(kv.key(), None)]);
+            let succeeded = client
+                .txn(txn)
+                .await
+                .map_err(|e| op_error("txn", kv.key(), e))?
+                .succeeded();
+            if succeeded {
+                deleted += 1;
+            } else {
+                warn!(
+                    "migration {}: `{}` changed since it was copied, left in place",
+                    self.name,
+                    String::from_utf8_lossy(kv.key())
+                );
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+fn checkpoint_key(name: &str) -> String {
+    format!("migrate/{name}/checkpoint")
+}
+
+/// Rejects a target prefix inside the source one on the same etcd, whose keys the run would
+/// copy again, and prefixes covering the checkpoint, which would be copied, deleted or
+/// overwritten with the keys.
+fn check_prefixes(name: &str, from: &[u8], to: &[u8], same_etcd: bool) -> Result<()> {
+    if same_etcd && to.starts_with(from) {
+        return Err(eyre!(
+            "migration {name}: target prefix `{}` inside the source prefix",
+            String::from_utf8_lossy(to)
+        ));
+    }
+    let checkpoint = checkpoint_key(name);
+    let mut prefixes = vec![("source", from)];
+    if same_etcd {
+        prefixes.push(("target", to));
+    }
+    for (which, prefix) in prefixes {
+        if checkpoint.as_bytes().starts_with(prefix) {
+            return Err(eyre!(
+                "migration {name}: {which} prefix `{}` covers the checkpoint `{checkpoint}`",
+                String::from_utf8_lossy(prefix)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use etcd_client::GetOptions;
+
+    use super::*;
+    use crate::etcd::EtcdConfig;
+
+    #[test]
+    fn prefixes_checked() {
+        assert!(check_prefixes("m", b"app/", b"app2/", true).is_ok());
+        assert!(check_prefixes("m", b"app/", b"app/v2/", true).is_err());
+        // on another target the keys cannot be read again
+        assert!(check_prefixes("m", b"app/", b"app/v2/", false).is_ok());
+        for from in [&b""[..], b"migrate/", b"migrate/m/"] {
+            assert!(check_prefixes("m", from, b"app/", true).is_err());
+            assert!(check_prefixes("m", from, b"app/", false).is_err());
+        }
+        assert!(check_prefixes("m", b"migrate/other/", b"app/", true).is_ok());
+        assert!(check_prefixes("m", b"app/", b"migrate/", true).is_err());
+        // the checkpoint is kept on the source etcd only
+        assert!(check_prefixes("m", b"app/", b"migrate/", false).is_ok());
+    }
+
+    /// The etcd at `ETCD_ENDPOINTS`, the tests needing one skipped without it.
+    async fn etcd() -> Option<Etcd> {
+        let endpoints = std::env::var("ETCD_ENDPOINTS").ok()?;
+        let config = EtcdConfig {
+            endpoints: endpoints.split(',').map(str::to_owned).collect(),
+            ..Default::default()
+        };
+        Some(Etcd::new(&config).await.unwrap())
+    }
+
+    /// Puts `keys` under a prefix of its own for the test, returns it.
+    async fn seed(etcd: &Etcd, name: &str, keys: &[&str]) -> String {
+        let prefix = format!("migrate-test/{name}-{}/", std::process::id());
+        etcd.delete_with_prefix(prefix.clone()).await.unwrap();
+        for key in keys {
+            etcd.put(format!("{prefix}from/{key}"), *key, 0)
+                .await
+                .unwrap();
+        }
+        prefix
+    }
+
+    async fn keys(etcd: &Etcd, prefix: &str) -> Vec<String> {
+        etcd.client
+            .clone()
+            .get(prefix, Some(GetOptions::new().with_prefix()))
+            .await
+            .unwrap()
+            .kvs()
+            .iter()
+            .map(|kv| kv.key_str().unwrap()[prefix.len()..].to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resumes_after_checkpoint() {
+        let Some(etcd) = etcd().await else {
+            return;
+        };
+        let prefix = seed(&etcd, "resume", &["a", "b", "c", "d"]).await;
+        let migration = Migration::new(
+            etcd.clone(),
+            &format!("resume-{}", std::process::id()),
+            format!("{prefix}from/"),
+            format!("{prefix}to/"),
+        )
+        .config(MigrateConfig {
+            batch: 1,
+            ..Default::default()
+        });
+        etcd.put(migration.checkpoint_key(), format!("{prefix}from/b"), 0)
+            .await
+            .unwrap();
+        let report = migration.run().await.unwrap();
+        assert!(report.resumed);
+        assert_eq!(report.scanned, 2);
+        assert_eq!(keys(&etcd, &format!("{prefix}to/")).await, ["c", "d"]);
+        assert_eq!(migration.checkpoint().await.unwrap(), None);
+        etcd.delete_with_prefix(prefix).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_nothing() {
+        let Some(etcd) = etcd().await else {
+            return;
+        };
+        let prefix = seed(&etcd, "dry-run", &["a", "b", "c"]).await;
+        let migration = Migration::new(
+            etcd.clone(),
+            &format!("dry-run-{}", std::process::id()),
+            format!("{prefix}from/"),
+            format!("{prefix}to/"),
+        )
+        .map_key(|key| (key != b"b").then(|| key.to_vec()))
+        .config(MigrateConfig {
+            dry_run: true,
+            delete_source: true,
+            batch: 2,
+            ..Default::default()
+        });
+        let report = migration.run().await.unwrap();
+        assert_eq!(
+            report,
+            MigrateReport {
+                scanned: 3,
+                copied: 2,
+                skipped: 1,
+                dry_run: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(keys(&etcd, &prefix).await, ["from/a", "from/b", "from/c"]);
+        assert_eq!(migration.checkpoint().await.unwrap(), None);
+        etcd.delete_with_prefix(prefix).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_changed_since_copied_left_in_place() {
+        let Some(etcd) = etcd().await else {
+            return;
+        };
+        let prefix = seed(&etcd, "delete", &["a", "b"]).await;
+        let migration = Migration::new(
+            etcd.clone(),
+            &format!("delete-{}", std::process::id()),
+            format!("{prefix}from/"),
+            format!("{prefix}to/"),
+        );
+        let mut scan = etcd.scan_prefix(format!("{prefix}from/"), 10);
+        let kvs = scan.next_page().await.unwrap().unwrap();
+        etcd.put(format!("{prefix}from/b"), "b2", 0).await.unwrap();
+        let kvs: Vec<_> = kvs.iter().collect();
+        assert_eq!(migration.delete(&kvs).await.unwrap(), 1);
+        assert_eq!(keys(&etcd, &prefix).await, ["from/b"]);
+        etcd.delete_with_prefix(prefix).await.unwrap();
+    }
+}
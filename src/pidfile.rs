@@ -0,0 +1,120 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+
+use color_eyre::Result;
+
+use crate::file_lock::FileLock;
+
+/// A locked pid file guarding a single instance per data or config directory, removed
+/// when dropped. A second instance pointed at the same file fails to start. A file left
+/// behind by a crashed instance is not locked by anyone and is taken over.
+#[derive(Debug)]
+pub struct PidFile {
+    lock: FileLock,
+    stale_pid: Option<u32>,
+}
+
+impl PidFile {
+    /// Writes the pid of this process to `path`, or fails if a running instance holds it.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        // read before taking the lock, which overwrites the pid
+        let previous = std::fs::read_to_string(path).ok();
+        // the holder removes the file before it releases the lock, so a lock taken on a
+        // file already removed is let go and taken again on the one now at the path
+        let lock = loop {
+            let lock = FileLock::acquire(path)?;
+            if lock.is_current() {
+                break lock;
+            }
+        };
+        let stale_pid = previous
+            .and_then(|pid| pid.trim().parse().ok())
+            .filter(|pid| *pid != std::process::id());
+        Ok(Self { lock, stale_pid })
+    }
+
+    /// [`PidFile::create`] `{name}.pid` in the directory `dir`.
+    pub fn create_in(dir: impl AsRef<Path>, name: &str) -> Result<Self> {
+        Self::create(dir.as_ref().join(format!("{name}.pid")))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.lock.path()
+    }
+
+    /// The pid found in a file left behind by an instance that did not exit cleanly.
+    pub const fn stale_pid(&self) -> Option<u32> {
+        self.stale_pid
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // the lock field is dropped, releasing the lock, only after the file is removed
+        let _ = std::fs::remove_file(self.lock.path());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("pidfile-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn single_instance_and_removed_on_drop() {
+        let dir = dir("single");
+        let pidfile = PidFile::create_in(&dir, "svc").unwrap();
+        let path = pidfile.path().to_owned();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        assert!(PidFile::create(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+        assert!(PidFile::create(&path).is_ok());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stale_pid_is_reported() {
+        let dir = dir("stale");
+        let path = dir.join("svc.pid");
+        std::fs::write(&path, "4194305\n").unwrap();
+        assert_eq!(PidFile::create(&path).unwrap().stale_pid(), Some(4194305));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn lock_on_a_removed_file_is_not_current() {
+        let dir = dir("removed");
+        let path = dir.join("svc.pid");
+        let lock = FileLock::acquire(&path).unwrap();
+        assert!(lock.is_current());
+        std::fs::remove_file(&path).unwrap();
+        assert!(!lock.is_current());
+        std::fs::write(&path, "").unwrap();
+        assert!(!lock.is_current());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
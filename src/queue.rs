@@ -0,0 +1,489 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Display},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tracing::{debug, error, info};
+
+use crate::shutdown::{Phase, Shutdown};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// wait for a free slot
+    #[default]
+    Block,
+    /// drop the oldest queued item to make room
+    Drop,
+    /// fail the push with [`PushError::Full`]
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// items queued before `overflow` applies
+    pub capacity: usize,
+    /// items handled at once
+    pub workers: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            workers: 4,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+/// Returned with the item that could not be queued.
+pub enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> PushError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(item) | Self::Closed(item) => item,
+        }
+    }
+}
+
+impl<T> Debug for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> Display for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("queue is full"),
+            Self::Closed(_) => f.write_str("queue is closed"),
+        }
+    }
+}
+
+impl<T> std::error::Error for PushError<T> {}
+
+#[cfg(feature = "metrics")]
+static DEPTH: std::sync::LazyLock<crate::metrics::IntGaugeVec> = std::sync::LazyLock::new(|| {
+    crate::gauge!(
+        "work_queue_depth",
+        "Items waiting in a work queue",
+        ["queue"]
+    )
+});
+
+#[cfg(feature = "metrics")]
+static DROPPED: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "work_queue_dropped_total",
+            "Items a full work queue dropped or rejected",
+            ["queue", "overflow"]
+        )
+    });
+
+struct Shared<T> {
+    name: String,
+    config: QueueConfig,
+    items: Mutex<VecDeque<T>>,
+    /// one permit per queued item
+    ready: Semaphore,
+    /// one permit per free slot
+    space: Semaphore,
+    closed: AtomicBool,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<T>> {
+        self.items.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn enqueue(&self, items: &mut VecDeque<T>, item: T) {
+        items.push_back(item);
+        self.ready.add_permits(1);
+        #[cfg(feature = "metrics")]
+        DEPTH
+            .with_label_values(&[&self.name])
+            .set(items.len() as i64);
+    }
+
+    fn dropped(&self, overflow: &str) {
+        debug!("work queue {} is full, overflow: {overflow}", self.name);
+        #[cfg(feature = "metrics")]
+        DROPPED.with_label_values(&[&self.name, overflow]).inc();
+    }
+}
+
+/// A bounded queue drained by a pool of workers. Pushing into a full queue waits,
+/// drops the oldest item or fails as its [`Overflow`] says, so a slow consumer pushes
+/// back on the producers instead of buffering without bound. Cloning is cheap and every
+/// clone pushes into the same queue.
+pub struct WorkQueue<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for WorkQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> WorkQueue<T> {
+    /// Starts `config.workers` workers, each handling one item at a time with `handler`.
+    /// Every item is handled in a task of its own, so a panicking handler loses its item
+    /// and not the worker.
+    pub fn new<F, Fut>(name: &str, config: QueueConfig, handler: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            name: name.to_owned(),
+            config,
+            items: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            ready: Semaphore::new(0),
+            space: Semaphore::new(config.capacity.max(1)),
+            closed: AtomicBool::new(false),
+            workers: Default::default(),
+        });
+        let handler = Arc::new(handler);
+        let workers = (0..config.workers.max(1))
+            .map(|_| tokio::spawn(work(shared.clone(), handler.clone())))
+            .collect();
+        *shared.workers.lock().unwrap_or_else(|e| e.into_inner()) = workers;
+        Self { shared }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    /// Items waiting for a worker.
+    pub fn len(&self) -> usize {
+        self.shared.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues `item`, waiting for a free slot if the queue is full and blocks.
+    pub async fn push(&self, item: T) -> Result<(), PushError<T>> {
+        let shared = &self.shared;
+        if shared.config.overflow == Overflow::Block {
+            if shared.closed.load(Ordering::Relaxed) {
+                return Err(PushError::Closed(item));
+            }
+            match shared.space.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return Err(PushError::Closed(item)),
+            }
+            let mut items = shared.lock();
+            // closed while waiting for the slot, the workers may be gone already
+            if shared.closed.load(Ordering::Relaxed) {
+                return Err(PushError::Closed(item));
+            }
+            shared.enqueue(&mut items, item);
+            return Ok(());
+        }
+        self.try_push(item)
+    }
+
+    /// Queues `item` without waiting, a full queue that blocks fails the push.
+    pub fn try_push(&self, item: T) -> Result<(), PushError<T>> {
+        let shared = &self.shared;
+        if shared.closed.load(Ordering::Relaxed) {
+            return Err(PushError::Closed(item));
+        }
+        let mut items = shared.lock();
+        if shared.closed.load(Ordering::Relaxed) {
+            return Err(PushError::Closed(item));
+        }
+        if let Ok(permit) = shared.space.try_acquire() {
+            permit.forget();
+            shared.enqueue(&mut items, item);
+            return Ok(());
+        }
+        match shared.config.overflow {
+            Overflow::Drop => {
+                // the queue keeps its length, so the permits stay as they are
+                if items.pop_front().is_some() {
+                    items.push_back(item);
+                } else {
+                    shared.enqueue(&mut items, item);
+                }
+                shared.dropped("drop");
+                Ok(())
+            }
+            Overflow::Block | Overflow::Reject => {
+                shared.dropped("reject");
+                Err(PushError::Full(item))
+            }
+        }
+    }
+
+    /// Fails further pushes and waits for the workers to handle the queued items.
+    pub async fn close(&self) {
+        let shared = &self.shared;
+        // under the lock of the items, so a push either sees it closed or queues its item
+        // before the workers drain the queue
+        let closed = {
+            let _items = shared.lock();
+            shared.closed.swap(true, Ordering::Relaxed)
+        };
+        if closed {
+            return;
+        }
+        shared.space.close();
+        // wakes every worker once more to find the queue drained
+        shared.ready.add_permits(shared.config.workers.max(1));
+        let workers =
+            std::mem::take(&mut *shared.workers.lock().unwrap_or_else(|e| e.into_inner()));
+        for worker in workers {
+            let _ = worker.await;
+        }
+        info!("work queue {} closed", shared.name);
+    }
+
+    /// Closes the queue in the [`Phase::Drain`] phase of `shutdown`.
+    pub fn close_on_shutdown(&self, shutdown: &Shutdown) {
+        let queue = self.clone();
+        let name = format!("work queue {}", self.shared.name);
+        shutdown.on(Phase::Drain, &name, move || async move {
+            queue.close().await;
+            Ok(())
+        });
+    }
+}
+
+async fn work<T, F, Fut>(shared: Arc<Shared<T>>, handler: Arc<F>)
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        match shared.ready.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return,
+        }
+        let item = {
+            let mut items = shared.lock();
+            let item = items.pop_front();
+            if item.is_some() {
+                shared.space.add_permits(1);
+                #[cfg(feature = "metrics")]
+                DEPTH
+                    .with_label_values(&[&shared.name])
+                    .set(items.len() as i64);
+            }
+            item
+        };
+        match item {
+            Some(item) => {
+                if let Err(e) = tokio::spawn(handler(item)).await {
+                    if e.is_panic() {
+                        error!("work queue {} handler panicked, item lost", shared.name);
+                    }
+                }
+            }
+            None if shared.closed.load(Ordering::Relaxed) => return,
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    fn config(capacity: usize, overflow: Overflow) -> QueueConfig {
+        QueueConfig {
+            capacity,
+            workers: 1,
+            overflow,
+        }
+    }
+
+    /// A queue recording the items it handles, each held until `release` has a permit
+    /// for it.
+    struct Held {
+        queue: WorkQueue<u32>,
+        release: Arc<Semaphore>,
+        handled: Arc<Mutex<Vec<u32>>>,
+        started: Arc<Notify>,
+    }
+
+    fn held(config: QueueConfig) -> Held {
+        let release = Arc::new(Semaphore::new(0));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let started = Arc::new(Notify::new());
+        let queue = WorkQueue::new("test", config, {
+            let (release, handled, started) = (release.clone(), handled.clone(), started.clone());
+            move |item| {
+                let (release, handled, started) =
+                    (release.clone(), handled.clone(), started.clone());
+                async move {
+                    started.notify_one();
+                    release.acquire().await.unwrap().forget();
+                    handled.lock().unwrap().push(item);
+                }
+            }
+        });
+        Held {
+            queue,
+            release,
+            handled,
+            started,
+        }
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_a_slot() {
+        let Held {
+            queue,
+            release,
+            handled,
+            started,
+        } = held(config(1, Overflow::Block));
+        queue.push(1).await.unwrap();
+        // the worker holds 1, 2 takes the only slot
+        started.notified().await;
+        queue.push(2).await.unwrap();
+        assert!(matches!(queue.try_push(3), Err(PushError::Full(3))));
+        let pushing = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(3).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!pushing.is_finished());
+        release.add_permits(3);
+        pushing.await.unwrap().unwrap();
+        queue.close().await;
+        assert_eq!(*handled.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_replaces_the_oldest() {
+        let Held {
+            queue,
+            release,
+            handled,
+            started,
+        } = held(config(2, Overflow::Drop));
+        queue.push(1).await.unwrap();
+        started.notified().await;
+        for item in 2..=5 {
+            queue.push(item).await.unwrap();
+        }
+        assert_eq!(queue.len(), 2);
+        release.add_permits(3);
+        queue.close().await;
+        assert_eq!(*handled.lock().unwrap(), [1, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn reject_fails_the_push() {
+        let Held {
+            queue,
+            release,
+            handled,
+            started,
+        } = held(config(1, Overflow::Reject));
+        queue.push(1).await.unwrap();
+        started.notified().await;
+        queue.push(2).await.unwrap();
+        let e = queue.push(3).await.unwrap_err();
+        assert!(matches!(e, PushError::Full(_)));
+        assert_eq!(e.into_inner(), 3);
+        release.add_permits(2);
+        queue.close().await;
+        assert_eq!(*handled.lock().unwrap(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn close_drains_then_rejects() {
+        let Held {
+            queue,
+            release,
+            handled,
+            started,
+        } = held(QueueConfig {
+            workers: 2,
+            ..config(8, Overflow::Block)
+        });
+        for item in 1..=6 {
+            queue.push(item).await.unwrap();
+        }
+        started.notified().await;
+        release.add_permits(6);
+        queue.close().await;
+        assert!(queue.is_empty());
+        let mut handled = handled.lock().unwrap().clone();
+        handled.sort_unstable();
+        assert_eq!(handled, [1, 2, 3, 4, 5, 6]);
+        assert!(matches!(queue.push(7).await, Err(PushError::Closed(7))));
+        assert!(matches!(queue.try_push(8), Err(PushError::Closed(8))));
+        // closing again returns at once
+        queue.close().await;
+    }
+
+    #[tokio::test]
+    async fn panicking_handler_keeps_the_worker() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let queue = WorkQueue::new("test", config(4, Overflow::Block), {
+            let handled = handled.clone();
+            move |item: u32| {
+                let handled = handled.clone();
+                async move {
+                    if item == 1 {
+                        panic!("poisoned item");
+                    }
+                    handled.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        for item in 1..=3 {
+            queue.push(item).await.unwrap();
+        }
+        queue.close().await;
+        assert_eq!(handled.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,11 +1,29 @@
-use color_eyre::{eyre::eyre, Result};
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use color_eyre::Result;
 pub use redis::*;
 
 use serde::{Deserialize, Serialize};
 
-use tracing::{error, info};
+use tracing::{error, field, info, info_span, Instrument, Span};
 
-use crate::service_register::{ServiceRegister, ServiceRegisterConfig};
+use crate::{
+    clock::{renew_interval, unix_secs},
+    error::CommonError,
+    health::{CheckFuture, HealthCheck},
+    retry::{retry_if, RetryPolicy},
+    service_register::{
+        instance_entries, register_entries, RegisterHealth, RegisterStatus, RegisterStatuses,
+        ServiceDiscovery, ServiceRegister, ServiceRegisterConfig,
+    },
+    shutdown::{CancellationToken, Phase, Shutdown, TaskScope},
+    slow::SlowLog,
+    supervisor::Supervisor,
+    tenant::{Tenant, TenantRedis},
+};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "redis-cluster")] {
@@ -15,22 +33,91 @@ cfg_if::cfg_if! {
     }
 }
 
+static TOKEN_BUCKET: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or burst
+local ts = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - ts) / 1000 * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+if rate > 0 then
+    redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000) + 1000)
+end
+return allowed
+",
+    )
+});
+
+/// Whether `e` may pass on retry, the command never reached a server or its reply was lost.
+fn transient(e: &RedisError) -> bool {
+    e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout() || e.is_io_error()
+}
+
+fn op_error(op: &str, e: redis::RedisError) -> CommonError {
+    CommonError::RedisOp {
+        op: op.to_owned(),
+        source: e.into(),
+    }
+}
+
+/// The sorted set of the urls of the instances of `service_name`, scored by the unix
+/// second they expire at. Kept out of the `traefik` prefix read by the router.
+fn instances_key(service_name: &str) -> String {
+    format!("discovery/{service_name}/instances")
+}
+
+/// A client span for one redis command, exported as a child of the active trace. The
+/// protocol carries no metadata, so the trace context stops at this process.
+fn span(command: &str) -> Span {
+    info_span!(
+        "redis",
+        otel.name = %format_args!("redis {command}"),
+        otel.kind = "client",
+        db.system = "redis",
+        db.operation = command,
+        db.key = field::Empty,
+    )
+}
+
 #[derive(Clone)]
 pub struct Redis {
     client: RedisClient,
     connection: RedisConnection,
+    register_statuses: Arc<RegisterStatuses>,
+    slow_threshold: Duration,
+    retry: RetryPolicy,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct RedisConfig {
     pub endpoints: Vec<String>,
+    /// milliseconds after which a command is logged as slow, 0 disables it
+    pub slow_threshold: u64,
+    /// retries of idempotent commands failing on a dropped or refused connection
+    pub retry: RetryPolicy,
 }
 
 impl Default for RedisConfig {
     fn default() -> Self {
         Self {
             endpoints: vec!["redis://127.0.0.1/".to_owned()],
+            slow_threshold: 100,
+            retry: RetryPolicy {
+                max_retries: 2,
+                initial_backoff: 50,
+                max_backoff: 500,
+                ..Default::default()
+            },
         }
     }
 }
@@ -40,23 +127,29 @@ impl Redis {
         cfg_if::cfg_if! {
             if #[cfg(feature = "redis-cluster")] {
                 let client = RedisClient::new(config.endpoints.clone())
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                    .map_err(|e| CommonError::RedisConnect(e.into()))?;
 
                 let connection = client
                     .get_async_connection()
                     .await
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                    .map_err(|e| CommonError::RedisConnect(e.into()))?;
             } else {
                 let client = Client::open(config.endpoints[0].clone())
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                    .map_err(|e| CommonError::RedisConnect(e.into()))?;
 
                 let connection = client
                     .get_multiplexed_async_connection()
                     .await
-                    .map_err(|e| eyre!("redis connect failed: {e}"))?;
+                    .map_err(|e| CommonError::RedisConnect(e.into()))?;
             }
         }
-        Ok(Self { client, connection })
+        Ok(Self {
+            client,
+            connection,
+            register_statuses: Default::default(),
+            slow_threshold: Duration::from_millis(config.slow_threshold),
+            retry: config.retry,
+        })
     }
 
     pub fn client(&self) -> RedisClient {
@@ -67,6 +160,78 @@ impl Redis {
         self.connection.to_owned()
     }
 
+    /// The keys of `tenant` only, see [`Tenant`].
+    pub fn for_tenant(&self, tenant: Tenant) -> TenantRedis {
+        TenantRedis::new(self.clone(), tenant)
+    }
+
+    /// Runs `cmd` on the shared connection within a `redis <COMMAND>` span. Commands are
+    /// not retried, see [`Redis::query_idempotent`].
+    pub async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> Result<T> {
+        self.execute(cmd, false).await
+    }
+
+    /// Like [`Redis::query`], retrying `cmd` on transient errors, for commands that can
+    /// run twice such as `GET`, `SET` or `DEL`.
+    pub async fn query_idempotent<T: FromRedisValue>(&self, cmd: &Cmd) -> Result<T> {
+        self.execute(cmd, true).await
+    }
+
+    async fn execute<T: FromRedisValue>(&self, cmd: &Cmd, idempotent: bool) -> Result<T> {
+        let command = cmd
+            .args_iter()
+            .next()
+            .and_then(|arg| match arg {
+                Arg::Simple(name) => std::str::from_utf8(name).ok(),
+                Arg::Cursor => None,
+            })
+            .unwrap_or_default()
+            .to_uppercase();
+        let key = match cmd.args_iter().nth(1) {
+            Some(Arg::Simple(key)) => key,
+            _ => &[],
+        };
+        let _slow = SlowLog::start("redis", &command, key, self.slow_threshold);
+        let run = || {
+            let mut conn = self.conn();
+            #[cfg(feature = "faults")]
+            let command = command.clone();
+            async move {
+                #[cfg(feature = "faults")]
+                if let Some(fault) = crate::faults::inject("redis", &command).await {
+                    return Err(std::io::Error::other(fault).into());
+                }
+                cmd.query_async(&mut conn).await
+            }
+        };
+        if idempotent {
+            retry_if(&self.retry, run, transient)
+                .instrument(span(&command))
+                .await
+        } else {
+            run().instrument(span(&command)).await
+        }
+        .map_err(|e| op_error(&command, e).into())
+    }
+
+    /// Takes one token from the cluster-wide bucket stored at `key`, refilled at `rate`
+    /// tokens per second up to `burst`. Returns whether the token was granted.
+    pub async fn rate_limit(&self, key: &str, rate: f64, burst: u64) -> Result<bool> {
+        let now = crate::clock::unix_millis();
+        let span = span("rate_limit");
+        span.record("db.key", key);
+        let _slow = SlowLog::start("redis", "rate_limit", key.as_bytes(), self.slow_threshold);
+        TOKEN_BUCKET
+            .key(key)
+            .arg(rate)
+            .arg(burst)
+            .arg(now)
+            .invoke_async::<_, bool>(&mut self.conn())
+            .instrument(span)
+            .await
+            .map_err(|e| op_error("rate_limit", e).into())
+    }
+
     pub async fn service_register(
         &self,
         service_name: &str,
@@ -74,62 +239,265 @@ impl Redis {
     ) -> Result<()> {
         self.keep_service_register(service_name, config).await
     }
-}
 
-impl ServiceRegister for Redis {
-    async fn keep_service_register(
+    /// Stops the service register loop and removes the keys of this instance, leaving the
+    /// ones shared with the other replicas.
+    pub async fn service_deregister(
         &self,
         service_name: &str,
-        config: ServiceRegisterConfig,
+        config: &ServiceRegisterConfig,
     ) -> Result<()> {
-        info!("keep_service_register: {config:?}");
-        let mut keep_alive_interval =
-            tokio::time::interval(tokio::time::Duration::from_secs((config.ttl / 2) as u64));
+        self.register_statuses
+            .get(service_name)
+            .stop_and_wait()
+            .await;
+        for (key, _) in instance_entries(service_name, config) {
+            let span = span("DEL");
+            span.record("db.key", &key);
+            let _slow = SlowLog::start("redis", "DEL", key.as_bytes(), self.slow_threshold);
+            retry_if(
+                &self.retry,
+                || {
+                    let mut conn = self.conn();
+                    let key = key.clone();
+                    async move { conn.del::<_, ()>(key).await }
+                },
+                transient,
+            )
+            .instrument(span)
+            .await
+            .map_err(|e| op_error("del", e))?;
+        }
+        self.unindex_instance(service_name, config)
+            .await
+            .map_err(|e| op_error("zrem", e))?;
+        info!("service_deregister: {service_name}");
+        Ok(())
+    }
 
+    /// Deregisters `service_name` in the first phase of `shutdown`.
+    pub fn deregister_on_shutdown(
+        &self,
+        shutdown: &Shutdown,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
         let redis = self.clone();
         let service_name = service_name.to_owned();
-        tokio::spawn(async move {
-            loop {
-                keep_alive_interval.tick().await;
-                let tags = config.tags.clone();
-                let service_name = service_name.clone();
-
-                match redis
-                    .conn()
-                    .set_ex(
-                        &format!(
-                            "traefik/http/services/{}/loadbalancer/servers/{}/url",
-                            service_name, service_name
-                        ),
-                        config.url.clone(),
-                        config.ttl as u64,
-                    )
-                    .await
-                {
-                    Ok(()) => {}
-                    Err(e) => error!("keep_service_register failed: {:?}", e),
+        shutdown.on(Phase::Deregister, "redis_register", move || async move {
+            redis.service_deregister(&service_name, &config).await
+        });
+    }
+
+    /// Renews the keys of `service_name` every half ttl until the service deregisters.
+    async fn register_loop(
+        self,
+        service_name: String,
+        config: ServiceRegisterConfig,
+        status: Arc<RegisterStatus>,
+        generation: u64,
+    ) -> Result<()> {
+        let mut keep_alive_interval = tokio::time::interval(renew_interval(config.ttl));
+        let mut withdrawn = false;
+        loop {
+            keep_alive_interval.tick().await;
+            let _round = status.round().await;
+            if !status.is_current(generation) {
+                break;
+            }
+            if status.is_withdrawn() {
+                if !withdrawn {
+                    info!("service register {service_name} withdrawn");
+                    for (key, _) in instance_entries(&service_name, &config) {
+                        if let Err(e) = self.conn().del::<_, ()>(key).await {
+                            error!("withdraw service register failed: {e}");
+                        }
+                    }
+                    if let Err(e) = self.unindex_instance(&service_name, &config).await {
+                        error!("withdraw service register failed: {e}");
+                    }
+                    withdrawn = true;
                 }
-                match redis
-                    .conn()
-                    .set_ex(
-                        &format!("traefik/http/routers/{}/service", service_name),
-                        service_name,
-                        config.ttl as u64,
-                    )
-                    .await
-                {
+                continue;
+            }
+            withdrawn = false;
+            let mut failed = false;
+            for (key, value) in register_entries(&service_name, &config) {
+                match self.conn().set_ex(key, value, config.ttl as u64).await {
                     Ok(()) => {}
-                    Err(e) => error!("keep_service_register failed: {:?}", e),
-                }
-                for tag in tags {
-                    let (key, value) = tag.split_once('=').unwrap_or_default();
-                    match redis.conn().set_ex(key, value, config.ttl as u64).await {
-                        Ok(()) => {}
-                        Err(e) => error!("keep_service_register failed: {:?}", e),
+                    Err(e) => {
+                        error!("keep_service_register failed: {:?}", e);
+                        failed = true;
                     }
                 }
             }
+            if let Err(e) = self.index_instance(&service_name, &config).await {
+                error!("keep_service_register failed: {:?}", e);
+                failed = true;
+            }
+            if failed {
+                status.failure();
+            } else {
+                status.success();
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the url of this instance to the instances of `service_name` until its ttl
+    /// passes. Redis cannot list the keys of a prefix across a cluster, so discovery reads
+    /// the instances from a sorted set scored by expiry instead.
+    async fn index_instance(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+    ) -> RedisResult<()> {
+        let key = instances_key(service_name);
+        let now = unix_secs();
+        let mut conn = self.conn();
+        conn.zrembyscore::<_, _, _, ()>(&key, "-inf", now).await?;
+        conn.zadd::<_, _, _, ()>(&key, &config.url, now + config.ttl as u64)
+            .await?;
+        conn.expire(&key, config.ttl).await
+    }
+
+    /// Removes the url of this instance from the instances of `service_name`.
+    async fn unindex_instance(
+        &self,
+        service_name: &str,
+        config: &ServiceRegisterConfig,
+    ) -> RedisResult<()> {
+        self.conn()
+            .zrem(instances_key(service_name), &config.url)
+            .await
+    }
+
+    /// Like [`ServiceRegister::keep_service_register`], with the register loop owned by
+    /// `supervisor` so it is restarted should it panic.
+    pub fn supervise_register(
+        &self,
+        supervisor: &Supervisor,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        let redis = self.clone();
+        let service_name = service_name.to_owned();
+        supervisor.spawn("redis_register", move || {
+            redis.clone().register_loop(
+                service_name.clone(),
+                config.clone(),
+                status.clone(),
+                generation,
+            )
+        });
+    }
+
+    /// Like [`ServiceRegister::keep_service_register`], with the register loop spawned
+    /// into `scope` so it stops once the scope is cancelled.
+    pub fn register_in(
+        &self,
+        scope: &TaskScope,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        let register =
+            self.clone()
+                .register_loop(service_name.to_owned(), config, status, generation);
+        scope.spawn("redis_register", async move {
+            if let Err(e) = register.await {
+                error!("keep_service_register failed: {e}");
+            }
+        });
+    }
+
+    /// Deregisters `service_name` once `cancel` is cancelled, e.g. by
+    /// [`crate::shutdown::wait_for_shutdown`].
+    pub fn deregister_on_cancel(
+        &self,
+        cancel: CancellationToken,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) {
+        let redis = self.clone();
+        let service_name = service_name.to_owned();
+        tokio::spawn(async move {
+            cancel.cancelled().await;
+            if let Err(e) = redis.service_deregister(&service_name, &config).await {
+                error!("service_deregister {service_name} failed: {e}");
+            }
         });
+    }
+
+    /// Health check reporting whether the register loop of `service_name` keeps renewing
+    /// its keys.
+    pub fn register_health(&self, service_name: &str) -> RegisterHealth {
+        RegisterHealth {
+            name: format!("redis_register/{service_name}"),
+            status: self.register_statuses.get(service_name),
+        }
+    }
+}
+
+impl HealthCheck for Redis {
+    fn name(&self) -> String {
+        "redis".to_owned()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            cmd("PING")
+                .query_async::<_, String>(&mut self.conn())
+                .await
+                .map_err(|e| op_error("ping", e))?;
+            Ok(())
+        })
+    }
+}
+
+impl ServiceDiscovery for Redis {
+    async fn discover(&self, service_name: &str) -> Result<Vec<String>> {
+        let key = instances_key(service_name);
+        let span = span("ZRANGEBYSCORE");
+        span.record("db.key", &key);
+        let _slow = SlowLog::start(
+            "redis",
+            "ZRANGEBYSCORE",
+            key.as_bytes(),
+            self.slow_threshold,
+        );
+        retry_if(
+            &self.retry,
+            || {
+                let mut conn = self.conn();
+                let key = key.clone();
+                async move { conn.zrangebyscore(key, unix_secs(), "+inf").await }
+            },
+            transient,
+        )
+        .instrument(span)
+        .await
+        .map_err(|e| op_error("zrangebyscore", e).into())
+    }
+}
+
+impl ServiceRegister for Redis {
+    async fn keep_service_register(
+        &self,
+        service_name: &str,
+        config: ServiceRegisterConfig,
+    ) -> Result<()> {
+        info!("keep_service_register: {config:?}");
+        let (status, generation) = self.register_statuses.start(service_name, config.ttl);
+        tokio::spawn(self.clone().register_loop(
+            service_name.to_owned(),
+            config,
+            status,
+            generation,
+        ));
         Ok(())
     }
 }
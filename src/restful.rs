@@ -12,16 +12,50 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fmt::{Display, Formatter};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant},
+};
 
-use color_eyre::eyre::Error;
-use salvo::{catcher::Catcher, prelude::*};
-use serde::Serialize;
+use color_eyre::eyre::{eyre, Report, Result as EyreResult};
+use salvo::{
+    catcher::Catcher,
+    compression::{Compression, CompressionLevel},
+    conn::{
+        rustls::{Keycert, RustlsConfig},
+        Acceptor,
+    },
+    cors::{AllowHeaders, AllowMethods, AllowOrigin, Cors, CorsHandler},
+    extract::{Extractible, Metadata},
+    http::{HeaderValue, Method, ResBody},
+    oapi::{
+        Components, EndpointArgRegister, EndpointOutRegister, Object, Operation, Parameter,
+        ParameterIn, RefOr, Required, Response as OapiResponse, Schema, SchemaType, ToSchema,
+    },
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::signal;
-use tracing::info;
+use tokio::sync::oneshot;
+use tracing::{error, info, info_span, warn, Instrument};
 
-use crate::error::CALError;
+use crate::{
+    admin::Admin,
+    auth::{Auth, AuthConfig},
+    clock::{self, Deadline},
+    error::{CALError, CommonError, Error},
+    health::{HealthAggregator, HealthRegistry},
+    limiter::RateLimiter,
+    shutdown::{signal_received, CancellationToken, Phase, Shutdown},
+    version::BuildInfo,
+};
+
+pub use crate::limiter::RateLimitQuota;
 
 pub type HttpServerHandle = salvo::server::ServerHandle;
 
@@ -50,21 +84,53 @@ impl Writer for RESTfulError {
     }
 }
 
+/// Maps any error to the envelope: a [`CALError`], [`Error`] or [`CommonError`] anywhere in
+/// the chain picks the code, everything else is a 500. Server side failures are logged with
+/// their full chain.
 impl<E> From<E> for RESTfulError
 where
-    E: Into<Error>,
+    E: Into<Report>,
 {
     fn from(err: E) -> Self {
-        match err.into().downcast::<CALError>() {
-            Ok(err) => Self {
-                code: err.into(),
-                err: err.to_string(),
-            },
-            Err(err) => Self {
-                code: CALError::InternalServerError.into(),
-                err: err.to_string(),
-            },
+        let report = err.into();
+        let (code, message) = report
+            .downcast_ref::<CALError>()
+            .map(|e| (*e, e.to_string()))
+            .or_else(|| {
+                report
+                    .downcast_ref::<Error>()
+                    .map(|e| (e.code(), e.to_string()))
+            })
+            .or_else(|| {
+                report.chain().find_map(|cause| {
+                    cause
+                        .downcast_ref::<CALError>()
+                        .map(|e| (*e, e.to_string()))
+                        .or_else(|| {
+                            cause
+                                .downcast_ref::<Error>()
+                                .map(|e| (e.code(), e.to_string()))
+                        })
+                        .or_else(|| {
+                            cause
+                                .downcast_ref::<CommonError>()
+                                .map(|e| (e.code(), e.to_string()))
+                        })
+                })
+            })
+            .unwrap_or_else(|| (CALError::InternalServerError, report.to_string()));
+        let code: u16 = code.into();
+        if code >= 500 {
+            error!("{report:?}");
         }
+        Self { code, err: message }
+    }
+}
+
+#[async_trait]
+impl Writer for Error {
+    async fn write(mut self, req: &mut Request, depot: &mut Depot, res: &mut Response) {
+        RESTfulError::from(self).write(req, depot, res).await
     }
 }
 
@@ -90,42 +156,1502 @@ async fn handle_http_error(
     }
 }
 
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const REQUEST_ID_KEY: &str = "common_rs::request_id";
+
+/// Forwards the caller's `X-Request-Id` (or generates one), records it on the span
+/// wrapping the rest of the request and echoes it in the response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestId;
+
+#[async_trait]
+impl Handler for RequestId {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_owned)
+            .unwrap_or_else(|| ulid::Ulid::new().to_string());
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+        );
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        depot.insert(REQUEST_ID_KEY, request_id);
+        ctrl.call_next(req, depot, res).instrument(span).await;
+    }
+}
+
+/// The id assigned to the current request by [`RequestId`].
+pub fn request_id(depot: &Depot) -> Option<&str> {
+    depot.get::<String>(REQUEST_ID_KEY).ok().map(String::as_str)
+}
+
+#[cfg(feature = "metrics")]
+struct HttpMetrics {
+    requests: crate::metrics::IntCounterVec,
+    duration: crate::metrics::HistogramVec,
+    in_flight: crate::metrics::IntGaugeVec,
+}
+
+#[cfg(feature = "metrics")]
+static HTTP_METRICS: std::sync::LazyLock<HttpMetrics> = std::sync::LazyLock::new(|| {
+    use crate::metrics::{register, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
+
+    HttpMetrics {
+        requests: register(
+            IntCounterVec::new(
+                Opts::new("http_requests_total", "Total number of HTTP requests"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+        ),
+        duration: register(
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "HTTP request latencies in seconds",
+                ),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+        ),
+        in_flight: register(
+            IntGaugeVec::new(
+                Opts::new(
+                    "http_requests_in_flight",
+                    "Number of HTTP requests currently being served",
+                ),
+                &["method", "route"],
+            )
+            .unwrap(),
+        ),
+    }
+});
+
+/// Records count, latency and in-flight requests per route. Route labels are the path
+/// patterns of the routers passed through [`label_routes`], e.g. `/block/{height}`, so
+/// that cardinality stays bounded; requests reaching no labelled route count as
+/// `unmatched`.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metrics;
+
+#[cfg(feature = "metrics")]
+const ROUTE_KEY: &str = "common_rs::route";
+
+#[cfg(feature = "metrics")]
+#[async_trait]
+impl Handler for Metrics {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let method = req.method().to_string();
+        let start = std::time::Instant::now();
+        ctrl.call_next(req, depot, res).await;
+
+        let route = depot
+            .get::<Arc<str>>(ROUTE_KEY)
+            .map_or("unmatched", |route| route);
+        let status = res
+            .status_code
+            .unwrap_or(StatusCode::OK)
+            .as_u16()
+            .to_string();
+        HTTP_METRICS
+            .requests
+            .with_label_values(&[&method, route, &status])
+            .inc();
+        HTTP_METRICS
+            .duration
+            .with_label_values(&[&method, route, &status])
+            .observe(start.elapsed().as_secs_f64());
+    }
+}
+
+/// Labels every route of `router` with its path pattern for [`Metrics`], to be called
+/// once the router is complete and after the openapi doc is made from it, since the
+/// handlers it wraps are no longer found by their type afterwards.
+#[cfg(feature = "metrics")]
+pub fn label_routes(router: Router) -> Router {
+    fn label(mut router: Router, parent: &str) -> Router {
+        let mut route = parent.to_owned();
+        for filter in &router.filters {
+            if let Some(pattern) = format!("{filter:?}").strip_prefix("path:") {
+                for segment in pattern.split('/').filter(|s| !s.is_empty()) {
+                    route.push('/');
+                    route.push_str(&route_segment(segment));
+                }
+            }
+        }
+        if let Some(goal) = router.goal.take() {
+            let route = if route.is_empty() { "/" } else { &route };
+            router.goal = Some(Arc::new(RouteGoal {
+                route: route.into(),
+                goal,
+            }));
+        }
+        router.routers = std::mem::take(&mut router.routers)
+            .into_iter()
+            .map(|child| label(child, &route))
+            .collect();
+        router
+    }
+    label(router, "")
+}
+
+/// `<id>`, `<id:num>` or `<**rest>` as `{id}` or `{rest}`, literal segments as they are.
+#[cfg(feature = "metrics")]
+fn route_segment(segment: &str) -> std::borrow::Cow<'_, str> {
+    match segment
+        .strip_prefix('<')
+        .and_then(|param| param.strip_suffix('>'))
+    {
+        Some(param) => {
+            let name = param.split(':').next().unwrap_or_default();
+            format!("{{{}}}", name.trim_start_matches('*')).into()
+        }
+        None => segment.into(),
+    }
+}
+
+/// The goal of a labelled route, counting it in flight while it runs.
+#[cfg(feature = "metrics")]
+struct RouteGoal {
+    route: Arc<str>,
+    goal: Arc<dyn Handler>,
+}
+
+/// Decrements the in-flight gauge when dropped, also when the client goes away and the
+/// handler with it.
+#[cfg(feature = "metrics")]
+struct InFlight(crate::metrics::IntGauge);
+
+#[cfg(feature = "metrics")]
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[async_trait]
+impl Handler for RouteGoal {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        depot.insert(ROUTE_KEY, self.route.clone());
+        let in_flight = HTTP_METRICS
+            .in_flight
+            .with_label_values(&[req.method().as_str(), &self.route]);
+        in_flight.inc();
+        let _in_flight = InFlight(in_flight);
+        self.goal.handle(req, depot, res, ctrl).await;
+    }
+}
+
+#[cfg(feature = "metrics")]
 #[handler]
-async fn health() -> impl Writer {
-    ok_no_data()
+async fn metrics(res: &mut Response) -> Result<(), RESTfulError> {
+    res.render(crate::metrics::gather()?);
+    res.add_header(
+        salvo::http::header::CONTENT_TYPE,
+        crate::metrics::TEXT_FORMAT,
+        true,
+    )?;
+    Ok(())
 }
 
-pub async fn http_serve(service_name: &str, port: u16, router: Router) {
-    let router = router.push(Router::with_path("health").get(health));
+/// Prometheus exposition of the shared metrics registry at `/metrics`.
+#[cfg(feature = "metrics")]
+pub fn metrics_router() -> Router {
+    Router::with_path("metrics").get(metrics)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Probe {
+    Health,
+    Ready,
+    Live,
+}
+
+struct HealthHandler {
+    health: HealthAggregator,
+    probe: Probe,
+}
+
+#[async_trait]
+impl Handler for HealthHandler {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        if let Probe::Live = self.probe {
+            return ok_no_data().write(req, depot, res).await;
+        }
+        let report = self.health.report().await;
+        let (code, message) = if report.is_up() {
+            (200, "OK".to_string())
+        } else {
+            (
+                CALError::ServiceUnavailable.into(),
+                CALError::ServiceUnavailable.to_string(),
+            )
+        };
+        let response = RESTfulResponse {
+            code,
+            message,
+            data: matches!(self.probe, Probe::Health).then_some(report),
+        };
+        response.write(req, depot, res).await
+    }
+}
+
+/// `/health` reports every registered check, `/ready` only whether all of them pass,
+/// and `/live` answers as long as the process is serving requests.
+pub fn health_router(health: impl Into<HealthAggregator>) -> Router {
+    let health = health.into();
+    Router::new()
+        .push(Router::with_path("health").get(HealthHandler {
+            health: health.clone(),
+            probe: Probe::Health,
+        }))
+        .push(Router::with_path("ready").get(HealthHandler {
+            health,
+            probe: Probe::Ready,
+        }))
+        .push(Router::with_path("live").get(HealthHandler {
+            health: HealthRegistry::default().into(),
+            probe: Probe::Live,
+        }))
+}
+
+struct VersionHandler(BuildInfo);
+
+#[async_trait]
+impl Handler for VersionHandler {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        RESTfulResponse::ok(self.0).write(req, depot, res).await
+    }
+}
+
+/// `/version` answers the [`BuildInfo`] of the service, e.g. `common_rs::build_info!()`.
+pub fn version_router(info: BuildInfo) -> Router {
+    Router::with_path("version").get(VersionHandler(info))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub listen_addr: String,
+    pub port: u16,
+    /// seconds to wait for in-flight connections to drain on shutdown, 0 means no limit
+    pub shutdown_timeout: u64,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    /// serve https instead of http when set
+    pub tls: Option<TlsConfig>,
+    pub access_log: AccessLogConfig,
+    pub openapi: OpenApiConfig,
+    /// milliseconds a request may take before answering 504, 0 means no limit
+    pub request_timeout: u64,
+    /// request timeouts in milliseconds keyed by path prefix, overriding `request_timeout`
+    pub route_timeouts: HashMap<String, u64>,
+    /// requests served at once before answering 503, 0 means no limit
+    pub max_concurrency: usize,
+    pub compression: CompressionConfig,
+    /// require api keys or jwt on every non public route when set
+    pub auth: Option<AuthConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0".to_owned(),
+            port: 8080,
+            shutdown_timeout: 30,
+            rate_limit: Default::default(),
+            cors: Default::default(),
+            tls: None,
+            access_log: Default::default(),
+            openapi: Default::default(),
+            request_timeout: 0,
+            route_timeouts: Default::default(),
+            max_concurrency: 0,
+            compression: Default::default(),
+            auth: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub gzip: bool,
+    pub brotli: bool,
+    /// algorithm specific quality, none uses each algorithm's default
+    pub level: Option<u32>,
+    /// responses smaller than this many bytes are sent as is
+    pub min_size: usize,
+    /// content types eligible for compression, `type/*` matches a whole type
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gzip: true,
+            brotli: true,
+            level: None,
+            min_size: 1024,
+            content_types: vec!["application/json".to_owned(), "text/*".to_owned()],
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn handler(&self) -> Compression {
+        let level = self
+            .level
+            .map_or(CompressionLevel::Default, CompressionLevel::Precise);
+        let mut compression = Compression::new().disable_all();
+        if self.gzip {
+            compression = compression.enable_gzip(level);
+        }
+        if self.brotli {
+            compression = compression.enable_brotli(level);
+        }
+        compression.min_length(self.min_size).content_types(
+            &self
+                .content_types
+                .iter()
+                .filter_map(|t| t.parse().ok())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+const DEADLINE_KEY: &str = "common_rs::deadline";
+
+/// Replaces the response with the error `code`, whose status it shares.
+fn reject(res: &mut Response, code: CALError, message: &str) {
+    res.replace_body(ResBody::None);
+    res.status_code(StatusCode::from_u16(code.into()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+    res.render(Json(json!({
+        "code": u16::from(code),
+        "message": message,
+    })));
+}
+
+/// Answers 504 once a request outlives its timeout. Handlers run under the request's
+/// [`Deadline`], which bounds their own upstream calls and is also stored in the depot,
+/// see [`request_deadline`].
+pub struct Timeout {
+    default: Option<Duration>,
+    routes: HashMap<String, Duration>,
+}
+
+impl Timeout {
+    pub fn new(default: u64, routes: &HashMap<String, u64>) -> Self {
+        Self {
+            default: (default != 0).then(|| Duration::from_millis(default)),
+            routes: routes
+                .iter()
+                .map(|(prefix, ms)| (prefix.clone(), Duration::from_millis(*ms)))
+                .collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || !self.routes.is_empty()
+    }
+}
+
+#[async_trait]
+impl Handler for Timeout {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let Some(timeout) = longest_prefix(&self.routes, req.uri().path())
+            .map(|(_, timeout)| *timeout)
+            .or(self.default)
+        else {
+            return;
+        };
+        let deadline = Deadline::after(timeout);
+        depot.insert(DEADLINE_KEY, deadline.instant());
+        if clock::timeout_at(deadline, ctrl.call_next(req, depot, res))
+            .await
+            .is_err()
+        {
+            tracing::warn!("request timed out after {timeout:?}");
+            reject(res, CALError::GatewayTimeout, "Gateway Timeout");
+            ctrl.skip_rest();
+        }
+    }
+}
+
+/// The instant the current request times out at, if a [`Timeout`] applies to it.
+pub fn request_deadline(depot: &Depot) -> Option<Instant> {
+    depot.get::<Instant>(DEADLINE_KEY).ok().copied()
+}
+
+/// Rejects requests with 503 once `max` of them are in flight instead of queueing them.
+pub struct ConcurrencyLimit {
+    semaphore: Arc<tokio::sync::Semaphore>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(tokio::sync::Semaphore::new(max)),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for ConcurrencyLimit {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        match self.semaphore.clone().try_acquire_owned() {
+            Ok(_permit) => {
+                ctrl.call_next(req, depot, res).await;
+            }
+            Err(_) => {
+                reject(
+                    res,
+                    CALError::ServiceUnavailable,
+                    "Too Many Concurrent Requests",
+                );
+                ctrl.skip_rest();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenApiConfig {
+    pub enabled: bool,
+    pub version: String,
+    pub spec_path: String,
+    pub swagger_path: String,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version: "0.0.1".to_owned(),
+            spec_path: "/api-doc/openapi.json".to_owned(),
+            swagger_path: "swagger-ui".to_owned(),
+        }
+    }
+}
+
+/// Serves the OpenAPI document generated from `router`'s endpoints at `spec_path`
+/// and the swagger ui browsing it at `swagger_path`.
+pub fn openapi_router(title: &str, config: &OpenApiConfig, router: &Router) -> Router {
+    let doc = OpenApi::new(title, &config.version).merge_router(router);
+    Router::new()
+        .push(doc.into_router(&config.spec_path))
+        .push(SwaggerUi::new(config.spec_path.clone()).into_router(&config.swagger_path))
+}
+
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    /// fraction of requests logged, server errors are always logged
+    pub sample_rate: f64,
+    pub log_bodies: bool,
+    /// bodies longer than this many bytes are truncated
+    pub max_body_len: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: 1.0,
+            log_bodies: false,
+            max_body_len: 1024,
+        }
+    }
+}
+
+/// Emits one `access_log` target event per sampled request with method, path,
+/// status and latency, plus truncated bodies when enabled.
+pub struct AccessLog {
+    config: AccessLogConfig,
+    count: AtomicU64,
+}
+
+impl AccessLog {
+    pub const fn new(config: AccessLogConfig) -> Self {
+        Self {
+            config,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Spreads the sampled requests evenly: the n-th request is logged whenever
+    /// `n * sample_rate` crosses an integer.
+    fn sampled(&self) -> bool {
+        let n = self.count.fetch_add(1, Ordering::Relaxed) as f64;
+        let rate = self.config.sample_rate.clamp(0.0, 1.0);
+        ((n + 1.0) * rate).floor() > (n * rate).floor()
+    }
+
+    fn truncate(&self, bytes: &[u8]) -> String {
+        let len = bytes.len().min(self.config.max_body_len);
+        let mut body = String::from_utf8_lossy(&bytes[..len]).into_owned();
+        if len < bytes.len() {
+            body.push_str("...");
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl Handler for AccessLog {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let sampled = self.sampled();
+        let request_body = if sampled && self.config.log_bodies {
+            req.payload().await.ok().map(|b| self.truncate(b))
+        } else {
+            None
+        };
+
+        let start = Instant::now();
+        ctrl.call_next(req, depot, res).await;
+        let latency = start.elapsed();
+
+        // salvo only fills in the status once every hoop returned, an empty
+        // response without one means no route matched
+        let status = res.status_code.unwrap_or(if res.body.is_none() {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::OK
+        });
+        if !sampled && !status.is_server_error() {
+            return;
+        }
+        let response_body = match &res.body {
+            ResBody::Once(bytes) if self.config.log_bodies => Some(self.truncate(bytes)),
+            _ => None,
+        };
+        info!(
+            target: ACCESS_LOG_TARGET,
+            method = %req.method(),
+            path = %req.uri().path(),
+            status = status.as_u16(),
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            request_body,
+            response_body,
+        );
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA bundle used to verify client certificates, none disables client auth
+    pub client_ca_path: Option<String>,
+    /// reject clients without a certificate signed by `client_ca_path`
+    pub client_auth_required: bool,
+}
+
+impl TlsConfig {
+    pub fn rustls_config(&self) -> EyreResult<RustlsConfig> {
+        let keycert = Keycert::new()
+            .cert_from_path(&self.cert_path)
+            .map_err(|e| eyre!("load tls cert `{}` failed: {e}", self.cert_path))?
+            .key_from_path(&self.key_path)
+            .map_err(|e| eyre!("load tls key `{}` failed: {e}", self.key_path))?;
+        let config = RustlsConfig::new(keycert);
+        match &self.client_ca_path {
+            Some(ca) if self.client_auth_required => config.client_auth_required_path(ca),
+            Some(ca) => config.client_auth_optional_path(ca),
+            None => Ok(config),
+        }
+        .map_err(|e| eyre!("load tls client ca failed: {e}"))
+    }
+}
+
+/// CORS is disabled while `allow_origins` is empty, `"*"` allows any origin, method or header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allow_origins: Vec<String>,
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// seconds browsers may cache the preflight response
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allow_origins: vec![],
+            allow_methods: ["GET", "POST", "PUT", "DELETE", "OPTIONS"]
+                .map(str::to_owned)
+                .to_vec(),
+            allow_headers: vec!["*".to_owned()],
+            expose_headers: vec![REQUEST_ID_HEADER.to_owned()],
+            allow_credentials: false,
+            max_age: 3600,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub const fn is_enabled(&self) -> bool {
+        !self.allow_origins.is_empty()
+    }
+
+    fn any(list: &[String]) -> bool {
+        list.iter().any(|v| v == "*")
+    }
+
+    /// Wildcards are mirrored from the request when credentials are allowed,
+    /// since browsers reject `*` together with credentials.
+    pub fn handler(&self) -> CorsHandler {
+        let cors = Cors::new()
+            .allow_credentials(self.allow_credentials)
+            .expose_headers(&self.expose_headers)
+            .max_age(self.max_age);
+        let cors = match (Self::any(&self.allow_origins), self.allow_credentials) {
+            (true, true) => cors.allow_origin(AllowOrigin::mirror_request()),
+            (true, false) => cors.allow_origin(AllowOrigin::any()),
+            _ => cors.allow_origin(&self.allow_origins),
+        };
+        let cors = match (Self::any(&self.allow_methods), self.allow_credentials) {
+            (true, true) => cors.allow_methods(AllowMethods::mirror_request()),
+            (true, false) => cors.allow_methods(AllowMethods::any()),
+            _ => cors.allow_methods(
+                self.allow_methods
+                    .iter()
+                    .filter_map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).ok())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let cors = match (Self::any(&self.allow_headers), self.allow_credentials) {
+            (true, true) => cors.allow_headers(AllowHeaders::mirror_request()),
+            (true, false) => cors.allow_headers(AllowHeaders::any()),
+            _ => cors.allow_headers(&self.allow_headers),
+        };
+        cors.into_handler()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// quota for routes not listed in `routes`, none means unlimited
+    pub quota: Option<RateLimitQuota>,
+    /// quotas keyed by path prefix matched on segment boundaries, `/block` covering
+    /// `/block/1` but not `/blocks`, the longest matching prefix wins
+    pub routes: HashMap<String, RateLimitQuota>,
+    /// keep a bucket per client ip instead of one per route
+    pub per_ip: bool,
+    /// take the client ip from `X-Forwarded-For` when running behind a proxy
+    pub trust_forwarded: bool,
+    /// proxies in front of the service that append to `X-Forwarded-For`, the client ip
+    /// being the entry this many from the right; the entries left of it are set by the
+    /// client and not trusted
+    pub forwarded_hops: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            quota: None,
+            routes: Default::default(),
+            per_ip: false,
+            trust_forwarded: false,
+            forwarded_hops: 1,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.quota.is_some() || !self.routes.is_empty()
+    }
+
+    fn quota_for(&self, path: &str) -> Option<(&str, RateLimitQuota)> {
+        longest_prefix(&self.routes, path)
+            .map(|(prefix, quota)| (prefix, *quota))
+            .or_else(|| self.quota.map(|quota| ("*", quota)))
+    }
+}
+
+/// Whether `prefix` covers `path` on segment boundaries: `/block` covers `/block/1`
+/// but not `/blocks`.
+pub(crate) fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+fn longest_prefix<'a, V>(routes: &'a HashMap<String, V>, path: &str) -> Option<(&'a str, &'a V)> {
+    routes
+        .iter()
+        .filter(|(prefix, _)| path_has_prefix(path, prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(prefix, v)| (prefix.as_str(), v))
+}
+
+/// Token bucket rate limiter answering `429 Too Many Requests` once a bucket is drained.
+/// Buckets live in memory unless a [`crate::redis::Redis`] backend is given, in which case
+/// the limit is shared across every instance.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Arc<RateLimitConfig>,
+    buckets: RateLimiter,
+    #[cfg(feature = "redis")]
+    redis: Option<crate::redis::Redis>,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig) -> Self {
+        // every bucket is taken from with the quota of its route
+        let buckets = RateLimiter::new(config.quota.unwrap_or(RateLimitQuota {
+            rate: 0.0,
+            burst: 0,
+        }));
+        Self {
+            config: Arc::new(config),
+            buckets,
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    pub fn redis(mut self, redis: crate::redis::Redis) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    fn client_ip(&self, req: &Request) -> String {
+        self.config
+            .trust_forwarded
+            .then(|| {
+                let hops = self.config.forwarded_hops.max(1);
+                req.headers()
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .flat_map(|v| v.split(','))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    // fewer entries than proxies, the request did not come through them
+                    .nth(hops - 1)
+                    .map(|v| v.trim().to_owned())
+            })
+            .flatten()
+            .or_else(|| {
+                req.remote_addr()
+                    .clone()
+                    .into_std()
+                    .map(|addr| addr.ip().to_string())
+            })
+            .unwrap_or_default()
+    }
+
+    fn take_local(&self, key: String, quota: RateLimitQuota) -> bool {
+        self.buckets.try_acquire_with(&key, quota, 1.0)
+    }
+
+    async fn take(&self, key: String, quota: RateLimitQuota) -> bool {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return match redis
+                .rate_limit(&format!("rate_limit/{key}"), quota.rate, quota.burst)
+                .await
+            {
+                Ok(allowed) => allowed,
+                Err(e) => {
+                    tracing::warn!("rate limit fall back to local bucket: {e}");
+                    self.take_local(key, quota)
+                }
+            };
+        }
+        self.take_local(key, quota)
+    }
+}
+
+#[async_trait]
+impl Handler for RateLimit {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let Some((route, quota)) = self.config.quota_for(req.uri().path()) else {
+            return;
+        };
+        let key = if self.config.per_ip {
+            format!("{route}/{}", self.client_ip(req))
+        } else {
+            route.to_owned()
+        };
+        if !self.take(key, quota).await {
+            RESTfulError {
+                code: CALError::TooManyRequests.into(),
+                err: CALError::TooManyRequests.to_string(),
+            }
+            .write(req, depot, res)
+            .await;
+            ctrl.skip_rest();
+        }
+    }
+}
+
+pub struct HttpServer {
+    config: HttpConfig,
+    service_name: String,
+    router: Router,
+    health: HealthAggregator,
+    auth: Option<Auth>,
+    admin: Option<Admin>,
+    version: Option<BuildInfo>,
+    shutdown: Option<Shutdown>,
+    cancel: Option<CancellationToken>,
+    #[cfg(feature = "redis")]
+    redis: Option<crate::redis::Redis>,
+}
+
+impl HttpServer {
+    pub fn new(config: HttpConfig) -> Self {
+        Self {
+            config,
+            service_name: "http".to_owned(),
+            router: Router::new(),
+            health: HealthRegistry::default().into(),
+            auth: None,
+            admin: None,
+            version: None,
+            shutdown: None,
+            cancel: None,
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    pub fn service_name(mut self, service_name: &str) -> Self {
+        service_name.clone_into(&mut self.service_name);
+        self
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Serve the health endpoints from a [`HealthRegistry`], checked on every probe,
+    /// or from a [`HealthAggregator`] caching its periodic results.
+    pub fn health(mut self, health: impl Into<HealthAggregator>) -> Self {
+        self.health = health.into();
+        self
+    }
+
+    /// Authenticate with a prepared [`Auth`], e.g. one reloading jwt keys from etcd,
+    /// instead of building one from `HttpConfig::auth`.
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
 
-    let doc = OpenApi::new(format!("{} api", service_name), "0.0.1").merge_router(&router);
+    /// Serve the admin router, it is left out when no admin token is configured.
+    pub fn admin(mut self, admin: Admin) -> Self {
+        self.admin = Some(admin);
+        self
+    }
 
-    let router = router
-        .unshift(doc.into_router("/api-doc/openapi.json"))
-        .unshift(SwaggerUi::new("/api-doc/openapi.json").into_router("swagger-ui"));
+    /// Serve `/version` with `info`, e.g. `common_rs::build_info!()`, and log it when the
+    /// server starts.
+    pub const fn version(mut self, info: BuildInfo) -> Self {
+        self.version = Some(info);
+        self
+    }
 
-    let service = Service::new(router).catcher(Catcher::default().hoop(handle_http_error));
+    /// Drain the server in the [`Phase::Drain`] phase of `shutdown` instead of on its own
+    /// signal handler. `/ready` answers 503 as soon as the shutdown is triggered.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
 
-    let acceptor = TcpListener::new(format!("0.0.0.0:{}", port)).bind().await;
+    /// Drain the server once `cancel` is cancelled instead of on its own signal handler,
+    /// e.g. with the token of [`crate::shutdown::wait_for_shutdown`].
+    pub fn cancel_on(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Share the rate limit buckets across instances through redis.
+    #[cfg(feature = "redis")]
+    pub fn redis(mut self, redis: crate::redis::Redis) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    pub async fn serve(self) -> EyreResult<()> {
+        let mut health = self.health;
+        if let Some(shutdown) = &self.shutdown {
+            health = health.live(shutdown.clone());
+        }
+        let mut router = self.router.push(health_router(health));
+        if let Some(info) = self.version {
+            info!("{} starting: {info}", self.service_name);
+            router = router.push(version_router(info));
+        }
+        let router = match self.admin {
+            Some(admin) if admin.is_enabled() => router.push(admin.router()),
+            Some(_) => {
+                warn!("admin token not configured, admin router disabled");
+                router
+            }
+            None => router,
+        };
+        #[cfg(feature = "metrics")]
+        let router = router.hoop(Metrics).push(metrics_router());
+
+        let router = if self.config.openapi.enabled {
+            let doc = openapi_router(
+                &format!("{} api", self.service_name),
+                &self.config.openapi,
+                &router,
+            );
+            router.unshift(doc)
+        } else {
+            router
+        };
 
-    let server = Server::new(acceptor);
+        #[cfg(feature = "metrics")]
+        let router = label_routes(router);
+
+        let mut service = Service::new(router).hoop(RequestId);
+        if self.config.compression.enabled {
+            service = service.hoop(self.config.compression.handler());
+        }
+        if self.config.access_log.enabled {
+            service = service.hoop(AccessLog::new(self.config.access_log));
+        }
+        if self.config.cors.is_enabled() {
+            service = service.hoop(self.config.cors.handler());
+        }
+        if self.config.rate_limit.is_enabled() {
+            let rate_limit = RateLimit::new(self.config.rate_limit.clone());
+            #[cfg(feature = "redis")]
+            let rate_limit = match self.redis {
+                Some(redis) => rate_limit.redis(redis),
+                None => rate_limit,
+            };
+            service = service.hoop(rate_limit);
+        }
+        if self.config.max_concurrency != 0 {
+            service = service.hoop(ConcurrencyLimit::new(self.config.max_concurrency));
+        }
+        let auth = match (self.auth, &self.config.auth) {
+            (Some(auth), _) => Some(auth),
+            (None, Some(config)) => Some(Auth::new(config.clone())?),
+            (None, None) => None,
+        };
+        if let Some(auth) = auth {
+            service = service.hoop(auth);
+        }
+        let timeout = Timeout::new(self.config.request_timeout, &self.config.route_timeouts);
+        if timeout.is_enabled() {
+            service = service.hoop(timeout);
+        }
+        let service = service.catcher(Catcher::default().hoop(handle_http_error));
+
+        let addr = format!("{}:{}", self.config.listen_addr, self.config.port);
+        let shutdown_timeout = (self.config.shutdown_timeout != 0)
+            .then(|| Duration::from_secs(self.config.shutdown_timeout));
+        let listener = TcpListener::new(addr.clone());
+        if let Some(tls) = &self.config.tls {
+            let acceptor = listener
+                .rustls(tls.rustls_config()?)
+                .try_bind()
+                .await
+                .map_err(|e| eyre!("bind {addr} failed: {e}"))?;
+            info!("{} listening on https://{}", self.service_name, addr);
+            run(
+                Server::new(acceptor),
+                service,
+                shutdown_timeout,
+                self.shutdown,
+                self.cancel,
+            )
+            .await;
+        } else {
+            let acceptor = listener
+                .try_bind()
+                .await
+                .map_err(|e| eyre!("bind {addr} failed: {e}"))?;
+            info!("{} listening on http://{}", self.service_name, addr);
+            run(
+                Server::new(acceptor),
+                service,
+                shutdown_timeout,
+                self.shutdown,
+                self.cancel,
+            )
+            .await;
+        }
+        info!("{} stopped", self.service_name);
+        Ok(())
+    }
+}
+
+async fn run<A: Acceptor + Send>(
+    server: Server<A>,
+    service: Service,
+    timeout: Option<Duration>,
+    shutdown: Option<Shutdown>,
+    cancel: Option<CancellationToken>,
+) {
     let handle = server.handle();
-    tokio::spawn(shutdown_signal(handle));
+    let Some(shutdown) = shutdown else {
+        tokio::spawn(shutdown_signal(handle, timeout, cancel));
+        server.serve(service).await;
+        return;
+    };
+    let (stopped, wait_stopped) = oneshot::channel();
+    shutdown.on(Phase::Drain, "http_server", move || async move {
+        handle.stop_graceful(timeout);
+        let _ = wait_stopped.await;
+        Ok(())
+    });
     server.serve(service).await;
+    let _ = stopped.send(());
+}
+
+pub async fn http_serve(service_name: &str, port: u16, router: Router) {
+    HttpServer::new(HttpConfig {
+        port,
+        ..Default::default()
+    })
+    .service_name(service_name)
+    .router(router)
+    .serve()
+    .await
+    .expect("http serve failed")
+}
+
+fn error_code_schema() -> Object {
+    Object::with_type(SchemaType::Integer)
+        .description(
+            CALError::ALL
+                .iter()
+                .map(|code| format!("{}: {code}", *code as u16))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+        .enum_values(CALError::ALL.iter().map(|code| *code as u16))
+}
+
+impl ToSchema for CALError {
+    fn to_schema(_components: &mut Components) -> RefOr<Schema> {
+        error_code_schema().into()
+    }
+}
+
+impl ToSchema for RESTfulError {
+    fn to_schema(_components: &mut Components) -> RefOr<Schema> {
+        Object::new()
+            .property("code", error_code_schema())
+            .required("code")
+            .property("message", Object::with_type(SchemaType::String))
+            .required("message")
+            .into()
+    }
+}
+
+impl EndpointOutRegister for RESTfulError {
+    fn register(components: &mut Components, operation: &mut Operation) {
+        operation.responses.insert(
+            "default",
+            OapiResponse::new("error envelope, `code` is one of the CALError codes")
+                .add_content("application/json", Self::to_schema(components)),
+        );
+    }
+}
+
+impl<T: ToSchema> ToSchema for RESTfulResponse<T> {
+    fn to_schema(components: &mut Components) -> RefOr<Schema> {
+        Object::new()
+            .property(
+                "code",
+                Object::with_type(SchemaType::Integer).enum_values([200]),
+            )
+            .required("code")
+            .property("message", Object::with_type(SchemaType::String))
+            .required("message")
+            .property("data", T::to_schema(components))
+            .into()
+    }
+}
+
+impl<T: ToSchema> EndpointOutRegister for RESTfulResponse<T> {
+    fn register(components: &mut Components, operation: &mut Operation) {
+        operation.responses.insert(
+            "200",
+            OapiResponse::new("OK").add_content("application/json", Self::to_schema(components)),
+        );
+    }
+}
+
+/// The largest `page_size` a list endpoint accepts.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+fn bad_request(message: impl Into<String>) -> RESTfulError {
+    RESTfulError {
+        code: CALError::BadRequest.into(),
+        err: message.into(),
+    }
+}
+
+fn query_parameter(name: &str, description: &str, schema: RefOr<Schema>) -> Parameter {
+    Parameter::new(name)
+        .parameter_in(ParameterIn::Query)
+        .required(Required::False)
+        .description(description)
+        .schema(schema)
+}
+
+/// Query parameters shared by every list endpoint, pages count from 1.
+///
+/// Taken as a handler argument it answers 400 when `page` is 0 or `page_size` is
+/// outside `1..=MAX_PAGE_SIZE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Pagination {
+    pub page: u64,
+    pub page_size: u64,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            page: 1,
+            page_size: 20,
+        }
+    }
+}
+
+impl Pagination {
+    pub fn validate(self) -> Result<Self, RESTfulError> {
+        if self.page == 0 {
+            return Err(bad_request("page must start from 1"));
+        }
+        if !(1..=MAX_PAGE_SIZE).contains(&self.page_size) {
+            return Err(bad_request(format!(
+                "page_size must be between 1 and {MAX_PAGE_SIZE}"
+            )));
+        }
+        Ok(self)
+    }
+
+    /// Rows to skip before this page.
+    pub const fn offset(&self) -> u64 {
+        self.page.saturating_sub(1).saturating_mul(self.page_size)
+    }
+
+    pub const fn limit(&self) -> u64 {
+        self.page_size
+    }
+
+    /// The slice of an in-memory `items` covered by this page.
+    pub fn slice<'a, T>(&self, items: &'a [T]) -> &'a [T] {
+        let start = usize::try_from(self.offset())
+            .unwrap_or(usize::MAX)
+            .min(items.len());
+        let end = start
+            .saturating_add(self.page_size as usize)
+            .min(items.len());
+        &items[start..end]
+    }
+}
+
+impl<'ex> Extractible<'ex> for Pagination {
+    fn metadata() -> &'ex Metadata {
+        static METADATA: LazyLock<Metadata> = LazyLock::new(|| Metadata::new("Pagination"));
+        &METADATA
+    }
+
+    async fn extract(
+        req: &'ex mut Request,
+    ) -> Result<Self, impl Writer + Send + std::fmt::Debug + 'static> {
+        req.parse_queries::<Self>()
+            .map_err(|e| bad_request(format!("invalid pagination: {e}")))
+            .and_then(Self::validate)
+    }
+}
+
+impl EndpointArgRegister for Pagination {
+    fn register(_components: &mut Components, operation: &mut Operation, _arg: &str) {
+        let page = query_parameter(
+            "page",
+            "page number starting from 1, defaults to 1",
+            Object::with_type(SchemaType::Integer).minimum(1.0).into(),
+        );
+        let page_size = query_parameter(
+            "page_size",
+            &format!("items per page, defaults to 20, at most {MAX_PAGE_SIZE}"),
+            Object::with_type(SchemaType::Integer)
+                .minimum(1.0)
+                .maximum(MAX_PAGE_SIZE as f64)
+                .into(),
+        );
+        operation.parameters.insert(page);
+        operation.parameters.insert(page_size);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// `?sort=<field>&order=asc|desc` query parameters of a list endpoint.
+///
+/// `sort` may only hold letters, digits, `_` and `.`; check it against the fields the
+/// endpoint can order by with [`SortParams::field`] before using it in a query.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SortParams {
+    pub sort: Option<String>,
+    pub order: SortOrder,
+}
+
+impl SortParams {
+    const MAX_FIELD_LEN: usize = 64;
+
+    pub fn validate(self) -> Result<Self, RESTfulError> {
+        if let Some(sort) = &self.sort {
+            if sort.is_empty()
+                || sort.len() > Self::MAX_FIELD_LEN
+                || !sort
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+            {
+                return Err(bad_request(format!("invalid sort field `{sort}`")));
+            }
+        }
+        Ok(self)
+    }
+
+    /// The requested sort field picked from `allowed`, `None` when unsorted.
+    pub fn field<'a>(&self, allowed: &[&'a str]) -> Result<Option<&'a str>, RESTfulError> {
+        match &self.sort {
+            None => Ok(None),
+            Some(sort) => allowed
+                .iter()
+                .find(|field| **field == sort)
+                .map(|field| Some(*field))
+                .ok_or_else(|| {
+                    bad_request(format!(
+                        "cannot sort by `{sort}`, expected one of: {}",
+                        allowed.join(", ")
+                    ))
+                }),
+        }
+    }
+
+    pub fn is_desc(&self) -> bool {
+        self.order == SortOrder::Desc
+    }
+}
+
+impl<'ex> Extractible<'ex> for SortParams {
+    fn metadata() -> &'ex Metadata {
+        static METADATA: LazyLock<Metadata> = LazyLock::new(|| Metadata::new("SortParams"));
+        &METADATA
+    }
+
+    async fn extract(
+        req: &'ex mut Request,
+    ) -> Result<Self, impl Writer + Send + std::fmt::Debug + 'static> {
+        req.parse_queries::<Self>()
+            .map_err(|e| bad_request(format!("invalid sort: {e}")))
+            .and_then(Self::validate)
+    }
+}
+
+impl EndpointArgRegister for SortParams {
+    fn register(components: &mut Components, operation: &mut Operation, _arg: &str) {
+        let sort = query_parameter(
+            "sort",
+            "field to sort by",
+            Object::with_type(SchemaType::String).into(),
+        );
+        let order = query_parameter(
+            "order",
+            "sort direction, defaults to asc",
+            SortOrder::to_schema(components),
+        );
+        operation.parameters.insert(sort);
+        operation.parameters.insert(order);
+    }
 }
 
-#[derive(Debug, Serialize)]
-pub struct RESTfulResponse<T: Serialize> {
-    code: u16,
-    message: String,
+/// The `data` of a list response: one page of items plus what a client needs to page on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: u64,
+    pub page_size: u64,
+    pub total: u64,
+}
+
+impl<T> Page<T> {
+    pub const fn new(items: Vec<T>, pagination: Pagination, total: u64) -> Self {
+        Self {
+            items,
+            page: pagination.page,
+            page_size: pagination.page_size,
+            total,
+        }
+    }
+
+    pub fn total_pages(&self) -> u64 {
+        self.total.div_ceil(self.page_size.max(1))
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.page < self.total_pages()
+    }
+}
+
+impl<T: ToSchema> ToSchema for Page<T> {
+    fn to_schema(components: &mut Components) -> RefOr<Schema> {
+        Object::new()
+            .property("items", salvo::oapi::Array::new(T::to_schema(components)))
+            .required("items")
+            .property("page", Object::with_type(SchemaType::Integer))
+            .required("page")
+            .property("page_size", Object::with_type(SchemaType::Integer))
+            .required("page_size")
+            .property("total", Object::with_type(SchemaType::Integer))
+            .required("total")
+            .into()
+    }
+}
+
+/// The envelope every endpoint answers with: `{"code": 200, "message": "OK", "data": ...}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RESTfulResponse<T> {
+    pub code: u16,
+    pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<T>,
+    pub data: Option<T>,
 }
 
-unsafe impl<T: Serialize> Send for RESTfulResponse<T> {}
+impl<T> RESTfulResponse<T> {
+    pub fn ok(data: T) -> Self {
+        Self {
+            code: 200,
+            message: "OK".to_string(),
+            data: Some(data),
+        }
+    }
+
+    pub fn error(code: CALError, message: &str) -> Self {
+        Self {
+            code: code.into(),
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+
+    pub const fn is_ok(&self) -> bool {
+        self.code == 200
+    }
+
+    /// The error code carried by a failed response, if it is one of ours.
+    pub fn error_code(&self) -> Option<CALError> {
+        CALError::try_from(self.code).ok()
+    }
+
+    /// Unwraps the envelope received from another service.
+    pub fn into_result(self) -> Result<Option<T>, RESTfulError> {
+        if self.is_ok() {
+            Ok(self.data)
+        } else {
+            Err(RESTfulError {
+                code: self.code,
+                err: self.message,
+            })
+        }
+    }
+}
 
 #[async_trait]
-impl<T: Serialize> Writer for RESTfulResponse<T> {
+impl<T: Serialize + Send> Writer for RESTfulResponse<T> {
     async fn write(mut self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
         res.status_code(
             StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
@@ -145,12 +1671,17 @@ impl<T: Serialize> Writer for RESTfulResponse<T> {
     }
 }
 
-pub fn ok<T: Serialize>(data: T) -> Result<impl Writer, RESTfulError> {
-    Ok(RESTfulResponse {
-        code: 200,
-        message: "OK".to_string(),
-        data: Some(data),
-    })
+pub fn ok<T: Serialize + Send>(data: T) -> Result<impl Writer, RESTfulError> {
+    Ok(RESTfulResponse::ok(data))
+}
+
+/// Answers one page of a list in the standard envelope.
+pub fn ok_page<T: Serialize + Send>(
+    items: Vec<T>,
+    pagination: Pagination,
+    total: u64,
+) -> Result<impl Writer, RESTfulError> {
+    ok(Page::new(items, pagination, total))
 }
 
 pub fn ok_no_data() -> Result<impl Writer, RESTfulError> {
@@ -191,27 +1722,160 @@ where
     })
 }
 
-async fn shutdown_signal(handle: HttpServerHandle) {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+async fn shutdown_signal(
+    handle: HttpServerHandle,
+    timeout: Option<Duration>,
+    cancel: Option<CancellationToken>,
+) {
+    match cancel {
+        Some(cancel) => cancel.cancelled().await,
+        None => signal_received().await,
+    }
+    handle.stop_graceful(timeout);
+}
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_on_segment_boundaries() {
+        assert!(path_has_prefix("/block", "/block"));
+        assert!(path_has_prefix("/block/1", "/block"));
+        assert!(path_has_prefix("/block/1", "/block/"));
+        assert!(path_has_prefix("/anything", "/"));
+        assert!(!path_has_prefix("/blocks", "/block"));
+        assert!(!path_has_prefix("/bloc", "/block"));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let quota = |burst| RateLimitQuota { rate: 1.0, burst };
+        let config = RateLimitConfig {
+            quota: Some(quota(1)),
+            routes: HashMap::from([
+                ("/api".to_owned(), quota(2)),
+                ("/api/block".to_owned(), quota(3)),
+            ]),
+            ..Default::default()
+        };
+        let burst = |path| {
+            config
+                .quota_for(path)
+                .map(|(prefix, quota)| (prefix, quota.burst))
+        };
+        assert_eq!(burst("/api/block/1"), Some(("/api/block", 3)));
+        assert_eq!(burst("/api/blocks"), Some(("/api", 2)));
+        assert_eq!(burst("/api"), Some(("/api", 2)));
+        assert_eq!(burst("/apis"), Some(("*", 1)));
+        let unlimited = RateLimitConfig::default();
+        assert!(unlimited.quota_for("/api").is_none());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn routes_labelled_by_pattern() {
+        use salvo::test::{ResponseExt, TestClient};
+
+        #[handler]
+        async fn matched(depot: &mut Depot) -> String {
+            depot
+                .get::<Arc<str>>(ROUTE_KEY)
+                .map(|route| route.to_string())
+                .unwrap_or_default()
+        }
+
+        #[handler]
+        async fn slow() {
+            std::future::pending::<()>().await;
+        }
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+        let router = Router::new()
+            .push(Router::with_path("block/<height:num>").get(matched))
+            .push(Router::with_path("user").push(Router::with_path("<name>").get(matched)))
+            .push(Router::with_path("files/<**rest>").get(matched))
+            .push(Router::with_path("slow").get(slow));
+        let service = Service::new(label_routes(router).hoop(Metrics));
+        for (path, route) in [
+            ("/block/7", "/block/{height}"),
+            // a parameter equal to a literal segment
+            ("/user/user", "/user/{name}"),
+            ("/files/a/b/c", "/files/{rest}"),
+        ] {
+            let body = TestClient::get(format!("http://127.0.0.1{path}"))
+                .send(&service)
+                .await
+                .take_string()
+                .await
+                .unwrap();
+            assert_eq!(body, route);
+        }
+
+        // the client going away drops the handler, the request is no longer in flight
+        let request = TestClient::get("http://127.0.0.1/slow").send(&service);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), request)
+                .await
+                .is_err()
+        );
+        let in_flight = HTTP_METRICS.in_flight.with_label_values(&["GET", "/slow"]);
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn spoofed_forwarded_for_shares_the_bucket() {
+        use salvo::test::TestClient;
+
+        #[handler]
+        async fn pong() -> &'static str {
+            "pong"
+        }
+
+        let limit = RateLimit::new(RateLimitConfig {
+            quota: Some(RateLimitQuota {
+                rate: 0.0,
+                burst: 1,
+            }),
+            per_ip: true,
+            trust_forwarded: true,
+            ..Default::default()
+        });
+        let service = Service::new(Router::new().hoop(limit).get(pong));
+        let status = |forwarded: &'static str| {
+            let service = &service;
+            async move {
+                TestClient::get("http://127.0.0.1/")
+                    .add_header("x-forwarded-for", forwarded, true)
+                    .send(service)
+                    .await
+                    .status_code
+            }
+        };
+        assert_eq!(status("1.1.1.1, 10.0.0.1").await, Some(StatusCode::OK));
+        // a new leftmost entry on every request is not a new client
+        assert_eq!(
+            status("2.2.2.2, 10.0.0.1").await,
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(status("10.0.0.2").await, Some(StatusCode::OK));
+    }
 
-    tokio::select! {
-        _ = ctrl_c => info!("ctrl_c signal received"),
-        _ = terminate => info!("terminate signal received"),
+    #[test]
+    fn client_ip_behind_two_proxies() {
+        let limit = RateLimit::new(RateLimitConfig {
+            trust_forwarded: true,
+            forwarded_hops: 2,
+            ..Default::default()
+        });
+        let mut req = Request::new();
+        req.headers_mut().insert(
+            "x-forwarded-for",
+            "6.6.6.6, 1.1.1.1, 10.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(limit.client_ip(&req), "1.1.1.1");
+        req.headers_mut()
+            .insert("x-forwarded-for", "1.1.1.1".parse().unwrap());
+        // too few entries, the remote address is taken instead
+        assert_eq!(limit.client_ip(&req), "");
     }
-    handle.stop_graceful(None);
 }
@@ -0,0 +1,265 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::clock::Deadline;
+
+/// Exponential backoff between attempts of a failing operation.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// retries after the first attempt
+    pub max_retries: u32,
+    /// milliseconds before the first retry
+    pub initial_backoff: u64,
+    /// milliseconds the backoff grows to at most
+    pub max_backoff: u64,
+    /// factor the backoff grows by on every retry
+    pub multiplier: f64,
+    /// milliseconds since the first attempt after which no retry is started, 0 for no limit
+    pub max_elapsed: u64,
+    /// wait somewhere between half and all of the backoff, so clients failing together
+    /// do not retry together
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: 100,
+            max_backoff: 2000,
+            multiplier: 2.0,
+            max_elapsed: 0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy making a single attempt.
+    pub fn never() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// The wait before retry number `retry`, counted from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = (self.initial_backoff as f64 * self.multiplier.max(1.0).powi(retry as i32))
+            .min(self.max_backoff as f64) as u64;
+        let backoff = Duration::from_millis(backoff);
+        if self.jitter {
+            jitter(backoff)
+        } else {
+            backoff
+        }
+    }
+}
+
+/// Somewhere between half and all of `backoff`.
+pub fn jitter(backoff: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or_default();
+    let millis = backoff.as_millis() as u64;
+    let half = millis / 2;
+    Duration::from_millis(half + nanos % (millis - half + 1))
+}
+
+/// Runs `op` until it succeeds or `policy` gives up, retrying every error.
+/// `retry(&policy, || async { etcd.get("key").await }).await`
+pub async fn retry<T, E, F, Fut>(policy: &RetryPolicy, op: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_if(policy, op, |_| true).await
+}
+
+/// Like [`retry`], returning at once the errors `retryable` classifies as permanent.
+/// No retry is started that would outlast the [`Deadline::current`] of the caller.
+pub async fn retry_if<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    mut op: F,
+    retryable: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let max_elapsed = (policy.max_elapsed > 0).then(|| Duration::from_millis(policy.max_elapsed));
+    let mut retries = 0;
+    loop {
+        let e = match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if retries >= policy.max_retries || !retryable(&e) {
+            return Err(e);
+        }
+        let backoff = policy.backoff(retries);
+        if max_elapsed.is_some_and(|max_elapsed| start.elapsed() + backoff > max_elapsed)
+            || Deadline::current().is_some_and(|deadline| deadline.remaining() <= backoff)
+        {
+            return Err(e);
+        }
+        retries += 1;
+        debug!(
+            "retry {retries}/{} in {backoff:?} after: {e}",
+            policy.max_retries
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn policy(max_retries: u32, initial_backoff: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff,
+            max_backoff: 1000,
+            multiplier: 2.0,
+            max_elapsed: 0,
+            jitter: false,
+        }
+    }
+
+    /// An op failing its first `failures` attempts, and the attempts it saw.
+    fn failing(failures: u32) -> (Cell<u32>, impl Fn(&Cell<u32>) -> Result<u32, &'static str>) {
+        let op = move |attempts: &Cell<u32>| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() > failures {
+                Ok(attempts.get())
+            } else {
+                Err("failed")
+            }
+        };
+        (Cell::new(0), op)
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let policy = RetryPolicy {
+            initial_backoff: 100,
+            max_backoff: 500,
+            ..policy(0, 0)
+        };
+        let backoffs: Vec<_> = (0..5)
+            .map(|retry| policy.backoff(retry).as_millis())
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 500, 500]);
+        // a multiplier below 1 never shrinks the backoff
+        let policy = RetryPolicy {
+            multiplier: 0.5,
+            ..policy
+        };
+        assert_eq!(policy.backoff(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_within_half_and_all() {
+        for millis in [0, 1, 7, 100, 2000] {
+            for _ in 0..100 {
+                let jittered = jitter(Duration::from_millis(millis)).as_millis() as u64;
+                assert!(
+                    (millis / 2..=millis).contains(&jittered),
+                    "{millis}: {jittered}"
+                );
+            }
+        }
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy(0, 100)
+        };
+        let backoff = policy.backoff(1);
+        assert!(backoff >= Duration::from_millis(100) && backoff <= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let (attempts, op) = failing(2);
+        let result = retry(&policy(3, 1), || async { op(&attempts) }).await;
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let (attempts, op) = failing(u32::MAX);
+        let result = retry(&policy(2, 1), || async { op(&attempts) }).await;
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts.get(), 3);
+        let (attempts, op) = failing(u32::MAX);
+        let result = retry(&RetryPolicy::never(), || async { op(&attempts) }).await;
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_error_returned_at_once() {
+        let (attempts, op) = failing(u32::MAX);
+        let result = retry_if(&policy(5, 1), || async { op(&attempts) }, |_| false).await;
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_retry_past_max_elapsed() {
+        let (attempts, op) = failing(u32::MAX);
+        let policy = RetryPolicy {
+            max_elapsed: 175,
+            ..policy(5, 50)
+        };
+        let start = Instant::now();
+        let result = retry(&policy, || async { op(&attempts) }).await;
+        assert_eq!(result, Err("failed"));
+        // waits 50 and 100ms, the 200ms backoff would end past 175ms
+        assert_eq!(attempts.get(), 3);
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn no_retry_past_deadline() {
+        let (attempts, op) = failing(u32::MAX);
+        let start = Instant::now();
+        let result = crate::clock::timeout(
+            Duration::from_millis(100),
+            retry(&policy(5, 60), || async { op(&attempts) }),
+        )
+        .await
+        .unwrap();
+        // the error of the op, not the deadline, after the one retry that fits
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts.get(), 2);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
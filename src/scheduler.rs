@@ -0,0 +1,632 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Timelike, Utc};
+use color_eyre::{eyre::eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::shutdown::{CancellationToken, Phase, Shutdown};
+
+/// A cron expression of five fields: minute, hour, day of month, month and day of week,
+/// in UTC. Fields take `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and lists of
+/// those. Sunday is 0 or 7. `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly`
+/// are accepted too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// whether day of month and day of week were both restricted, in which case a day
+    /// matching either of them matches
+    either_day: bool,
+}
+
+/// Days searched for the next match, long enough for `0 0 29 2 *`.
+const SEARCH_DAYS: u32 = 366 * 8;
+
+impl Cron {
+    /// The first minute after `after` the expression matches.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+        let mut date = start.date_naive();
+        for day in 0..SEARCH_DAYS {
+            if self.matches_day(date) {
+                let (first_hour, first_minute) = if day == 0 {
+                    (start.hour(), start.minute())
+                } else {
+                    (0, 0)
+                };
+                for hour in (first_hour..24).filter(|h| self.hours & 1 << h != 0) {
+                    let first_minute = if hour == first_hour { first_minute } else { 0 };
+                    if let Some(minute) = (first_minute..60).find(|m| self.minutes & 1 << m != 0) {
+                        return Some(date.and_hms_opt(hour, minute, 0)?.and_utc());
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if self.months & 1 << date.month() == 0 {
+            return false;
+        }
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// Parses one field into a bit set of the values it matches, and whether it restricts
+/// them at all.
+fn parse_field(text: &str, field: &str, min: u32, max: u32) -> Result<(u64, bool)> {
+    let invalid = || eyre!("invalid cron {field} `{text}`");
+    let mut bits = 0u64;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (
+                    from.parse().map_err(|_| invalid())?,
+                    to.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let from = range.parse().map_err(|_| invalid())?;
+                    (from, if step > 1 { max } else { from })
+                }
+            },
+        };
+        if step == 0 || from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    let all = (min..=max).fold(0u64, |all, value| all | 1 << value);
+    Ok((bits, bits != all))
+}
+
+impl FromStr for Cron {
+    type Err = Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let expanded = match text.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" => "0 0 1 1 *",
+            text => text,
+        };
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(eyre!("invalid cron `{text}`: expected 5 fields"));
+        };
+        let (days, days_restricted) = parse_field(day, "day of month", 1, 31)?;
+        let (mut weekdays, weekdays_restricted) = parse_field(weekday, "day of week", 0, 7)?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            text: text.to_owned(),
+            minutes: parse_field(minute, "minute", 0, 59)?.0,
+            hours: parse_field(hour, "hour", 0, 23)?.0,
+            days,
+            months: parse_field(month, "month", 1, 12)?.0,
+            weekdays,
+            either_day: days_restricted && weekdays_restricted,
+        })
+    }
+}
+
+impl Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// When a job runs: on a [`Cron`] expression or `@every` interval like `@every 30s`,
+/// with `ms`, `s`, `m` and `h` units. Intervals are aligned to the unix epoch, so every
+/// instance of a service runs a job at the same ticks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Cron(Cron),
+    Every(Duration),
+}
+
+impl Schedule {
+    /// The first tick after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Cron(cron) => cron.next_after(after),
+            Self::Every(interval) => {
+                let interval = interval.as_millis().max(1) as i64;
+                let next = (after.timestamp_millis().div_euclid(interval) + 1) * interval;
+                DateTime::from_timestamp_millis(next)
+            }
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let Some(interval) = text.trim().strip_prefix("@every") else {
+            return text.parse().map(Self::Cron);
+        };
+        let interval = interval.trim();
+        let invalid = || eyre!("invalid interval `{interval}`");
+        let split = interval
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = interval[..split].parse().map_err(|_| invalid())?;
+        let interval = match &interval[split..] {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 3600),
+            _ => return Err(invalid()),
+        };
+        if interval.is_zero() {
+            return Err(invalid());
+        }
+        Ok(Self::Every(interval))
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cron(cron) => cron.fmt(f),
+            Self::Every(interval) => write!(f, "@every {}ms", interval.as_millis()),
+        }
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    /// ticks skipped because the previous run was still going or another instance ran it
+    pub skipped: u64,
+    /// unix milliseconds the last run started at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct Job {
+    status: Mutex<JobStatus>,
+    running: AtomicBool,
+}
+
+impl Job {
+    fn update(&self, update: impl FnOnce(&mut JobStatus)) {
+        update(&mut self.status.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+#[cfg(feature = "metrics")]
+static RUNS: std::sync::LazyLock<crate::metrics::IntCounterVec> = std::sync::LazyLock::new(|| {
+    crate::counter!(
+        "scheduled_job_runs_total",
+        "Ticks of a scheduled job by outcome: success, failure or skipped",
+        ["job", "outcome"]
+    )
+});
+
+#[cfg(feature = "metrics")]
+static DURATION: std::sync::LazyLock<crate::metrics::HistogramVec> =
+    std::sync::LazyLock::new(|| {
+        crate::histogram!(
+            "scheduled_job_duration_seconds",
+            "Duration of a scheduled job run",
+            ["job"]
+        )
+    });
+
+/// How a tick decides whether this instance runs the job.
+#[derive(Clone)]
+enum Election {
+    Local,
+    #[cfg(feature = "etcd")]
+    Etcd(Box<crate::etcd::Etcd>),
+}
+
+/// Runs named jobs on their [`Schedule`]. A tick arriving while the previous run of a
+/// job is still going is skipped, and so is a tick another instance took when the job is
+/// added with [`Scheduler::add_singleton`].
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    jobs: Arc<Mutex<Vec<Arc<Job>>>>,
+    cancel: CancellationToken,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `job` on every tick of `schedule` on this instance.
+    pub fn add<F, Fut>(&self, name: &str, schedule: Schedule, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.spawn(name, schedule, Election::Local, job);
+    }
+
+    /// Runs `job` on every tick of `schedule` on one instance of the cluster only, the
+    /// one taking the etcd lock of the tick first. The lock is held until the next tick,
+    /// for 1 second at least, whether or not the job finished.
+    #[cfg(feature = "etcd")]
+    pub fn add_singleton<F, Fut>(
+        &self,
+        name: &str,
+        schedule: Schedule,
+        etcd: &crate::etcd::Etcd,
+        job: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.spawn(name, schedule, Election::Etcd(Box::new(etcd.clone())), job);
+    }
+
+    pub fn status(&self) -> Vec<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|job| job.status.lock().unwrap_or_else(|e| e.into_inner()).clone())
+            .collect()
+    }
+
+    /// Stops scheduling further runs, runs in progress go on.
+    pub fn stop(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Stops the scheduler in the [`Phase::Cancel`] phase of `shutdown`.
+    pub fn stop_on_shutdown(&self, shutdown: &Shutdown) {
+        let scheduler = self.clone();
+        shutdown.on(Phase::Cancel, "scheduler", move || async move {
+            scheduler.stop();
+            Ok(())
+        });
+    }
+
+    fn spawn<F, Fut>(&self, name: &str, schedule: Schedule, election: Election, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let job = Arc::new(Job {
+            status: Mutex::new(JobStatus {
+                name: name.to_owned(),
+                schedule: schedule.to_string(),
+                running: false,
+                runs: 0,
+                failures: 0,
+                skipped: 0,
+                last_run: None,
+                next_run: None,
+                last_error: None,
+            }),
+            running: AtomicBool::new(false),
+        });
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(job.clone());
+        let cancel = self.cancel.clone();
+        let name = name.to_owned();
+        tokio::spawn(async move {
+            cancel
+                .run_until_cancelled(tick(name, schedule, election, job, Arc::new(start)))
+                .await
+        });
+    }
+}
+
+async fn tick<F, Fut>(
+    name: String,
+    schedule: Schedule,
+    election: Election,
+    job: Arc<Job>,
+    start: Arc<F>,
+) where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut after = Utc::now();
+    loop {
+        let Some(next) = schedule.next_after(after) else {
+            warn!("scheduled job {name} has no further runs");
+            job.update(|status| status.next_run = None);
+            return;
+        };
+        job.update(|status| status.next_run = Some(next.timestamp_millis()));
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        after = next;
+
+        if job.running.load(Ordering::Relaxed) {
+            warn!("scheduled job {name} still running, tick {next} skipped");
+            skip(&name, &job);
+            continue;
+        }
+        if !election.wins(&name, &schedule, next).await {
+            skip(&name, &job);
+            continue;
+        }
+        job.running.store(true, Ordering::Relaxed);
+        job.update(|status| {
+            status.running = true;
+            status.last_run = Some(Utc::now().timestamp_millis());
+        });
+        tokio::spawn(run(name.clone(), job.clone(), start()));
+    }
+}
+
+impl Election {
+    /// Whether this instance runs the tick at `at`.
+    #[cfg_attr(not(feature = "etcd"), allow(unused_variables))]
+    async fn wins(&self, name: &str, schedule: &Schedule, at: DateTime<Utc>) -> bool {
+        match self {
+            Self::Local => true,
+            #[cfg(feature = "etcd")]
+            Self::Etcd(etcd) => {
+                // held until the next tick, so instances with a late clock find it taken;
+                // a tick less than a second away gives 0, which `try_lock` takes as 1
+                // second, as every tick locks a key of its own that is harmless
+                let ttl = schedule
+                    .next_after(at)
+                    .map(|next| (next - at).num_seconds())
+                    .unwrap_or(60);
+                let key = format!("scheduler/{name}/{}", at.timestamp_millis());
+                match etcd.try_lock(&key, ttl).await {
+                    Ok(lock) => lock.is_some(),
+                    Err(e) => {
+                        warn!("scheduled job {name} lock failed: {e}, tick {at} skipped");
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn skip(name: &str, job: &Job) {
+    job.update(|status| status.skipped += 1);
+    #[cfg(feature = "metrics")]
+    RUNS.with_label_values(&[name, "skipped"]).inc();
+}
+
+async fn run<Fut>(name: String, job: Arc<Job>, run: Fut)
+where
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let start = Instant::now();
+    // a run of its own, so a panic counts as a failed run
+    let result = tokio::spawn(run)
+        .await
+        .unwrap_or_else(|e| Err(eyre!("{e}")));
+    let elapsed = start.elapsed();
+    #[cfg(feature = "metrics")]
+    {
+        DURATION
+            .with_label_values(&[&name])
+            .observe(elapsed.as_secs_f64());
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        RUNS.with_label_values(&[&name, outcome]).inc();
+    }
+    match &result {
+        Ok(()) => info!("scheduled job {name} done in {elapsed:?}"),
+        Err(e) => error!("scheduled job {name} failed after {elapsed:?}: {e}"),
+    }
+    job.update(|status| {
+        status.running = false;
+        status.runs += 1;
+        if let Err(e) = result {
+            status.failures += 1;
+            status.last_error = Some(e.to_string());
+        }
+    });
+    job.running.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn next(cron: &str, after: DateTime<Utc>) -> DateTime<Utc> {
+        cron.parse::<Cron>().unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn fields_parsed() {
+        let all = |min: u32, max: u32| (min..=max).fold(0u64, |all, v| all | 1 << v);
+        assert_eq!(
+            parse_field("*", "minute", 0, 59).unwrap(),
+            (all(0, 59), false)
+        );
+        assert_eq!(parse_field("5", "minute", 0, 59).unwrap(), (1 << 5, true));
+        assert_eq!(
+            parse_field("1-3,10", "hour", 0, 23).unwrap(),
+            (0b1110 | 1 << 10, true)
+        );
+        assert_eq!(
+            parse_field("*/20", "minute", 0, 59).unwrap().0,
+            1 | 1 << 20 | 1 << 40
+        );
+        assert_eq!(
+            parse_field("50/5", "minute", 0, 59).unwrap().0,
+            1 << 50 | 1 << 55
+        );
+        assert_eq!(
+            parse_field("1-10/4", "day of month", 1, 31).unwrap().0,
+            1 << 1 | 1 << 5 | 1 << 9
+        );
+    }
+
+    #[test]
+    fn invalid_rejected() {
+        for cron in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+            "@often",
+        ] {
+            assert!(cron.parse::<Cron>().is_err(), "{cron:?}");
+        }
+        let e = "61 * * * *".parse::<Cron>().unwrap_err();
+        assert_eq!(e.to_string(), "invalid cron minute `61`");
+    }
+
+    #[test]
+    fn next_minute_and_hour() {
+        let now = at(2024, 1, 1, 10, 30);
+        assert_eq!(next("* * * * *", now), at(2024, 1, 1, 10, 31));
+        assert_eq!(next("30 * * * *", now), at(2024, 1, 1, 11, 30));
+        assert_eq!(next("*/15 * * * *", now), at(2024, 1, 1, 10, 45));
+        assert_eq!(next("0 9 * * *", now), at(2024, 1, 2, 9, 0));
+        assert_eq!(next("@hourly", now), at(2024, 1, 1, 11, 0));
+        // seconds are dropped, the next whole minute comes first
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 59).unwrap();
+        assert_eq!(next("* * * * *", now), at(2024, 1, 1, 10, 31));
+    }
+
+    #[test]
+    fn next_day_month_and_weekday() {
+        // 2024-01-01 is a monday
+        let now = at(2024, 1, 1, 0, 0);
+        assert_eq!(next("@daily", now), at(2024, 1, 2, 0, 0));
+        assert_eq!(next("@weekly", now), at(2024, 1, 7, 0, 0));
+        assert_eq!(next("0 0 * * 7", now), at(2024, 1, 7, 0, 0));
+        assert_eq!(next("@monthly", now), at(2024, 2, 1, 0, 0));
+        assert_eq!(next("@yearly", now), at(2025, 1, 1, 0, 0));
+        assert_eq!(next("0 12 31 * *", now), at(2024, 1, 31, 12, 0));
+        assert_eq!(
+            next("0 0 31 * *", at(2024, 1, 31, 0, 0)),
+            at(2024, 3, 31, 0, 0)
+        );
+        assert_eq!(next("0 0 29 2 *", now), at(2024, 2, 29, 0, 0));
+        assert_eq!(
+            next("0 0 29 2 *", at(2024, 3, 1, 0, 0)),
+            at(2028, 2, 29, 0, 0)
+        );
+    }
+
+    #[test]
+    fn restricted_days_match_either() {
+        // the 15th or any friday, 2024-01-05 being the first friday
+        let cron = "0 0 15 * 5";
+        let now = at(2024, 1, 1, 0, 0);
+        assert_eq!(next(cron, now), at(2024, 1, 5, 0, 0));
+        assert_eq!(next(cron, at(2024, 1, 12, 0, 0)), at(2024, 1, 15, 0, 0));
+        // day of week alone restricts the days
+        assert_eq!(
+            next("0 0 * * 5", at(2024, 1, 5, 0, 0)),
+            at(2024, 1, 12, 0, 0)
+        );
+    }
+
+    #[test]
+    fn every_parsed_and_aligned() {
+        let every = |text: &str| match text.parse::<Schedule>().unwrap() {
+            Schedule::Every(interval) => interval,
+            schedule => panic!("not an interval: {schedule}"),
+        };
+        assert_eq!(every("@every 250ms"), Duration::from_millis(250));
+        assert_eq!(every("@every 30s"), Duration::from_secs(30));
+        assert_eq!(every("@every 5m"), Duration::from_secs(300));
+        assert_eq!(every(" @every 2h "), Duration::from_secs(7200));
+        for text in ["@every", "@every 0s", "@every 5", "@every s", "@every 5d"] {
+            assert!(text.parse::<Schedule>().is_err(), "{text:?}");
+        }
+        let schedule: Schedule = "@every 5m".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 1, 1, 10, 31)),
+            Some(at(2024, 1, 1, 10, 35))
+        );
+        assert_eq!(
+            schedule.next_after(at(2024, 1, 1, 10, 35)),
+            Some(at(2024, 1, 1, 10, 40))
+        );
+        assert_eq!(schedule.to_string(), "@every 300000ms");
+    }
+}
@@ -1,12 +1,27 @@
+#[cfg(feature = "health")]
+use std::sync::{
+    atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+    Arc,
+};
+
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "health")]
+use crate::{
+    clock::unix_secs,
+    error::CommonError,
+    health::{CheckFuture, HealthCheck},
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ServiceRegisterConfig {
     pub url: String,
     pub tags: Vec<String>,
     pub ttl: i64,
+    /// this instance among the servers of the service, a hash of `url` if empty
+    pub instance: String,
 }
 
 impl Default for ServiceRegisterConfig {
@@ -15,8 +30,67 @@ impl Default for ServiceRegisterConfig {
             tags: Default::default(),
             ttl: 60,
             url: Default::default(),
+            instance: Default::default(),
+        }
+    }
+}
+
+impl ServiceRegisterConfig {
+    /// The server segment this instance registers its url under, so that the replicas of
+    /// a service each keep a key of their own.
+    pub fn instance_id(&self) -> String {
+        if !self.instance.is_empty() {
+            return self.instance.clone();
+        }
+        // fnv-1a, stable across builds so a restarted instance takes its key back
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self.url.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
         }
+        format!("{hash:016x}")
+    }
+}
+
+/// The keys kept alive for `service_name` and their values.
+#[cfg(any(feature = "etcd", feature = "redis"))]
+pub(crate) fn register_entries(
+    service_name: &str,
+    config: &ServiceRegisterConfig,
+) -> Vec<(String, String)> {
+    let mut entries = instance_entries(service_name, config);
+    entries.push((
+        format!("traefik/http/routers/{}/service", service_name),
+        service_name.to_owned(),
+    ));
+    for tag in &config.tags {
+        let (key, value) = tag.split_once('=').unwrap_or_default();
+        entries.push((key.to_owned(), value.to_owned()));
     }
+    entries
+}
+
+/// The keys of [`register_entries`] owned by this instance alone, removed once it
+/// deregisters. The keys shared by the replicas are left to expire with the last one.
+#[cfg(any(feature = "etcd", feature = "redis"))]
+pub(crate) fn instance_entries(
+    service_name: &str,
+    config: &ServiceRegisterConfig,
+) -> Vec<(String, String)> {
+    vec![(
+        format!(
+            "{}{}/url",
+            discovery_prefix(service_name),
+            config.instance_id()
+        ),
+        config.url.clone(),
+    )]
+}
+
+/// The prefix under which the instances of `service_name` register their urls.
+#[cfg(any(feature = "etcd", feature = "redis"))]
+pub(crate) fn discovery_prefix(service_name: &str) -> String {
+    format!("traefik/http/services/{service_name}/loadbalancer/servers/")
 }
 
 pub trait ServiceRegister {
@@ -26,3 +100,230 @@ pub trait ServiceRegister {
         config: ServiceRegisterConfig,
     ) -> impl std::future::Future<Output = Result<()>> + Send;
 }
+
+/// Resolves the urls other services registered through [`ServiceRegister`].
+pub trait ServiceDiscovery {
+    fn discover(
+        &self,
+        service_name: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<String>>> + Send;
+
+    /// Resolves once the instances of `service_name` may have changed. Backends unable
+    /// to watch never resolve, leaving callers to discover periodically.
+    fn changed(&self, _service_name: &str) -> impl std::future::Future<Output = Result<()>> + Send {
+        std::future::pending()
+    }
+}
+
+#[cfg(feature = "health")]
+/// Progress of a keep_service_register loop, shared with its health check.
+#[derive(Debug, Default)]
+pub struct RegisterStatus {
+    ttl: AtomicI64,
+    last_success: AtomicU64,
+    failures: AtomicU64,
+    stopped: AtomicBool,
+    withdrawn: AtomicBool,
+    /// bumped by every start, a loop of an earlier one ends
+    generation: AtomicU64,
+    /// held by the loop through every round, see [`RegisterStatus::stop_and_wait`]
+    #[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+    round: tokio::sync::Mutex<()>,
+}
+
+#[cfg(feature = "health")]
+impl RegisterStatus {
+    /// Starts a register loop, also after a deregister, and returns the generation it
+    /// runs as. The loop of an earlier start ends before its next round.
+    pub fn start(&self, ttl: i64) -> u64 {
+        self.ttl.store(ttl, Ordering::Relaxed);
+        self.stopped.store(false, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether the loop started as `generation` is still to run.
+    pub fn is_current(&self, generation: u64) -> bool {
+        !self.is_stopped() && self.generation.load(Ordering::Relaxed) == generation
+    }
+
+    pub fn success(&self) {
+        self.last_success.store(unix_secs(), Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Ends the loop before its next round, once the service deregisters.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Ends the loop and waits for its round in flight, so that keys removed afterwards
+    /// are not put back by it.
+    #[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+    pub(crate) async fn stop_and_wait(&self) {
+        self.stop();
+        drop(self.round.lock().await);
+    }
+
+    /// Taken by the loop for a round, which it skips once stopped.
+    #[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+    pub(crate) async fn round(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.round.lock().await
+    }
+
+    /// Removes the keys of the service in the next round and keeps them out until
+    /// withdrawn is set back, e.g. in maintenance mode.
+    pub fn withdraw(&self, withdrawn: bool) {
+        self.withdrawn.store(withdrawn, Ordering::Relaxed);
+    }
+
+    pub fn is_withdrawn(&self) -> bool {
+        self.withdrawn.load(Ordering::Relaxed)
+    }
+
+    pub fn started(&self) -> bool {
+        self.ttl.load(Ordering::Relaxed) != 0
+    }
+
+    /// unix seconds of the last round in which every key was renewed
+    pub fn last_success(&self) -> Option<u64> {
+        match self.last_success.load(Ordering::Relaxed) {
+            0 => None,
+            t => Some(t),
+        }
+    }
+
+    pub fn consecutive_failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// The [`RegisterStatus`] of every service registered through one client, so that two
+/// services registered on it each keep a loop and a health check of their own.
+#[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+#[derive(Debug, Default)]
+pub(crate) struct RegisterStatuses(
+    std::sync::Mutex<std::collections::HashMap<String, Arc<RegisterStatus>>>,
+);
+
+#[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+impl RegisterStatuses {
+    pub(crate) fn get(&self, service_name: &str) -> Arc<RegisterStatus> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(service_name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// [`RegisterStatus::start`]s the loop of `service_name`.
+    pub(crate) fn start(&self, service_name: &str, ttl: i64) -> (Arc<RegisterStatus>, u64) {
+        let status = self.get(service_name);
+        let generation = status.start(ttl);
+        (status, generation)
+    }
+}
+
+#[cfg(feature = "health")]
+/// A point-in-time view of a [`RegisterStatus`], as served by the admin router.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisterReport {
+    pub name: String,
+    pub started: bool,
+    pub stopped: bool,
+    pub withdrawn: bool,
+    pub ttl: i64,
+    pub last_success: Option<u64>,
+    pub consecutive_failures: u64,
+}
+
+#[cfg(feature = "health")]
+pub struct RegisterHealth {
+    pub(crate) name: String,
+    pub(crate) status: Arc<RegisterStatus>,
+}
+
+#[cfg(feature = "health")]
+impl RegisterHealth {
+    pub fn report(&self) -> RegisterReport {
+        RegisterReport {
+            name: self.name.clone(),
+            started: self.status.started(),
+            stopped: self.status.is_stopped(),
+            withdrawn: self.status.is_withdrawn(),
+            ttl: self.status.ttl.load(Ordering::Relaxed),
+            last_success: self.status.last_success(),
+            consecutive_failures: self.status.consecutive_failures(),
+        }
+    }
+}
+
+#[cfg(feature = "health")]
+impl HealthCheck for RegisterHealth {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            if !self.status.started() {
+                return Err(
+                    CommonError::Registration("service register not started".to_owned()).into(),
+                );
+            }
+            let ttl = self.status.ttl.load(Ordering::Relaxed) as u64;
+            match self.status.last_success() {
+                Some(t) if unix_secs().saturating_sub(t) <= ttl => Ok(()),
+                Some(t) => Err(CommonError::Registration(format!(
+                    "service register not renewed since {t}, {} consecutive failures",
+                    self.status.consecutive_failures()
+                ))
+                .into()),
+                None => Err(CommonError::Registration(format!(
+                    "service register not succeeded yet, {} consecutive failures",
+                    self.status.consecutive_failures()
+                ))
+                .into()),
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "health"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_again_after_stop() {
+        let status = RegisterStatus::default();
+        let first = status.start(10);
+        assert!(status.is_current(first));
+        status.stop();
+        assert!(!status.is_current(first));
+        let second = status.start(10);
+        assert!(status.is_current(second));
+        // the loop of the first start does not come back with the second
+        assert!(!status.is_current(first));
+    }
+
+    #[cfg(any(feature = "consul", feature = "etcd", feature = "redis"))]
+    #[test]
+    fn status_per_service() {
+        let statuses = RegisterStatuses::default();
+        let (http, _) = statuses.start("http", 10);
+        let (grpc, grpc_generation) = statuses.start("grpc", 10);
+        assert!(Arc::ptr_eq(&http, &statuses.get("http")));
+        http.stop();
+        http.withdraw(true);
+        assert!(grpc.is_current(grpc_generation));
+        assert!(!grpc.is_withdrawn());
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::{Display, Formatter},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "health")]
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use tokio::{signal, sync::watch, task::JoinSet};
+pub use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+#[cfg(feature = "health")]
+use crate::health::{CheckFuture, HealthCheck};
+
+/// The order in which registered components are stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// take the service out of discovery so no new traffic arrives
+    Deregister,
+    /// let servers finish the requests in flight
+    Drain,
+    /// stop background tasks and release connections
+    Cancel,
+}
+
+impl Phase {
+    const ALL: [Self; 3] = [Self::Deregister, Self::Drain, Self::Cancel];
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Deregister => write!(f, "deregister"),
+            Self::Drain => write!(f, "drain"),
+            Self::Cancel => write!(f, "cancel"),
+        }
+    }
+}
+
+type StopFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+struct Hook {
+    phase: Phase,
+    name: String,
+    stop: Box<dyn FnOnce() -> StopFuture + Send>,
+}
+
+struct Inner {
+    hooks: Mutex<Vec<Hook>>,
+    phase: watch::Sender<Option<Phase>>,
+    timeout: Duration,
+    cancel: CancellationToken,
+}
+
+/// Coordinates the shutdown of every subsystem of a process.
+///
+/// Once SIGINT/SIGTERM arrives or [`Shutdown::trigger`] is called, the hooks of each
+/// [`Phase`] run concurrently and the next phase starts when they all finished or
+/// `timeout` elapsed.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    /// `timeout` bounds every phase.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                hooks: Default::default(),
+                phase: watch::channel(None).0,
+                timeout,
+                cancel: CancellationToken::new(),
+            }),
+        }
+    }
+
+    /// Registers `stop` to be run during `phase`.
+    pub fn on<F, Fut>(&self, phase: Phase, name: &str, stop: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.inner
+            .hooks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Hook {
+                phase,
+                name: name.to_owned(),
+                stop: Box::new(move || Box::pin(stop())),
+            });
+    }
+
+    /// Spawns a background task dropped once the [`Phase::Cancel`] phase starts.
+    pub fn spawn<F>(&self, name: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.clone();
+        let name = name.to_owned();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = shutdown.reached(Phase::Cancel) => info!("{name} cancelled"),
+            }
+        });
+    }
+
+    /// Starts shutting down without waiting for a signal.
+    pub fn trigger(&self) {
+        self.inner.phase.send_if_modified(|phase| {
+            let idle = phase.is_none();
+            if idle {
+                *phase = Some(Phase::Deregister);
+            }
+            idle
+        });
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.phase.borrow().is_some()
+    }
+
+    /// Resolves once shutdown has got to `phase`.
+    pub async fn reached(&self, phase: Phase) {
+        let mut receiver = self.inner.phase.subscribe();
+        let _ = receiver
+            .wait_for(|current| current.is_some_and(|current| current >= phase))
+            .await;
+    }
+
+    /// Resolves once the background tasks should stop.
+    pub async fn cancelled(&self) {
+        self.reached(Phase::Cancel).await
+    }
+
+    /// A token cancelled as the [`Phase::Cancel`] phase starts, for components taking a
+    /// [`CancellationToken`].
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.inner.cancel.child_token()
+    }
+
+    /// Waits for a signal or [`Shutdown::trigger`], then runs every phase in order.
+    pub async fn run(&self) {
+        tokio::select! {
+            _ = signal_received() => self.trigger(),
+            _ = self.reached(Phase::Deregister) => info!("shutdown triggered"),
+        }
+        for phase in Phase::ALL {
+            self.inner.phase.send_replace(Some(phase));
+            if phase == Phase::Cancel {
+                self.inner.cancel.cancel();
+            }
+            self.run_phase(phase).await;
+        }
+        info!("shutdown completed");
+    }
+
+    async fn run_phase(&self, phase: Phase) {
+        let hooks: Vec<Hook> = {
+            let mut hooks = self.inner.hooks.lock().unwrap_or_else(|e| e.into_inner());
+            let (current, rest) = hooks.drain(..).partition(|hook| hook.phase == phase);
+            *hooks = rest;
+            current
+        };
+        if hooks.is_empty() {
+            return;
+        }
+        info!("shutdown {phase}: {} components", hooks.len());
+
+        let mut pending: Vec<String> = hooks.iter().map(|hook| hook.name.clone()).collect();
+        let mut tasks = JoinSet::new();
+        for hook in hooks {
+            let stop = (hook.stop)();
+            tasks.spawn(async move { (hook.name, stop.await) });
+        }
+        let finished = tokio::time::timeout(self.inner.timeout, async {
+            while let Some(joined) = tasks.join_next().await {
+                match joined {
+                    Ok((name, result)) => {
+                        if let Err(e) = result {
+                            error!("shutdown {phase} {name} failed: {e}");
+                        }
+                        pending.retain(|pending| *pending != name);
+                    }
+                    Err(e) => error!("shutdown {phase} task failed: {e}"),
+                }
+            }
+        })
+        .await;
+        if finished.is_err() {
+            warn!(
+                "shutdown {phase} timed out after {:?}, abandoning: {}",
+                self.inner.timeout,
+                pending.join(", ")
+            );
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+/// Down as soon as the shutdown is triggered, so load balancers stop routing here.
+#[cfg(feature = "health")]
+impl HealthCheck for Shutdown {
+    fn name(&self) -> String {
+        "shutdown".to_owned()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            match *self.inner.phase.borrow() {
+                Some(phase) => Err(eyre!("shutting down: {phase}")),
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+/// A token cancelled once SIGINT or SIGTERM arrives, or ctrl-c on Windows. Every clone
+/// and child token hands the signal on to the servers, register loops and watchers of
+/// a process not coordinated by a [`Shutdown`].
+pub fn wait_for_shutdown() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = signal_received() => cancel.cancel(),
+            _ = cancel.cancelled() => {}
+        }
+    });
+    token
+}
+
+/// Background tasks cancelled and awaited as a unit: every task spawned into the scope
+/// is dropped once the scope is cancelled, and [`TaskScope::join`] waits for all of
+/// them. Cloning is cheap and every clone spawns into the same scope.
+#[derive(Clone, Default)]
+pub struct TaskScope {
+    cancel: CancellationToken,
+    tasks: Arc<Mutex<JoinSet<()>>>,
+}
+
+impl TaskScope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A scope cancelled along with this one, whose tasks are joined on their own.
+    pub fn child(&self) -> Self {
+        Self::from(self.cancel.child_token())
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn spawn<F>(&self, name: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let cancel = self.cancel.clone();
+        let name = name.to_owned();
+        let mut tasks = self.lock();
+        // reap the finished tasks, the set keeps their results until joined
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(async move {
+            if cancel.run_until_cancelled(task).await.is_none() {
+                info!("{name} cancelled");
+            }
+        });
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Waits for every task of the scope, also the ones spawned meanwhile.
+    pub async fn join(&self) {
+        loop {
+            let mut tasks = std::mem::take(&mut *self.lock());
+            if tasks.is_empty() {
+                return;
+            }
+            while let Some(result) = tasks.join_next().await {
+                if let Err(e) = result {
+                    error!("scoped task failed: {e}");
+                }
+            }
+        }
+    }
+
+    /// Cancels the scope and waits up to `timeout` for its tasks to stop, aborting the
+    /// ones still running then.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.cancel();
+        if tokio::time::timeout(timeout, self.join()).await.is_err() {
+            warn!("scoped tasks still running after {timeout:?}, aborted");
+            self.lock().abort_all();
+        }
+    }
+
+    /// Cancels and joins the scope in the [`Phase::Cancel`] phase of `shutdown`.
+    pub fn stop_on_shutdown(&self, shutdown: &Shutdown) {
+        let scope = self.clone();
+        shutdown.on(Phase::Cancel, "task scope", move || async move {
+            scope.cancel();
+            scope.join().await;
+            Ok(())
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl From<CancellationToken> for TaskScope {
+    fn from(cancel: CancellationToken) -> Self {
+        Self {
+            cancel,
+            tasks: Default::default(),
+        }
+    }
+}
+
+pub(crate) async fn signal_received() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("ctrl_c signal received"),
+        _ = terminate => info!("terminate signal received"),
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+/// Warns when dropped later than `threshold` after [`SlowLog::start`], within the span of
+/// the caller so the log carries its request id. A zero threshold disables it.
+pub(crate) struct SlowLog(Option<Pending>);
+
+struct Pending {
+    backend: &'static str,
+    operation: String,
+    key: String,
+    threshold: Duration,
+    start: Instant,
+}
+
+impl SlowLog {
+    pub(crate) fn start(
+        backend: &'static str,
+        operation: &str,
+        key: &[u8],
+        threshold: Duration,
+    ) -> Self {
+        Self((!threshold.is_zero()).then(|| Pending {
+            backend,
+            operation: operation.to_owned(),
+            key: String::from_utf8_lossy(key).into_owned(),
+            threshold,
+            start: Instant::now(),
+        }))
+    }
+}
+
+impl Drop for SlowLog {
+    fn drop(&mut self) {
+        let Some(pending) = &self.0 else {
+            return;
+        };
+        let elapsed = pending.start.elapsed();
+        if elapsed > pending.threshold {
+            warn!(
+                slow.backend = pending.backend,
+                slow.operation = pending.operation,
+                slow.key = pending.key,
+                slow.elapsed_ms = elapsed.as_millis() as u64,
+                "slow {} {} `{}` took {elapsed:?}",
+                pending.backend,
+                pending.operation,
+                pending.key,
+            );
+        }
+    }
+}
@@ -58,9 +58,7 @@ pub fn verify(address: &[u8], signature: &[u8], message: &[u8]) -> Result<()> {
 }
 
 fn hash(input: &[u8]) -> [u8; HASH_BYTES_LEN] {
-    let mut result = [0u8; HASH_BYTES_LEN];
-    result.copy_from_slice(libsm::sm3::hash::Sm3Hash::new(input).get_hash().as_ref());
-    result
+    crate::crypto::sm3(input).into_bytes()
 }
 
 pub fn pk2address(pk: &[u8]) -> [u8; ADDR_BYTES_LEN] {
@@ -0,0 +1,460 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    any::Any,
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+use tracing::{error, info, warn};
+
+use crate::{
+    health::{CheckFuture, HealthCheck},
+    retry::RetryPolicy,
+    shutdown::{Phase, Shutdown},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Restart {
+    /// also restart a task that returned `Ok`, for tasks meant to run forever
+    Always,
+    /// restart a task that returned an error or panicked
+    #[default]
+    OnFailure,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    /// milliseconds before the first restart, doubled on every further one in `window`
+    pub initial_backoff: u64,
+    pub max_backoff: u64,
+    /// restarts within `window` after which the task is given up
+    pub max_restarts: u32,
+    /// seconds the restarts are counted over
+    pub window: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            restart: Restart::OnFailure,
+            initial_backoff: 500,
+            max_backoff: 30000,
+            max_restarts: 10,
+            window: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// waiting out the backoff before the next start
+    Restarting,
+    /// returned and not restarted by its policy
+    Exited,
+    /// restarted too often and given up
+    Failed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct Task {
+    status: Mutex<TaskStatus>,
+    abort: Mutex<Option<AbortHandle>>,
+    stopped: AtomicBool,
+}
+
+impl Task {
+    fn update(&self, update: impl FnOnce(&mut TaskStatus)) {
+        update(&mut self.status.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(abort) = &*self.abort.lock().unwrap_or_else(|e| e.into_inner()) {
+            abort.abort();
+        }
+        self.update(|status| {
+            if matches!(status.state, TaskState::Running | TaskState::Restarting) {
+                status.state = TaskState::Stopped;
+            }
+        });
+    }
+}
+
+#[cfg(feature = "metrics")]
+static RESTARTS: std::sync::LazyLock<crate::metrics::IntCounterVec> =
+    std::sync::LazyLock::new(|| {
+        crate::counter!(
+            "supervisor_restarts_total",
+            "Restarts of a supervised task",
+            ["task"]
+        )
+    });
+
+/// Owns long-running tasks, restarting them with backoff when they fail or panic as
+/// their [`RestartPolicy`] says, and reports their state. As a [`HealthCheck`] it is
+/// down once a task was given up.
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    tasks: Arc<Mutex<Vec<Arc<Task>>>>,
+}
+
+impl Supervisor {
+    /// `policy` applies to the tasks spawned without one of their own.
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            tasks: Default::default(),
+        }
+    }
+
+    /// Runs a task made by `start`, which is called again for every restart.
+    pub fn spawn<F, Fut>(&self, name: &str, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.spawn_with(name, self.policy, start);
+    }
+
+    /// Like [`Supervisor::spawn`] with a policy of its own. Tasks that exited or were
+    /// stopped are forgotten from here on, as is a given up task of the same name.
+    pub fn spawn_with<F, Fut>(&self, name: &str, policy: RestartPolicy, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            status: Mutex::new(TaskStatus {
+                name: name.to_owned(),
+                state: TaskState::Running,
+                restarts: 0,
+                last_error: None,
+            }),
+            abort: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        });
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.retain(|task| {
+            let status = task.status.lock().unwrap_or_else(|e| e.into_inner());
+            match status.state {
+                TaskState::Running | TaskState::Restarting => true,
+                TaskState::Failed => status.name != name,
+                TaskState::Exited | TaskState::Stopped => false,
+            }
+        });
+        tasks.push(task.clone());
+        drop(tasks);
+        tokio::spawn(supervise(name.to_owned(), policy, task, start));
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|task| {
+                task.status
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone()
+            })
+            .collect()
+    }
+
+    /// Aborts every task without restarting it.
+    pub fn stop(&self) {
+        for task in self.tasks.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            task.stop();
+        }
+    }
+
+    /// Stops every task in the [`Phase::Cancel`] phase of `shutdown`.
+    pub fn stop_on_shutdown(&self, shutdown: &Shutdown) {
+        let supervisor = self.clone();
+        shutdown.on(Phase::Cancel, "supervisor", move || async move {
+            supervisor.stop();
+            Ok(())
+        });
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+async fn supervise<F, Fut>(name: String, policy: RestartPolicy, task: Arc<Task>, start: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let backoff = RetryPolicy {
+        initial_backoff: policy.initial_backoff,
+        max_backoff: policy.max_backoff,
+        ..Default::default()
+    };
+    let window = Duration::from_secs(policy.window);
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    loop {
+        let handle = tokio::spawn(start());
+        *task.abort.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle.abort_handle());
+        if task.stopped.load(Ordering::Relaxed) {
+            handle.abort();
+        }
+        let error = match handle.await {
+            _ if task.stopped.load(Ordering::Relaxed) => return,
+            Ok(Ok(())) if policy.restart != Restart::Always => {
+                info!("supervised task {name} exited");
+                task.update(|status| status.state = TaskState::Exited);
+                return;
+            }
+            Ok(Ok(())) => "exited".to_owned(),
+            Ok(Err(e)) => e.to_string(),
+            Err(e) if e.is_panic() => format!("panicked: {}", panic_message(&*e.into_panic())),
+            Err(e) => e.to_string(),
+        };
+        task.update(|status| status.last_error = Some(error.clone()));
+        if policy.restart == Restart::Never {
+            error!("supervised task {name} failed: {error}, not restarted");
+            task.update(|status| status.state = TaskState::Exited);
+            return;
+        }
+
+        let now = Instant::now();
+        while restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > window)
+        {
+            restarts.pop_front();
+        }
+        if restarts.len() >= policy.max_restarts as usize {
+            error!(
+                "supervised task {name} failed: {error}, given up after {} restarts in {}s",
+                restarts.len(),
+                policy.window
+            );
+            task.update(|status| status.state = TaskState::Failed);
+            return;
+        }
+        let wait = backoff.backoff(restarts.len() as u32);
+        restarts.push_back(now);
+        warn!("supervised task {name} failed: {error}, restart in {wait:?}");
+        task.update(|status| {
+            status.state = TaskState::Restarting;
+            status.restarts += 1;
+        });
+        #[cfg(feature = "metrics")]
+        RESTARTS.with_label_values(&[&name]).inc();
+        tokio::time::sleep(wait).await;
+        if task.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        task.update(|status| status.state = TaskState::Running);
+    }
+}
+
+impl HealthCheck for Supervisor {
+    fn name(&self) -> String {
+        "supervisor".to_owned()
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            let failed: Vec<_> = self
+                .status()
+                .into_iter()
+                .filter(|task| task.state == TaskState::Failed)
+                .map(|task| task.name)
+                .collect();
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(eyre!("supervised tasks given up: {}", failed.join(", ")))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    fn policy(restart: Restart, max_restarts: u32) -> RestartPolicy {
+        RestartPolicy {
+            restart,
+            initial_backoff: 1,
+            max_backoff: 1,
+            max_restarts,
+            window: 60,
+        }
+    }
+
+    /// Waits for the task `name` to settle in `state`, returns its status.
+    async fn wait_for(supervisor: &Supervisor, name: &str, state: TaskState) -> TaskStatus {
+        for _ in 0..500 {
+            if let Some(status) = supervisor
+                .status()
+                .into_iter()
+                .find(|status| status.name == name && status.state == state)
+            {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        panic!("{name} never {state:?}: {:?}", supervisor.status());
+    }
+
+    /// Spawns `name` counting its starts, each returning what `run` makes of the count.
+    fn spawn_counted(
+        supervisor: &Supervisor,
+        name: &str,
+        policy: RestartPolicy,
+        run: fn(u32) -> Result<()>,
+    ) -> Arc<AtomicU32> {
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+        supervisor.spawn_with(name, policy, move || {
+            let start = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            async move { run(start) }
+        });
+        starts
+    }
+
+    #[tokio::test]
+    async fn on_failure_restarts_until_success() {
+        let supervisor = Supervisor::default();
+        let starts = spawn_counted(&supervisor, "flaky", policy(Restart::OnFailure, 5), |n| {
+            if n < 3 {
+                Err(eyre!("attempt {n}"))
+            } else {
+                Ok(())
+            }
+        });
+        let status = wait_for(&supervisor, "flaky", TaskState::Exited).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("attempt 2"));
+        assert!(supervisor.check().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn always_restarts_on_success() {
+        let supervisor = Supervisor::default();
+        let starts = spawn_counted(&supervisor, "loop", policy(Restart::Always, 3), |_| Ok(()));
+        let status = wait_for(&supervisor, "loop", TaskState::Failed).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 4);
+        assert_eq!(status.restarts, 3);
+        assert_eq!(status.last_error.as_deref(), Some("exited"));
+    }
+
+    #[tokio::test]
+    async fn never_restarts() {
+        let supervisor = Supervisor::default();
+        let starts = spawn_counted(&supervisor, "once", policy(Restart::Never, 3), |_| {
+            Err(eyre!("broken"))
+        });
+        let status = wait_for(&supervisor, "once", TaskState::Exited).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 1);
+        assert_eq!(status.restarts, 0);
+        assert_eq!(status.last_error.as_deref(), Some("broken"));
+    }
+
+    #[tokio::test]
+    async fn given_up_after_max_restarts() {
+        let supervisor = Supervisor::default();
+        let starts = spawn_counted(&supervisor, "broken", policy(Restart::OnFailure, 2), |_| {
+            Err(eyre!("broken"))
+        });
+        wait_for(&supervisor, "broken", TaskState::Failed).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+        let e = supervisor.check().await.unwrap_err();
+        assert_eq!(e.to_string(), "supervised tasks given up: broken");
+        // restarts older than the window no longer count
+        let policy = RestartPolicy {
+            window: 0,
+            ..policy(Restart::OnFailure, 1)
+        };
+        let starts = spawn_counted(&supervisor, "slow", policy, |n| {
+            std::thread::sleep(Duration::from_millis(2));
+            if n < 4 {
+                Err(eyre!("broken"))
+            } else {
+                Ok(())
+            }
+        });
+        wait_for(&supervisor, "slow", TaskState::Exited).await;
+        assert_eq!(starts.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn panic_captured() {
+        let supervisor = Supervisor::default();
+        spawn_counted(&supervisor, "panics", policy(Restart::Never, 0), |_| {
+            panic!("boom")
+        });
+        let status = wait_for(&supervisor, "panics", TaskState::Exited).await;
+        assert_eq!(status.last_error.as_deref(), Some("panicked: boom"));
+    }
+
+    #[tokio::test]
+    async fn stop_and_prune() {
+        let supervisor = Supervisor::default();
+        supervisor.spawn("forever", std::future::pending);
+        spawn_counted(&supervisor, "done", policy(Restart::Never, 0), |_| Ok(()));
+        spawn_counted(&supervisor, "failed", policy(Restart::OnFailure, 0), |_| {
+            Err(eyre!("broken"))
+        });
+        wait_for(&supervisor, "done", TaskState::Exited).await;
+        wait_for(&supervisor, "failed", TaskState::Failed).await;
+        supervisor.stop();
+        wait_for(&supervisor, "forever", TaskState::Stopped).await;
+        // the next spawn forgets the finished tasks, a given up one stays until replaced
+        spawn_counted(&supervisor, "next", policy(Restart::Never, 0), |_| Ok(()));
+        let names: Vec<_> = supervisor.status().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["failed", "next"]);
+        spawn_counted(&supervisor, "failed", policy(Restart::Never, 0), |_| Ok(()));
+        assert!(supervisor.check().await.is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, sync::Arc};
+
+use color_eyre::{eyre::eyre, Result};
+
+/// A tenant, e.g. a chain, sharing a deployment with others. Its keys live under
+/// `tenants/{id}/` in every store, so that scoping them in [`TenantEtcd`], [`TenantRedis`]
+/// or a local cache keeps the tenants from reading or deleting the keys of each other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant {
+    id: Arc<str>,
+    prefix: Arc<str>,
+}
+
+impl Tenant {
+    /// Fails for an empty id or one with `/` or a redis pattern character, which could
+    /// reach into the keys of another tenant.
+    pub fn new(id: &str) -> Result<Self> {
+        if id.is_empty() || id.contains(['/', '*', '?', '[', ']']) {
+            return Err(eyre!("invalid tenant id: `{id}`"));
+        }
+        Ok(Self {
+            id: id.into(),
+            prefix: format!("tenants/{id}/").into(),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// `tenants/{id}/`, the start of every key of the tenant.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// `key` within the keys of the tenant.
+    pub fn scope(&self, key: impl AsRef<[u8]>) -> Vec<u8> {
+        [self.prefix.as_bytes(), key.as_ref()].concat()
+    }
+
+    /// Like [`Tenant::scope`] for string keys.
+    pub fn scope_str(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    /// The key as the tenant knows it, `None` if `key` is not one of its keys.
+    pub fn strip<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.strip_prefix(self.prefix.as_bytes())
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// The keys of one [`Tenant`] in etcd, from [`crate::etcd::Etcd::for_tenant`]. Keys are
+/// given without the tenant prefix and come back with it, see [`Tenant::strip`].
+#[cfg(feature = "etcd")]
+#[derive(Clone)]
+pub struct TenantEtcd {
+    etcd: crate::etcd::Etcd,
+    tenant: Tenant,
+}
+
+#[cfg(feature = "etcd")]
+impl TenantEtcd {
+    pub(crate) const fn new(etcd: crate::etcd::Etcd, tenant: Tenant) -> Self {
+        Self { etcd, tenant }
+    }
+
+    pub const fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+
+    pub async fn put(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Vec<u8>>,
+        ttl: i64,
+    ) -> Result<Option<crate::etcd::KeyValue>> {
+        self.etcd.put(self.tenant.scope(key), value, ttl).await
+    }
+
+    pub async fn get(&self, key: impl AsRef<[u8]>) -> Result<crate::etcd::KeyValue> {
+        self.etcd.get(self.tenant.scope(key)).await
+    }
+
+    pub async fn get_with_prefix(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<Vec<crate::etcd::KeyValue>> {
+        self.etcd.get_with_prefix(self.tenant.scope(prefix)).await
+    }
+
+    pub async fn delete(&self, key: impl AsRef<[u8]>) -> Result<i64> {
+        self.etcd.delete(self.tenant.scope(key)).await
+    }
+
+    /// Deletes the keys of the tenant under `prefix`, an empty one deleting every key
+    /// of the tenant and no other.
+    pub async fn delete_with_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<i64> {
+        self.etcd
+            .delete_with_prefix(self.tenant.scope(prefix))
+            .await
+    }
+
+    /// Watches the keys of the tenant under `prefix`.
+    pub async fn watch_prefix(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<(etcd_client::Watcher, etcd_client::WatchStream)> {
+        let prefix = self.tenant.scope(prefix);
+        self.etcd
+            .client
+            .clone()
+            .watch(
+                prefix.clone(),
+                Some(etcd_client::WatchOptions::new().with_prefix()),
+            )
+            .await
+            .map_err(|e| crate::etcd::op_error("watch", &prefix, e).into())
+    }
+}
+
+/// The keys of one [`Tenant`] in redis, from [`crate::redis::Redis::for_tenant`].
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct TenantRedis {
+    redis: crate::redis::Redis,
+    tenant: Tenant,
+}
+
+#[cfg(feature = "redis")]
+impl TenantRedis {
+    pub(crate) const fn new(redis: crate::redis::Redis, tenant: Tenant) -> Self {
+        Self { redis, tenant }
+    }
+
+    pub const fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+
+    /// The command `name` on the key `key` of the tenant, further arguments to be added.
+    /// Build commands here rather than with [`crate::redis::cmd`] to stay within the
+    /// tenant.
+    pub fn cmd(&self, name: &str, key: &str) -> crate::redis::Cmd {
+        let mut cmd = crate::redis::cmd(name);
+        cmd.arg(self.tenant.scope_str(key));
+        cmd
+    }
+
+    pub async fn query<T: crate::redis::FromRedisValue>(
+        &self,
+        cmd: &crate::redis::Cmd,
+    ) -> Result<T> {
+        self.redis.query(cmd).await
+    }
+
+    pub async fn get<T: crate::redis::FromRedisValue>(&self, key: &str) -> Result<T> {
+        self.redis.query_idempotent(&self.cmd("GET", key)).await
+    }
+
+    /// Sets `key` to `value` for `ttl` seconds, or for good with 0.
+    pub async fn set(
+        &self,
+        key: &str,
+        value: impl crate::redis::ToRedisArgs,
+        ttl: u64,
+    ) -> Result<()> {
+        let mut cmd = self.cmd("SET", key);
+        cmd.arg(value);
+        if ttl != 0 {
+            cmd.arg("EX").arg(ttl);
+        }
+        self.redis.query_idempotent(&cmd).await
+    }
+
+    pub async fn del(&self, key: &str) -> Result<i64> {
+        self.redis.query_idempotent(&self.cmd("DEL", key)).await
+    }
+}
@@ -0,0 +1,699 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The JSON representations of the CITA-Cloud protobuf types served by the cache API.
+//!
+//! Bytes are rendered as `0x` prefixed lowercase hex and amounts (`value`, `quota_used`)
+//! as hex quantities without leading zeros. Parsing accepts either case, with or
+//! without the prefix, and pads quantities back to 32 big-endian bytes. The width of a
+//! quantity is not kept: one narrower than 32 bytes, e.g. an empty `value`, comes back
+//! from a round trip through JSON as 32 bytes of the same integer.
+
+use cita_cloud_proto::{
+    blockchain::{
+        raw_transaction::Tx, Block, BlockHeader, CompactBlock, CompactBlockBody, RawTransaction,
+        RawTransactions, Transaction, UnverifiedTransaction, UnverifiedUtxoTransaction,
+        UtxoTransaction, Witness,
+    },
+    controller::SystemConfig,
+    evm::{Log, Receipt},
+};
+use color_eyre::{eyre::eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::util::strip_prefix;
+pub use crate::util::{hex, parse_hex, to_hex};
+
+/// Width of the 256 bit integers carried as bytes.
+const QUANTITY_LEN: usize = 32;
+
+/// Renders a big-endian integer, `0x0` for zero.
+pub fn to_quantity(bytes: &[u8]) -> String {
+    let hex = to_hex(bytes);
+    match hex[2..].trim_start_matches('0') {
+        "" => "0x0".to_owned(),
+        digits => format!("0x{digits}"),
+    }
+}
+
+/// Parses a hex quantity into 32 big-endian bytes, whatever the width it was rendered
+/// from.
+pub fn parse_quantity(text: &str) -> Result<Vec<u8>> {
+    let digits = strip_prefix(text).trim_start_matches('0');
+    if digits.len() > QUANTITY_LEN * 2 {
+        return Err(eyre!("invalid quantity `{text}`: wider than 256 bits"));
+    }
+    let padded = format!("{digits:0>width$}", width = QUANTITY_LEN * 2);
+    parse_hex(&padded).map_err(|_| eyre!("invalid quantity `{text}`"))
+}
+
+/// `#[serde(with = "hex_list")]` for `Vec<Vec<u8>>` fields.
+pub mod hex_list {
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(list.len()))?;
+        for bytes in list {
+            seq.serialize_element(&super::to_hex(bytes))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|text| super::parse_hex(text).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// `#[serde(with = "quantity")]` for 256 bit integers kept as `Vec<u8>`.
+pub mod quantity {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::to_quantity(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_quantity(&text).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderJson {
+    #[serde(with = "hex")]
+    pub prev_hash: Vec<u8>,
+    pub timestamp: u64,
+    pub height: u64,
+    #[serde(with = "hex")]
+    pub transactions_root: Vec<u8>,
+    #[serde(with = "hex")]
+    pub proposer: Vec<u8>,
+}
+
+impl From<BlockHeader> for HeaderJson {
+    fn from(header: BlockHeader) -> Self {
+        Self {
+            prev_hash: header.prevhash,
+            timestamp: header.timestamp,
+            height: header.height,
+            transactions_root: header.transactions_root,
+            proposer: header.proposer,
+        }
+    }
+}
+
+impl From<HeaderJson> for BlockHeader {
+    fn from(header: HeaderJson) -> Self {
+        Self {
+            prevhash: header.prev_hash,
+            timestamp: header.timestamp,
+            height: header.height,
+            transactions_root: header.transactions_root,
+            proposer: header.proposer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WitnessJson {
+    #[serde(with = "hex")]
+    pub sender: Vec<u8>,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+}
+
+impl From<Witness> for WitnessJson {
+    fn from(witness: Witness) -> Self {
+        Self {
+            sender: witness.sender,
+            signature: witness.signature,
+        }
+    }
+}
+
+impl From<WitnessJson> for Witness {
+    fn from(witness: WitnessJson) -> Self {
+        Self {
+            signature: witness.signature,
+            sender: witness.sender,
+        }
+    }
+}
+
+/// A normal transaction with its hash and witness flattened in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionJson {
+    #[serde(with = "hex")]
+    pub hash: Vec<u8>,
+    pub version: u32,
+    #[serde(with = "hex")]
+    pub to: Vec<u8>,
+    pub nonce: String,
+    pub quota: u64,
+    pub valid_until_block: u64,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+    #[serde(with = "quantity")]
+    pub value: Vec<u8>,
+    #[serde(with = "hex")]
+    pub chain_id: Vec<u8>,
+    #[serde(with = "hex")]
+    pub sender: Vec<u8>,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+}
+
+impl From<UnverifiedTransaction> for TransactionJson {
+    fn from(utx: UnverifiedTransaction) -> Self {
+        let tx = utx.transaction.unwrap_or_default();
+        let witness = utx.witness.unwrap_or_default();
+        Self {
+            hash: utx.transaction_hash,
+            version: tx.version,
+            to: tx.to,
+            nonce: tx.nonce,
+            quota: tx.quota,
+            valid_until_block: tx.valid_until_block,
+            data: tx.data,
+            value: tx.value,
+            chain_id: tx.chain_id,
+            sender: witness.sender,
+            signature: witness.signature,
+        }
+    }
+}
+
+impl From<TransactionJson> for UnverifiedTransaction {
+    fn from(tx: TransactionJson) -> Self {
+        Self {
+            transaction: Some(Transaction {
+                version: tx.version,
+                to: tx.to,
+                nonce: tx.nonce,
+                quota: tx.quota,
+                valid_until_block: tx.valid_until_block,
+                data: tx.data,
+                value: tx.value,
+                chain_id: tx.chain_id,
+            }),
+            transaction_hash: tx.hash,
+            witness: Some(Witness {
+                signature: tx.signature,
+                sender: tx.sender,
+            }),
+        }
+    }
+}
+
+/// A system config change transaction with its hash flattened in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtxoTransactionJson {
+    #[serde(with = "hex")]
+    pub hash: Vec<u8>,
+    pub version: u32,
+    #[serde(with = "hex")]
+    pub pre_tx_hash: Vec<u8>,
+    #[serde(with = "hex")]
+    pub output: Vec<u8>,
+    pub lock_id: u64,
+    pub witnesses: Vec<WitnessJson>,
+}
+
+impl From<UnverifiedUtxoTransaction> for UtxoTransactionJson {
+    fn from(utx: UnverifiedUtxoTransaction) -> Self {
+        let tx = utx.transaction.unwrap_or_default();
+        Self {
+            hash: utx.transaction_hash,
+            version: tx.version,
+            pre_tx_hash: tx.pre_tx_hash,
+            output: tx.output,
+            lock_id: tx.lock_id,
+            witnesses: utx.witnesses.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<UtxoTransactionJson> for UnverifiedUtxoTransaction {
+    fn from(tx: UtxoTransactionJson) -> Self {
+        Self {
+            transaction: Some(UtxoTransaction {
+                version: tx.version,
+                pre_tx_hash: tx.pre_tx_hash,
+                output: tx.output,
+                lock_id: tx.lock_id,
+            }),
+            transaction_hash: tx.hash,
+            witnesses: tx.witnesses.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Either kind of transaction, told apart by `"type": "normal" | "utxo"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RawTransactionJson {
+    Normal(TransactionJson),
+    Utxo(UtxoTransactionJson),
+}
+
+impl RawTransactionJson {
+    pub fn hash(&self) -> &[u8] {
+        match self {
+            Self::Normal(tx) => &tx.hash,
+            Self::Utxo(tx) => &tx.hash,
+        }
+    }
+}
+
+impl TryFrom<RawTransaction> for RawTransactionJson {
+    type Error = Report;
+
+    fn try_from(raw: RawTransaction) -> Result<Self> {
+        match raw.tx {
+            Some(Tx::NormalTx(tx)) => Ok(Self::Normal(tx.into())),
+            Some(Tx::UtxoTx(tx)) => Ok(Self::Utxo(tx.into())),
+            None => Err(eyre!("empty raw transaction")),
+        }
+    }
+}
+
+impl From<RawTransactionJson> for RawTransaction {
+    fn from(tx: RawTransactionJson) -> Self {
+        let tx = match tx {
+            RawTransactionJson::Normal(tx) => Tx::NormalTx(tx.into()),
+            RawTransactionJson::Utxo(tx) => Tx::UtxoTx(tx.into()),
+        };
+        Self { tx: Some(tx) }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockJson {
+    pub version: u32,
+    pub header: HeaderJson,
+    pub transactions: Vec<RawTransactionJson>,
+    #[serde(with = "hex")]
+    pub proof: Vec<u8>,
+    #[serde(with = "hex")]
+    pub state_root: Vec<u8>,
+}
+
+impl TryFrom<Block> for BlockJson {
+    type Error = Report;
+
+    fn try_from(block: Block) -> Result<Self> {
+        let header = block.header.unwrap_or_default();
+        let height = header.height;
+        let transactions = block
+            .body
+            .unwrap_or_default()
+            .body
+            .into_iter()
+            .map(|tx| tx.try_into().map_err(|e| eyre!("block {height}: {e}")))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            version: block.version,
+            header: header.into(),
+            transactions,
+            proof: block.proof,
+            state_root: block.state_root,
+        })
+    }
+}
+
+impl From<BlockJson> for Block {
+    fn from(block: BlockJson) -> Self {
+        Self {
+            version: block.version,
+            header: Some(block.header.into()),
+            body: Some(RawTransactions {
+                body: block.transactions.into_iter().map(Into::into).collect(),
+            }),
+            proof: block.proof,
+            state_root: block.state_root,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactBlockJson {
+    pub version: u32,
+    pub header: HeaderJson,
+    #[serde(with = "hex_list")]
+    pub tx_hashes: Vec<Vec<u8>>,
+}
+
+impl From<CompactBlock> for CompactBlockJson {
+    fn from(block: CompactBlock) -> Self {
+        Self {
+            version: block.version,
+            header: block.header.unwrap_or_default().into(),
+            tx_hashes: block.body.unwrap_or_default().tx_hashes,
+        }
+    }
+}
+
+impl From<CompactBlockJson> for CompactBlock {
+    fn from(block: CompactBlockJson) -> Self {
+        Self {
+            version: block.version,
+            header: Some(block.header.into()),
+            body: Some(CompactBlockBody {
+                tx_hashes: block.tx_hashes,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogJson {
+    #[serde(with = "hex")]
+    pub address: Vec<u8>,
+    #[serde(with = "hex_list")]
+    pub topics: Vec<Vec<u8>>,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+    #[serde(with = "hex")]
+    pub block_hash: Vec<u8>,
+    pub block_number: u64,
+    #[serde(with = "hex")]
+    pub transaction_hash: Vec<u8>,
+    pub transaction_index: u64,
+    pub log_index: u64,
+    pub transaction_log_index: u64,
+}
+
+impl From<Log> for LogJson {
+    fn from(log: Log) -> Self {
+        Self {
+            address: log.address,
+            topics: log.topics,
+            data: log.data,
+            block_hash: log.block_hash,
+            block_number: log.block_number,
+            transaction_hash: log.transaction_hash,
+            transaction_index: log.transaction_index,
+            log_index: log.log_index,
+            transaction_log_index: log.transaction_log_index,
+        }
+    }
+}
+
+impl From<LogJson> for Log {
+    fn from(log: LogJson) -> Self {
+        Self {
+            address: log.address,
+            topics: log.topics,
+            data: log.data,
+            block_hash: log.block_hash,
+            block_number: log.block_number,
+            transaction_hash: log.transaction_hash,
+            transaction_index: log.transaction_index,
+            log_index: log.log_index,
+            transaction_log_index: log.transaction_log_index,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiptJson {
+    #[serde(with = "hex")]
+    pub transaction_hash: Vec<u8>,
+    pub transaction_index: u64,
+    #[serde(with = "hex")]
+    pub block_hash: Vec<u8>,
+    pub block_number: u64,
+    #[serde(with = "quantity")]
+    pub cumulative_quota_used: Vec<u8>,
+    #[serde(with = "quantity")]
+    pub quota_used: Vec<u8>,
+    #[serde(with = "hex")]
+    pub contract_address: Vec<u8>,
+    pub logs: Vec<LogJson>,
+    #[serde(with = "hex")]
+    pub state_root: Vec<u8>,
+    #[serde(with = "hex")]
+    pub logs_bloom: Vec<u8>,
+    /// empty when the transaction succeeded
+    pub error_message: String,
+}
+
+impl From<Receipt> for ReceiptJson {
+    fn from(receipt: Receipt) -> Self {
+        Self {
+            transaction_hash: receipt.transaction_hash,
+            transaction_index: receipt.transaction_index,
+            block_hash: receipt.block_hash,
+            block_number: receipt.block_number,
+            cumulative_quota_used: receipt.cumulative_quota_used,
+            quota_used: receipt.quota_used,
+            contract_address: receipt.contract_address,
+            logs: receipt.logs.into_iter().map(Into::into).collect(),
+            state_root: receipt.state_root,
+            logs_bloom: receipt.logs_bloom,
+            error_message: receipt.error_message,
+        }
+    }
+}
+
+impl From<ReceiptJson> for Receipt {
+    fn from(receipt: ReceiptJson) -> Self {
+        Self {
+            transaction_hash: receipt.transaction_hash,
+            transaction_index: receipt.transaction_index,
+            block_hash: receipt.block_hash,
+            block_number: receipt.block_number,
+            cumulative_quota_used: receipt.cumulative_quota_used,
+            quota_used: receipt.quota_used,
+            contract_address: receipt.contract_address,
+            logs: receipt.logs.into_iter().map(Into::into).collect(),
+            state_root: receipt.state_root,
+            logs_bloom: receipt.logs_bloom,
+            error_message: receipt.error_message,
+        }
+    }
+}
+
+/// The current system config, without the hashes of the transactions that set it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemConfigJson {
+    pub version: u32,
+    #[serde(with = "hex")]
+    pub chain_id: Vec<u8>,
+    #[serde(with = "hex")]
+    pub admin: Vec<u8>,
+    pub block_interval: u32,
+    #[serde(with = "hex_list")]
+    pub validators: Vec<Vec<u8>>,
+    pub emergency_brake: bool,
+    pub quota_limit: u32,
+    pub block_limit: u32,
+}
+
+impl From<SystemConfig> for SystemConfigJson {
+    fn from(config: SystemConfig) -> Self {
+        Self {
+            version: config.version,
+            chain_id: config.chain_id,
+            admin: config.admin,
+            block_interval: config.block_interval,
+            validators: config.validators,
+            emergency_brake: config.emergency_brake,
+            quota_limit: config.quota_limit,
+            block_limit: config.block_limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(value: u64) -> Vec<u8> {
+        let mut bytes = vec![0; QUANTITY_LEN];
+        bytes[QUANTITY_LEN - 8..].copy_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn quantity_round_trip() {
+        for value in [0, 1, 0x10, 0xff, 0x1234_5678, u64::MAX] {
+            let bytes = quantity(value);
+            let text = to_quantity(&bytes);
+            assert_eq!(text, format!("{value:#x}"));
+            assert_eq!(parse_quantity(&text).unwrap(), bytes);
+        }
+        let max = vec![0xff; QUANTITY_LEN];
+        assert_eq!(parse_quantity(&to_quantity(&max)).unwrap(), max);
+    }
+
+    #[test]
+    fn quantity_normalised_to_32_bytes() {
+        assert_eq!(to_quantity(&[]), "0x0");
+        assert_eq!(parse_quantity("0x0").unwrap(), quantity(0));
+        assert_eq!(
+            parse_quantity(&to_quantity(&[0, 0x2a])).unwrap(),
+            quantity(0x2a)
+        );
+        assert_eq!(parse_quantity("2A").unwrap(), quantity(0x2a));
+        assert_eq!(parse_quantity("0X0002a").unwrap(), quantity(0x2a));
+    }
+
+    #[test]
+    fn quantity_rejected() {
+        assert!(parse_quantity(&format!("0x1{}", "0".repeat(64))).is_err());
+        assert!(parse_quantity("0xzz").is_err());
+        // leading zeros do not count towards the width
+        assert!(parse_quantity(&format!("0x{}1", "0".repeat(64))).is_ok());
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x01, 0xab, 0xff];
+        assert_eq!(to_hex(&bytes), "0x0001abff");
+        assert_eq!(parse_hex("0x0001abff").unwrap(), bytes);
+        assert_eq!(parse_hex("0001ABFF").unwrap(), bytes);
+        assert_eq!(to_hex(&[]), "0x");
+        assert!(parse_hex("0x").unwrap().is_empty());
+    }
+
+    fn header(height: u64) -> BlockHeader {
+        BlockHeader {
+            prevhash: vec![1; 32],
+            timestamp: 1_700_000_000_000,
+            height,
+            transactions_root: vec![2; 32],
+            proposer: vec![3; 20],
+        }
+    }
+
+    fn normal_tx() -> RawTransaction {
+        RawTransaction {
+            tx: Some(Tx::NormalTx(UnverifiedTransaction {
+                transaction: Some(Transaction {
+                    version: 1,
+                    to: vec![4; 20],
+                    nonce: "nonce".to_owned(),
+                    quota: 300_000,
+                    valid_until_block: 120,
+                    data: vec![5, 6],
+                    value: quantity(7),
+                    chain_id: vec![8; 32],
+                }),
+                transaction_hash: vec![9; 32],
+                witness: Some(Witness {
+                    signature: vec![10; 65],
+                    sender: vec![11; 20],
+                }),
+            })),
+        }
+    }
+
+    fn utxo_tx() -> RawTransaction {
+        RawTransaction {
+            tx: Some(Tx::UtxoTx(UnverifiedUtxoTransaction {
+                transaction: Some(UtxoTransaction {
+                    version: 1,
+                    pre_tx_hash: vec![12; 32],
+                    output: vec![13; 20],
+                    lock_id: 1002,
+                }),
+                transaction_hash: vec![14; 32],
+                witnesses: vec![Witness {
+                    signature: vec![15; 65],
+                    sender: vec![16; 20],
+                }],
+            })),
+        }
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let block = Block {
+            version: 0,
+            header: Some(header(100)),
+            body: Some(RawTransactions {
+                body: vec![normal_tx(), utxo_tx()],
+            }),
+            proof: vec![17; 8],
+            state_root: vec![18; 32],
+        };
+        let json = BlockJson::try_from(block.clone()).unwrap();
+        assert_eq!(json.header.height, 100);
+        assert_eq!(json.transactions[0].hash(), &[9; 32]);
+        assert_eq!(json.transactions[1].hash(), &[14; 32]);
+        assert_eq!(Block::from(json), block);
+    }
+
+    #[test]
+    fn empty_transaction_rejected() {
+        let block = Block {
+            header: Some(header(7)),
+            body: Some(RawTransactions {
+                body: vec![RawTransaction { tx: None }],
+            }),
+            ..Default::default()
+        };
+        let e = BlockJson::try_from(block).unwrap_err();
+        assert_eq!(e.to_string(), "block 7: empty raw transaction");
+    }
+
+    #[test]
+    fn compact_block_round_trip() {
+        let block = CompactBlock {
+            version: 0,
+            header: Some(header(5)),
+            body: Some(CompactBlockBody {
+                tx_hashes: vec![vec![1; 32], vec![2; 32]],
+            }),
+        };
+        assert_eq!(
+            CompactBlock::from(CompactBlockJson::from(block.clone())),
+            block
+        );
+    }
+
+    #[test]
+    fn receipt_round_trip() {
+        let receipt = Receipt {
+            transaction_hash: vec![1; 32],
+            transaction_index: 2,
+            block_hash: vec![3; 32],
+            block_number: 4,
+            cumulative_quota_used: quantity(21_000),
+            quota_used: quantity(21_000),
+            contract_address: vec![5; 20],
+            logs: vec![Log {
+                address: vec![6; 20],
+                topics: vec![vec![7; 32]],
+                data: vec![8],
+                block_hash: vec![3; 32],
+                block_number: 4,
+                transaction_hash: vec![1; 32],
+                transaction_index: 2,
+                log_index: 0,
+                transaction_log_index: 0,
+            }],
+            state_root: vec![9; 32],
+            logs_bloom: vec![0; 256],
+            error_message: String::new(),
+        };
+        assert_eq!(Receipt::from(ReceiptJson::from(receipt.clone())), receipt);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hex encoding and the fixed length byte strings of CITA-Cloud. Hex is rendered `0x`
+//! prefixed and lowercase, parsing accepts either case, with or without the prefix.
+
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub const ADDRESS_LEN: usize = 20;
+pub const HASH_LEN: usize = 32;
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    for byte in bytes {
+        hex.push(DIGITS[(byte >> 4) as usize] as char);
+        hex.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
+
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let digits = strip_prefix(text);
+    if !digits.len().is_multiple_of(2) {
+        return Err(eyre!("invalid hex `{text}`: odd length"));
+    }
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(digit(text, pair[0])? << 4 | digit(text, pair[1])?))
+        .collect()
+}
+
+/// Parses hex of exactly `N` bytes.
+pub fn parse_hex_array<const N: usize>(text: &str) -> Result<[u8; N]> {
+    parse_hex(text)?
+        .try_into()
+        .map_err(|bytes: Vec<u8>| eyre!("invalid hex `{text}`: {} bytes, not {N}", bytes.len()))
+}
+
+pub(crate) fn strip_prefix(text: &str) -> &str {
+    text.strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text)
+}
+
+fn digit(text: &str, c: u8) -> Result<u8> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(eyre!("invalid hex `{text}`: unexpected `{}`", c as char)),
+    }
+}
+
+/// `#[serde(with = "hex")]` for `Vec<u8>` fields.
+pub mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::to_hex(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_hex(&text).map_err(D::Error::custom)
+    }
+}
+
+macro_rules! fixed_bytes {
+    ($(#[$doc:meta])* $name:ident, $len:expr) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name([u8; $len]);
+
+        impl $name {
+            pub const LEN: usize = $len;
+
+            pub const fn new(bytes: [u8; $len]) -> Self {
+                Self(bytes)
+            }
+
+            pub const fn as_bytes(&self) -> &[u8; $len] {
+                &self.0
+            }
+
+            pub const fn into_bytes(self) -> [u8; $len] {
+                self.0
+            }
+
+            pub fn to_vec(&self) -> Vec<u8> {
+                self.0.to_vec()
+            }
+        }
+
+        impl From<[u8; $len]> for $name {
+            fn from(bytes: [u8; $len]) -> Self {
+                Self(bytes)
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = Report;
+
+            fn try_from(bytes: &[u8]) -> Result<Self> {
+                bytes.try_into().map(Self).map_err(|_| {
+                    eyre!(
+                        "invalid {}: {} bytes, not {}",
+                        stringify!($name).to_lowercase(),
+                        bytes.len(),
+                        $len
+                    )
+                })
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Report;
+
+            fn from_str(text: &str) -> Result<Self> {
+                parse_hex_array(text).map(Self)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str(&to_hex(&self.0))
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({self})", stringify!($name))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&to_hex(&self.0))
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let text = String::deserialize(deserializer)?;
+                text.parse().map_err(D::Error::custom)
+            }
+        }
+    };
+}
+
+fixed_bytes!(
+    /// A 20 byte account or contract address.
+    Address,
+    ADDRESS_LEN
+);
+
+fixed_bytes!(
+    /// A 32 byte block or transaction hash.
+    Hash,
+    HASH_LEN
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x0f, 0xa0, 0xff];
+        assert_eq!(to_hex(&bytes), "0x000fa0ff");
+        assert_eq!(parse_hex("0x000fa0ff").unwrap(), bytes);
+        assert_eq!(parse_hex("0X000FA0FF").unwrap(), bytes);
+        assert_eq!(parse_hex("000fA0fF").unwrap(), bytes);
+        assert_eq!(to_hex(&[]), "0x");
+        assert!(parse_hex("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn hex_rejected() {
+        assert!(parse_hex("0x0").is_err());
+        assert!(parse_hex("0xgg").is_err());
+        assert!(parse_hex("0x 0").is_err());
+        assert!(parse_hex("0x0x00").is_err());
+    }
+
+    #[test]
+    fn hex_array_checks_length() {
+        assert_eq!(parse_hex_array::<2>("0x0102").unwrap(), [1, 2]);
+        let e = parse_hex_array::<2>("0x010203").unwrap_err();
+        assert_eq!(e.to_string(), "invalid hex `0x010203`: 3 bytes, not 2");
+    }
+
+    #[test]
+    fn address_round_trip() {
+        let text = "0x0123456789abcdef0123456789abcdef01234567";
+        let address: Address = text.parse().unwrap();
+        assert_eq!(address.to_string(), text);
+        assert_eq!(format!("{address:?}"), format!("Address({text})"));
+        assert_eq!(
+            text.to_uppercase()
+                .replacen("0X", "0x", 1)
+                .parse::<Address>()
+                .unwrap(),
+            address
+        );
+        assert_eq!(Address::try_from(address.as_ref()).unwrap(), address);
+        assert_eq!(Address::new(address.into_bytes()), address);
+    }
+
+    #[test]
+    fn address_rejects_other_lengths() {
+        assert!("0x0123".parse::<Address>().is_err());
+        let e = Address::try_from(&[0u8; HASH_LEN][..]).unwrap_err();
+        assert_eq!(e.to_string(), "invalid address: 32 bytes, not 20");
+        assert!(Hash::try_from(&[0u8; HASH_LEN][..]).is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt::{self, Display},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Version of this crate the service was built with.
+pub const COMMON_RS_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What a binary was built from, captured at compile time by [`build_info!`](crate::build_info).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_time: &'static str,
+    pub rustc: &'static str,
+    pub common_rs: &'static str,
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (commit {}, built {}, {}, common-rs {})",
+            self.name, self.version, self.git_commit, self.build_time, self.rustc, self.common_rs
+        )
+    }
+}
+
+/// The [`BuildInfo`] of the crate calling it. The git commit, build time and rustc
+/// version come from a build script calling [`emit_build_env`], `unknown` without one.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::version::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: match option_env!("GIT_COMMIT") {
+                Some(commit) => commit,
+                None => "unknown",
+            },
+            build_time: match option_env!("BUILD_TIME") {
+                Some(time) => time,
+                None => "unknown",
+            },
+            rustc: match option_env!("RUSTC_VERSION") {
+                Some(rustc) => rustc,
+                None => "unknown",
+            },
+            common_rs: $crate::version::COMMON_RS_VERSION,
+        }
+    };
+}
+
+/// Sets the variables read by [`build_info!`](crate::build_info), to be called from the
+/// `build.rs` of a service with this crate as a build dependency. The build time honors
+/// `SOURCE_DATE_EPOCH` for reproducible builds.
+///
+/// The build script runs again once the checked out commit changes, so
+/// [`build_info!`](crate::build_info) never reports a stale commit; the build time is the
+/// time of that run.
+pub fn emit_build_env() {
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    if let Some(git_dir) = output("git", &["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        // a commit on the checked out branch moves the branch, not HEAD
+        if let Some(branch) = output("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={git_dir}/{branch}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let rustc = output(
+        &std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned()),
+        &["--version"],
+    );
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT={commit}");
+    }
+    if let Some(rustc) = rustc {
+        println!("cargo:rustc-env=RUSTC_VERSION={rustc}");
+    }
+    println!("cargo:rustc-env=BUILD_TIME={}", rfc3339(secs));
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .filter(|s| !s.is_empty())
+}
+
+/// `secs` since the unix epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3339_dates() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(946_684_799), "1999-12-31T23:59:59Z");
+        // 2000 is a leap year, being divisible by 400, and 2100 is not
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_709_210_096), "2024-02-29T12:34:56Z");
+        assert_eq!(rfc3339(4_107_542_400), "2100-03-01T00:00:00Z");
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use salvo::{
+    prelude::*,
+    websocket::{Message, WebSocket, WebSocketUpgrade},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+use crate::restful::RESTfulError;
+
+/// Close code sent to a client dropped for not keeping up with its queue.
+const POLICY_VIOLATION: u16 = 1008;
+/// Close code sent when the broadcaster or the heartbeat ends the session.
+const GOING_AWAY: u16 = 1001;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WsConfig {
+    /// seconds between the pings sent to every client
+    pub heartbeat_interval: u64,
+    /// seconds without any frame from a client before it is disconnected
+    pub idle_timeout: u64,
+    /// messages queued for one client before it is dropped as too slow
+    pub queue_size: usize,
+    /// largest message accepted from a client, in bytes
+    pub max_message_size: usize,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: 15,
+            idle_timeout: 45,
+            queue_size: 64,
+            max_message_size: 64 * 1024,
+        }
+    }
+}
+
+/// Fans out notifications of type `T` to every websocket client subscribed through
+/// [`Broadcaster::handler`]. Each message is serialized to JSON once, on publish.
+pub struct Broadcaster<T> {
+    sender: broadcast::Sender<String>,
+    _message: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for Broadcaster<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize> Broadcaster<T> {
+    /// `capacity` bounds how far the slowest client's queue feeder may fall behind.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self {
+            sender,
+            _message: PhantomData,
+        }
+    }
+
+    /// Publishes `message` and returns how many clients it was queued for.
+    pub fn publish(&self, message: &T) -> Result<usize> {
+        let text = serde_json::to_string(message)
+            .map_err(|e| eyre!("serialize websocket message failed: {e}"))?;
+        Ok(self.sender.send(text).unwrap_or(0))
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// A handler upgrading requests to websocket sessions fed by this broadcaster.
+    pub fn handler(&self, config: WsConfig) -> Subscribe {
+        Subscribe {
+            sender: self.sender.clone(),
+            config,
+        }
+    }
+}
+
+/// Upgrades the request and streams every published message to the client as a text
+/// frame. Frames sent by the client only count as liveness.
+pub struct Subscribe {
+    sender: broadcast::Sender<String>,
+    config: WsConfig,
+}
+
+#[async_trait]
+impl Handler for Subscribe {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        let receiver = self.sender.subscribe();
+        let config = self.config;
+        if let Err(e) = WebSocketUpgrade::new()
+            .max_message_size(config.max_message_size)
+            .upgrade(req, res, move |ws| session(ws, receiver, config))
+            .await
+        {
+            RESTfulError {
+                code: e.code.as_u16(),
+                err: e.brief,
+            }
+            .write(req, depot, res)
+            .await;
+        }
+    }
+}
+
+async fn session(mut ws: WebSocket, mut receiver: broadcast::Receiver<String>, config: WsConfig) {
+    let (queue, mut outbound) = mpsc::channel::<String>(config.queue_size.max(1));
+    let mut feeder = tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(text) => {
+                    if queue.try_send(text).is_err() {
+                        return Some("outbound queue full");
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => return Some("lagged behind"),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    let period = Duration::from_secs(config.heartbeat_interval.max(1));
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut last_seen = Instant::now();
+    let close = loop {
+        tokio::select! {
+            incoming = ws.recv() => match incoming {
+                Some(Ok(message)) if message.is_close() => break None,
+                Some(Ok(_)) => last_seen = Instant::now(),
+                Some(Err(e)) => {
+                    debug!("websocket receive failed: {e}");
+                    break None;
+                }
+                None => break None,
+            },
+            text = outbound.recv() => match text {
+                Some(text) => {
+                    if let Err(e) = ws.send(Message::text(text)).await {
+                        debug!("websocket send failed: {e}");
+                        break None;
+                    }
+                }
+                None => break Some(match (&mut feeder).await.ok().flatten() {
+                    Some(reason) => {
+                        warn!("websocket client dropped: {reason}");
+                        Message::close_with(POLICY_VIOLATION, reason)
+                    }
+                    None => Message::close_with(GOING_AWAY, "broadcaster closed"),
+                }),
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > idle_timeout {
+                    break Some(Message::close_with(GOING_AWAY, "idle timeout"));
+                }
+                if let Err(e) = ws.send(Message::ping(Vec::new())).await {
+                    debug!("websocket ping failed: {e}");
+                    break None;
+                }
+            }
+        }
+    };
+    feeder.abort();
+    if let Some(close) = close {
+        let _ = ws.send(close).await;
+    }
+    let _ = ws.close().await;
+}